@@ -67,7 +67,6 @@ async fn main() {
                 port_allocated: info.payload.port_allocated,
                 port_capacity: info.payload.port_capacity,
             }])
-            .to_string()
         );
 
         println!("Interfaces:");
@@ -89,7 +88,6 @@ async fn main() {
                     })
                     .collect::<Vec<Interface>>()
             )
-            .to_string()
         );
     } else {
         println!("turn server not runing!");
@@ -78,13 +78,9 @@ pub struct Statistics {
     pub error_pkts: u64,
 }
 
-impl<'a> Display for SessionAddr {
+impl Display for SessionAddr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            format!("address={}&interface={}", self.address, self.interface)
-        )
+        write!(f, "address={}&interface={}", self.address, self.interface)
     }
 }
 
@@ -1,5 +1,6 @@
 use criterion::*;
-use mycrl_stun::Decoder;
+use hmac::{Hmac, Mac};
+use mycrl_stun::{util, Attributes, Decoder, MessageReader};
 
 const CHANNEL_BIND: [u8; 108] = [
     0x00, 0x09, 0x00, 0x58, 0x21, 0x12, 0xa4, 0x42, 0x35, 0x6a, 0x52, 0x42, 0x33, 0x4c, 0x65, 0x68,
@@ -41,6 +42,38 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 
     stun_decoder.finish();
+
+    // The auth hot path, the sha1 of the hmac uses the sha extensions of the
+    // cpu when they are detected at runtime.
+    let mut stun_auth = c.benchmark_group("stun_auth");
+    let key = util::long_term_credential_digest("panda", "panda", "raspberry");
+
+    stun_auth.bench_function("long_term_credential_digest", |b| {
+        b.iter(|| util::long_term_credential_digest("panda", "panda", "raspberry"))
+    });
+
+    stun_auth.throughput(Throughput::Bytes(channel_bind.len() as u64));
+    stun_auth.bench_function("hmac_sha1", |b| {
+        b.iter(|| util::hmac_sha1(&key, &[channel_bind]).unwrap())
+    });
+
+    // The rust implementation, to compare with the hmac of openssl when the
+    // `openssl` feature is enabled.
+    stun_auth.bench_function("hmac_sha1_rust", |b| {
+        b.iter(|| {
+            let mut mac = Hmac::<sha1::Sha1>::new_from_slice(&key).unwrap();
+            mac.update(channel_bind);
+            mac.finalize()
+        })
+    });
+
+    let mut attributes = Attributes::default();
+    let message = MessageReader::decode(channel_bind, &mut attributes).unwrap();
+    stun_auth.bench_function("message_integrity", |b| {
+        b.iter(|| message.integrity(&key).is_ok())
+    });
+
+    stun_auth.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
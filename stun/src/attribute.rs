@@ -189,6 +189,28 @@ impl Addr {
     ///
     /// let addr = Addr::decode(&addr_bytes, &token, false).unwrap();
     /// assert_eq!(addr, source);
+    ///
+    /// // rfc5769 2.3. sample ipv6 response
+    /// let xor_addr_bytes: [u8; 20] = [
+    ///     0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79,
+    ///     0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+    /// ];
+    ///
+    /// let token: [u8; 12] = [
+    ///     0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    /// ];
+    ///
+    /// let source = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+    ///
+    /// let addr = Addr::decode(&xor_addr_bytes, &token, true).unwrap();
+    /// assert_eq!(addr, source);
+    ///
+    /// let mut buffer = bytes::BytesMut::with_capacity(1280);
+    /// Addr::encode(&source, &token, &mut buffer, true);
+    /// assert_eq!(&xor_addr_bytes, &buffer[..]);
+    ///
+    /// // the family does not match the length of the address.
+    /// assert!(Addr::decode(&xor_addr_bytes[..8], &token, true).is_err());
     /// ```
     pub fn decode(packet: &[u8], token: &[u8], is_xor: bool) -> Result<SocketAddr, StunError> {
         if packet.len() < 4 {
@@ -330,8 +352,10 @@ pub fn xor_v6(addr: Ipv6Addr, token: &[u8]) -> IpAddr {
         *b ^= (0x2112A442 >> (24 - i * 8)) as u8;
     }
 
-    for (i, b) in octets.iter_mut().enumerate().take(16).skip(4) {
-        *b ^= token[i - 4];
+    // The token is the 96-bit transaction id, a shorter token only covers part of
+    // the address instead of reading out of bounds.
+    for (b, t) in octets.iter_mut().skip(4).zip(token) {
+        *b ^= t;
     }
 
     IpAddr::V6(From::from(octets))
@@ -433,6 +457,7 @@ pub enum AttrKind {
     AddressErrorCode = 0x8001,
     Icmp = 0x8004,
     Software = 0x8022,
+    AlternateServer = 0x8023,
     Fingerprint = 0x8028,
     IceControlled = 0x8029,
     IceControlling = 0x802A,
@@ -656,6 +681,33 @@ impl<'a> Attribute<'a> for MessageIntegrity {
 /// seen from the TURN server.  (For example, the peer's server-reflexive
 /// transport address if the peer is behind a NAT.)  It is encoded in the
 /// same way as XOR-MAPPED-ADDRESS [RFC5389].
+///
+/// # Test
+///
+/// ```
+/// use bytes::BytesMut;
+/// use mycrl_stun::attribute::*;
+///
+/// let token: [u8; 12] = [
+///     0x6c, 0x46, 0x62, 0x54, 0x75, 0x4b, 0x44, 0x51, 0x46, 0x48, 0x4c, 0x71,
+/// ];
+///
+/// for source in [
+///     "192.168.0.107:56748",
+///     "[2001:db8::1]:3478",
+///     "[::ffff:192.10.47.15]:8080",
+///     "[fe80::1:2:3:4]:65535",
+/// ] {
+///     let source = source.parse().unwrap();
+///
+///     let mut buffer = BytesMut::with_capacity(1280);
+///     XorPeerAddress::encode(source, &mut buffer, &token);
+///     assert_eq!(buffer.len(), if source.is_ipv4() { 8 } else { 20 });
+///
+///     let addr = XorPeerAddress::decode(&buffer, &token).unwrap();
+///     assert_eq!(addr, source);
+/// }
+/// ```
 pub struct XorPeerAddress;
 
 impl<'a> Attribute<'a> for XorPeerAddress {
@@ -679,6 +731,33 @@ impl<'a> Attribute<'a> for XorPeerAddress {
 /// specifies the address and port that the server allocated to the
 /// client.  It is encoded in the same way as XOR-MAPPED-ADDRESS
 /// [RFC5389].
+///
+/// # Test
+///
+/// ```
+/// use bytes::BytesMut;
+/// use mycrl_stun::attribute::*;
+///
+/// let token: [u8; 12] = [
+///     0x6c, 0x46, 0x62, 0x54, 0x75, 0x4b, 0x44, 0x51, 0x46, 0x48, 0x4c, 0x71,
+/// ];
+///
+/// for source in [
+///     "192.168.0.107:56748",
+///     "[2001:db8::1]:3478",
+///     "[::ffff:192.10.47.15]:8080",
+///     "[fe80::1:2:3:4]:65535",
+/// ] {
+///     let source = source.parse().unwrap();
+///
+///     let mut buffer = BytesMut::with_capacity(1280);
+///     XorRelayedAddress::encode(source, &mut buffer, &token);
+///     assert_eq!(buffer.len(), if source.is_ipv4() { 8 } else { 20 });
+///
+///     let addr = XorRelayedAddress::decode(&buffer, &token).unwrap();
+///     assert_eq!(addr, source);
+/// }
+/// ```
 pub struct XorRelayedAddress;
 
 impl<'a> Attribute<'a> for XorRelayedAddress {
@@ -729,6 +808,33 @@ impl<'a> Attribute<'a> for XorRelayedAddress {
 /// misguided attempt to provide a generic Application Layer Gateway
 /// (ALG) function.  Such behavior interferes with the operation of STUN
 /// and also causes failure of STUN's message-integrity checking.
+///
+/// # Test
+///
+/// ```
+/// use bytes::BytesMut;
+/// use mycrl_stun::attribute::*;
+///
+/// let token: [u8; 12] = [
+///     0x6c, 0x46, 0x62, 0x54, 0x75, 0x4b, 0x44, 0x51, 0x46, 0x48, 0x4c, 0x71,
+/// ];
+///
+/// for source in [
+///     "192.168.0.107:56748",
+///     "[2001:db8::1]:3478",
+///     "[::ffff:192.10.47.15]:8080",
+///     "[fe80::1:2:3:4]:65535",
+/// ] {
+///     let source = source.parse().unwrap();
+///
+///     let mut buffer = BytesMut::with_capacity(1280);
+///     XorMappedAddress::encode(source, &mut buffer, &token);
+///     assert_eq!(buffer.len(), if source.is_ipv4() { 8 } else { 20 });
+///
+///     let addr = XorMappedAddress::decode(&buffer, &token).unwrap();
+///     assert_eq!(addr, source);
+/// }
+/// ```
 pub struct XorMappedAddress;
 
 impl<'a> Attribute<'a> for XorMappedAddress {
@@ -783,6 +889,28 @@ impl<'a> Attribute<'a> for MappedAddress {
     }
 }
 
+/// The alternate server represents an alternate transport address
+/// identifying a different STUN server that the STUN client should try.
+///
+/// It is encoded in the same way as MAPPED-ADDRESS, and thus refers to a
+/// single server by IP address.
+pub struct AlternateServer;
+
+impl<'a> Attribute<'a> for AlternateServer {
+    type Error = StunError;
+    type Item = SocketAddr;
+
+    const KIND: AttrKind = AttrKind::AlternateServer;
+
+    fn encode(value: Self::Item, bytes: &mut BytesMut, token: &'a [u8]) {
+        Addr::encode(&value, token, bytes, false)
+    }
+
+    fn decode(bytes: &'a [u8], token: &'a [u8]) -> Result<Self::Item, Self::Error> {
+        Addr::decode(bytes, token, false)
+    }
+}
+
 /// The RESPONSE-ORIGIN attribute is inserted by the server and indicates
 /// the source IP address and port the response was sent from.  It is
 /// useful for detecting double NAT configurations.  It is only present
@@ -844,6 +972,17 @@ impl<'a> Attribute<'a> for ResponseOrigin {
 ///      
 /// 500  Server Error: The server has suffered a temporary error.  The
 ///      client should try again.
+///
+/// RFC8656 adds the following TURN specific codes:
+///
+/// 403  Forbidden, 437  Allocation Mismatch, 440  Address Family not
+/// Supported, 441  Wrong Credentials, 442  Unsupported Transport Protocol,
+/// 443  Peer Address Family Mismatch, 486  Allocation Quota Reached,
+/// 508  Insufficient Capacity.
+///
+/// The 446 (Connection Already Exists) and 447 (Connection Timeout or
+/// Failure) codes of RFC6062 only apply to the TCP relaying, which is not
+/// supported, an allocate request for it is answered with 442.
 const fn errno(code: u16) -> u16 {
     ((code / 100) << 8) | (code % 100)
 }
@@ -1011,7 +1150,7 @@ impl From<ErrorKind> for &'static str {
             ErrorKind::StaleNonce => "Stale Nonce",
             ErrorKind::AddressFamilyNotSupported => "Address Family not Supported",
             ErrorKind::WrongCredentials => "Wrong Credentials",
-            ErrorKind::UnsupportedTransportAddress => "Unsupported Transport Address",
+            ErrorKind::UnsupportedTransportAddress => "Unsupported Transport Protocol",
             ErrorKind::AllocationQuotaReached => "Allocation Quota Reached",
             ErrorKind::ServerError => "Server Error",
             ErrorKind::InsufficientCapacity => "Insufficient Capacity",
@@ -1105,8 +1244,9 @@ impl<'a> Attribute<'a> for ReqeestedTransport {
     }
 
     fn decode(bytes: &'a [u8], _: &'a [u8]) -> Result<Self::Item, Self::Error> {
+        // Only the protocol is compared, the RFFU field is ignored.
         let value = u32::from_be_bytes(bytes.try_into()?);
-        Transport::try_from(value).map_err(|_| StunError::InvalidInput)
+        Transport::try_from(value & 0xFF000000).map_err(|_| StunError::InvalidInput)
     }
 }
 
@@ -1382,3 +1522,65 @@ impl<'a> Attribute<'a> for DontFragment {
         Ok(())
     }
 }
+
+/// The ICMP error that the server received for a datagram that it relayed to
+/// the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpError {
+    /// The type of the ICMP message, its interpretation depends on whether
+    /// the ICMP was received over IPv4 or IPv6.
+    pub kind: u8,
+    /// The code of the ICMP message.
+    pub code: u8,
+    /// The error data of the ICMP message, such as the MTU of a "Packet Too
+    /// Big" message.
+    pub data: u32,
+}
+
+/// This attribute is used by servers to signal the reason a UDP packet was
+/// dropped.  The value portion of this attribute is 8 bytes long, the
+/// Reserved field MUST be sent as 0 and ignored when received, followed by
+/// the ICMP Type, the ICMP Code and the Error Data.
+///
+/// # Test
+///
+/// ```
+/// use bytes::BytesMut;
+/// use mycrl_stun::attribute::*;
+///
+/// let buffer = [0x00u8, 0x00, 0x03, 0x03, 0x00, 0x00, 0x00, 0x00];
+/// let error = IcmpError {
+///     kind: 3,
+///     code: 3,
+///     data: 0,
+/// };
+///
+/// let mut buf = BytesMut::with_capacity(1280);
+/// Icmp::encode(error, &mut buf, &[]);
+/// assert_eq!(&buf[..], &buffer);
+/// assert_eq!(Icmp::decode(&buffer, &[]).unwrap(), error);
+/// ```
+pub struct Icmp;
+
+impl<'a> Attribute<'a> for Icmp {
+    type Error = StunError;
+    type Item = IcmpError;
+
+    const KIND: AttrKind = AttrKind::Icmp;
+
+    fn encode(value: Self::Item, bytes: &mut BytesMut, _: &'a [u8]) {
+        bytes.put_u16(0x0000);
+        bytes.put_u8(value.kind);
+        bytes.put_u8(value.code);
+        bytes.put_u32(value.data);
+    }
+
+    fn decode(bytes: &'a [u8], _: &'a [u8]) -> Result<Self::Item, Self::Error> {
+        let bytes: [u8; 8] = bytes.try_into()?;
+        Ok(IcmpError {
+            kind: bytes[2],
+            code: bytes[3],
+            data: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        })
+    }
+}
@@ -47,6 +47,8 @@
 pub mod attribute;
 pub mod channel;
 pub mod message;
+#[cfg(feature = "serde")]
+pub mod serialize;
 pub mod util;
 
 pub use self::{
@@ -128,17 +130,34 @@ pub enum Method {
     Refresh(Kind),
     SendIndication,
     DataIndication,
+    /// A method that the codec does not know, such as a custom indication,
+    /// with the message type that carries it.
+    Other(u16),
 }
 
 impl Method {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_stun::*;
+    ///
+    /// assert!(Method::Binding(Kind::Error).is_error());
+    /// assert!(!Method::Binding(Kind::Response).is_error());
+    /// assert!(Method::Other(0x0310).is_error());
+    /// assert!(!Method::Other(0x0210).is_error());
+    /// ```
     pub fn is_error(&self) -> bool {
         match self {
-            Method::Binding(Kind::Error)
-            | Method::Refresh(Kind::Error)
-            | Method::Allocate(Kind::Error)
-            | Method::CreatePermission(Kind::Error)
-            | Method::ChannelBind(Kind::Error) => true,
-            _ => false,
+            // The class bits of the error responses.
+            Method::Other(it) => it & 0x0110 == 0x0110,
+            _ => matches!(
+                self,
+                Method::Binding(Kind::Error)
+                    | Method::Refresh(Kind::Error)
+                    | Method::Allocate(Kind::Error)
+                    | Method::CreatePermission(Kind::Error)
+                    | Method::ChannelBind(Kind::Error)
+            ),
         }
     }
 }
@@ -214,6 +233,8 @@ impl TryFrom<u16> for Method {
     /// );
     /// assert_eq!(Method::try_from(0x0016).unwrap(), Method::SendIndication);
     /// assert_eq!(Method::try_from(0x0017).unwrap(), Method::DataIndication);
+    /// assert_eq!(Method::try_from(0x0210).unwrap(), Method::Other(0x0210));
+    /// assert!(Method::try_from(0x4000).is_err());
     /// ```
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         Ok(match value {
@@ -234,6 +255,8 @@ impl TryFrom<u16> for Method {
             0x0114 => Self::Refresh(Kind::Error),
             0x0016 => Self::SendIndication,
             0x0017 => Self::DataIndication,
+            // The two most significant bits of a stun message type are zero.
+            it if it & 0xC000 == 0 => Self::Other(it),
             _ => return Err(StunError::UnknownMethod),
         })
     }
@@ -263,6 +286,7 @@ impl From<Method> for u16 {
     /// assert_eq!(0x0114u16, Method::Refresh(Kind::Error).into());
     /// assert_eq!(0x0016u16, Method::SendIndication.into());
     /// assert_eq!(0x0017u16, Method::DataIndication.into());
+    /// assert_eq!(0x0210u16, Method::Other(0x0210).into());
     /// ```
     fn from(val: Method) -> Self {
         match val {
@@ -283,6 +307,7 @@ impl From<Method> for u16 {
             Method::Refresh(Kind::Error) => 0x0114,
             Method::SendIndication => 0x0016,
             Method::DataIndication => 0x0017,
+            Method::Other(it) => it,
         }
     }
 }
@@ -329,7 +354,6 @@ impl Attributes {
             .iter()
             .filter(move |(k, _)| k == kind)
             .map(|(_, v)| v)
-            .into_iter()
     }
 
     pub fn clear(&mut self) {
@@ -339,6 +363,54 @@ impl Attributes {
     }
 }
 
+/// The class of a packet, that the demultiplexer tells from the header of
+/// the packet without decoding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketClass {
+    /// A stun message whose header is valid.
+    Message,
+    /// A channel data message whose length fits the packet.
+    ChannelData,
+    /// Shorter than the header of a message.
+    Truncated,
+    /// Neither a stun message nor a channel data message, such as rtp or
+    /// dtls.
+    Unknown,
+    /// A stun header without the magic cookie.
+    BadCookie,
+    /// A stun header whose length is not a multiple of 4 or is not the size
+    /// of the packet, or a channel data whose length does not fit the
+    /// packet.
+    BadLength,
+}
+
+impl PacketClass {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_stun::PacketClass;
+    ///
+    /// assert_eq!(PacketClass::BadCookie.as_str(), "bad_cookie");
+    /// assert!(PacketClass::ChannelData.is_valid());
+    /// assert!(!PacketClass::Unknown.is_valid());
+    /// ```
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Message => "message",
+            Self::ChannelData => "channel_data",
+            Self::Truncated => "truncated",
+            Self::Unknown => "unknown",
+            Self::BadCookie => "bad_cookie",
+            Self::BadLength => "bad_length",
+        }
+    }
+
+    /// Whether the packet is dispatched to the decoder.
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Self::Message | Self::ChannelData)
+    }
+}
+
 #[derive(Default)]
 pub struct Decoder(Attributes);
 
@@ -367,20 +439,68 @@ impl Decoder {
     /// }
     /// ```
     pub fn decode<'a>(&'a mut self, bytes: &'a [u8]) -> Result<Payload<'a>, StunError> {
-        assert!(bytes.len() >= 4);
+        Ok(match Self::classify(bytes) {
+            PacketClass::Message => {
+                self.0.clear();
 
-        let flag = bytes[0] >> 6;
-        if flag > 3 {
-            return Err(StunError::InvalidInput);
-        }
+                Payload::Message(MessageReader::decode(bytes, &mut self.0)?)
+            }
+            PacketClass::ChannelData => Payload::ChannelData(ChannelData::try_from(bytes)?),
+            PacketClass::BadCookie => return Err(StunError::NotCookie),
+            _ => return Err(StunError::InvalidInput),
+        })
+    }
 
-        Ok(if flag == 0 {
-            self.0.clear();
+    /// Tell the class of the packet from its header, the packet is a whole
+    /// message, with the padding of the channel data if any.
+    ///
+    /// The first two bits of a stun message are zero, its length is a
+    /// multiple of 4 and is the size of the packet after the header, and it
+    /// carries the magic cookie. The channel numbers are within 0x4000 and
+    /// 0x7FFF, and the length of a channel data is the size of the packet
+    /// after the header, apart from the padding.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_stun::*;
+    ///
+    /// let binding = [
+    ///     0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 0x72, 0x6d, 0x49, 0x42,
+    ///     0x72, 0x52, 0x64, 0x48, 0x57, 0x62, 0x4b, 0x2b,
+    /// ];
+    ///
+    /// assert_eq!(Decoder::classify(&binding), PacketClass::Message);
+    /// assert_eq!(Decoder::classify(&binding[..12]), PacketClass::Truncated);
+    /// assert_eq!(Decoder::classify(&[0u8; 20]), PacketClass::BadCookie);
+    ///
+    /// let mut padded = binding.to_vec();
+    /// padded.extend_from_slice(&[0u8; 4]);
+    /// assert_eq!(Decoder::classify(&padded), PacketClass::BadLength);
+    ///
+    /// assert_eq!(Decoder::classify(&[0x40, 0x00, 0x00, 0x01, 0xff]), PacketClass::ChannelData);
+    /// assert_eq!(
+    ///     Decoder::classify(&[0x40, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00, 0x00]),
+    ///     PacketClass::ChannelData
+    /// );
+    /// assert_eq!(Decoder::classify(&[0x40, 0x00, 0x00, 0x08, 0xff]), PacketClass::BadLength);
+    /// assert_eq!(Decoder::classify(&[0x80, 0x60, 0x00, 0x01, 0xff]), PacketClass::Unknown);
+    /// ```
+    pub fn classify(bytes: &[u8]) -> PacketClass {
+        if bytes.len() < 4 {
+            return PacketClass::Truncated;
+        }
 
-            Payload::Message(MessageReader::decode(bytes, &mut self.0)?)
-        } else {
-            Payload::ChannelData(ChannelData::try_from(bytes)?)
-        })
+        let size = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        match bytes[0] >> 6 {
+            0 if bytes.len() < 20 => PacketClass::Truncated,
+            0 if bytes[4..8] != message::COOKIE => PacketClass::BadCookie,
+            0 if !size.is_multiple_of(4) || size + 20 != bytes.len() => PacketClass::BadLength,
+            0 => PacketClass::Message,
+            1 if size + 4 > bytes.len() || bytes.len() - 4 - size > 3 => PacketClass::BadLength,
+            1 => PacketClass::ChannelData,
+            _ => PacketClass::Unknown,
+        }
     }
 
     /// # Test
@@ -8,17 +8,19 @@ use super::{
 };
 
 const ZOER_BUF: [u8; 10] = [0u8; 10];
-const COOKIE: [u8; 4] = 0x2112A442u32.to_be_bytes();
+pub(crate) const COOKIE: [u8; 4] = 0x2112A442u32.to_be_bytes();
 
-/// (username, password, realm)
-type Digest = [u8; 16];
+// The key of the message integrity, the digest of (username, realm, password)
+// for the long-term credentials, or the password for the short-term
+// credentials.
+type Digest = [u8];
 
 pub struct MessageWriter<'a> {
     pub token: &'a [u8],
     pub bytes: &'a mut BytesMut,
 }
 
-impl<'a, 'b> MessageWriter<'a> {
+impl<'a> MessageWriter<'a> {
     pub fn new(method: Method, token: &'a [u8; 12], bytes: &'a mut BytesMut) -> Self {
         unsafe { bytes.set_len(0) }
         bytes.put_u16(method.into());
@@ -236,6 +238,26 @@ impl<'a, 'b> MessageWriter<'a> {
     }
 }
 
+/// The part of a message that MessageIntegrity signs, and the
+/// MessageIntegrity of the message.
+#[derive(Debug, Clone)]
+pub struct SignedMessage {
+    bytes: Vec<u8>,
+    integrity: Vec<u8>,
+}
+
+impl SignedMessage {
+    /// check whether the MessageIntegrity of the message matches the key.
+    pub fn verify(&self, digest: &Digest) -> Result<(), StunError> {
+        let hmac_output = util::hmac_sha1(digest, &[&self.bytes])?.into_bytes();
+        if self.integrity != hmac_output.as_slice() {
+            return Err(StunError::IntegrityFailed);
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct MessageReader<'a> {
     /// message type.
@@ -276,6 +298,56 @@ impl<'a> MessageReader<'a> {
         T::decode(&self.bytes[range.clone()], self.token).ok()
     }
 
+    /// Whether the message has the attribute, even if its value can not be
+    /// decoded.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_stun::attribute::*;
+    /// use mycrl_stun::*;
+    ///
+    /// let buffer = [
+    ///     0x00u8, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 0x72, 0x6d, 0x49,
+    ///     0x42, 0x72, 0x52, 0x64, 0x48, 0x57, 0x62, 0x4b, 0x2b,
+    /// ];
+    ///
+    /// let mut attributes = Attributes::default();
+    /// let message = MessageReader::decode(&buffer[..], &mut attributes).unwrap();
+    /// assert!(!message.contains::<UserName>());
+    /// ```
+    pub fn contains<T: Attribute<'a>>(&self) -> bool {
+        self.attributes.get(&T::KIND).is_some()
+    }
+
+    /// All the attributes in the order of the message, with the raw values.
+    pub fn attributes(&self) -> impl Iterator<Item = (AttrKind, &'a [u8])> + '_ {
+        self.attributes
+            .0
+            .iter()
+            .map(|(kind, range)| (*kind, &self.bytes[range.clone()]))
+    }
+
+    /// The size of the message in bytes.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_stun::*;
+    ///
+    /// let buffer = [
+    ///     0x00u8, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 0x72, 0x6d, 0x49,
+    ///     0x42, 0x72, 0x52, 0x64, 0x48, 0x57, 0x62, 0x4b, 0x2b,
+    /// ];
+    ///
+    /// let mut attributes = Attributes::default();
+    /// let message = MessageReader::decode(&buffer[..], &mut attributes).unwrap();
+    /// assert_eq!(message.size(), 20);
+    /// ```
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
     /// Gets all the values of an attribute from a list.
     ///
     /// Normally a stun message can have multiple attributes with the same name,
@@ -301,9 +373,7 @@ impl<'a> MessageReader<'a> {
     pub fn get_all<T: Attribute<'a>>(&self) -> impl Iterator<Item = T::Item> {
         self.attributes
             .get_all(&T::KIND)
-            .map(|it| T::decode(&self.bytes[it.clone()], self.token))
-            .filter(|it| it.is_ok())
-            .map(|it| it.unwrap())
+            .filter_map(|it| T::decode(&self.bytes[it.clone()], self.token).ok())
     }
 
     /// check MessageReaderIntegrity attribute.
@@ -371,6 +441,60 @@ impl<'a> MessageReader<'a> {
         Ok(())
     }
 
+    /// copy the part of the message that MessageIntegrity signs.
+    ///
+    /// the copy owns its bytes, so that the message integrity can be checked
+    /// after the message is dropped, such as on another thread.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use std::convert::TryFrom;
+    /// use mycrl_stun::*;
+    ///
+    /// let buffer = [
+    ///     0x00u8, 0x03, 0x00, 0x50, 0x21, 0x12, 0xa4, 0x42, 0x64, 0x4f, 0x5a,
+    ///     0x78, 0x6a, 0x56, 0x33, 0x62, 0x4b, 0x52, 0x33, 0x31, 0x00, 0x19, 0x00,
+    ///     0x04, 0x11, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x05, 0x70, 0x61, 0x6e,
+    ///     0x64, 0x61, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x09, 0x72, 0x61, 0x73,
+    ///     0x70, 0x62, 0x65, 0x72, 0x72, 0x79, 0x00, 0x00, 0x00, 0x00, 0x15, 0x00,
+    ///     0x10, 0x31, 0x63, 0x31, 0x33, 0x64, 0x32, 0x62, 0x32, 0x34, 0x35, 0x62,
+    ///     0x33, 0x61, 0x37, 0x33, 0x34, 0x00, 0x08, 0x00, 0x14, 0xd6, 0x78, 0x26,
+    ///     0x99, 0x0e, 0x15, 0x56, 0x15, 0xe5, 0xf4, 0x24, 0x74, 0xe2, 0x3c, 0x26,
+    ///     0xc5, 0xb1, 0x03, 0xb2, 0x6d,
+    /// ];
+    ///
+    /// let signed = {
+    ///     let mut attributes = Attributes::default();
+    ///     let message = MessageReader::decode(&buffer[..], &mut attributes).unwrap();
+    ///     message.signed().unwrap()
+    /// };
+    ///
+    /// let digest = util::long_term_credential_digest("panda", "panda", "raspberry");
+    /// assert!(signed.verify(&digest).is_ok());
+    /// assert!(signed.verify(&[0u8; 16]).is_err());
+    /// ```
+    pub fn signed(&self) -> Result<SignedMessage, StunError> {
+        if self.bytes.is_empty() || self.valid_offset < 20 {
+            return Err(StunError::InvalidInput);
+        }
+
+        let integrity = self
+            .get::<MessageIntegrity>()
+            .ok_or(StunError::NotIntegrity)?;
+
+        let valid_offset = self.valid_offset as usize;
+        let mut bytes = Vec::with_capacity(valid_offset);
+        bytes.extend_from_slice(&self.bytes[0..2]);
+        bytes.extend_from_slice(&(self.valid_offset + 4).to_be_bytes());
+        bytes.extend_from_slice(&self.bytes[4..valid_offset]);
+
+        Ok(SignedMessage {
+            integrity: integrity.to_vec(),
+            bytes,
+        })
+    }
+
     /// # Test
     ///
     /// ```
@@ -0,0 +1,209 @@
+//! Structured rendering of decoded messages.
+//!
+//! With the `serde` feature, a decoded message can be serialized with any
+//! serde serializer, such as json for logging and debugging. The known
+//! attributes are rendered with their decoded values, and the attributes
+//! whose value can not be decoded are rendered as hex.
+
+use std::{fmt::Write, net::SocketAddr};
+
+use serde::{
+    ser::{SerializeMap, SerializeSeq},
+    Serialize, Serializer,
+};
+
+use crate::{attribute::*, message::MessageReader, Kind, Method};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{:02x}", byte);
+            hex
+        })
+}
+
+/// The name and the class of the method.
+fn method(method: Method) -> (&'static str, &'static str) {
+    let class = |kind| match kind {
+        Kind::Request => "request",
+        Kind::Response => "response",
+        Kind::Error => "error",
+    };
+
+    match method {
+        Method::Binding(kind) => ("binding", class(kind)),
+        Method::Allocate(kind) => ("allocate", class(kind)),
+        Method::CreatePermission(kind) => ("create-permission", class(kind)),
+        Method::ChannelBind(kind) => ("channel-bind", class(kind)),
+        Method::Refresh(kind) => ("refresh", class(kind)),
+        Method::SendIndication => ("send", "indication"),
+        Method::DataIndication => ("data", "indication"),
+        Method::Other(it) => (
+            "other",
+            match it & 0x0110 {
+                0x0000 => "request",
+                0x0010 => "indication",
+                0x0100 => "response",
+                _ => "error",
+            },
+        ),
+    }
+}
+
+fn family(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "ipv4",
+        IpFamily::V6 => "ipv6",
+    }
+}
+
+enum Rendered<'a> {
+    Str(&'a str),
+    Addr(SocketAddr),
+    Error(Error<'a>),
+    Number(u64),
+    Bool(bool),
+    Unit,
+}
+
+struct Value<'a> {
+    kind: AttrKind,
+    bytes: &'a [u8],
+    token: &'a [u8],
+}
+
+impl<'a> Value<'a> {
+    fn get<T: Attribute<'a>>(&self) -> Option<T::Item> {
+        T::decode(self.bytes, self.token).ok()
+    }
+
+    fn render(&self) -> Option<Rendered<'a>> {
+        Some(match self.kind {
+            AttrKind::UserName => Rendered::Str(self.get::<UserName>()?),
+            AttrKind::Realm => Rendered::Str(self.get::<Realm>()?),
+            AttrKind::Nonce => Rendered::Str(self.get::<Nonce>()?),
+            AttrKind::Software => Rendered::Str(self.get::<Software>()?),
+            AttrKind::MappedAddress => Rendered::Addr(self.get::<MappedAddress>()?),
+            AttrKind::XorMappedAddress => Rendered::Addr(self.get::<XorMappedAddress>()?),
+            AttrKind::XorPeerAddress => Rendered::Addr(self.get::<XorPeerAddress>()?),
+            AttrKind::XorRelayedAddress => Rendered::Addr(self.get::<XorRelayedAddress>()?),
+            AttrKind::AlternateServer => Rendered::Addr(self.get::<AlternateServer>()?),
+            AttrKind::ResponseOrigin => Rendered::Addr(self.get::<ResponseOrigin>()?),
+            AttrKind::ErrorCode => Rendered::Error(self.get::<ErrorCode>()?),
+            AttrKind::Lifetime => Rendered::Number(self.get::<Lifetime>()? as u64),
+            AttrKind::Priority => Rendered::Number(self.get::<Priority>()? as u64),
+            AttrKind::Fingerprint => Rendered::Number(self.get::<Fingerprint>()? as u64),
+            AttrKind::ChannelNumber => Rendered::Number(self.get::<ChannelNumber>()? as u64),
+            AttrKind::IceControlled => Rendered::Number(self.get::<IceControlled>()?),
+            AttrKind::IceControlling => Rendered::Number(self.get::<IceControlling>()?),
+            AttrKind::ReservationToken => Rendered::Number(self.get::<ReservationToken>()?),
+            AttrKind::EvenPort => Rendered::Bool(self.get::<EvenPort>()?),
+            AttrKind::ReqeestedTransport => {
+                Rendered::Str(match self.get::<ReqeestedTransport>()? {
+                    Transport::TCP => "tcp",
+                    Transport::UDP => "udp",
+                })
+            }
+            AttrKind::RequestedAddressFamily => {
+                Rendered::Str(family(self.get::<RequestedAddressFamily>()?))
+            }
+            AttrKind::AdditionalAddressFamily => {
+                Rendered::Str(family(self.get::<AdditionalAddressFamily>()?))
+            }
+            AttrKind::UseCandidate | AttrKind::DontFragment => Rendered::Unit,
+            _ => return None,
+        })
+    }
+}
+
+impl Serialize for Value<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The addresses are rendered as strings, the attributes without a
+        // value as null, and the values that can not be decoded as hex.
+        match self.render() {
+            Some(Rendered::Str(it)) => serializer.serialize_str(it),
+            Some(Rendered::Addr(it)) => serializer.collect_str(&it),
+            Some(Rendered::Number(it)) => serializer.serialize_u64(it),
+            Some(Rendered::Bool(it)) => serializer.serialize_bool(it),
+            Some(Rendered::Unit) => serializer.serialize_unit(),
+            Some(Rendered::Error(it)) => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("code", &it.code)?;
+                map.serialize_entry("message", it.message)?;
+                map.end()
+            }
+            None => serializer.serialize_str(&hex(self.bytes)),
+        }
+    }
+}
+
+struct Entry<'a>(Value<'a>);
+
+impl Serialize for Entry<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("kind", &format!("{:?}", self.0.kind))?;
+        map.serialize_entry("value", &self.0)?;
+        map.end()
+    }
+}
+
+struct Entries<'a, 'b>(&'b MessageReader<'a>);
+
+impl Serialize for Entries<'_, '_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(None)?;
+        for (kind, bytes) in self.0.attributes() {
+            seq.serialize_element(&Entry(Value {
+                token: self.0.token,
+                bytes,
+                kind,
+            }))?;
+        }
+
+        seq.end()
+    }
+}
+
+/// # Test
+///
+/// ```
+/// use bytes::BytesMut;
+/// use mycrl_stun::attribute::*;
+/// use mycrl_stun::*;
+///
+/// let mut buf = BytesMut::new();
+/// let mut message = MessageWriter::new(Method::Allocate(Kind::Request), &[0u8; 12], &mut buf);
+/// message.append::<ReqeestedTransport>(Transport::UDP);
+/// message.append::<UserName>("panda");
+/// message.flush(None).unwrap();
+///
+/// let mut attributes = Attributes::default();
+/// let message = MessageReader::decode(&buf, &mut attributes).unwrap();
+///
+/// assert_eq!(
+///     serde_json::to_value(&message).unwrap(),
+///     serde_json::json!({
+///         "method": "allocate",
+///         "class": "request",
+///         "token": "000000000000000000000000",
+///         "attributes": [
+///             { "kind": "ReqeestedTransport", "value": "udp" },
+///             { "kind": "UserName", "value": "panda" },
+///         ],
+///     })
+/// );
+/// ```
+impl Serialize for MessageReader<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (name, class) = method(self.method);
+
+        let mut map = serializer.serialize_map(Some(4))?;
+        map.serialize_entry("method", name)?;
+        map.serialize_entry("class", class)?;
+        map.serialize_entry("token", &hex(self.token))?;
+        map.serialize_entry("attributes", &Entries(self))?;
+        map.end()
+    }
+}
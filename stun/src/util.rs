@@ -1,5 +1,5 @@
 use crc::{Crc, CRC_32_ISO_HDLC};
-use hmac::{digest::CtOutput, Hmac, Mac};
+use hmac::{digest::CtOutput, Hmac};
 use md5::{Digest, Md5};
 
 use crate::StunError;
@@ -48,6 +48,10 @@ pub fn long_term_credential_digest(username: &str, password: &str, realm: &str)
 
 /// HMAC SHA1 digest.
 ///
+/// With the `openssl` feature the digest is computed with the HMAC of openssl,
+/// which uses the SHA extensions of the x86_64 cpus and the crypto extensions
+/// of the ARMv8 cpus.
+///
 /// # Test
 ///
 /// ```
@@ -75,16 +79,71 @@ pub fn long_term_credential_digest(username: &str, password: &str, realm: &str)
 ///     .unwrap()
 ///     .into_bytes();
 /// assert_eq!(hmac_output.as_slice(), &sign);
+///
+/// let long_key = [0x5au8; 80];
+/// let hmac_output = mycrl_stun::util::hmac_sha1(&long_key, &[&buffer[..10], &buffer[10..]])
+///     .unwrap()
+///     .into_bytes();
+/// assert_eq!(
+///     hmac_output.as_slice(),
+///     &[
+///         0xe1, 0x6a, 0x08, 0x77, 0x01, 0xaf, 0x84, 0x2d, 0x95, 0x34, 0x4b, 0x7b, 0x78, 0xfd,
+///         0xeb, 0x1c, 0xdf, 0x3a, 0xc0, 0xe8,
+///     ]
+/// );
+///
+/// // RFC 2202 test cases.
+/// let cases: [(&[u8], &[u8], &str); 3] = [
+///     (&[0x0b; 20], b"Hi There", "b617318655057264e28bc0b6fb378c8ef146be00"),
+///     (b"Jefe", b"what do ya want for nothing?", "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"),
+///     (
+///         &[0xaa; 80],
+///         b"Test Using Larger Than Block-Size Key - Hash Key First",
+///         "aa4ae5e15272d00e95705637ce8a3b55ed402112",
+///     ),
+/// ];
+///
+/// for (key, data, digest) in cases {
+///     let hmac_output = mycrl_stun::util::hmac_sha1(key, &[data]).unwrap().into_bytes();
+///     let hex = hmac_output.iter().map(|it| format!("{:02x}", it)).collect::<String>();
+///     assert_eq!(hex, digest);
+/// }
 /// ```
 pub fn hmac_sha1(key: &[u8], source: &[&[u8]]) -> Result<CtOutput<Hmac<sha1::Sha1>>, StunError> {
-    match Hmac::<sha1::Sha1>::new_from_slice(key) {
-        Err(_) => Err(StunError::SummaryFailed),
-        Ok(mut mac) => {
+    #[cfg(feature = "openssl")]
+    {
+        use hmac::digest::generic_array::GenericArray;
+        use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+        let sign = || {
+            let key = PKey::hmac(key)?;
+            let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
             for buf in source {
-                mac.update(buf);
+                signer.update(buf)?;
             }
 
-            Ok(mac.finalize())
+            signer.sign_to_vec()
+        };
+
+        match sign() {
+            Ok(it) if it.len() == 20 => Ok(CtOutput::new(GenericArray::clone_from_slice(&it))),
+            _ => Err(StunError::SummaryFailed),
+        }
+    }
+
+    #[cfg(not(feature = "openssl"))]
+    {
+        use hmac::Mac;
+
+        match Hmac::<sha1::Sha1>::new_from_slice(key) {
+            Err(_) => Err(StunError::SummaryFailed),
+            Ok(mut mac) => {
+                for buf in source {
+                    mac.update(buf);
+                }
+
+                Ok(mac.finalize())
+            }
         }
     }
 }
@@ -99,3 +158,36 @@ pub fn hmac_sha1(key: &[u8], source: &[&[u8]]) -> Result<CtOutput<Hmac<sha1::Sha
 pub fn fingerprint(bytes: &[u8]) -> u32 {
     Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(bytes) ^ 0x5354_554e
 }
+
+/// Check the FINGERPRINT of a stun message without decoding it.
+///
+/// Returns `None` if the message does not end with a FINGERPRINT attribute,
+/// so that the garbage can be rejected before the attributes are parsed.
+///
+/// # Test
+///
+/// ```
+/// use bytes::BytesMut;
+/// use mycrl_stun::{util::*, Kind, MessageWriter, Method};
+///
+/// let mut bytes = BytesMut::new();
+/// MessageWriter::new(Method::Binding(Kind::Request), &[0u8; 12], &mut bytes)
+///     .flush(Some(&long_term_credential_digest("panda", "panda", "raspberry")))
+///     .unwrap();
+///
+/// assert_eq!(verify_fingerprint(&bytes), Some(true));
+///
+/// let last = bytes.len() - 1;
+/// bytes[last] ^= 1;
+/// assert_eq!(verify_fingerprint(&bytes), Some(false));
+/// assert_eq!(verify_fingerprint(&bytes[..20]), None);
+/// ```
+pub fn verify_fingerprint(bytes: &[u8]) -> Option<bool> {
+    let size = bytes.len();
+    if size < 28 || bytes[size - 8..size - 4] != [0x80, 0x28, 0x00, 0x04] {
+        return None;
+    }
+
+    let value = u32::from_be_bytes(bytes[size - 4..].try_into().ok()?);
+    Some(fingerprint(&bytes[..size - 8]) == value)
+}
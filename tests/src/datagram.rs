@@ -0,0 +1,297 @@
+//! The udp server with an in-memory datagram socket.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::BytesMut;
+use stun::{
+    attribute::{
+        ChannelNumber, Data, Nonce, Realm, ReqeestedTransport, Transport, XorMappedAddress,
+        XorPeerAddress, XorRelayedAddress,
+    },
+    Attributes, ChannelData, Kind, MessageReader, MessageWriter, Method,
+};
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
+use turn::Service;
+use turn_server::{
+    audit::Audit,
+    config::{Anonymize, Malformed, MalformedAction},
+    malformed::{MalformedFilter, MAX_SOURCES},
+    router::Router,
+    server::{serve_datagram, DatagramSocket, DEFAULT_MAX_DATAGRAM_SIZE},
+    statistics::Statistics,
+};
+
+use crate::mock::{audit_log, Credential, Static, TOKEN};
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+struct MemorySocket {
+    local_addr: SocketAddr,
+    inbound: Mutex<mpsc::UnboundedReceiver<Datagram>>,
+    outbound: mpsc::UnboundedSender<Datagram>,
+}
+
+impl DatagramSocket for MemorySocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (bytes, addr) = self
+            .inbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(io::ErrorKind::BrokenPipe)?;
+
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok((bytes.len(), addr))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.outbound
+            .send((buf.to_vec(), target))
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+
+        Ok(buf.len())
+    }
+}
+
+/// The other end of the in-memory socket.
+struct Network {
+    external: SocketAddr,
+    inbound: mpsc::UnboundedSender<Datagram>,
+    outbound: mpsc::UnboundedReceiver<Datagram>,
+    bytes: BytesMut,
+}
+
+impl Network {
+    fn message(&mut self, method: Method) -> MessageWriter<'_> {
+        MessageWriter::new(method, &TOKEN, &mut self.bytes)
+    }
+
+    /// Send the buffer from the address and receive the next datagram that
+    /// the server sends.
+    async fn request(&mut self, from: SocketAddr) -> Result<Datagram> {
+        self.inbound.send((self.bytes.to_vec(), from))?;
+        self.recv().await
+    }
+
+    async fn recv(&mut self) -> Result<Datagram> {
+        timeout(Duration::from_secs(1), self.outbound.recv())
+            .await?
+            .ok_or_else(|| anyhow!("socket closed"))
+    }
+
+    async fn allocate(&mut self, from: SocketAddr) -> Result<(Credential, u16)> {
+        {
+            let mut message = self.message(Method::Allocate(Kind::Request));
+            message.append::<ReqeestedTransport>(Transport::UDP);
+            message.flush(None)?;
+        }
+
+        let (bytes, _) = self.request(from).await?;
+        let mut attributes = Attributes::default();
+        let message = MessageReader::decode(&bytes, &mut attributes)?;
+        let realm = message.get::<Realm>().unwrap();
+        let credential = Credential {
+            username: "test".to_string(),
+            nonce: message.get::<Nonce>().unwrap().to_string(),
+            digest: stun::util::long_term_credential_digest("test", "test", realm),
+            realm: realm.to_string(),
+        };
+
+        {
+            let mut message = self.message(Method::Allocate(Kind::Request));
+            message.append::<ReqeestedTransport>(Transport::UDP);
+            credential.sign(message)?;
+        }
+
+        let (bytes, to) = self.request(from).await?;
+        ensure!(to == from);
+
+        let mut attributes = Attributes::default();
+        let message = MessageReader::decode(&bytes, &mut attributes)?;
+        ensure!(message.method == Method::Allocate(Kind::Response));
+
+        let port = message.get::<XorRelayedAddress>().unwrap().port();
+        Ok((credential, port))
+    }
+
+    async fn channel_bind(
+        &mut self,
+        from: SocketAddr,
+        credential: &Credential,
+        port: u16,
+    ) -> Result<()> {
+        let mut relay = self.external;
+        relay.set_port(port);
+
+        {
+            let mut message = self.message(Method::ChannelBind(Kind::Request));
+            message.append::<ChannelNumber>(0x4000);
+            message.append::<XorPeerAddress>(relay);
+            credential.sign(message)?;
+        }
+
+        let (bytes, _) = self.request(from).await?;
+        let mut attributes = Attributes::default();
+        let message = MessageReader::decode(&bytes, &mut attributes)?;
+        ensure!(message.method == Method::ChannelBind(Kind::Response));
+        Ok(())
+    }
+}
+
+/// Serve the in-memory socket and return the other end of it.
+fn start(external: SocketAddr) -> Result<Network> {
+    let (inbound, inbound_receiver) = mpsc::unbounded_channel();
+    let (outbound_sender, outbound) = mpsc::unbounded_channel();
+
+    let service = Service::new("localhost".to_string(), vec![external], Static);
+    serve_datagram(
+        MemorySocket {
+            inbound: Mutex::new(inbound_receiver),
+            outbound: outbound_sender,
+            local_addr: external,
+        },
+        external,
+        &Statistics::default(),
+        &service,
+        &Router::default(),
+        &MalformedFilter::new(Malformed::default(), Anonymize::default(), Audit::default()),
+    )?;
+
+    Ok(Network {
+        bytes: BytesMut::with_capacity(1500),
+        external,
+        inbound,
+        outbound,
+    })
+}
+
+#[tokio::test]
+async fn memory_datagram_socket_testing() -> Result<()> {
+    let mut network = start("127.0.0.1:3478".parse()?)?;
+
+    let client: SocketAddr = "10.0.0.1:40000".parse()?;
+    let peer: SocketAddr = "10.0.0.2:40000".parse()?;
+
+    {
+        network
+            .message(Method::Binding(Kind::Request))
+            .flush(None)?;
+
+        let (bytes, to) = network.request(client).await?;
+        ensure!(to == client);
+
+        let mut attributes = Attributes::default();
+        let message = MessageReader::decode(&bytes, &mut attributes)?;
+        ensure!(message.get::<XorMappedAddress>() == Some(client));
+    }
+
+    let (credential, port) = network.allocate(client).await?;
+    let (peer_credential, peer_port) = network.allocate(peer).await?;
+
+    // The channels are only relayed when they are bound on both sides.
+    network.channel_bind(client, &credential, peer_port).await?;
+    network.channel_bind(peer, &peer_credential, port).await?;
+
+    // The channel data is relayed to the peer through the same socket.
+    network.bytes.clear();
+    ChannelData {
+        number: 0x4000,
+        bytes: &[1, 2, 3, 4],
+    }
+    .encode(&mut network.bytes);
+
+    let (bytes, to) = network.request(client).await?;
+    ensure!(to == peer);
+    ensure!(bytes == network.bytes.as_ref());
+    Ok(())
+}
+
+#[tokio::test]
+async fn oversize_datagram_testing() -> Result<()> {
+    let mut network = start("127.0.0.1:3478".parse()?)?;
+    let client: SocketAddr = "10.0.0.1:40000".parse()?;
+
+    // The header and the attribute header take 24 bytes.
+    for (size, answered) in [
+        (DEFAULT_MAX_DATAGRAM_SIZE - 24, true),
+        (DEFAULT_MAX_DATAGRAM_SIZE - 20, false),
+    ] {
+        {
+            let mut message = network.message(Method::Binding(Kind::Request));
+            message.append::<Data>(&vec![0u8; size]);
+            message.flush(None)?;
+        }
+
+        ensure!(network.request(client).await.is_ok() == answered);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn malformed_sources_testing() -> Result<()> {
+    let filter = MalformedFilter::new(
+        Malformed {
+            action: MalformedAction::Ban,
+            ban_threshold: 0,
+            ..Default::default()
+        },
+        Anonymize::default(),
+        Audit::default(),
+    );
+
+    // More distinct sources than are tracked, all of them recent.
+    for index in 0..MAX_SOURCES as u32 + 1024 {
+        let ip = std::net::Ipv4Addr::from(0x0a00_0000 + index);
+        filter.report(SocketAddr::new(ip.into(), 1));
+    }
+
+    let sources = filter.sources();
+    ensure!(sources.len() == MAX_SOURCES);
+
+    // The sources beyond the limit are not tracked, and so not banned.
+    ensure!(filter.is_banned("10.0.0.0".parse()?));
+    ensure!(!filter.is_banned(std::net::Ipv4Addr::from(0x0a00_0000 + MAX_SOURCES as u32).into()));
+    Ok(())
+}
+
+#[test]
+fn malformed_ban_audit_testing() -> Result<()> {
+    let (audit, path) = audit_log("malformed")?;
+    let filter = MalformedFilter::new(
+        Malformed {
+            action: MalformedAction::Ban,
+            ban_threshold: 1,
+            ban_duration: 30,
+            ..Default::default()
+        },
+        Anonymize::default(),
+        audit,
+    );
+
+    let addr = "10.0.0.1:1".parse()?;
+    filter.report(addr);
+    filter.report(addr);
+    filter.report(addr);
+
+    let lines = std::fs::read_to_string(&path)?;
+    let records = lines
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    ensure!(records.len() == 1);
+    ensure!(records[0]["kind"] == "malformed_ban");
+    ensure!(records[0]["detail"]["address"] == "10.0.0.1:1");
+    ensure!(records[0]["detail"]["duration"] == 30);
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! Lookups of the geoip databases.
+//!
+//! The databases are written in the MaxMind DB format by a minimal writer,
+//! which only supports the ipv4 databases with 24-bit records.
+
+use std::{
+    fs::{self, File},
+    net::Ipv4Addr,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Result};
+use turn_server::{
+    config,
+    geoip::{GeoIp, Location},
+};
+
+enum Data {
+    Str(&'static str),
+    Uint(u32),
+    Map(Vec<(&'static str, Data)>),
+}
+
+fn encode(data: &Data, buf: &mut Vec<u8>) {
+    match data {
+        Data::Str(it) if it.len() < 29 => {
+            buf.push((2 << 5) | it.len() as u8);
+            buf.extend_from_slice(it.as_bytes());
+        }
+        Data::Str(it) => {
+            buf.extend_from_slice(&[(2 << 5) | 29, (it.len() - 29) as u8]);
+            buf.extend_from_slice(it.as_bytes());
+        }
+        Data::Uint(it) => {
+            buf.push((6 << 5) | 4);
+            buf.extend_from_slice(&it.to_be_bytes());
+        }
+        Data::Map(entries) => {
+            buf.push((7 << 5) | entries.len() as u8);
+            for (key, value) in entries {
+                encode(&Data::Str(key), buf);
+                encode(value, buf);
+            }
+        }
+    }
+}
+
+/// Write an ipv4 database of the networks and their data.
+fn build(networks: Vec<(Ipv4Addr, u32, Data)>) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    let mut data = Vec::new();
+    let mut nodes = vec![[Record::Empty; 2]];
+    for (ip, len, value) in networks {
+        let offset = data.len();
+        encode(&value, &mut data);
+
+        let bits = u32::from(ip);
+        let mut node = 0;
+        for i in 0..len {
+            let bit = ((bits >> (31 - i)) & 1) as usize;
+            if i + 1 == len {
+                nodes[node][bit] = Record::Data(offset);
+                break;
+            }
+
+            node = match nodes[node][bit] {
+                Record::Node(it) => it,
+                _ => {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+        }
+    }
+
+    let count = nodes.len();
+    let mut buf = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match record {
+            Record::Empty => count,
+            Record::Node(it) => *it,
+            Record::Data(it) => count + 16 + it,
+        };
+
+        buf.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+    }
+
+    buf.extend_from_slice(&[0u8; 16]);
+    buf.extend_from_slice(&data);
+    buf.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+    encode(
+        &Data::Map(vec![
+            ("node_count", Data::Uint(count as u32)),
+            ("record_size", Data::Uint(24)),
+            ("ip_version", Data::Uint(4)),
+        ]),
+        &mut buf,
+    );
+
+    buf
+}
+
+fn country(code: &'static str) -> Data {
+    Data::Map(vec![(
+        "country",
+        Data::Map(vec![("iso_code", Data::Str(code))]),
+    )])
+}
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("turn-server-{}-{}.mmdb", std::process::id(), name))
+}
+
+#[test]
+fn geoip_lookup_testing() -> Result<()> {
+    let countries = path("country");
+    let asn = path("asn");
+
+    fs::write(
+        &countries,
+        build(vec![
+            (Ipv4Addr::new(10, 0, 0, 0), 8, country("DE")),
+            (Ipv4Addr::new(192, 168, 1, 0), 24, country("FR")),
+        ]),
+    )?;
+
+    fs::write(
+        &asn,
+        build(vec![(
+            Ipv4Addr::new(10, 0, 0, 0),
+            8,
+            Data::Map(vec![
+                ("autonomous_system_number", Data::Uint(64500)),
+                ("autonomous_system_organization", Data::Str("Example")),
+            ]),
+        )]),
+    )?;
+
+    let geoip = GeoIp::new(config::GeoIp {
+        databases: vec![
+            countries.to_string_lossy().to_string(),
+            asn.to_string_lossy().to_string(),
+        ],
+        reload_interval: 0,
+    })?;
+
+    ensure!(
+        geoip.lookup("10.1.2.3".parse()?)
+            == Location {
+                country: Some("DE".to_string()),
+                asn: Some(64500),
+                organization: Some("Example".to_string()),
+            }
+    );
+
+    ensure!(geoip.lookup("192.168.1.7".parse()?).country.as_deref() == Some("FR"));
+    ensure!(geoip.lookup("192.168.1.7".parse()?).asn.is_none());
+    ensure!(geoip.lookup("172.16.0.1".parse()?) == Location::default());
+    ensure!(geoip.lookup("::ffff:10.0.0.1".parse()?).country.as_deref() == Some("DE"));
+    ensure!(geoip.lookup("2001:db8::1".parse()?) == Location::default());
+
+    // The database is replaced when its file is modified.
+    ensure!(!geoip.reload()?);
+    fs::write(
+        &countries,
+        build(vec![(Ipv4Addr::new(10, 0, 0, 0), 8, country("NL"))]),
+    )?;
+    File::options()
+        .write(true)
+        .open(&countries)?
+        .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+
+    ensure!(geoip.reload()?);
+    ensure!(geoip.lookup("10.1.2.3".parse()?).country.as_deref() == Some("NL"));
+    ensure!(geoip.lookup("192.168.1.7".parse()?).country.is_none());
+
+    // A broken file keeps the previous database.
+    fs::write(&countries, b"broken")?;
+    File::options()
+        .write(true)
+        .open(&countries)?
+        .set_modified(SystemTime::now() + Duration::from_secs(120))?;
+
+    ensure!(geoip.reload().is_err());
+    ensure!(geoip.lookup("10.1.2.3".parse()?).country.as_deref() == Some("NL"));
+
+    fs::remove_file(countries)?;
+    fs::remove_file(asn)?;
+    Ok(())
+}
@@ -1,3 +1,16 @@
+#[cfg(test)]
+mod datagram;
+#[cfg(test)]
+mod geoip;
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod processors;
+#[cfg(test)]
+mod proxy;
+#[cfg(test)]
+mod soak;
+
 #[cfg(test)]
 mod tests {
     use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
@@ -8,11 +21,11 @@ mod tests {
     use bytes::BytesMut;
     use stun::{
         attribute::{
-            ChannelNumber, Data, ErrorCode, ErrorKind, Lifetime, MappedAddress, Nonce, Realm,
+            ChannelNumber, Data, ErrorCode, ErrorKind, Icmp, Lifetime, MappedAddress, Nonce, Realm,
             ReqeestedTransport, ResponseOrigin, Transport, UserName, XorMappedAddress,
             XorPeerAddress, XorRelayedAddress,
         },
-        ChannelData, Decoder, Kind, MessageReader, MessageWriter, Method, Payload,
+        Attributes, ChannelData, Decoder, Kind, MessageReader, MessageWriter, Method, Payload,
     };
     use turn_driver::{
         start_hooks_server, Controller, Events, Hooks, SessionAddr, Transport as DriverTransport,
@@ -26,7 +39,9 @@ mod tests {
     };
 
     use turn_server::{
-        config::{Api, Auth, Config, Interface, Log, Transport as TurnTransport, Turn},
+        config::{
+            Api, Auth, Config, Health, Interface, Log, Mechanism, Transport as TurnTransport, Turn,
+        },
         startup,
     };
 
@@ -45,12 +60,21 @@ mod tests {
                     realm: "localhost".to_string(),
                     interfaces: vec![Interface {
                         transport: TurnTransport::UDP,
+                        credential: Default::default(),
+                        max_datagram_size: 2048,
+                        proxy_protocol: false,
                         external: bind,
                         bind,
                     }],
+                    ..Default::default()
+                },
+                health: Health {
+                    self_test: true,
+                    ..Default::default()
                 },
                 auth,
                 api,
+                ..Default::default()
             }))
             .await
             .unwrap();
@@ -84,7 +108,7 @@ mod tests {
             Ok(self.socket.local_addr()?)
         }
 
-        fn create_message(&mut self, method: Method) -> MessageWriter {
+        fn create_message(&mut self, method: Method) -> MessageWriter<'_> {
             MessageWriter::new(method, &TOKEN, &mut self.send_bytes)
         }
 
@@ -97,7 +121,7 @@ mod tests {
             Ok(())
         }
 
-        async fn read_message(&mut self) -> Result<MessageReader> {
+        async fn read_message(&mut self) -> Result<MessageReader<'_>> {
             let size = timeout(
                 Duration::from_secs(1),
                 self.socket.recv(&mut self.recv_bytes),
@@ -115,7 +139,7 @@ mod tests {
             }
         }
 
-        async fn read_channel_data(&mut self) -> Result<ChannelData> {
+        async fn read_channel_data(&mut self) -> Result<ChannelData<'_>> {
             let size = timeout(
                 Duration::from_secs(1),
                 self.socket.recv(&mut self.recv_bytes),
@@ -162,7 +186,7 @@ mod tests {
         }
 
         pub fn local_addr(&self) -> Result<SocketAddr> {
-            Ok(self.operationer.local_addr()?)
+            self.operationer.local_addr()
         }
 
         pub async fn binding(&mut self) -> Result<()> {
@@ -241,7 +265,7 @@ mod tests {
 
         pub async fn create_permission(&mut self, port: u16) -> Result<()> {
             {
-                let mut peer = self.server.clone();
+                let mut peer = self.server;
                 peer.set_port(port);
 
                 let mut message = self
@@ -266,7 +290,7 @@ mod tests {
 
         pub async fn channel_bind(&mut self, port: u16, channel: u16) -> Result<()> {
             {
-                let mut peer = self.server.clone();
+                let mut peer = self.server;
                 peer.set_port(port);
 
                 let mut message = self
@@ -315,7 +339,7 @@ mod tests {
         }
 
         pub async fn send_indication(&mut self, port: u16, data: &[u8]) -> Result<()> {
-            let mut peer = self.server.clone();
+            let mut peer = self.server;
             peer.set_port(port);
 
             let mut message = self.operationer.create_message(Method::SendIndication);
@@ -437,6 +461,7 @@ mod tests {
             Auth {
                 static_auth_secret: Some("static_auth_secret".to_string()),
                 static_credentials: HashMap::with_capacity(1),
+                mechanisms: Vec::new(),
             },
             Api {
                 bind: "127.0.0.1:3001".parse()?,
@@ -458,6 +483,201 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn turn_credential_chain_testing() -> Result<()> {
+        create_turn_server(
+            "127.0.0.1:3480".parse()?,
+            Auth {
+                static_auth_secret: Some("static_auth_secret".to_string()),
+                static_credentials: {
+                    let mut it = HashMap::with_capacity(2);
+                    it.insert("static_credentials".to_string(), "test".to_string());
+                    it.insert("1:expired".to_string(), "test".to_string());
+                    it
+                },
+                mechanisms: vec![Mechanism::Secret, Mechanism::Static],
+            },
+            Api {
+                bind: "127.0.0.1:3002".parse()?,
+                hooks: None,
+            },
+        )
+        .await?;
+
+        // The secret only recognizes the usernames that start with a timestamp.
+        for (username, password) in [
+            (
+                "99999999999:user",
+                encode_password("99999999999:user", "static_auth_secret")?,
+            ),
+            ("static_credentials", "test".to_string()),
+        ] {
+            let mut turn = TurnClient::new(
+                "127.0.0.1:3480".parse()?,
+                Credentials {
+                    username: username.to_string(),
+                    password,
+                },
+            )
+            .await?;
+
+            turn.allocate().await?;
+        }
+
+        // An expired credential is refused by the secret, the static credentials
+        // are not consulted.
+        let mut turn = TurnClient::new(
+            "127.0.0.1:3480".parse()?,
+            Credentials {
+                username: "1:expired".to_string(),
+                password: "test".to_string(),
+            },
+        )
+        .await?;
+
+        ensure!(turn.allocate().await.is_err());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn turn_icmp_error_testing() -> Result<()> {
+        create_turn_server(
+            "127.0.0.1:3481".parse()?,
+            Auth {
+                static_auth_secret: None,
+                static_credentials: {
+                    let mut it = HashMap::with_capacity(1);
+                    it.insert("test".to_string(), "test".to_string());
+                    it
+                },
+                mechanisms: Vec::new(),
+            },
+            Api {
+                bind: "127.0.0.1:3003".parse()?,
+                hooks: None,
+            },
+        )
+        .await?;
+
+        let mut clients = Vec::with_capacity(2);
+        for _ in 0..2 {
+            clients.push(
+                TurnClient::new(
+                    "127.0.0.1:3481".parse()?,
+                    Credentials {
+                        username: "test".to_string(),
+                        password: "test".to_string(),
+                    },
+                )
+                .await?,
+            );
+        }
+
+        let mut peer = clients.pop().unwrap();
+        let mut turn = clients.pop().unwrap();
+
+        let port = turn.allocate().await?;
+        let peer_port = peer.allocate().await?;
+        turn.create_permission(peer_port).await?;
+        peer.create_permission(port).await?;
+
+        // The port of the peer is unreachable once its socket is closed.
+        drop(peer);
+        turn.send_indication(peer_port, b"unreachable").await?;
+
+        let socket = &mut turn.operationer;
+        let size = timeout(
+            Duration::from_secs(1),
+            socket.socket.recv(&mut socket.recv_bytes),
+        )
+        .await??;
+
+        let mut attributes = Attributes::default();
+        let message = MessageReader::decode(&socket.recv_bytes[..size], &mut attributes)?;
+        ensure!(message.method == Method::DataIndication);
+        ensure!(message.get::<XorPeerAddress>().map(|it| it.port()) == Some(peer_port));
+        ensure!(message.get::<Data>().is_none());
+
+        // Destination unreachable, port unreachable.
+        let icmp = message.get::<Icmp>().unwrap();
+        ensure!(icmp.kind == 3 && icmp.code == 3);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn turn_ip_header_testing() -> Result<()> {
+        use turn_server::{header, server::IpHeader};
+
+        create_turn_server(
+            "127.0.0.1:3482".parse()?,
+            Auth {
+                static_auth_secret: None,
+                static_credentials: {
+                    let mut it = HashMap::with_capacity(1);
+                    it.insert("test".to_string(), "test".to_string());
+                    it
+                },
+                mechanisms: Vec::new(),
+            },
+            Api {
+                bind: "127.0.0.1:3004".parse()?,
+                hooks: None,
+            },
+        )
+        .await?;
+
+        let mut clients = Vec::with_capacity(2);
+        for _ in 0..2 {
+            clients.push(
+                TurnClient::new(
+                    "127.0.0.1:3482".parse()?,
+                    Credentials {
+                        username: "test".to_string(),
+                        password: "test".to_string(),
+                    },
+                )
+                .await?,
+            );
+        }
+
+        let mut peer = clients.pop().unwrap();
+        let mut turn = clients.pop().unwrap();
+
+        let port = turn.allocate().await?;
+        let peer_port = peer.allocate().await?;
+        turn.channel_bind(peer_port, 0x4000).await?;
+        peer.channel_bind(port, 0x4000).await?;
+
+        turn.operationer.socket.set_ttl(32)?;
+        turn.operationer.socket.set_tos(0b10)?;
+        header::enable(&peer.operationer.socket)?;
+
+        // The time to live is decremented by the relay and the ECN bits are
+        // carried over.
+        let data = "ttl and ecn".as_bytes();
+        turn.send_channel_data(0x4000, data).await?;
+
+        let socket = &mut peer.operationer;
+        let (size, _, ip_header) = timeout(
+            Duration::from_secs(1),
+            header::recv_from(&socket.socket, &mut socket.recv_bytes),
+        )
+        .await??;
+
+        ensure!(size == data.len() + 4);
+        ensure!(
+            ip_header
+                == IpHeader {
+                    ttl: Some(31),
+                    ecn: Some(0b10),
+                }
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn turn_server_testing() -> Result<()> {
         let controller = Arc::new(Controller::new("http://127.0.0.1:3000")?);
@@ -482,11 +702,11 @@ mod tests {
                     );
                     it
                 },
+                mechanisms: Vec::new(),
             },
-            {
-                let mut api = Api::default();
-                api.hooks = Some("http://127.0.0.1:8088".to_string());
-                api
+            Api {
+                hooks: Some("http://127.0.0.1:8088".to_string()),
+                ..Default::default()
             },
         )
         .await?;
@@ -496,7 +716,7 @@ mod tests {
             assert_eq!(info.port_allocated, 0);
             assert_eq!(info.port_capacity, 16383);
 
-            let interface = info.interfaces.get(0).unwrap();
+            let interface = info.interfaces.first().unwrap();
             assert_eq!(interface.bind, "127.0.0.1:3478".parse()?);
             assert_eq!(interface.external, "127.0.0.1:3478".parse()?);
             assert_eq!(interface.transport, DriverTransport::UDP);
@@ -737,7 +957,7 @@ mod tests {
             assert_eq!(info.port_allocated, 4);
             assert_eq!(info.port_capacity, 16383);
 
-            let interface = info.interfaces.get(0).unwrap();
+            let interface = info.interfaces.first().unwrap();
             assert_eq!(interface.bind, "127.0.0.1:3478".parse()?);
             assert_eq!(interface.external, "127.0.0.1:3478".parse()?);
             assert_eq!(interface.transport, DriverTransport::UDP);
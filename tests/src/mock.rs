@@ -0,0 +1,238 @@
+//! An in-memory transport for the service.
+//!
+//! The transport feeds crafted buffers into the same dispatch path as the
+//! udp server and captures the responses, so that the processors can be
+//! tested with inputs that a well behaved client never sends.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::BytesMut;
+use stun::{
+    attribute::{
+        ErrorCode, Nonce, Realm, ReqeestedTransport, Transport, UserName, XorRelayedAddress,
+    },
+    Attributes, Kind, MessageReader, MessageWriter, Method,
+};
+use turn::{
+    operations::{IngressTransport, TransportContext},
+    Observer, Operationer, ResponseMethod, Service, SessionAddr,
+};
+use turn_server::{
+    audit::Audit,
+    config::{self, Config},
+};
+
+pub const TOKEN: [u8; 12] = [7u8; 12];
+
+/// An audit log written to a temporary file, returns it with the path of
+/// the file.
+pub fn audit_log(name: &str) -> Result<(Audit, PathBuf)> {
+    let path = std::env::temp_dir().join(format!(
+        "turn-server-{}-{}.audit.log",
+        std::process::id(),
+        name
+    ));
+
+    let _ = std::fs::remove_file(&path);
+    let audit = Audit::new(&Config {
+        audit: config::Audit {
+            file: Some(path.to_string_lossy().to_string()),
+            syslog: None,
+        },
+        ..Default::default()
+    })?;
+
+    Ok((audit, path))
+}
+
+/// Accepts any username with the password `test`.
+#[derive(Clone)]
+pub struct Static;
+
+impl Observer for Static {
+    async fn get_password(&self, _: &SessionAddr, _: &str) -> Option<String> {
+        Some("test".to_string())
+    }
+}
+
+/// A response captured by the transport.
+#[derive(Debug, Clone)]
+pub struct Captured {
+    pub method: ResponseMethod,
+    pub bytes: Vec<u8>,
+    pub relay: Option<SocketAddr>,
+    pub endpoint: Option<SocketAddr>,
+}
+
+impl Captured {
+    pub fn decode<'a>(&'a self, attributes: &'a mut Attributes) -> Result<MessageReader<'a>> {
+        Ok(MessageReader::decode(&self.bytes, attributes)?)
+    }
+
+    /// The error code of an error response.
+    pub fn error(&self) -> Option<u16> {
+        let mut attributes = Attributes::default();
+        self.decode(&mut attributes)
+            .ok()?
+            .get::<ErrorCode>()
+            .map(|it| it.code)
+    }
+}
+
+/// The long-term credential that a client got from a 401 response.
+#[derive(Debug, Clone, Default)]
+pub struct Credential {
+    pub username: String,
+    pub nonce: String,
+    pub realm: String,
+    pub digest: [u8; 16],
+}
+
+impl Credential {
+    /// Append the credential to the message and flush it with the integrity.
+    pub fn sign(&self, mut message: MessageWriter<'_>) -> Result<()> {
+        message.append::<UserName>(&self.username);
+        message.append::<Realm>(&self.realm);
+        message.append::<Nonce>(&self.nonce);
+        message.flush(Some(&self.digest))?;
+        Ok(())
+    }
+}
+
+pub struct MockTransport<T: Observer + 'static> {
+    operationer: Operationer<T>,
+    pub interface: SocketAddr,
+    pub bytes: BytesMut,
+    /// All of the responses in the order they were sent.
+    pub responses: Vec<Captured>,
+}
+
+impl<T: Clone + Observer + 'static> MockTransport<T> {
+    pub fn new(service: &Service<T>, interface: SocketAddr) -> Self {
+        Self::with_context(
+            service,
+            interface,
+            TransportContext::new(IngressTransport::Udp, interface),
+        )
+    }
+
+    /// Create the transport with the metadata of the listener.
+    pub fn with_context(
+        service: &Service<T>,
+        interface: SocketAddr,
+        transport: TransportContext,
+    ) -> Self {
+        Self {
+            operationer: service.get_operationer(interface, interface, transport),
+            bytes: BytesMut::with_capacity(1500),
+            responses: Vec::new(),
+            interface,
+        }
+    }
+
+    /// Write a new request into the buffer of the transport.
+    pub fn message(&mut self, method: Method) -> MessageWriter<'_> {
+        MessageWriter::new(method, &TOKEN, &mut self.bytes)
+    }
+
+    /// Send the buffer of the transport from the address.
+    pub async fn send(&mut self, from: SocketAddr) -> Result<Option<Captured>> {
+        let bytes = std::mem::take(&mut self.bytes);
+        let res = self.send_bytes(from, &bytes).await;
+        self.bytes = bytes;
+        res
+    }
+
+    /// Send the bytes from the address, the bytes do not have to be a valid
+    /// message.
+    pub async fn send_bytes(&mut self, from: SocketAddr, bytes: &[u8]) -> Result<Option<Captured>> {
+        // The udp server drops the datagrams that are too small to have a
+        // header.
+        if bytes.len() < 4 {
+            return Ok(None);
+        }
+
+        let res = self
+            .operationer
+            .route(bytes, from)
+            .await?
+            .map(|it| Captured {
+                method: it.method,
+                bytes: it.bytes.to_vec(),
+                relay: it.relay,
+                endpoint: it.endpoint,
+            });
+
+        if let Some(it) = &res {
+            self.responses.push(it.clone());
+        }
+
+        Ok(res)
+    }
+
+    /// Send the buffer and check the method of the response.
+    pub async fn expect(&mut self, from: SocketAddr, method: Method) -> Result<Captured> {
+        let res = self
+            .send(from)
+            .await?
+            .ok_or_else(|| anyhow!("no response for {:?}", method))?;
+
+        ensure!(
+            res.method == ResponseMethod::Stun(method),
+            "unexpected {:?}",
+            res.method
+        );
+
+        Ok(res)
+    }
+
+    /// Get the credential of the username with an unauthenticated allocate
+    /// request.
+    pub async fn challenge(&mut self, from: SocketAddr, username: &str) -> Result<Credential> {
+        {
+            let mut message = self.message(Method::Allocate(Kind::Request));
+            message.append::<ReqeestedTransport>(Transport::UDP);
+            message.flush(None)?;
+        }
+
+        let res = self.expect(from, Method::Allocate(Kind::Error)).await?;
+
+        let mut attributes = Attributes::default();
+        let message = res.decode(&mut attributes)?;
+        let realm = message.get::<Realm>().ok_or_else(|| anyhow!("no realm"))?;
+
+        Ok(Credential {
+            username: username.to_string(),
+            nonce: message
+                .get::<Nonce>()
+                .ok_or_else(|| anyhow!("no nonce"))?
+                .to_string(),
+            digest: stun::util::long_term_credential_digest(username, "test", realm),
+            realm: realm.to_string(),
+        })
+    }
+
+    /// Allocate a relay port for the address, returns the credential and
+    /// the port.
+    pub async fn allocate(&mut self, from: SocketAddr) -> Result<(Credential, u16)> {
+        let credential = self.challenge(from, "test").await?;
+
+        {
+            let mut message = self.message(Method::Allocate(Kind::Request));
+            message.append::<ReqeestedTransport>(Transport::UDP);
+            credential.sign(message)?;
+        }
+
+        let res = self.expect(from, Method::Allocate(Kind::Response)).await?;
+
+        let mut attributes = Attributes::default();
+        let port = res
+            .decode(&mut attributes)?
+            .get::<XorRelayedAddress>()
+            .ok_or_else(|| anyhow!("no relayed address"))?
+            .port();
+
+        Ok((credential, port))
+    }
+}
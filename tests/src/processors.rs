@@ -0,0 +1,1073 @@
+//! The processors against malformed, replayed and out-of-order requests.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{ensure, Result};
+use stun::{
+    attribute::{
+        AlternateServer, ChannelNumber, Data, ErrorKind, Lifetime, MessageIntegrity, Nonce,
+        ReqeestedTransport, Transport, UserName, XorPeerAddress, XorRelayedAddress,
+    },
+    Attributes, ChannelData, Kind, MessageReader, MessageWriter, Method,
+};
+use turn::{
+    integrity::IntegrityPool,
+    operations::{
+        CredentialMechanism, IngressTransport, Processor, ProcessorFuture, Requet, Response,
+        TransportContext, DEFAULT_LIFETIME, MAX_LIFETIME,
+    },
+    sessions::{AddressRebind, Counters, DuplicateAllocate, PERMISSION_LIFETIME},
+    Clock, Observer, Operation, ResponseMethod, Service, SessionAddr, DEFAULT_PORT_RANGE,
+};
+use turn_server::{
+    admission::AdmissionController,
+    alerts::Alerts,
+    config::{Admission, Anonymous, Config},
+    memory::MemoryBudget,
+    router::Router,
+    statistics::Statistics,
+    usage::Usage,
+};
+
+use crate::mock::{audit_log, Captured, MockTransport, Static};
+
+fn create_service() -> Service<Static> {
+    Service::with_clock(
+        "localhost".to_string(),
+        vec![interface()],
+        DEFAULT_PORT_RANGE,
+        Clock::Manual,
+        Static,
+    )
+}
+
+fn interface() -> SocketAddr {
+    "127.0.0.1:3478".parse().unwrap()
+}
+
+fn client(port: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], port))
+}
+
+fn peer(port: u16) -> SocketAddr {
+    let mut peer = interface();
+    peer.set_port(port);
+    peer
+}
+
+#[tokio::test]
+async fn malformed_input_testing() -> Result<()> {
+    let service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+
+    // Too small to have a header.
+    ensure!(transport
+        .send_bytes(client(1), &[0x00, 0x01])
+        .await?
+        .is_none());
+
+    // Not a stun message or channel data.
+    ensure!(transport.send_bytes(client(1), &[0xff; 20]).await.is_err());
+
+    // A header without the magic cookie.
+    ensure!(transport.send_bytes(client(1), &[0x00; 20]).await.is_err());
+
+    // Every truncation of a valid request.
+    let credential = transport.challenge(client(1), "test").await?;
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        credential.sign(message)?;
+    }
+
+    let bytes = transport.bytes.to_vec();
+    for size in 0..bytes.len() {
+        if let Ok(Some(res)) = transport.send_bytes(client(1), &bytes[..size]).await {
+            ensure!(
+                res.error().is_some(),
+                "truncated request succeeded: size={}",
+                size
+            );
+        }
+    }
+
+    // Channel data with a length longer than the datagram.
+    ensure!(transport
+        .send_bytes(client(1), &[0x40, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00])
+        .await
+        .is_err());
+
+    // Rtp and dtls are not channel data.
+    ensure!(transport
+        .send_bytes(client(1), &[0x80, 0x60, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+        .await
+        .is_err());
+
+    // A request followed by trailing bytes.
+    let mut padded = bytes.clone();
+    padded.extend_from_slice(&[0u8; 4]);
+    ensure!(transport.send_bytes(client(1), &padded).await.is_err());
+
+    ensure!(service.get_sessions().allocated() == 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn replayed_request_testing() -> Result<()> {
+    let service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (credential, _) = transport.allocate(client(1)).await?;
+
+    // The last request of the allocation was the authenticated one.
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::AllocationMismatch as u16));
+
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(0);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Refresh(Kind::Response))
+        .await?;
+
+    // The nonce is dropped with the allocation.
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::StaleNonce as u16));
+
+    ensure!(service.get_sessions().counters().allocated == 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn wrong_credentials_testing() -> Result<()> {
+    let service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (credential, _) = transport.allocate(client(1)).await?;
+
+    // A request of another username that does not prove its credentials is
+    // not taken as using the wrong credentials.
+    let mut other = credential.clone();
+    other.username = "other".to_string();
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        other.sign(message)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::Unauthorized as u16));
+
+    other.digest = stun::util::long_term_credential_digest("other", "test", &other.realm);
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        other.sign(message)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::WrongCredentials as u16));
+
+    // The session keeps its credentials.
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Refresh(Kind::Response))
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn duplicate_allocate_testing() -> Result<()> {
+    let service = create_service();
+    let sessions = service.get_sessions();
+    sessions.set_duplicate_allocate(DuplicateAllocate::Replace);
+
+    let mut transport = MockTransport::new(&service, interface());
+    let (credential, port) = transport.allocate(client(1)).await?;
+
+    // The retransmission of the allocate request gets the same port.
+    let res = transport
+        .expect(client(1), Method::Allocate(Kind::Response))
+        .await?;
+
+    let mut attributes = Attributes::default();
+    let relayed = res.decode(&mut attributes)?.get::<XorRelayedAddress>();
+    ensure!(relayed.map(|it| it.port()) == Some(port));
+
+    let (_, peer_port) = transport.allocate(client(2)).await?;
+    {
+        let mut message = transport.message(Method::CreatePermission(Kind::Request));
+        message.append::<XorPeerAddress>(peer(peer_port));
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::CreatePermission(Kind::Response))
+        .await?;
+
+    // A new allocate request replaces the allocation and its permissions.
+    {
+        let mut message = MessageWriter::new(
+            Method::Allocate(Kind::Request),
+            &[8u8; 12],
+            &mut transport.bytes,
+        );
+
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Allocate(Kind::Response))
+        .await?;
+
+    let counters = sessions.counters();
+    ensure!(counters.allocated == 2);
+    ensure!(counters.permissions == 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn address_rebind_testing() -> Result<()> {
+    let service = create_service();
+    let sessions = service.get_sessions();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let other = SocketAddr::from(([10, 0, 0, 2], 1));
+    let (credential, port) = transport.allocate(client(1)).await?;
+    let (peer_credential, peer_port) = transport.allocate(other).await?;
+
+    {
+        let mut message = transport.message(Method::ChannelBind(Kind::Request));
+        message.append::<ChannelNumber>(0x4000);
+        message.append::<XorPeerAddress>(peer(port));
+        peer_credential.sign(message)?;
+    }
+
+    transport
+        .expect(other, Method::ChannelBind(Kind::Response))
+        .await?;
+
+    // The client is not aware that its address changed and keeps the nonce of
+    // the previous address, which is stale on the new port by default.
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::StaleNonce as u16));
+    ensure!(sessions
+        .get_session(&SessionAddr {
+            address: client(2),
+            interface: interface(),
+        })
+        .get_ref()
+        .is_none());
+
+    // Only the port changed, the allocation moves to the new address, but not
+    // with the nonce of the new address, which matches no allocation.
+    sessions.set_address_rebind(AddressRebind::SameIp);
+    let fresh = transport.challenge(client(2), "test").await?;
+    {
+        let mut message = transport.message(Method::CreatePermission(Kind::Request));
+        message.append::<XorPeerAddress>(peer(peer_port));
+        fresh.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::AllocationMismatch as u16));
+
+    {
+        let mut message = transport.message(Method::CreatePermission(Kind::Request));
+        message.append::<XorPeerAddress>(peer(peer_port));
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(2), Method::CreatePermission(Kind::Response))
+        .await?;
+
+    let moved = SessionAddr {
+        address: client(2),
+        interface: interface(),
+    };
+
+    ensure!(sessions.get_port_session(port) == Some(moved));
+    ensure!(sessions.has_permission(&moved, peer_port));
+
+    // The data is relayed between the new address and the peer, in both
+    // directions, and nothing is relayed for the previous address.
+    let indicate = |transport: &mut MockTransport<Static>, port: u16| {
+        let mut message = transport.message(Method::SendIndication);
+        message.append::<XorPeerAddress>(peer(port));
+        message.append::<Data>(b"hello");
+        message.flush(None)
+    };
+
+    indicate(&mut transport, port)?;
+    let res = transport.send(other).await?.unwrap();
+    ensure!(res.relay == Some(client(2)));
+    ensure!(res.endpoint.is_none());
+
+    indicate(&mut transport, peer_port)?;
+    ensure!(transport.send(client(2)).await?.and_then(|it| it.relay) == Some(other));
+
+    indicate(&mut transport, peer_port)?;
+    ensure!(transport.send(client(1)).await?.is_none());
+
+    transport.bytes.clear();
+    ChannelData {
+        number: 0x4000,
+        bytes: &[0u8; 4],
+    }
+    .encode(&mut transport.bytes);
+
+    ensure!(transport.send(client(2)).await?.and_then(|it| it.relay) == Some(other));
+    ensure!(transport.send(client(1)).await?.is_none());
+
+    // The nonce moved with the allocation, it is stale on another ip under the
+    // same ip policy.
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(2), Method::Refresh(Kind::Response))
+        .await?;
+
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    let res = transport
+        .send(SocketAddr::from(([10, 0, 0, 3], 1)))
+        .await?
+        .unwrap();
+    ensure!(res.error() == Some(ErrorKind::StaleNonce as u16));
+
+    let counters = sessions.counters();
+    ensure!(counters.allocated == 2);
+    ensure!(counters.ports == 2);
+    ensure!(counters.permissions == 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn address_rebind_stream_testing() -> Result<()> {
+    let service = create_service();
+    let sessions = service.get_sessions();
+    sessions.set_address_rebind(AddressRebind::SameUser);
+
+    // The allocation of a stream transport ends with its connection, a new
+    // connection does not take it over.
+    let mut transport = MockTransport::with_context(
+        &service,
+        interface(),
+        TransportContext::new(IngressTransport::Tcp, interface()),
+    );
+
+    let (credential, port) = transport.allocate(client(1)).await?;
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::StaleNonce as u16));
+    ensure!(
+        sessions.get_port_session(port)
+            == Some(SessionAddr {
+                address: client(1),
+                interface: interface(),
+            })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn out_of_order_request_testing() -> Result<()> {
+    let service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (_, port) = transport.allocate(client(1)).await?;
+    let credential = transport.challenge(client(2), "test").await?;
+
+    {
+        let mut message = transport.message(Method::CreatePermission(Kind::Request));
+        message.append::<XorPeerAddress>(peer(port));
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::AllocationMismatch as u16));
+
+    {
+        let mut message = transport.message(Method::ChannelBind(Kind::Request));
+        message.append::<ChannelNumber>(0x4000);
+        message.append::<XorPeerAddress>(peer(port));
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::AllocationMismatch as u16));
+
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::AllocationMismatch as u16));
+
+    // Channel data before the channel is bound.
+    transport.bytes.clear();
+    ChannelData {
+        number: 0x4000,
+        bytes: &[0u8; 4],
+    }
+    .encode(&mut transport.bytes);
+
+    ensure!(transport.send(client(1)).await?.is_none());
+    ensure!(service.get_sessions().allocated() == 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_allocation_testing() -> Result<()> {
+    let service = create_service();
+    let sessions = service.get_sessions();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (credential, _) = transport.allocate(client(1)).await?;
+
+    // The lifetime shorter than the default is raised to it.
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(60);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Refresh(Kind::Response))
+        .await?;
+
+    sessions.advance(DEFAULT_LIFETIME as u64 - 1);
+    ensure!(sessions.allocated() == 1);
+
+    sessions.advance(1);
+    ensure!(sessions.counters() == Counters::default());
+
+    // The credential of the expired allocation is stale.
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::StaleNonce as u16));
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_permission_testing() -> Result<()> {
+    let service = create_service();
+    let sessions = service.get_sessions();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (credential, port) = transport.allocate(client(1)).await?;
+    let (_, peer_port) = transport.allocate(client(2)).await?;
+
+    {
+        let mut message = transport.message(Method::CreatePermission(Kind::Request));
+        message.append::<XorPeerAddress>(peer(peer_port));
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::CreatePermission(Kind::Response))
+        .await?;
+
+    let indicate = |transport: &mut MockTransport<Static>| {
+        let mut message = transport.message(Method::SendIndication);
+        message.append::<XorPeerAddress>(peer(port));
+        message.append::<Data>(b"hello");
+        message.flush(None)
+    };
+
+    indicate(&mut transport)?;
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.relay == Some(client(1)));
+
+    // The data of the peer is dropped once the permission expires, although
+    // both allocations are still alive.
+    sessions.advance(PERMISSION_LIFETIME);
+    ensure!(sessions.allocated() == 2);
+
+    indicate(&mut transport)?;
+    ensure!(transport.send(client(2)).await?.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn recycled_port_testing() -> Result<()> {
+    // There are only two ports, the port of an expired allocation is the next
+    // one to be allocated.
+    let service = Service::with_clock(
+        "localhost".to_string(),
+        vec![interface()],
+        50000..50002,
+        Clock::Manual,
+        Static,
+    );
+
+    let sessions = service.get_sessions();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (credential, port) = transport.allocate(client(1)).await?;
+    let (peer_credential, peer_port) = transport.allocate(client(3)).await?;
+
+    {
+        let mut message = transport.message(Method::ChannelBind(Kind::Request));
+        message.append::<ChannelNumber>(0x4000);
+        message.append::<XorPeerAddress>(peer(peer_port));
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::ChannelBind(Kind::Response))
+        .await?;
+
+    let indicate = |transport: &mut MockTransport<Static>| {
+        let mut message = transport.message(Method::SendIndication);
+        message.append::<XorPeerAddress>(peer(port));
+        message.append::<Data>(b"hello");
+        message.flush(None)
+    };
+
+    let channel_data = |transport: &mut MockTransport<Static>| {
+        transport.bytes.clear();
+        ChannelData {
+            number: 0x4000,
+            bytes: &[0u8; 4],
+        }
+        .encode(&mut transport.bytes);
+    };
+
+    indicate(&mut transport)?;
+    ensure!(transport.send(client(3)).await?.and_then(|it| it.relay) == Some(client(1)));
+
+    channel_data(&mut transport);
+    ensure!(transport.send(client(3)).await?.and_then(|it| it.relay) == Some(client(1)));
+
+    // Only the allocation of the client expires.
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(MAX_LIFETIME);
+        peer_credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(3), Method::Refresh(Kind::Response))
+        .await?;
+
+    let first = sessions.lookup_by_relay_port(port);
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(DEFAULT_LIFETIME);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Refresh(Kind::Response))
+        .await?;
+
+    sessions.advance(DEFAULT_LIFETIME as u64);
+    ensure!(sessions.lookup_by_relay_port(port).is_none());
+
+    // The port is allocated to a new session, the peer of the previous owner
+    // can not reach it.
+    ensure!(transport.allocate(client(2)).await?.1 == port);
+
+    let second = sessions.lookup_by_relay_port(port);
+    ensure!(second.map(|it| it.session.address) == Some(client(2)));
+    ensure!(second.map(|it| it.generation) != first.map(|it| it.generation));
+
+    indicate(&mut transport)?;
+    ensure!(transport.send(client(3)).await?.is_none());
+
+    channel_data(&mut transport);
+    ensure!(transport.send(client(3)).await?.is_none());
+
+    let counters = sessions.counters();
+    ensure!(counters.permissions == 0);
+    ensure!(counters.channels == 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn granted_lifetime_testing() -> Result<()> {
+    let service = create_service();
+    let sessions = service.get_sessions();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let lifetime = |res: &Captured| -> Result<Option<u32>> {
+        let mut attributes = Attributes::default();
+        Ok(res.decode(&mut attributes)?.get::<Lifetime>())
+    };
+
+    // The requested lifetime of the allocation is clamped to the maximum.
+    let credential = transport.challenge(client(1), "test").await?;
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.append::<Lifetime>(7200);
+        credential.sign(message)?;
+    }
+
+    let res = transport
+        .expect(client(1), Method::Allocate(Kind::Response))
+        .await?;
+
+    ensure!(lifetime(&res)? == Some(3600));
+    ensure!(
+        sessions
+            .get_session(&SessionAddr {
+                address: client(1),
+                interface: interface(),
+            })
+            .get_ref()
+            .map(|it| (it.expires, it.allocate.ingress))
+            == Some((3600, Some(IngressTransport::Udp)))
+    );
+
+    // The lifetime of a refresh is granted within the default and the maximum,
+    // a zero lifetime deletes the allocation.
+    for (requested, granted) in [(7200, 3600), (60, 600), (1200, 1200), (0, 0)] {
+        {
+            let mut message = transport.message(Method::Refresh(Kind::Request));
+            message.append::<Lifetime>(requested);
+            credential.sign(message)?;
+        }
+
+        let res = transport
+            .expect(client(1), Method::Refresh(Kind::Response))
+            .await?;
+
+        ensure!(lifetime(&res)? == Some(granted));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn wrong_credential_testing() -> Result<()> {
+    let service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+
+    let mut credential = transport.challenge(client(1), "test").await?;
+    credential.digest = stun::util::long_term_credential_digest("test", "wrong", &credential.realm);
+
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::Unauthorized as u16));
+    ensure!(service.get_sessions().allocated() == 0);
+    Ok(())
+}
+
+// An indication of a method in the range of the expert review.
+const CUSTOM_INDICATION: Method = Method::Other(0x0210);
+
+/// Echoes the data of the custom indications to the sender.
+struct Echo;
+
+impl Processor<Static> for Echo {
+    fn process<'c, 'a: 'c>(
+        &'c self,
+        req: Requet<'c, 'a, Static, MessageReader<'c>>,
+    ) -> ProcessorFuture<'c, 'a> {
+        Box::pin(async move {
+            let data = req.message.get::<Data>()?;
+            {
+                let mut message = MessageWriter::extend(CUSTOM_INDICATION, req.message, req.bytes);
+                message.append::<Data>(data);
+                message.flush(None).ok()?;
+            }
+
+            Some(Response {
+                method: ResponseMethod::Stun(CUSTOM_INDICATION),
+                bytes: req.bytes,
+                endpoint: None,
+                relay: None,
+            })
+        })
+    }
+}
+
+#[tokio::test]
+async fn custom_method_testing() -> Result<()> {
+    let send = |transport: &mut MockTransport<Static>| {
+        let mut message = transport.message(CUSTOM_INDICATION);
+        message.append::<Data>(b"hello");
+        message.flush(None)
+    };
+
+    // The methods without a processor are dropped.
+    let mut service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+    send(&mut transport)?;
+    ensure!(transport.send(client(1)).await?.is_none());
+
+    service.register(CUSTOM_INDICATION, Echo);
+    let mut transport = MockTransport::new(&service, interface());
+    send(&mut transport)?;
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.method == ResponseMethod::Stun(CUSTOM_INDICATION));
+
+    let mut attributes = Attributes::default();
+    let message = res.decode(&mut attributes)?;
+    ensure!(message.method == CUSTOM_INDICATION);
+    ensure!(message.get::<Data>() == Some(&b"hello"[..]));
+    Ok(())
+}
+
+/// Refuses the channel bindings.
+#[derive(Clone)]
+struct NoChannels;
+
+impl Observer for NoChannels {
+    async fn get_password(&self, _: &SessionAddr, _: &str) -> Option<String> {
+        Some("test".to_string())
+    }
+
+    fn authorize(&self, _: &SessionAddr, _: &str, operation: Operation<'_>) -> bool {
+        !matches!(operation, Operation::ChannelBind(..))
+    }
+}
+
+#[tokio::test]
+async fn refused_operation_testing() -> Result<()> {
+    let service = Service::new("localhost".to_string(), vec![interface()], NoChannels);
+    let mut transport = MockTransport::new(&service, interface());
+
+    let (credential, _) = transport.allocate(client(1)).await?;
+    let (_, port) = transport.allocate(client(2)).await?;
+
+    {
+        let mut message = transport.message(Method::CreatePermission(Kind::Request));
+        message.append::<XorPeerAddress>(peer(port));
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::CreatePermission(Kind::Response))
+        .await?;
+
+    {
+        let mut message = transport.message(Method::ChannelBind(Kind::Request));
+        message.append::<ChannelNumber>(0x4000);
+        message.append::<XorPeerAddress>(peer(port));
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::Forbidden as u16));
+    ensure!(service.get_sessions().counters().channels == 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn refused_operation_audit_testing() -> Result<()> {
+    let (audit, path) = audit_log("operations")?;
+    let config = Arc::new(Config {
+        anonymous: Anonymous {
+            subnets: vec!["10.0.0.0/8".parse().map_err(anyhow::Error::msg)?],
+            max_allocations: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+
+    let statistics = Statistics::default();
+    let usage = Usage::new(
+        config.usage.clone(),
+        config.turn.realm.clone(),
+        statistics.clone(),
+    );
+    let observer =
+        turn_server::observer::Observer::new(config, statistics, audit, Alerts::default(), usage)
+            .await?;
+
+    let service = Service::new("localhost".to_string(), vec![interface()], observer);
+    let mut transport = MockTransport::new(&service, interface());
+
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.flush(None)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::AllocationQuotaReached as u16));
+
+    let record: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&path)?.trim())?;
+    ensure!(record["kind"] == "operation_refused");
+    ensure!(record["actor"]["username"] == "");
+    ensure!(record["detail"]["operation"] == "allocate");
+    ensure!(record["detail"]["reason"] == "anonymous-quota");
+    Ok(())
+}
+
+#[tokio::test]
+async fn short_term_credential_testing() -> Result<()> {
+    let service = create_service();
+    let mut transport = MockTransport::with_context(&service, interface(), {
+        let mut transport = TransportContext::new(IngressTransport::Udp, interface());
+        transport.credential = CredentialMechanism::ShortTerm;
+        transport
+    });
+
+    // There is no challenge, a request without the credentials is malformed.
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.flush(None)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::BadRequest as u16));
+
+    // The key is the password, without a nonce or a realm.
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.append::<UserName>("test");
+        message.flush(Some(b"wrong"))?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::Unauthorized as u16));
+
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.append::<UserName>("test");
+        message.flush(Some(b"test"))?;
+    }
+
+    let res = transport
+        .expect(client(2), Method::Allocate(Kind::Response))
+        .await?;
+
+    let mut attributes = Attributes::default();
+    let message = res.decode(&mut attributes)?;
+    ensure!(message.integrity(b"test").is_ok());
+    ensure!(message.get::<Nonce>().is_none());
+
+    ensure!(service.get_sessions().allocated() == 1);
+    Ok(())
+}
+
+/// Trusts the clients of `10.0.0.1`.
+#[derive(Clone)]
+struct Trusted;
+
+impl Observer for Trusted {
+    async fn get_password(&self, _: &SessionAddr, _: &str) -> Option<String> {
+        Some("test".to_string())
+    }
+
+    fn is_trusted(&self, addr: &SessionAddr) -> bool {
+        addr.address.ip() == client(0).ip()
+    }
+}
+
+#[tokio::test]
+async fn anonymous_allocation_testing() -> Result<()> {
+    let service = Service::new("localhost".to_string(), vec![interface()], Trusted);
+    let mut transport = MockTransport::new(&service, interface());
+
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.flush(None)?;
+    }
+
+    // The other sources are still challenged.
+    let res = transport
+        .send(SocketAddr::from(([10, 0, 0, 2], 1)))
+        .await?
+        .unwrap();
+
+    ensure!(res.error() == Some(ErrorKind::Unauthorized as u16));
+
+    let res = transport
+        .expect(client(1), Method::Allocate(Kind::Response))
+        .await?;
+
+    let mut attributes = Attributes::default();
+    ensure!(res
+        .decode(&mut attributes)?
+        .get::<MessageIntegrity>()
+        .is_none());
+
+    let session = service
+        .get_sessions()
+        .get_session(&SessionAddr {
+            address: client(1),
+            interface: interface(),
+        })
+        .get_ref()
+        .map(|it| it.auth.username.clone());
+
+    ensure!(session.as_deref() == Some(""));
+    ensure!(service.get_sessions().allocated() == 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn deny_new_sessions_testing() -> Result<()> {
+    let mut service = create_service();
+    let (audit, path) = audit_log("admission")?;
+    let admission = AdmissionController::new(
+        Admission {
+            alternate_server: Some(peer(3479)),
+            ..Default::default()
+        },
+        Router::default(),
+        MemoryBudget::default(),
+        audit,
+    );
+
+    service.register(Method::Allocate(Kind::Request), admission.clone());
+    let mut transport = MockTransport::new(&service, interface());
+    let (credential, _) = transport.allocate(client(1)).await?;
+
+    // New sessions are redirected to the alternate server.
+    admission.set_deny_new_sessions(true);
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        message.flush(None)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::TryAlternate as u16));
+
+    let mut attributes = Attributes::default();
+    ensure!(res.decode(&mut attributes)?.get::<AlternateServer>() == Some(peer(3479)));
+
+    // The refusal is recorded in the audit log.
+    let record: serde_json::Value = serde_json::from_str(std::fs::read_to_string(&path)?.trim())?;
+    ensure!(record["kind"] == "allocate_refused");
+    ensure!(record["detail"]["reason"] == "maintenance");
+
+    // The existing sessions and the bindings are still served.
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Refresh(Kind::Response))
+        .await?;
+
+    transport
+        .message(Method::Binding(Kind::Request))
+        .flush(None)?;
+    transport
+        .expect(client(2), Method::Binding(Kind::Response))
+        .await?;
+
+    admission.set_deny_new_sessions(false);
+    transport.allocate(client(2)).await?;
+
+    ensure!(service.get_sessions().allocated() == 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn integrity_pool_testing() -> Result<()> {
+    let mut service = create_service();
+    service.set_integrity_pool(IntegrityPool::new(2, 16));
+
+    let mut transport = MockTransport::new(&service, interface());
+    let (credential, _) = transport.allocate(client(1)).await?;
+
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    transport
+        .expect(client(1), Method::Refresh(Kind::Response))
+        .await?;
+
+    // A wrong key is still refused on the workers.
+    let mut wrong = credential.clone();
+    wrong.digest = [0u8; 16];
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        wrong.sign(message)?;
+    }
+
+    let res = transport.send(client(1)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::Unauthorized as u16));
+
+    // Without room in the queue, the new sessions are shed before the hmac.
+    let mut service = create_service();
+    service.set_integrity_pool(IntegrityPool::new(1, 0));
+
+    let mut transport = MockTransport::new(&service, interface());
+    let credential = transport.challenge(client(2), "test").await?;
+    {
+        let mut message = transport.message(Method::Allocate(Kind::Request));
+        message.append::<ReqeestedTransport>(Transport::UDP);
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(2)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::InsufficientCapacity as u16));
+    ensure!(service.get_integrity_pool().unwrap().shed() == 1);
+    ensure!(service.get_sessions().allocated() == 0);
+
+    // The existing sessions are shed as well, their hmac is not computed on
+    // the receiving thread.
+    let mut service = create_service();
+    let mut transport = MockTransport::new(&service, interface());
+    let (credential, _) = transport.allocate(client(3)).await?;
+
+    service.set_integrity_pool(IntegrityPool::new(1, 0));
+    let mut transport = MockTransport::new(&service, interface());
+    {
+        let mut message = transport.message(Method::Refresh(Kind::Request));
+        message.append::<Lifetime>(600);
+        credential.sign(message)?;
+    }
+
+    let res = transport.send(client(3)).await?.unwrap();
+    ensure!(res.error() == Some(ErrorKind::InsufficientCapacity as u16));
+    ensure!(service.get_integrity_pool().unwrap().shed() == 0);
+    ensure!(service.get_integrity_pool().unwrap().shed_sessions() == 1);
+    Ok(())
+}
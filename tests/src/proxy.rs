@@ -0,0 +1,107 @@
+//! The PROXY protocol headers of the tcp listeners.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use stun::{attribute::XorMappedAddress, Decoder, Kind, MessageWriter, Method, Payload};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use turn::Service;
+use turn_server::{
+    buffers::SocketBuffers,
+    config::{Config, Interface, Transport, Turn},
+    malformed::MalformedFilter,
+    memory::MemoryBudget,
+    proxy::LOCAL_V2,
+    router::Router,
+    server,
+    statistics::Statistics,
+};
+
+use crate::mock::{Static, TOKEN};
+
+/// Send the header and a binding request on a new connection, returns the
+/// XOR-MAPPED-ADDRESS of the response and the local address of the
+/// connection.
+async fn binding(target: SocketAddr, header: &[u8]) -> Result<(SocketAddr, SocketAddr)> {
+    let mut stream = TcpStream::connect(target).await?;
+    let mut bytes = BytesMut::new();
+    MessageWriter::new(Method::Binding(Kind::Request), &TOKEN, &mut bytes).flush(None)?;
+    stream.write_all(&[header, &bytes[..]].concat()).await?;
+
+    let mut buf = [0u8; 1500];
+    timeout(Duration::from_secs(1), stream.read_exact(&mut buf[..20])).await??;
+    let size = Decoder::message_size(&buf, true)?;
+    timeout(
+        Duration::from_secs(1),
+        stream.read_exact(&mut buf[20..size]),
+    )
+    .await??;
+
+    let mut decoder = Decoder::default();
+    let Payload::Message(message) = decoder.decode(&buf[..size])? else {
+        return Err(anyhow!("response is not a stun message"));
+    };
+
+    let mapped = message
+        .get::<XorMappedAddress>()
+        .ok_or_else(|| anyhow!("response does not contain XOR-MAPPED-ADDRESS"))?;
+
+    Ok((mapped, stream.local_addr()?))
+}
+
+#[tokio::test]
+async fn proxy_protocol_testing() -> Result<()> {
+    let bind: SocketAddr = "127.0.0.1:3490".parse()?;
+    let config = Config {
+        turn: Turn {
+            interfaces: vec![Interface {
+                transport: Transport::TCP,
+                credential: Default::default(),
+                max_datagram_size: 2048,
+                proxy_protocol: true,
+                external: bind,
+                bind,
+            }],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let service = Service::new("localhost".to_string(), vec![bind], Static);
+    server::start(
+        &config,
+        &Statistics::default(),
+        &service,
+        &Router::default(),
+        &MalformedFilter::new(config.malformed, config.privacy.log, Default::default()),
+        &MemoryBudget::default(),
+        &SocketBuffers::new(config.buffers),
+    )
+    .await?;
+
+    // The address of the client is taken from the header.
+    let (mapped, _) = binding(bind, b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 3490\r\n").await?;
+    assert_eq!(mapped, "203.0.113.7:51234".parse()?);
+
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+    header.extend_from_slice(&"2001:db8::7".parse::<std::net::Ipv6Addr>()?.octets());
+    header.extend_from_slice(&"2001:db8::2".parse::<std::net::Ipv6Addr>()?.octets());
+    header.extend_from_slice(&[0xc8, 0x22, 0x0d, 0xa2]);
+
+    let (mapped, _) = binding(bind, &header).await?;
+    assert_eq!(mapped, "[2001:db8::7]:51234".parse()?);
+
+    // A local connection keeps its own address.
+    let (mapped, local) = binding(bind, &LOCAL_V2).await?;
+    assert_eq!(mapped, local);
+
+    // The connections without a header are closed.
+    assert!(binding(bind, b"").await.is_err());
+
+    Ok(())
+}
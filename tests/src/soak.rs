@@ -0,0 +1,204 @@
+//! Allocation churn against the session bookkeeping.
+//!
+//! The service is driven in process, so that a lot of allocations can be
+//! created and closed in a short time. Some of the allocations of each round
+//! are released by the client and the rest are left to expire, and when all
+//! of them are gone the bookkeeping tables must be empty again.
+//!
+//! The test runs for a few seconds by default, set `TURN_SOAK_SECONDS` to run
+//! it for longer:
+//!
+//! ```bash
+//! TURN_SOAK_SECONDS=3600 cargo test -p tests soak -- --nocapture
+//! ```
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, ensure, Result};
+use stun::{
+    attribute::{ChannelNumber, Lifetime, XorPeerAddress},
+    ChannelData, Kind, Method,
+};
+use turn::{
+    operations::DEFAULT_LIFETIME, sessions::Counters, Clock, ResponseMethod, Service,
+    DEFAULT_PORT_RANGE,
+};
+
+use crate::mock::{Credential, MockTransport, Static};
+
+// The number of allocations created in each round, the allocations are
+// paired with each other.
+const CLIENTS: u32 = 32;
+
+const CHANNEL: u16 = 0x4000;
+
+struct Client {
+    address: SocketAddr,
+    credential: Credential,
+    port: u16,
+}
+
+struct Driver {
+    transport: MockTransport<Static>,
+    // Every client of the soak gets a new address.
+    next: u32,
+}
+
+impl Driver {
+    async fn allocate(&mut self) -> Result<Client> {
+        self.next += 1;
+
+        let address = SocketAddr::from((Ipv4Addr::from(0x0a00_0000 + self.next), 40000));
+        let (credential, port) = self.transport.allocate(address).await?;
+
+        Ok(Client {
+            credential,
+            address,
+            port,
+        })
+    }
+
+    async fn bind(&mut self, client: &Client, port: u16) -> Result<()> {
+        let mut peer = self.transport.interface;
+        peer.set_port(port);
+
+        {
+            let mut message = self
+                .transport
+                .message(Method::CreatePermission(Kind::Request));
+            message.append::<XorPeerAddress>(peer);
+            client.credential.sign(message)?;
+        }
+
+        self.transport
+            .expect(client.address, Method::CreatePermission(Kind::Response))
+            .await?;
+
+        {
+            let mut message = self.transport.message(Method::ChannelBind(Kind::Request));
+            message.append::<ChannelNumber>(CHANNEL);
+            message.append::<XorPeerAddress>(peer);
+            client.credential.sign(message)?;
+        }
+
+        self.transport
+            .expect(client.address, Method::ChannelBind(Kind::Response))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Send channel data and check that it is relayed to the peer.
+    async fn relay(&mut self, client: &Client, peer: &Client) -> Result<()> {
+        self.transport.bytes.clear();
+        ChannelData {
+            number: CHANNEL,
+            bytes: &[0u8; 96],
+        }
+        .encode(&mut self.transport.bytes);
+
+        let res = self
+            .transport
+            .send(client.address)
+            .await?
+            .ok_or_else(|| anyhow!("channel data not relayed"))?;
+
+        ensure!(res.method == ResponseMethod::ChannelData);
+        ensure!(res.relay == Some(peer.address));
+        ensure!(res.endpoint.is_none());
+        Ok(())
+    }
+
+    async fn refresh(&mut self, client: &Client, lifetime: u32) -> Result<()> {
+        {
+            let mut message = self.transport.message(Method::Refresh(Kind::Request));
+            message.append::<Lifetime>(lifetime);
+            client.credential.sign(message)?;
+        }
+
+        self.transport
+            .expect(client.address, Method::Refresh(Kind::Response))
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn allocation_churn_soak_testing() -> Result<()> {
+    let seconds = std::env::var("TURN_SOAK_SECONDS")
+        .ok()
+        .and_then(|it| it.parse().ok())
+        .unwrap_or(3);
+
+    let interface: SocketAddr = "127.0.0.1:3478".parse()?;
+    let service = Service::with_clock(
+        "localhost".to_string(),
+        vec![interface],
+        DEFAULT_PORT_RANGE,
+        Clock::Manual,
+        Static,
+    );
+
+    let sessions = service.get_sessions();
+    let mut driver = Driver {
+        transport: MockTransport::new(&service, interface),
+        next: 0,
+    };
+
+    ensure!(sessions.counters() == Counters::default());
+
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let mut rounds = 0u64;
+
+    while Instant::now() < deadline {
+        let mut clients = Vec::with_capacity(CLIENTS as usize);
+        for _ in 0..CLIENTS {
+            clients.push(driver.allocate().await?);
+        }
+
+        for pair in clients.chunks(2) {
+            driver.bind(&pair[0], pair[1].port).await?;
+            driver.bind(&pair[1], pair[0].port).await?;
+            driver.relay(&pair[0], &pair[1]).await?;
+            driver.relay(&pair[1], &pair[0]).await?;
+        }
+
+        // A third of the allocations are released by the client and the rest
+        // expire on their own, so some of the pairs are split between the two.
+        for (index, client) in clients.iter().enumerate() {
+            driver
+                .refresh(client, if index % 3 == 0 { 0 } else { 1 })
+                .await?;
+        }
+
+        // The captured responses are not needed.
+        driver.transport.responses.clear();
+        rounds += 1;
+
+        // The allocations of the last rounds are still alive when the next ones
+        // are created, and they expire while the churn goes on.
+        sessions.advance(DEFAULT_LIFETIME as u64 / 3);
+    }
+
+    // Let the remaining allocations expire.
+    sessions.advance(DEFAULT_LIFETIME as u64);
+
+    println!(
+        "soak: rounds={}, allocations={}",
+        rounds,
+        rounds * CLIENTS as u64
+    );
+
+    ensure!(
+        sessions.counters() == Counters::default(),
+        "leaked: {:?}",
+        sessions.counters()
+    );
+
+    ensure!(sessions.available() == sessions.capacity());
+    Ok(())
+}
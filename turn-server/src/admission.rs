@@ -0,0 +1,280 @@
+use std::{
+    fs::read_to_string,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use turn::sessions::Sessions;
+
+use serde_json::json;
+use stun::{
+    attribute::{AlternateServer, Error, ErrorCode, ErrorKind, Nonce, Realm, UserName},
+    Kind, MessageReader, MessageWriter, Method,
+};
+
+use turn::{
+    operations::{allocate, Processor, ProcessorFuture, Requet, Response},
+    Observer, ResponseMethod,
+};
+
+use crate::{
+    audit::{Actor, Audit},
+    config::Admission,
+    memory::MemoryBudget,
+    router::Router,
+};
+
+/// Read the 1 minute load average, this is only available on linux.
+fn load_average() -> Option<f64> {
+    read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Read the resident memory of the process in megabytes, this is only
+/// available on linux.
+fn resident_memory() -> Option<u64> {
+    let status = read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|it| it.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
+
+struct Inner {
+    config: Admission,
+    router: Router,
+    budget: MemoryBudget,
+    audit: Audit,
+    // The load is stored as the bits of a f64.
+    load: AtomicU64,
+    memory: AtomicU64,
+    deny_new_sessions: AtomicBool,
+}
+
+/// Sheds new allocations under load.
+///
+/// The controller is registered as the processor of allocate requests, new
+/// allocations are refused while the server is overloaded, so that the
+/// existing sessions keep their quality. The cpu load and the memory are
+/// sampled every second, on platforms where they can not be read they never
+/// shed requests.
+///
+/// New allocations can also be refused on demand, for maintenance windows
+/// and controlled rollouts, while the existing sessions and the stun binding
+/// requests are still served. The refused allocations are recorded in the
+/// audit log.
+#[derive(Clone)]
+pub struct AdmissionController(Arc<Inner>);
+
+impl AdmissionController {
+    pub fn new(config: Admission, router: Router, budget: MemoryBudget, audit: Audit) -> Self {
+        Self(Arc::new(Inner {
+            load: AtomicU64::new(0f64.to_bits()),
+            memory: AtomicU64::new(0),
+            deny_new_sessions: AtomicBool::new(config.deny_new_sessions),
+            router,
+            budget,
+            audit,
+            config,
+        }))
+    }
+
+    /// Sample the cpu load, the memory and the memory budget once per
+    /// second.
+    pub fn start_sampler<T>(&self, sessions: Arc<Sessions<T>>)
+    where
+        T: Observer + 'static,
+    {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let cpus = num_cpus::get() as f64;
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+            loop {
+                interval.tick().await;
+
+                if let Some(load) = load_average() {
+                    this.0.load.store((load / cpus).to_bits(), Ordering::Relaxed);
+                }
+
+                if let Some(memory) = resident_memory() {
+                    this.0.memory.store(memory, Ordering::Relaxed);
+                }
+
+                if this.0.config.memory_budget > 0 {
+                    let exceeded = this.0.budget.is_exceeded();
+                    let used = this.0.budget.update(
+                        &sessions.counters(),
+                        this.0.router.queued_bytes(),
+                        (this.0.config.memory_budget << 20) as usize,
+                    );
+
+                    if this.0.budget.is_exceeded() != exceeded {
+                        log::warn!(
+                            "memory budget {}: used={}MB, budget={}MB",
+                            if exceeded { "recovered" } else { "exceeded" },
+                            used >> 20,
+                            this.0.config.memory_budget
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    /// Returns the name of the exceeded limit if the server is overloaded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{
+    ///     admission::AdmissionController, audit::Audit, config::Admission, memory::MemoryBudget, router::Router,
+    /// };
+    ///
+    /// let controller = AdmissionController::new(
+    ///     Admission {
+    ///         max_queue: 1,
+    ///         ..Default::default()
+    ///     },
+    ///     Router::default(),
+    ///     MemoryBudget::default(),
+    ///     Audit::default(),
+    /// );
+    ///
+    /// assert_eq!(controller.overloaded(), None);
+    /// ```
+    pub fn overloaded(&self) -> Option<&'static str> {
+        let config = &self.0.config;
+
+        if config.max_load > 0.0 && f64::from_bits(self.0.load.load(Ordering::Relaxed)) > config.max_load {
+            return Some("load");
+        }
+
+        if config.max_memory > 0 && self.0.memory.load(Ordering::Relaxed) > config.max_memory {
+            return Some("memory");
+        }
+
+        if config.max_queue > 0 && self.0.router.queued() > config.max_queue {
+            return Some("queue");
+        }
+
+        if config.memory_budget > 0 && self.0.budget.is_exceeded() {
+            return Some("budget");
+        }
+
+        None
+    }
+
+    /// Refuse or accept new allocations, the sessions that are already
+    /// allocated are not affected.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{
+    ///     admission::AdmissionController, audit::Audit, config::Admission, memory::MemoryBudget, router::Router,
+    /// };
+    ///
+    /// let controller = AdmissionController::new(
+    ///     Admission::default(),
+    ///     Router::default(),
+    ///     MemoryBudget::default(),
+    ///     Audit::default(),
+    /// );
+    ///
+    /// assert!(!controller.is_denying_new_sessions());
+    ///
+    /// controller.set_deny_new_sessions(true);
+    /// assert!(controller.is_denying_new_sessions());
+    /// ```
+    pub fn set_deny_new_sessions(&self, deny: bool) {
+        self.0.deny_new_sessions.store(deny, Ordering::Relaxed);
+    }
+
+    pub fn is_denying_new_sessions(&self) -> bool {
+        self.0.deny_new_sessions.load(Ordering::Relaxed)
+    }
+
+    fn reject<'a, T: Observer>(&self, req: Requet<'_, 'a, T, MessageReader<'_>>, reason: &str) -> Option<Response<'a>> {
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS
+                .allocate_refused
+                .with_label_values(&[reason])
+                .inc();
+        }
+
+        // The request is refused before it is authenticated, the username is the
+        // one that it presented.
+        self.0.audit.record(
+            Actor::Client(req.address, req.message.get::<UserName>().unwrap_or("")),
+            "allocate_refused",
+            json!({ "reason": reason }),
+        );
+
+        {
+            let mut message = MessageWriter::extend(Method::Allocate(Kind::Error), req.message, req.bytes);
+
+            if let Some(server) = self.0.config.alternate_server {
+                message.append::<ErrorCode>(Error::from(ErrorKind::TryAlternate));
+                message.append::<AlternateServer>(server);
+            } else {
+                message.append::<ErrorCode>(Error::from(ErrorKind::InsufficientCapacity));
+            }
+
+            message.append::<Nonce>(&req.service.sessions.get_nonce(req.address).get_ref()?.0);
+            message.append::<Realm>(&req.service.realm);
+            message.flush(None).ok()?;
+        }
+
+        Some(Response {
+            method: ResponseMethod::Stun(Method::Allocate(Kind::Error)),
+            bytes: req.bytes,
+            endpoint: None,
+            relay: None,
+        })
+    }
+}
+
+impl<T: Observer + 'static> Processor<T> for AdmissionController {
+    fn process<'c, 'a: 'c>(&'c self, req: Requet<'c, 'a, T, MessageReader<'c>>) -> ProcessorFuture<'c, 'a> {
+        Box::pin(async move {
+            // The retransmitted and the repeated allocate requests of the sessions
+            // that are already allocated are still answered.
+            if self.is_denying_new_sessions()
+                && req
+                    .service
+                    .sessions
+                    .get_session(req.address)
+                    .get_ref()
+                    .and_then(|it| it.allocate.port)
+                    .is_none()
+            {
+                log::debug!(
+                    "allocate refused, new sessions are denied: interface={:?}",
+                    req.address.interface
+                );
+
+                return self.reject(req, "maintenance");
+            }
+
+            if let Some(reason) = self.overloaded() {
+                log::debug!(
+                    "allocate refused, server overloaded: interface={:?}, reason={}",
+                    req.address.interface,
+                    reason
+                );
+
+                return self.reject(req, reason);
+            }
+
+            allocate::process(req).await
+        })
+    }
+}
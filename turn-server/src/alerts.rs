@@ -0,0 +1,188 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use reqwest::{Client, ClientBuilder};
+use serde_json::json;
+use turn::{Observer, Service};
+
+use crate::config;
+
+/// A threshold of an alert, it only reports the changes of the state, so
+/// that an alert is raised once and resolved once.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::alerts::Threshold;
+///
+/// let mut threshold = Threshold::new(90);
+///
+/// assert_eq!(threshold.check(90), None);
+/// assert_eq!(threshold.check(91), Some(true));
+/// assert_eq!(threshold.check(95), None);
+/// assert_eq!(threshold.check(50), Some(false));
+///
+/// // A threshold of 0 is disabled.
+/// assert_eq!(Threshold::new(0).check(100), None);
+/// ```
+#[derive(Debug)]
+pub struct Threshold {
+    limit: u64,
+    firing: bool,
+}
+
+impl Threshold {
+    pub fn new(limit: u64) -> Self {
+        Self { limit, firing: false }
+    }
+
+    /// Check the value against the threshold, returns `Some(true)` when the
+    /// alert is raised and `Some(false)` when it is resolved.
+    pub fn check(&mut self, value: u64) -> Option<bool> {
+        if self.limit == 0 {
+            return None;
+        }
+
+        let firing = value > self.limit;
+        if firing == self.firing {
+            return None;
+        }
+
+        self.firing = firing;
+        Some(firing)
+    }
+}
+
+/// The built-in alerts of the turn server.
+///
+/// The thresholds of the config are checked at the interval of the config,
+/// and every change of the state of an alert is posted to the webhook, for
+/// the small deployments that do not have a monitoring stack.
+#[derive(Clone, Default)]
+pub struct Alerts {
+    auth_failures: Arc<AtomicU64>,
+}
+
+impl Alerts {
+    /// Count a failed authentication.
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Check the thresholds at the interval, the alerts are only checked when
+    /// the webhook is set.
+    pub fn start<T>(&self, config: config::Alerts, realm: String, service: Service<T>) -> Result<()>
+    where
+        T: Clone + Observer + 'static,
+    {
+        let Some(webhook) = config.webhook.clone() else {
+            return Ok(());
+        };
+
+        let notifier = Notifier {
+            client: ClientBuilder::new().timeout(Duration::from_secs(5)).build()?,
+            webhook,
+            realm,
+        };
+
+        let auth_failures = self.auth_failures.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+            let mut port_usage = Threshold::new(config.port_usage);
+            let mut auth_failure_rate = Threshold::new(config.auth_failures);
+
+            // The number of failed authentications at each check within the last
+            // minute, the oldest is at the front.
+            let mut samples = VecDeque::<(Instant, u64)>::new();
+
+            loop {
+                interval.tick().await;
+
+                let sessions = service.get_sessions();
+                let capacity = sessions.capacity().max(1) as u64;
+                let usage = (capacity - sessions.available() as u64) * 100 / capacity;
+                if let Some(firing) = port_usage.check(usage) {
+                    let text = if firing {
+                        format!("port pool usage is {}%, above {}%", usage, config.port_usage)
+                    } else {
+                        format!("port pool usage is back to {}%", usage)
+                    };
+
+                    notifier
+                        .notify("port_usage", firing, usage, config.port_usage, text)
+                        .await;
+                }
+
+                let now = Instant::now();
+                let total = auth_failures.load(Ordering::Relaxed);
+                while samples
+                    .front()
+                    .is_some_and(|(time, _)| now.duration_since(*time) > Duration::from_secs(60))
+                {
+                    samples.pop_front();
+                }
+
+                let failures = total - samples.front().map(|(_, it)| *it).unwrap_or(total);
+                samples.push_back((now, total));
+
+                if let Some(firing) = auth_failure_rate.check(failures) {
+                    let text = if firing {
+                        format!(
+                            "{} authentications failed within a minute, above {}",
+                            failures, config.auth_failures
+                        )
+                    } else {
+                        format!("authentication failures are back to {} within a minute", failures)
+                    };
+
+                    notifier
+                        .notify("auth_failures", firing, failures, config.auth_failures, text)
+                        .await;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+struct Notifier {
+    client: Client,
+    webhook: String,
+    realm: String,
+}
+
+impl Notifier {
+    /// Post the change of the state of an alert to the webhook.
+    async fn notify(&self, alert: &str, firing: bool, value: u64, threshold: u64, text: String) {
+        let state = if firing { "firing" } else { "resolved" };
+        log::warn!(
+            "alert {}: alert={}, value={}, threshold={}",
+            state,
+            alert,
+            value,
+            threshold
+        );
+
+        let body = json!({
+            "text": format!("[turn-server {}] {}", self.realm, text),
+            "alert": alert,
+            "state": state,
+            "realm": self.realm,
+            "value": value,
+            "threshold": threshold,
+        });
+
+        if let Err(e) = self.client.post(&self.webhook).json(&body).send().await {
+            log::error!("failed to post alert to webhook: alert={}, err={}", alert, e);
+        }
+    }
+}
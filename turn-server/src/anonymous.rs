@@ -0,0 +1,106 @@
+use std::{net::IpAddr, sync::Arc};
+
+use ahash::AHashSet;
+use parking_lot::Mutex;
+use turn::SessionAddr;
+
+use crate::config::Anonymous;
+
+struct Inner {
+    config: Anonymous,
+    allocations: Mutex<AHashSet<SessionAddr>>,
+}
+
+/// The quotas of the allocations without credentials.
+///
+/// An allocation is counted from the moment it is authorized until the
+/// session is closed, so that the concurrent requests can not exceed the
+/// quotas.
+#[derive(Clone)]
+pub struct AnonymousRelay(Arc<Inner>);
+
+impl AnonymousRelay {
+    pub fn new(config: Anonymous) -> Self {
+        Self(Arc::new(Inner {
+            allocations: Mutex::new(AHashSet::with_capacity(config.max_allocations)),
+            config,
+        }))
+    }
+
+    /// Whether the source ip is in one of the trusted subnets.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{anonymous::AnonymousRelay, config::Anonymous};
+    ///
+    /// let relay = AnonymousRelay::new(Anonymous {
+    ///     subnets: vec!["10.0.0.0/8".parse().unwrap()],
+    ///     ..Default::default()
+    /// });
+    ///
+    /// assert!(relay.is_trusted("10.1.2.3".parse().unwrap()));
+    /// assert!(!relay.is_trusted("192.168.1.2".parse().unwrap()));
+    /// ```
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.config.subnets.iter().any(|it| it.contains(ip))
+    }
+
+    /// Count the allocation of addr, returns `false` if it is over the
+    /// quotas.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::SessionAddr;
+    /// use turn_server::{anonymous::AnonymousRelay, config::Anonymous};
+    ///
+    /// let relay = AnonymousRelay::new(Anonymous {
+    ///     subnets: vec!["10.0.0.0/8".parse().unwrap()],
+    ///     max_allocations: 2,
+    ///     max_allocations_per_ip: 1,
+    /// });
+    ///
+    /// let addr = |address: &str| SessionAddr {
+    ///     address: address.parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// assert!(relay.reserve(&addr("10.0.0.1:1000")));
+    /// assert!(!relay.reserve(&addr("10.0.0.1:1001")));
+    /// assert!(relay.reserve(&addr("10.0.0.2:1000")));
+    /// assert!(!relay.reserve(&addr("10.0.0.3:1000")));
+    /// assert_eq!(relay.allocations(), 2);
+    ///
+    /// relay.release(&addr("10.0.0.1:1000"));
+    /// assert!(relay.reserve(&addr("10.0.0.1:1001")));
+    /// ```
+    pub fn reserve(&self, addr: &SessionAddr) -> bool {
+        let mut allocations = self.0.allocations.lock();
+        if allocations.contains(addr) {
+            return true;
+        }
+
+        if allocations.len() >= self.0.config.max_allocations {
+            return false;
+        }
+
+        let ip = addr.address.ip();
+        if allocations.iter().filter(|it| it.address.ip() == ip).count() >= self.0.config.max_allocations_per_ip {
+            return false;
+        }
+
+        allocations.insert(*addr);
+        true
+    }
+
+    /// Stop counting the allocation of addr.
+    pub fn release(&self, addr: &SessionAddr) {
+        self.0.allocations.lock().remove(addr);
+    }
+
+    /// The number of allocations without credentials.
+    pub fn allocations(&self) -> usize {
+        self.0.allocations.lock().len()
+    }
+}
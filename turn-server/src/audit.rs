@@ -0,0 +1,134 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::Level;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use turn::SessionAddr;
+
+use crate::{
+    config::{Anonymize, Config},
+    logger::syslog::Syslog,
+};
+
+/// Who triggered an audited event.
+#[derive(Debug, Clone, Copy)]
+pub enum Actor<'a> {
+    /// An administrator operating through the api server, identified by the
+    /// address of the http client.
+    Admin(SocketAddr),
+    /// A turn client, identified by its session and the username it
+    /// presented.
+    Client(&'a SessionAddr, &'a str),
+    /// The turn server itself.
+    Server,
+}
+
+impl Actor<'_> {
+    fn to_value(self, anonymize: Anonymize) -> Value {
+        match self {
+            Self::Admin(address) => json!({
+                "kind": "admin",
+                "address": address,
+            }),
+            Self::Client(session, username) => json!({
+                "kind": "client",
+                "session": {
+                    "address": anonymize.apply(session.address),
+                    "interface": session.interface,
+                },
+                "username": username,
+            }),
+            Self::Server => json!({
+                "kind": "server",
+            }),
+        }
+    }
+}
+
+/// Append-only audit log.
+///
+/// Administrative actions and security relevant events are written to the
+/// audit log as one json object per line, each carrying a timestamp, the
+/// actor that triggered it and the kind of event. The log file is opened in
+/// append mode and is never truncated by the turn server.
+///
+/// # Example
+///
+/// ```
+/// use serde_json::json;
+/// use turn_server::audit::*;
+///
+/// let audit = Audit::default();
+/// audit.record(Actor::Server, "startup", json!({}));
+/// ```
+#[derive(Clone, Default)]
+pub struct Audit {
+    file: Option<Arc<Mutex<File>>>,
+    syslog: Option<Arc<Syslog>>,
+    anonymize: Anonymize,
+}
+
+impl Audit {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            anonymize: config.privacy.audit,
+            file: if let Some(path) = &config.audit.file {
+                Some(Arc::new(Mutex::new(
+                    OpenOptions::new().create(true).append(true).open(path)?,
+                )))
+            } else {
+                None
+            },
+            syslog: if let Some(it) = &config.audit.syslog {
+                Some(Arc::new(Syslog::new(it)?))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Apply the anonymization mode of the audit log to a client address, for
+    /// addresses that are part of the event detail.
+    pub fn anonymize(&self, addr: SocketAddr) -> String {
+        self.anonymize.apply(addr)
+    }
+
+    /// Record an event in the audit log.
+    ///
+    /// This does nothing if the audit log is not enabled. A failure to write
+    /// the log does not interrupt the caller and is only reported through the
+    /// error log.
+    pub fn record(&self, actor: Actor, kind: &str, detail: Value) {
+        if self.file.is_none() && self.syslog.is_none() {
+            return;
+        }
+
+        let mut line = json!({
+            "timestamp": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|it| it.as_secs())
+                .unwrap_or(0),
+            "actor": actor.to_value(self.anonymize),
+            "kind": kind,
+            "detail": detail,
+        })
+        .to_string();
+
+        if let Some(syslog) = &self.syslog {
+            syslog.send(Level::Info, kind, &line);
+        }
+
+        if let Some(file) = &self.file {
+            line.push('\n');
+            if let Err(e) = file.lock().write_all(line.as_bytes()) {
+                log::error!("failed to write audit log, err={}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::net::UdpSocket;
+
+use crate::config;
+
+/// The row of a udp socket in `/proc/net/udp` and `/proc/net/udp6`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TableEntry {
+    /// The bytes waiting in the send queue.
+    pub tx_queue: usize,
+    /// The datagrams that the kernel dropped, mostly because the receive
+    /// buffer was full.
+    pub drops: u64,
+}
+
+/// Parse the udp table of the kernel into the rows by the inode of the
+/// sockets, the rows that can not be parsed are skipped.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::buffers::*;
+///
+/// let table = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+///  1085: 00000000:0D96 00000000:0000 07 00000200:00000000 00:00000000 00000000     0        0 51331 2 0000000000000000 17
+///  1201: 0100007F:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000   101        0 9876 2 0000000000000000 0
+/// broken";
+///
+/// let rows = parse_table(table);
+/// assert_eq!(rows.len(), 2);
+/// assert_eq!(rows[&51331], TableEntry { tx_queue: 512, drops: 17 });
+/// assert_eq!(rows[&9876].drops, 0);
+/// ```
+pub fn parse_table(text: &str) -> AHashMap<u64, TableEntry> {
+    let mut rows = AHashMap::new();
+    for line in text.lines().skip(1) {
+        let columns = line.split_whitespace().collect::<Vec<_>>();
+        if columns.len() < 13 {
+            continue;
+        }
+
+        let tx_queue = columns[4]
+            .split(':')
+            .next()
+            .and_then(|it| usize::from_str_radix(it, 16).ok());
+        if let (Some(tx_queue), Ok(inode), Ok(drops)) = (tx_queue, columns[9].parse(), columns[12].parse()) {
+            rows.insert(inode, TableEntry { tx_queue, drops });
+        }
+    }
+
+    rows
+}
+
+/// The buffers and the drops of an udp socket.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SocketBuffer {
+    pub interface: SocketAddr,
+    /// The size of the receive buffer, as the kernel reports it.
+    pub receive: usize,
+    /// The size of the send buffer, as the kernel reports it.
+    pub send: usize,
+    /// The datagrams that the kernel dropped since the socket was bound.
+    pub drops: u64,
+}
+
+/// The buffers of a socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Buffer {
+    Receive,
+    Send,
+}
+
+impl Buffer {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Receive => "receive",
+            Self::Send => "send",
+        }
+    }
+}
+
+struct Socket {
+    socket: Arc<UdpSocket>,
+    inode: u64,
+    buffer: SocketBuffer,
+    // Whether the kernel refused to grow the buffers, so that it is only
+    // reported once.
+    capped: bool,
+}
+
+struct Inner {
+    config: config::Buffers,
+    sockets: Mutex<Vec<Socket>>,
+}
+
+/// The buffers of the udp sockets.
+///
+/// The sizes of the config are set on the sockets when they are bound. The
+/// buffers without a size start with the defaults of the kernel, and are
+/// grown up to the maximum of the config when the kernel drops datagrams
+/// because the receive buffer is full, or when the send queue fills up, so
+/// that the drops at high rates are not silent. The drops and the queues are
+/// read from `/proc/net/udp`, on the other platforms only the sizes of the
+/// config are set.
+#[derive(Clone)]
+pub struct SocketBuffers(Arc<Inner>);
+
+impl SocketBuffers {
+    pub fn new(config: config::Buffers) -> Self {
+        Self(Arc::new(Inner {
+            sockets: Default::default(),
+            config,
+        }))
+    }
+
+    /// Whether the drops of the sockets can be read on this platform, the
+    /// buffers are only grown if they can.
+    pub fn is_adaptive(&self) -> bool {
+        sys::ADAPTIVE && self.0.config.interval > 0
+    }
+
+    /// Set the sizes of the config on the socket of the interface, and check
+    /// its drops from now on.
+    pub fn register(&self, interface: SocketAddr, socket: Arc<UdpSocket>) -> std::io::Result<()> {
+        for (buffer, size) in [
+            (Buffer::Receive, self.0.config.receive),
+            (Buffer::Send, self.0.config.send),
+        ] {
+            if size > 0 {
+                sys::set(&socket, buffer, size)?;
+            }
+        }
+
+        let inode = sys::inode(&socket)?;
+        let drops = sys::table().get(&inode).map(|it| it.drops).unwrap_or(0);
+        self.0.sockets.lock().push(Socket {
+            buffer: SocketBuffer {
+                receive: sys::get(&socket, Buffer::Receive)?,
+                send: sys::get(&socket, Buffer::Send)?,
+                interface,
+                drops,
+            },
+            capped: false,
+            socket,
+            inode,
+        });
+
+        Ok(())
+    }
+
+    /// The buffers of the sockets.
+    pub fn get(&self) -> Vec<SocketBuffer> {
+        self.0.sockets.lock().iter().map(|it| it.buffer).collect()
+    }
+
+    /// Check the drops and the queues of the sockets at the interval of the
+    /// config.
+    pub fn start(&self) {
+        if !self.is_adaptive() {
+            return;
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(this.0.config.interval));
+
+            loop {
+                interval.tick().await;
+                this.adjust();
+            }
+        });
+    }
+
+    /// Update the drops of the sockets, and grow the buffers that are not
+    /// set by the config if they overflowed since the last check.
+    pub fn adjust(&self) {
+        let table = sys::table();
+        let config = &self.0.config;
+        for socket in self.0.sockets.lock().iter_mut() {
+            let Some(entry) = table.get(&socket.inode) else {
+                continue;
+            };
+
+            let buffer = &mut socket.buffer;
+            let dropped = entry.drops.saturating_sub(buffer.drops);
+            buffer.drops = entry.drops;
+
+            #[cfg(feature = "prometheus")]
+            {
+                crate::statistics::prometheus::METRICS
+                    .socket_drops
+                    .with_label_values(&[&buffer.interface.to_string()])
+                    .inc_by(dropped);
+            }
+
+            let mut grow = Vec::with_capacity(2);
+            if dropped > 0 && config.receive == 0 && buffer.receive < config.max {
+                grow.push((Buffer::Receive, buffer.receive));
+            }
+
+            if entry.tx_queue * 2 >= buffer.send && config.send == 0 && buffer.send < config.max {
+                grow.push((Buffer::Send, buffer.send));
+            }
+
+            for (kind, size) in grow {
+                // The kernel doubles the requested size for its bookkeeping and
+                // reports the doubled size, so requesting the reported size
+                // doubles the buffer.
+                let grown = sys::set(&socket.socket, kind, size.min(config.max / 2))
+                    .and_then(|_| sys::get(&socket.socket, kind));
+                match grown {
+                    Ok(grown) if grown > size => {
+                        log::info!(
+                            "udp socket buffer grown: interface={}, buffer={}, size={}, dropped={}",
+                            buffer.interface,
+                            kind.as_str(),
+                            grown,
+                            dropped,
+                        );
+
+                        match kind {
+                            Buffer::Receive => buffer.receive = grown,
+                            Buffer::Send => buffer.send = grown,
+                        }
+                    }
+                    Ok(_) if !socket.capped => {
+                        socket.capped = true;
+                        log::warn!(
+                            "udp socket buffer is capped by the kernel, raise net.core.rmem_max and \
+                             net.core.wmem_max: interface={}, receive={}, send={}",
+                            buffer.interface,
+                            buffer.receive,
+                            buffer.send,
+                        );
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        log::warn!(
+                            "failed to grow udp socket buffer: interface={}, err={}",
+                            buffer.interface,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        fs::read_to_string,
+        io::{Error, Result},
+        mem::{size_of, zeroed},
+        os::fd::AsRawFd,
+    };
+
+    use ahash::AHashMap;
+    use tokio::net::UdpSocket;
+
+    use super::{Buffer, TableEntry};
+
+    pub const ADAPTIVE: bool = true;
+
+    /// The rows of the udp sockets of both address families.
+    pub fn table() -> AHashMap<u64, TableEntry> {
+        let mut rows = AHashMap::new();
+        for path in ["/proc/net/udp", "/proc/net/udp6"] {
+            if let Ok(text) = read_to_string(path) {
+                rows.extend(super::parse_table(&text));
+            }
+        }
+
+        rows
+    }
+
+    /// The inode of the socket, which identifies its row in the udp table.
+    pub fn inode(socket: &UdpSocket) -> Result<u64> {
+        let mut stat: libc::stat = unsafe { zeroed() };
+        if unsafe { libc::fstat(socket.as_raw_fd(), &mut stat) } != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(stat.st_ino)
+    }
+
+    pub fn get(socket: &UdpSocket, buffer: Buffer) -> Result<usize> {
+        let name = match buffer {
+            Buffer::Receive => libc::SO_RCVBUF,
+            Buffer::Send => libc::SO_SNDBUF,
+        };
+
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(value as usize)
+    }
+
+    /// Set the size of the buffer, beyond the limits of the kernel if the
+    /// process is allowed to.
+    pub fn set(socket: &UdpSocket, buffer: Buffer, size: usize) -> Result<()> {
+        let names = match buffer {
+            Buffer::Receive => [libc::SO_RCVBUFFORCE, libc::SO_RCVBUF],
+            Buffer::Send => [libc::SO_SNDBUFFORCE, libc::SO_SNDBUF],
+        };
+
+        let value = size.min(libc::c_int::MAX as usize) as libc::c_int;
+        for name in names {
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    name,
+                    &value as *const _ as *const libc::c_void,
+                    size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+
+            if ret == 0 {
+                return Ok(());
+            }
+        }
+
+        Err(Error::last_os_error())
+    }
+}
+
+/// The other platforms have no table of the udp sockets, so the drops are
+/// unknown and only the sizes of the config are set.
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io::Result;
+
+    use ahash::AHashMap;
+    use socket2::SockRef;
+    use tokio::net::UdpSocket;
+
+    use super::{Buffer, TableEntry};
+
+    pub const ADAPTIVE: bool = false;
+
+    pub fn table() -> AHashMap<u64, TableEntry> {
+        AHashMap::new()
+    }
+
+    pub fn inode(_: &UdpSocket) -> Result<u64> {
+        Ok(0)
+    }
+
+    pub fn get(socket: &UdpSocket, buffer: Buffer) -> Result<usize> {
+        let socket = SockRef::from(socket);
+        match buffer {
+            Buffer::Receive => socket.recv_buffer_size(),
+            Buffer::Send => socket.send_buffer_size(),
+        }
+    }
+
+    pub fn set(socket: &UdpSocket, buffer: Buffer, size: usize) -> Result<()> {
+        let socket = SockRef::from(socket);
+        match buffer {
+            Buffer::Receive => socket.set_recv_buffer_size(size),
+            Buffer::Send => socket.set_send_buffer_size(size),
+        }
+    }
+}
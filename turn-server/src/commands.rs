@@ -0,0 +1,128 @@
+use std::{process::Stdio, sync::Arc, time::Duration};
+
+use serde_json::Value;
+use tokio::{io::AsyncWriteExt, process::Command, runtime::Handle, sync::Semaphore, time::timeout};
+
+use crate::config::Commands;
+
+struct Inner {
+    config: Commands,
+    permits: Arc<Semaphore>,
+    // The sessions are expired on a thread outside of the runtime, so the
+    // commands are spawned on the runtime that the hooks are created in.
+    runtime: Option<Handle>,
+}
+
+/// Executes the external commands of the lifecycle events.
+///
+/// The event is written to the stdin of the command as json, followed by a
+/// newline, and the stdin is closed. The commands run in the background, the
+/// number of running commands is limited, and the commands that do not exit
+/// in time are killed.
+#[derive(Clone)]
+pub struct CommandHooks(Arc<Inner>);
+
+impl CommandHooks {
+    pub fn new(config: Commands) -> Self {
+        Self(Arc::new(Inner {
+            permits: Arc::new(Semaphore::new(config.max_concurrency)),
+            runtime: Handle::try_current().ok(),
+            config,
+        }))
+    }
+
+    /// The command of the event, if there is one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{commands::CommandHooks, config::Commands};
+    ///
+    /// let hooks = CommandHooks::new(Commands {
+    ///     closed: vec!["logger".to_string(), "-t".to_string(), "turn".to_string()],
+    ///     ..Default::default()
+    /// });
+    ///
+    /// assert_eq!(hooks.command("closed").map(|it| it.len()), Some(3));
+    /// assert_eq!(hooks.command("allocated"), None);
+    /// assert_eq!(hooks.command("unknown"), None);
+    /// ```
+    pub fn command(&self, kind: &str) -> Option<&[String]> {
+        let command = match kind {
+            "allocated" => &self.0.config.allocated,
+            "closed" => &self.0.config.closed,
+            "auth_failed" => &self.0.config.auth_failed,
+            _ => return None,
+        };
+
+        if command.is_empty() {
+            None
+        } else {
+            Some(command)
+        }
+    }
+
+    /// Execute the command of the event, the `kind` field of the event
+    /// selects the command.
+    pub fn emit(&self, event: Value) {
+        let Some(runtime) = &self.0.runtime else {
+            return;
+        };
+
+        let Some(kind) = event.get("kind").and_then(|it| it.as_str()) else {
+            return;
+        };
+
+        let Some(command) = self.command(kind).map(|it| it.to_vec()) else {
+            return;
+        };
+
+        // The events are dropped rather than queued when too many commands are
+        // running, a slow command must not build up a backlog.
+        let Ok(permit) = self.0.permits.clone().try_acquire_owned() else {
+            log::warn!("command hook dropped, too many running commands: kind={}", kind);
+            return;
+        };
+
+        let duration = Duration::from_secs(self.0.config.timeout);
+        runtime.spawn(async move {
+            let mut child = match Command::new(&command[0])
+                .args(&command[1..])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()
+            {
+                Ok(it) => it,
+                Err(e) => {
+                    log::error!("failed to execute command hook: program={:?}, err={}", command[0], e);
+                    return;
+                }
+            };
+
+            if let Some(mut stdin) = child.stdin.take() {
+                let mut bytes = event.to_string().into_bytes();
+                bytes.push(b'\n');
+
+                // A command that does not read the event is not an error.
+                let _ = stdin.write_all(&bytes).await;
+            }
+
+            // The child is killed when it is dropped at the timeout.
+            match timeout(duration, child.wait()).await {
+                Ok(Ok(status)) if !status.success() => {
+                    log::warn!("command hook failed: program={:?}, status={}", command[0], status);
+                }
+                Ok(Err(e)) => {
+                    log::error!("failed to wait command hook: program={:?}, err={}", command[0], e);
+                }
+                Err(_) => {
+                    log::warn!("command hook timeout: program={:?}", command[0]);
+                }
+                _ => (),
+            }
+
+            drop(permit);
+        });
+    }
+}
@@ -1,9 +1,21 @@
-use std::{collections::HashMap, fs::read_to_string, net::SocketAddr, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::read_to_string,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    str::FromStr,
+};
 
 use anyhow::anyhow;
 use clap::Parser;
 use itertools::Itertools;
+use once_cell::sync::Lazy;
+use rand::{thread_rng, RngCore};
 use serde::{Deserialize, Serialize};
+use turn::{operations::CredentialMechanism, DEFAULT_PORT_RANGE};
+
+use crate::tools::Command;
 
 #[repr(C)]
 #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
@@ -37,6 +49,54 @@ pub struct Interface {
     /// you need to manually specify the server external IP
     /// address and service listening port.
     pub external: SocketAddr,
+    /// credential mechanism
+    ///
+    /// The credential mechanism of the requests received on the interface,
+    /// "long-term" or "short-term". The short-term credentials use the
+    /// password as the key of the message integrity without a nonce or a
+    /// realm, they are only meant for trusted deployments where the
+    /// credentials are exchanged out of band.
+    #[serde(default)]
+    pub credential: Credential,
+    /// maximum datagram size
+    ///
+    /// The largest datagram that is accepted on the interface and relayed
+    /// through it, in bytes, which is also the size of the receive buffers
+    /// of the interface. The larger datagrams are dropped, it can be raised
+    /// for the deployments that support jumbo frames.
+    #[serde(default = "Interface::max_datagram_size")]
+    pub max_datagram_size: usize,
+    /// proxy protocol
+    ///
+    /// Whether the connections of a tcp interface start with a PROXY
+    /// protocol header of the version 1 or 2, which carries the address of
+    /// the client when the server is behind a layer 4 load balancer. The
+    /// connections without a valid header are closed.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl Interface {
+    fn max_datagram_size() -> usize {
+        crate::server::DEFAULT_MAX_DATAGRAM_SIZE
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Credential {
+    #[default]
+    LongTerm,
+    ShortTerm,
+}
+
+impl From<Credential> for CredentialMechanism {
+    fn from(value: Credential) -> Self {
+        match value {
+            Credential::LongTerm => Self::LongTerm,
+            Credential::ShortTerm => Self::ShortTerm,
+        }
+    }
 }
 
 impl FromStr for Interface {
@@ -54,6 +114,9 @@ impl FromStr for Interface {
             .ok_or_else(|| anyhow!("invalid interface address: {}", s))?;
 
         Ok(Interface {
+            max_datagram_size: Self::max_datagram_size(),
+            credential: Credential::default(),
+            proxy_protocol: false,
             external: external.parse::<SocketAddr>()?,
             bind: bind.parse::<SocketAddr>()?,
             transport: transport.parse()?,
@@ -79,6 +142,50 @@ pub struct Turn {
     /// ipv4 and ipv6.
     #[serde(default = "Turn::interfaces")]
     pub interfaces: Vec<Interface>,
+
+    /// relay port range
+    ///
+    /// The range of the ports that are allocated to the sessions as the port
+    /// of the relayed transport address, the end of the range is exclusive.
+    /// The range should not include the well-known ports 0 - 1023.
+    #[serde(default = "Turn::port_range")]
+    pub port_range: Range<u16>,
+
+    /// relay port fair share
+    ///
+    /// The percentage of free ports in the port range below which the
+    /// ports are shared fairly between the source addresses of the clients,
+    /// a source that already holds more than its share is refused, so that
+    /// one source can not exhaust the pool for everyone else. 0 disables it.
+    #[serde(default)]
+    pub fair_share: usize,
+
+    /// duplicate allocate policy
+    ///
+    /// What is done with an allocate request on a 5-tuple that already has
+    /// an allocation, `reject` answers with a 437 (Allocation Mismatch) error
+    /// as the rfc requires, `replace` releases the existing allocation and
+    /// allocates a new one, for the clients that allocate again without
+    /// releasing.
+    #[serde(default)]
+    pub duplicate_allocate: DuplicateAllocate,
+
+    /// address rebind policy
+    ///
+    /// What is done with a request of an address without an allocation,
+    /// when the user has an allocation on the interface from another
+    /// address, such as after a NAT rebinding or an ECMP re-hash of an
+    /// anycast address. `disabled` rejects it with a 438 (Stale Nonce)
+    /// error and only reports the change, `same-ip` moves the
+    /// allocation to the new address if only the port changed, `same-user`
+    /// moves it from any ip. The allocation is found by the nonce that was
+    /// issued to its address, on the datagram transports only.
+    ///
+    /// A client that shares the username of the allocation and sees the
+    /// nonce, which is not secret, can take the allocation over, so
+    /// `same-user` should not be used when the users share a username.
+    #[serde(default)]
+    pub address_rebind: AddressRebind,
 }
 
 impl Turn {
@@ -95,6 +202,10 @@ impl Turn {
     fn interfaces() -> Vec<Interface> {
         vec![]
     }
+
+    fn port_range() -> Range<u16> {
+        DEFAULT_PORT_RANGE
+    }
 }
 
 impl Default for Turn {
@@ -102,6 +213,10 @@ impl Default for Turn {
         Self {
             realm: Self::realm(),
             interfaces: Self::interfaces(),
+            port_range: Self::port_range(),
+            fair_share: 0,
+            duplicate_allocate: DuplicateAllocate::default(),
+            address_rebind: AddressRebind::default(),
         }
     }
 }
@@ -145,11 +260,12 @@ impl Default for Api {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
@@ -170,12 +286,6 @@ impl FromStr for LogLevel {
     }
 }
 
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Info
-    }
-}
-
 impl LogLevel {
     pub fn as_level(&self) -> log::Level {
         match *self {
@@ -188,16 +298,220 @@ impl LogLevel {
     }
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl LogRotation {
+    /// The index of the rotation period the timestamp falls into, the log
+    /// file is rotated when this changes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::config::LogRotation;
+    ///
+    /// assert_eq!(LogRotation::Never.period(7200), 0);
+    /// assert_eq!(LogRotation::Hourly.period(7200), 2);
+    /// assert_eq!(LogRotation::Daily.period(7200), 0);
+    /// ```
+    pub fn period(&self, timestamp: u64) -> u64 {
+        match self {
+            Self::Never => 0,
+            Self::Hourly => timestamp / 3600,
+            Self::Daily => timestamp / 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    /// Local syslog daemon over a unix datagram socket.
+    #[default]
+    Unix,
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[repr(u8)]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    #[default]
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    Authpriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Syslog {
+    /// syslog transport
+    ///
+    /// Possible values are "unix" for the local syslog daemon, "udp", "tcp"
+    /// and "tls" for remote syslog servers.
+    #[serde(default)]
+    pub transport: SyslogTransport,
+    /// syslog address
+    ///
+    /// The socket path of the local syslog daemon, or the host and port of the
+    /// remote syslog server. The default is "/dev/log" for the local syslog
+    /// daemon.
+    pub address: Option<String>,
+    /// syslog facility
+    #[serde(default)]
+    pub facility: SyslogFacility,
+    /// syslog app name
+    #[serde(default = "Syslog::app_name")]
+    pub app_name: String,
+    /// tls ca certificate
+    ///
+    /// PEM file of the certificate authorities used to verify the remote
+    /// syslog server, the webpki roots are used if not set.
+    pub ca: Option<String>,
+}
+
+impl Syslog {
+    fn app_name() -> String {
+        "turn-server".to_string()
+    }
+
+    pub fn get_address(&self) -> &str {
+        self.address.as_deref().unwrap_or("/dev/log")
+    }
+}
+
+impl Default for Syslog {
+    fn default() -> Self {
+        Self {
+            transport: SyslogTransport::default(),
+            facility: SyslogFacility::default(),
+            app_name: Self::app_name(),
+            address: None,
+            ca: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
 pub struct Log {
     /// log level
     ///
     /// An enum representing the available verbosity levels of the logger.
     #[serde(default)]
     pub level: LogLevel,
+    /// log to stdout
+    ///
+    /// Whether to output logs to stdout, this can be disabled when logs are
+    /// written to files.
+    #[serde(default = "Log::stdout")]
+    pub stdout: bool,
+    /// log file
+    ///
+    /// All logs are appended to this file.
+    pub file: Option<String>,
+    /// error log file
+    ///
+    /// Only warnings and errors are appended to this file.
+    pub error_file: Option<String>,
+    /// log file rotation
+    ///
+    /// Rotate log files every hour or every day, possible values are "never",
+    /// "hourly" and "daily".
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// log file size limit
+    ///
+    /// The log file is rotated when its size exceeds this number of bytes, 0
+    /// means unlimited.
+    #[serde(default)]
+    pub max_size: u64,
+    /// rotated log files to keep
+    ///
+    /// Only the newest rotated log files are kept, older ones are removed.
+    #[serde(default = "Log::max_files")]
+    pub max_files: usize,
+    /// compress rotated log files
+    ///
+    /// Compress rotated log files with gzip.
+    #[serde(default)]
+    pub compress: bool,
+    /// syslog output
+    ///
+    /// Logs are also sent to syslog if this is set.
+    pub syslog: Option<Syslog>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+impl Log {
+    fn stdout() -> bool {
+        true
+    }
+
+    fn max_files() -> usize {
+        7
+    }
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::default(),
+            stdout: Self::stdout(),
+            file: None,
+            error_file: None,
+            rotation: LogRotation::default(),
+            max_size: 0,
+            max_files: Self::max_files(),
+            compress: false,
+            syslog: None,
+        }
+    }
+}
+
+/// A mechanism in the chain of credential mechanisms.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mechanism {
+    /// The static usernames and passwords of `static_credentials`.
+    Static,
+    /// The `get_password` hook of the wasm plugin.
+    Plugin,
+    /// The ephemeral credentials of the TURN REST api, signed with
+    /// `static_auth_secret`.
+    Secret,
+    /// The http hooks service.
+    Hooks,
+}
+
+impl Mechanism {
+    /// The order of the mechanisms when no chain is configured.
+    pub const DEFAULT: [Self; 4] = [Self::Static, Self::Plugin, Self::Secret, Self::Hooks];
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct Auth {
     /// static user password
     ///
@@ -213,150 +527,1465 @@ pub struct Auth {
     ///
     /// If set, the turn server will not request external services via the HTTP
     /// Hooks API to obtain the key.
-    pub static_auth_secret: Option<String>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct Config {
     #[serde(default)]
-    pub turn: Turn,
-    #[serde(default)]
-    pub api: Api,
-    #[serde(default)]
-    pub log: Log,
+    pub static_auth_secret: Option<String>,
+    /// credential mechanisms
+    ///
+    /// The ordered chain of the credential mechanisms, the first mechanism
+    /// that recognizes the username decides whether it is accepted, and the
+    /// following mechanisms are not consulted. In a chain the secret only
+    /// recognizes the usernames that start with a timestamp. When the chain
+    /// is empty, the mechanisms are consulted in the default order and the
+    /// secret recognizes every username, so that the hooks are not consulted
+    /// at all when the secret is set.
     #[serde(default)]
-    pub auth: Auth,
+    pub mechanisms: Vec<Mechanism>,
 }
 
-#[derive(Parser, Debug)]
-#[command(
-    about = env!("CARGO_PKG_DESCRIPTION"),
-    version = env!("CARGO_PKG_VERSION"),
-    author = env!("CARGO_PKG_AUTHORS"),
-)]
-struct Cli {
-    /// Specify the configuration file path
-    ///
-    /// Example: --config /etc/turn-rs/config.toml
-    #[arg(long, short)]
-    config: Option<String>,
-    /// Static user password
-    ///
-    /// Example: --auth-static-credentials test=test
-    #[arg(long, value_parser = Cli::parse_credential)]
-    auth_static_credentials: Option<Vec<(String, String)>>,
-    /// Static authentication key value (string) that applies only to the TURN
-    /// REST API
-    #[arg(long)]
-    auth_static_auth_secret: Option<String>,
-    /// An enum representing the available verbosity levels of the logger
-    #[arg(
-        long,
-        value_parser = clap::value_parser!(LogLevel),
-    )]
-    log_level: Option<LogLevel>,
-    /// This option specifies the http server binding address used to control
-    /// the turn server
-    #[arg(long)]
-    api_bind: Option<SocketAddr>,
-    /// This option is used to specify the http address of the hooks service
-    ///
-    /// Example: --api-hooks http://localhost:8080/turn
-    #[arg(long)]
-    api_hooks: Option<String>,
-    /// TURN server realm
-    #[arg(long)]
-    turn_realm: Option<String>,
-    /// TURN server listen interfaces
-    ///
-    /// Example: --turn-interfaces udp@127.0.0.1:3478/127.0.0.1:3478
-    #[arg(long)]
-    turn_interfaces: Option<Vec<Interface>>,
+// The key used to hash client addresses is generated when the process starts and
+// is never written anywhere, so hashed addresses can be correlated within the
+// lifetime of the process but can not be reversed afterwards.
+static ANONYMIZE_KEY: Lazy<[u8; 32]> = Lazy::new(|| {
+    let mut key = [0u8; 32];
+    thread_rng().fill_bytes(&mut key);
+    key
+});
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Anonymize {
+    /// Client addresses are output as is.
+    #[default]
+    None,
+    /// Only the network part of the address is output, ipv4 addresses are
+    /// truncated to /24 and ipv6 addresses are truncated to /48.
+    Truncate,
+    /// The ip address is replaced with a keyed hash of it.
+    Hash,
 }
 
-impl Cli {
-    // [username]:[password]
-    fn parse_credential(s: &str) -> Result<(String, String), anyhow::Error> {
-        let (username, password) = s
-            .split('=')
-            .collect_tuple()
-            .ok_or_else(|| anyhow!("invalid credential str: {}", s))?;
-        Ok((username.to_string(), password.to_string()))
+impl FromStr for Anonymize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "none" => Self::None,
+            "truncate" => Self::Truncate,
+            "hash" => Self::Hash,
+            _ => return Err(format!("unknown anonymize mode: {value}")),
+        })
     }
 }
 
-impl Config {
-    /// Load configure from config file and command line parameters.
+impl Anonymize {
+    /// Apply the anonymization mode to a client address.
     ///
-    /// Load command line parameters, if the configuration file path is
-    /// specified, the configuration is read from the configuration file,
-    /// otherwise the default configuration is used.
-    pub fn load() -> anyhow::Result<Self> {
-        let cli = Cli::parse();
-        let mut config = toml::from_str::<Self>(
-            &cli.config
-                .and_then(|path| read_to_string(path).ok())
-                .unwrap_or("".to_string()),
-        )?;
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::config::Anonymize;
+    ///
+    /// let addr = "192.168.1.23:8080".parse().unwrap();
+    ///
+    /// assert_eq!(Anonymize::None.apply(addr), "192.168.1.23:8080");
+    /// assert_eq!(Anonymize::Truncate.apply(addr), "192.168.1.0:8080");
+    /// assert_ne!(Anonymize::Hash.apply(addr), "192.168.1.23:8080");
+    ///
+    /// let addr = "[2001:db8:85a3::8a2e:370:7334]:8080".parse().unwrap();
+    /// assert_eq!(Anonymize::Truncate.apply(addr), "[2001:db8:85a3::]:8080");
+    /// ```
+    pub fn apply(&self, addr: SocketAddr) -> String {
+        match self {
+            Self::None => addr.to_string(),
+            Self::Truncate => {
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) => {
+                        let [a, b, c, _] = ip.octets();
+                        IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+                    }
+                    IpAddr::V6(ip) => {
+                        let [a, b, c, ..] = ip.segments();
+                        IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+                    }
+                };
 
-        // Command line arguments have a high priority and override configuration file
-        // options; here they are used to replace the configuration parsed out of the
-        // configuration file.
-        {
-            if let Some(credentials) = cli.auth_static_credentials {
-                for (k, v) in credentials {
-                    config.auth.static_credentials.insert(k, v);
-                }
+                SocketAddr::new(ip, addr.port()).to_string()
             }
+            Self::Hash => {
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
 
-            if let Some(secret) = cli.auth_static_auth_secret {
-                config.auth.static_auth_secret.replace(secret);
-            }
+                let mut output = String::with_capacity(24);
+                if let Ok(hash) = stun::util::hmac_sha1(ANONYMIZE_KEY.as_slice(), &[&ip]) {
+                    for byte in &hash.into_bytes()[..8] {
+                        let _ = write!(output, "{:02x}", byte);
+                    }
+                }
 
-            if let Some(level) = cli.log_level {
-                config.log.level = level;
+                let _ = write!(output, ":{}", addr.port());
+                output
             }
+        }
+    }
+}
 
-            if let Some(bind) = cli.api_bind {
-                config.api.bind = bind;
-            }
+/// An ip network, written as `address/prefix`, an address without prefix is a
+/// network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    ip: IpAddr,
+    prefix: u8,
+}
 
-            if let Some(hooks) = cli.api_hooks {
-                config.api.hooks.replace(hooks);
-            }
+impl FromStr for Subnet {
+    type Err = String;
 
-            if let Some(realm) = cli.turn_realm {
-                config.turn.realm = realm;
-            }
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match value.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (value, None),
+        };
 
-            if let Some(interfaces) = cli.turn_interfaces {
-                for interface in interfaces {
-                    config.turn.interfaces.push(interface);
-                }
-            }
+        let ip = IpAddr::from_str(ip).map_err(|_| format!("invalid subnet address: {value}"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(it) => it.parse().map_err(|_| format!("invalid subnet prefix: {value}"))?,
+            None => max,
+        };
+
+        if prefix > max {
+            return Err(format!("invalid subnet prefix: {value}"));
         }
 
-        // Filters out transport protocols that are not enabled.
-        {
-            let mut interfaces = Vec::with_capacity(config.turn.interfaces.len());
+        Ok(Self { ip, prefix })
+    }
+}
 
-            {
-                for it in &config.turn.interfaces {
-                    #[cfg(feature = "udp")]
-                    if it.transport == Transport::UDP {
-                        interfaces.push(it.clone());
-                    }
+impl<'de> Deserialize<'de> for Subnet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
 
-                    #[cfg(feature = "tcp")]
-                    if it.transport == Transport::TCP {
-                        interfaces.push(it.clone());
-                    }
-                }
-            }
+impl Subnet {
+    /// Whether the address is in the network, addresses of the other family
+    /// are never in the network.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::config::Subnet;
+    ///
+    /// let subnet: Subnet = "192.168.0.0/16".parse().unwrap();
+    /// assert!(subnet.contains("192.168.1.23".parse().unwrap()));
+    /// assert!(!subnet.contains("192.169.1.23".parse().unwrap()));
+    /// assert!(!subnet.contains("::1".parse().unwrap()));
+    ///
+    /// let subnet: Subnet = "2001:db8::/32".parse().unwrap();
+    /// assert!(subnet.contains("2001:db8::1".parse().unwrap()));
+    ///
+    /// let subnet: Subnet = "10.0.0.1".parse().unwrap();
+    /// assert!(subnet.contains("10.0.0.1".parse().unwrap()));
+    /// assert!(!subnet.contains("10.0.0.2".parse().unwrap()));
+    ///
+    /// assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+    /// ```
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (network, ip, bits) = match (self.ip, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => (u32::from(network) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
 
-            config.turn.interfaces = interfaces;
+        if self.prefix == 0 {
+            return true;
+        }
+
+        let shift = bits - self.prefix as u32;
+        (network >> shift) == (ip >> shift)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Privacy {
+    /// anonymize client addresses in logs
+    ///
+    /// Possible values are "none", "truncate" and "hash".
+    #[serde(default)]
+    pub log: Anonymize,
+    /// anonymize client addresses in hooks events
+    ///
+    /// This only applies to the events pushed to the hooks service and to
+    /// the command hooks, the password request still carries the real
+    /// address.
+    #[serde(default)]
+    pub hooks: Anonymize,
+    /// anonymize client addresses in the audit log
+    #[serde(default)]
+    pub audit: Anonymize,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Audit {
+    /// audit log file
+    ///
+    /// Administrative actions, authentication failures and policy rejections
+    /// are appended to this file as one json object per line.
+    pub file: Option<String>,
+    /// audit syslog output
+    ///
+    /// The audit log is also sent to syslog if this is set, usually with the
+    /// "authpriv" facility. If neither this nor the file is set, the audit log
+    /// is disabled.
+    pub syslog: Option<Syslog>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Tcp {
+    /// tcp idle timeout
+    ///
+    /// A tcp connection that has not received any data within this number
+    /// of seconds is closed, and its allocation is released. 0 disables the
+    /// idle timeout.
+    #[serde(default)]
+    pub idle_timeout: u64,
+    /// maximum connections per listener
+    ///
+    /// New connections are refused when a tcp listener already has this
+    /// number of connections. 0 means unlimited.
+    #[serde(default)]
+    pub max_connections: usize,
+    /// maximum connections per source ip
+    ///
+    /// New connections are refused when a source ip already has this number
+    /// of connections on a tcp listener. 0 means unlimited.
+    #[serde(default)]
+    pub max_connections_per_ip: usize,
+    /// trusted proxies
+    ///
+    /// The subnets of the load balancers that may send the PROXY protocol
+    /// header on the interfaces that expect it, the connections from other
+    /// sources are closed. The loopback addresses are always trusted, for
+    /// the probes of the server itself. Empty trusts all sources.
+    #[serde(default)]
+    pub proxy_trusted: Vec<Subnet>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Ingress {
+    /// control queue
+    ///
+    /// The number of stun messages that wait on each receive task of an udp
+    /// interface. The channel data and the send indications are processed as
+    /// soon as they are received, the other stun messages are queued and
+    /// processed in between by the weights, so that a surge of new
+    /// allocations does not delay the relayed data. The stun messages beyond
+    /// the queue are dropped and retransmitted by the clients. 0 processes
+    /// all messages in the order they are received.
+    #[serde(default = "Ingress::control_queue")]
+    pub control_queue: usize,
+    /// data weight
+    ///
+    /// The number of data messages that are processed before the queued stun
+    /// messages get a turn, while both are waiting.
+    #[serde(default = "Ingress::data_weight")]
+    pub data_weight: usize,
+    /// control weight
+    ///
+    /// The number of queued stun messages that are processed in a turn.
+    #[serde(default = "Ingress::control_weight")]
+    pub control_weight: usize,
+}
+
+impl Ingress {
+    fn control_queue() -> usize {
+        64
+    }
+
+    fn data_weight() -> usize {
+        8
+    }
+
+    fn control_weight() -> usize {
+        1
+    }
+}
+
+impl Default for Ingress {
+    fn default() -> Self {
+        Self {
+            control_queue: Self::control_queue(),
+            data_weight: Self::data_weight(),
+            control_weight: Self::control_weight(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Buffers {
+    /// receive buffer
+    ///
+    /// The size in bytes of the receive buffer of the udp sockets, which is
+    /// requested from the kernel as it is. 0 starts with the default of the
+    /// kernel and grows the buffer when the kernel drops datagrams because
+    /// it is full.
+    #[serde(default)]
+    pub receive: usize,
+    /// send buffer
+    ///
+    /// The size in bytes of the send buffer of the udp sockets, which is
+    /// requested from the kernel as it is. 0 starts with the default of the
+    /// kernel and grows the buffer when its queue fills up.
+    #[serde(default)]
+    pub send: usize,
+    /// maximum buffer
+    ///
+    /// The size in bytes that the buffers are not grown beyond.
+    #[serde(default = "Buffers::max")]
+    pub max: usize,
+    /// adjust interval
+    ///
+    /// In seconds, the drops and the queues of the sockets are checked at
+    /// this interval. 0 disables the growing of the buffers.
+    #[serde(default = "Buffers::interval")]
+    pub interval: u64,
+}
+
+impl Buffers {
+    fn max() -> usize {
+        8 * 1024 * 1024
+    }
+
+    fn interval() -> u64 {
+        10
+    }
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            receive: 0,
+            send: 0,
+            max: Self::max(),
+            interval: Self::interval(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct Reflection {
+    /// maximum error responses
+    ///
+    /// The number of error responses per second that are sent to a source
+    /// ip, the error responses beyond the limit are dropped. Error responses
+    /// are not authenticated, and a 401 response carries the realm and the
+    /// nonce, so they can be larger than the request. 0 disables the limit.
+    #[serde(default)]
+    pub max_error_responses: u32,
+    /// minimum request size
+    ///
+    /// Stun messages smaller than this number of bytes are dropped without
+    /// a response. 0 disables the limit.
+    #[serde(default)]
+    pub min_request_size: usize,
+}
+
+impl Reflection {
+    pub fn is_enabled(&self) -> bool {
+        self.max_error_responses > 0 || self.min_request_size > 0
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Plugin {
+    /// wasm plugin path
+    ///
+    /// The path of the wasm module that provides the auth and policy hooks,
+    /// the hooks are only available with the `wasm` feature.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// reload interval
+    ///
+    /// In seconds, the module is reloaded when the file is modified, it is
+    /// checked at this interval. 0 disables the reload.
+    #[serde(default = "Plugin::reload_interval")]
+    pub reload_interval: u64,
+    /// maximum fuel
+    ///
+    /// The number of wasm instructions, roughly, that a call of a hook can
+    /// execute before it is trapped.
+    #[serde(default = "Plugin::max_fuel")]
+    pub max_fuel: u64,
+    /// maximum memory
+    ///
+    /// In megabytes, the linear memory of a hook can not grow beyond it.
+    #[serde(default = "Plugin::max_memory")]
+    pub max_memory: usize,
+}
+
+impl Plugin {
+    fn reload_interval() -> u64 {
+        5
+    }
+
+    fn max_fuel() -> u64 {
+        10_000_000
+    }
+
+    fn max_memory() -> usize {
+        16
+    }
+}
+
+impl Default for Plugin {
+    fn default() -> Self {
+        Self {
+            path: None,
+            reload_interval: Self::reload_interval(),
+            max_fuel: Self::max_fuel(),
+            max_memory: Self::max_memory(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Commands {
+    /// allocated command
+    ///
+    /// The program and the arguments that are executed when an allocation
+    /// is created, the event is written to the stdin of the program as json.
+    /// Empty disables the command.
+    #[serde(default)]
+    pub allocated: Vec<String>,
+    /// closed command
+    ///
+    /// The program and the arguments that are executed when a session is
+    /// closed.
+    #[serde(default)]
+    pub closed: Vec<String>,
+    /// auth failed command
+    ///
+    /// The program and the arguments that are executed when the credentials
+    /// of a request can not be verified.
+    #[serde(default)]
+    pub auth_failed: Vec<String>,
+    /// maximum concurrency
+    ///
+    /// The maximum number of commands that run at the same time, the events
+    /// beyond the limit are dropped.
+    #[serde(default = "Commands::max_concurrency")]
+    pub max_concurrency: usize,
+    /// command timeout
+    ///
+    /// In seconds, the commands that run for longer are killed.
+    #[serde(default = "Commands::timeout")]
+    pub timeout: u64,
+}
+
+impl Commands {
+    fn max_concurrency() -> usize {
+        16
+    }
+
+    fn timeout() -> u64 {
+        5
+    }
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Self {
+            allocated: Vec::new(),
+            closed: Vec::new(),
+            auth_failed: Vec::new(),
+            max_concurrency: Self::max_concurrency(),
+            timeout: Self::timeout(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct Admission {
+    /// maximum load
+    ///
+    /// New allocate requests are refused when the 1 minute load average
+    /// divided by the number of cpus is greater than this value. 0 disables
+    /// the limit.
+    #[serde(default)]
+    pub max_load: f64,
+    /// maximum memory
+    ///
+    /// New allocate requests are refused when the resident memory of the
+    /// process in megabytes is greater than this value. 0 disables the
+    /// limit.
+    #[serde(default)]
+    pub max_memory: u64,
+    /// maximum queue depth
+    ///
+    /// New allocate requests are refused when the number of relayed packets
+    /// waiting to be sent is greater than this value. 0 disables the limit.
+    #[serde(default)]
+    pub max_queue: usize,
+    /// memory budget
+    ///
+    /// In megabytes, new allocate requests are refused when the approximate
+    /// memory used by the relayed packets waiting to be sent, the buffers of
+    /// the tcp connections and the session tables is greater than this
+    /// value, and the idle tcp connections release their buffers. 0 disables
+    /// the budget.
+    #[serde(default)]
+    pub memory_budget: u64,
+    /// alternate server
+    ///
+    /// Refused allocate requests are answered with 300 (Try Alternate) and
+    /// this server in the ALTERNATE-SERVER attribute, otherwise they are
+    /// answered with 508 (Insufficient Capacity).
+    pub alternate_server: Option<SocketAddr>,
+    /// deny new sessions
+    ///
+    /// Start with new allocate requests refused, as if the server was
+    /// overloaded, while the existing sessions and the stun binding requests
+    /// are still served. It can be toggled through the api.
+    #[serde(default)]
+    pub deny_new_sessions: bool,
+}
+
+impl Admission {
+    pub fn is_enabled(&self) -> bool {
+        self.max_load > 0.0 || self.max_memory > 0 || self.max_queue > 0 || self.memory_budget > 0
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Anonymous {
+    /// trusted subnets
+    ///
+    /// The clients from these networks may allocate without credentials,
+    /// the clients from all other sources still have to authenticate.
+    #[serde(default)]
+    pub subnets: Vec<Subnet>,
+    /// maximum anonymous allocations
+    ///
+    /// The number of allocations without credentials at the same time, the
+    /// allocations beyond it are refused with 486 (Allocation Quota
+    /// Reached).
+    #[serde(default = "Anonymous::max_allocations")]
+    pub max_allocations: usize,
+    /// maximum anonymous allocations per ip
+    ///
+    /// The number of allocations without credentials at the same time from
+    /// one source ip, the allocations beyond it are refused with 486
+    /// (Allocation Quota Reached).
+    #[serde(default = "Anonymous::max_allocations_per_ip")]
+    pub max_allocations_per_ip: usize,
+}
+
+impl Anonymous {
+    fn max_allocations() -> usize {
+        100
+    }
+
+    fn max_allocations_per_ip() -> usize {
+        10
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.subnets.is_empty()
+    }
+}
+
+impl Default for Anonymous {
+    fn default() -> Self {
+        Self {
+            subnets: Vec::new(),
+            max_allocations: Self::max_allocations(),
+            max_allocations_per_ip: Self::max_allocations_per_ip(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Statsd {
+    /// statsd address
+    ///
+    /// The address of the statsd or DogStatsD agent that the metrics are
+    /// pushed to, such as "127.0.0.1:8125", the metrics are only pushed with
+    /// the `statsd` feature.
+    #[serde(default)]
+    pub address: Option<String>,
+    /// metric prefix
+    ///
+    /// The prefix of the metric names, it is separated from the names by a
+    /// dot.
+    #[serde(default = "Statsd::prefix")]
+    pub prefix: String,
+    /// metric tags
+    ///
+    /// The DogStatsD tags that are added to every metric, such as
+    /// "env:production".
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// push interval
+    ///
+    /// In seconds, the gauges and the counters are pushed at this interval.
+    #[serde(default = "Statsd::interval")]
+    pub interval: u64,
+}
+
+impl Statsd {
+    fn prefix() -> String {
+        "turn".to_string()
+    }
+
+    fn interval() -> u64 {
+        10
+    }
+}
+
+impl Default for Statsd {
+    fn default() -> Self {
+        Self {
+            address: None,
+            prefix: Self::prefix(),
+            tags: Vec::new(),
+            interval: Self::interval(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Influxdb {
+    /// write url
+    ///
+    /// The url of the write api of the database, such as
+    /// "http://127.0.0.1:8086/api/v2/write?org=turn&bucket=turn", the
+    /// snapshots are only pushed with the `influxdb` feature.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// api token
+    ///
+    /// The token is sent in the Authorization header of the writes.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// measurement name
+    ///
+    /// The name of the measurement of the snapshots, the traffic is written
+    /// to the measurement with the `_traffic` suffix.
+    #[serde(default = "Influxdb::measurement")]
+    pub measurement: String,
+    /// measurement tags
+    ///
+    /// The tags that are added to every point, such as the name of the
+    /// host.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    /// snapshot interval
+    ///
+    /// In seconds, a snapshot of the metrics is taken at this interval.
+    #[serde(default = "Influxdb::interval")]
+    pub interval: u64,
+    /// batch size
+    ///
+    /// The number of snapshots that are written in one request.
+    #[serde(default = "Influxdb::batch_size")]
+    pub batch_size: usize,
+    /// maximum backoff
+    ///
+    /// In seconds, a failed write is retried after the interval, and the
+    /// delay is doubled after every failure up to this value.
+    #[serde(default = "Influxdb::max_backoff")]
+    pub max_backoff: u64,
+}
+
+impl Influxdb {
+    fn measurement() -> String {
+        "turn".to_string()
+    }
+
+    fn interval() -> u64 {
+        10
+    }
+
+    fn batch_size() -> usize {
+        6
+    }
+
+    fn max_backoff() -> u64 {
+        300
+    }
+}
+
+impl Default for Influxdb {
+    fn default() -> Self {
+        Self {
+            url: None,
+            token: None,
+            measurement: Self::measurement(),
+            tags: HashMap::new(),
+            interval: Self::interval(),
+            batch_size: Self::batch_size(),
+            max_backoff: Self::max_backoff(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Alerts {
+    /// alert webhook
+    ///
+    /// The url that the alerts are posted to as json, the `text` field makes
+    /// it compatible with the incoming webhooks of Slack.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// port pool usage threshold
+    ///
+    /// In percent, an alert is raised when more of the port pool is
+    /// allocated. 0 disables the alert.
+    #[serde(default = "Alerts::port_usage")]
+    pub port_usage: u64,
+    /// auth failure threshold
+    ///
+    /// An alert is raised when more authentications fail within a minute.
+    /// 0 disables the alert.
+    #[serde(default)]
+    pub auth_failures: u64,
+    /// check interval
+    ///
+    /// In seconds, the thresholds are checked at this interval.
+    #[serde(default = "Alerts::interval")]
+    pub interval: u64,
+}
+
+impl Alerts {
+    fn port_usage() -> u64 {
+        90
+    }
+
+    fn interval() -> u64 {
+        10
+    }
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            webhook: None,
+            port_usage: Self::port_usage(),
+            auth_failures: 0,
+            interval: Self::interval(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Maintenance {
+    /// expiry sweep interval
+    ///
+    /// In seconds, the expired sessions and nonces are removed at this
+    /// interval, they can outlive their expiry by up to this interval.
+    #[serde(default = "Maintenance::sweep_interval")]
+    pub sweep_interval: u64,
+    /// expiry sweep batch size
+    ///
+    /// The number of expired sessions or nonces that are removed while the
+    /// session tables are locked once. 0 removes them all at once.
+    #[serde(default = "Maintenance::sweep_batch_size")]
+    pub sweep_batch_size: usize,
+    /// nonce lifetime
+    ///
+    /// In seconds, the nonces are rotated after this time, the clients then
+    /// have to authenticate with the new nonce.
+    #[serde(default = "Maintenance::nonce_lifetime")]
+    pub nonce_lifetime: u64,
+    /// statistics interval
+    ///
+    /// In seconds, the traffic of the server and the sessions is sampled at
+    /// this interval for the rates of the api.
+    #[serde(default = "Maintenance::stats_interval")]
+    pub stats_interval: u64,
+    /// interval jitter
+    ///
+    /// In seconds, up to this time is added at random to every interval of
+    /// the sweeps and the statistics.
+    #[serde(default)]
+    pub jitter: u64,
+    /// channel idle timeout
+    ///
+    /// In seconds, a bound channel that no data is relayed over for this
+    /// time is reported as stale, even if the allocation is still being
+    /// refreshed. 0 disables the detection.
+    #[serde(default)]
+    pub channel_idle_timeout: u64,
+}
+
+impl Maintenance {
+    fn sweep_interval() -> u64 {
+        1
+    }
+
+    fn sweep_batch_size() -> usize {
+        1024
+    }
+
+    fn nonce_lifetime() -> u64 {
+        600
+    }
+
+    fn stats_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            sweep_interval: Self::sweep_interval(),
+            sweep_batch_size: Self::sweep_batch_size(),
+            nonce_lifetime: Self::nonce_lifetime(),
+            stats_interval: Self::stats_interval(),
+            jitter: 0,
+            channel_idle_timeout: 0,
+        }
+    }
+}
+
+impl From<Maintenance> for turn::Maintenance {
+    fn from(value: Maintenance) -> Self {
+        Self {
+            sweep_interval: value.sweep_interval,
+            sweep_batch_size: value.sweep_batch_size,
+            nonce_lifetime: value.nonce_lifetime,
+            jitter: value.jitter,
+            channel_idle_timeout: value.channel_idle_timeout,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+pub struct Persistence {
+    /// state file
+    ///
+    /// The secret that the nonces are signed with, and the auth that was
+    /// replaced through the api, are kept in this file, so that a restarted
+    /// server accepts the nonces that the clients cached and keeps the
+    /// shared secrets loaded at runtime. If not set, the nonces are random
+    /// and nothing is kept across restarts.
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Tags {
+    /// maximum tags
+    ///
+    /// The maximum number of tags that the auth backends can attach to a
+    /// session, the tags beyond it are dropped.
+    #[serde(default = "Tags::max_tags")]
+    pub max_tags: usize,
+    /// maximum tag length
+    ///
+    /// The maximum length in bytes of the keys and the values of the tags,
+    /// the tags with a longer key or value are dropped.
+    #[serde(default = "Tags::max_length")]
+    pub max_length: usize,
+    /// metrics label
+    ///
+    /// The key of the tag whose values label the tagged allocations of the
+    /// prometheus metrics, such as "tenant". If not set, the tags are not
+    /// in the metrics.
+    #[serde(default)]
+    pub metrics_label: Option<String>,
+    /// maximum label values
+    ///
+    /// The maximum number of distinct values of the metrics label, the
+    /// allocations with further values are counted as "other", so that the
+    /// number of series stays bounded.
+    #[serde(default = "Tags::max_label_values")]
+    pub max_label_values: usize,
+}
+
+impl Tags {
+    fn max_tags() -> usize {
+        8
+    }
+
+    fn max_length() -> usize {
+        64
+    }
+
+    fn max_label_values() -> usize {
+        32
+    }
+}
+
+impl Default for Tags {
+    fn default() -> Self {
+        Self {
+            max_tags: Self::max_tags(),
+            max_length: Self::max_length(),
+            metrics_label: None,
+            max_label_values: Self::max_label_values(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Usage {
+    /// tenant tag
+    ///
+    /// The key of the session tag that the usage is aggregated by, such as
+    /// "tenant". The sessions without the tag, or all sessions if not set,
+    /// are aggregated by the realm.
+    #[serde(default)]
+    pub tenant_tag: Option<String>,
+    /// usage window
+    ///
+    /// In seconds, the usage is aggregated over windows of this length, the
+    /// report of every completed window is posted to the webhook.
+    #[serde(default = "Usage::window")]
+    pub window: u64,
+    /// usage webhook
+    ///
+    /// The url that the usage reports are posted to. If not set, the reports
+    /// are only available through the api.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    /// maximum tenants
+    ///
+    /// The maximum number of tenants within a window, the usage of further
+    /// tenants is aggregated as "other".
+    #[serde(default = "Usage::max_tenants")]
+    pub max_tenants: usize,
+}
+
+impl Usage {
+    fn window() -> u64 {
+        3600
+    }
+
+    fn max_tenants() -> usize {
+        1000
+    }
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            tenant_tag: None,
+            window: Self::window(),
+            webhook: None,
+            max_tenants: Self::max_tenants(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GeoIp {
+    /// geoip databases
+    ///
+    /// The paths of the MaxMind databases, such as GeoLite2-Country and
+    /// GeoLite2-ASN, that the sessions, the events and the metrics are
+    /// annotated with the country and the autonomous system of the client
+    /// from. If empty, nothing is annotated.
+    #[serde(default)]
+    pub databases: Vec<String>,
+    /// reload interval
+    ///
+    /// In seconds, the databases are reloaded when their files are modified,
+    /// they are checked at this interval. 0 disables the reload.
+    #[serde(default = "GeoIp::reload_interval")]
+    pub reload_interval: u64,
+}
+
+impl GeoIp {
+    fn reload_interval() -> u64 {
+        60
+    }
+}
+
+impl Default for GeoIp {
+    fn default() -> Self {
+        Self {
+            databases: Vec::new(),
+            reload_interval: Self::reload_interval(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Integrity {
+    /// integrity workers
+    ///
+    /// The number of threads that check the message integrity of the
+    /// requests, so that the hmac cost of a flood of requests stays off the
+    /// threads that forward the relayed data. 0 checks it on the thread that
+    /// received the request.
+    #[serde(default = "Integrity::workers")]
+    pub workers: usize,
+    /// queue capacity
+    ///
+    /// The requests of the sessions that have an allocation and the requests
+    /// of the new sessions wait in separate queues of this capacity, the
+    /// workers take the requests of the existing sessions first. When a
+    /// queue is full, the request is answered with 508 (Insufficient
+    /// Capacity).
+    #[serde(default = "Integrity::queue")]
+    pub queue: usize,
+}
+
+impl Integrity {
+    fn workers() -> usize {
+        2
+    }
+
+    fn queue() -> usize {
+        1024
+    }
+}
+
+impl Default for Integrity {
+    fn default() -> Self {
+        Self {
+            workers: Self::workers(),
+            queue: Self::queue(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateAllocate {
+    /// The request is rejected with a 437 (Allocation Mismatch) error.
+    #[default]
+    Reject,
+    /// The existing allocation is released and a new one is allocated.
+    Replace,
+}
+
+impl From<DuplicateAllocate> for turn::DuplicateAllocate {
+    fn from(value: DuplicateAllocate) -> Self {
+        match value {
+            DuplicateAllocate::Reject => Self::Reject,
+            DuplicateAllocate::Replace => Self::Replace,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressRebind {
+    /// The request is rejected with a 437 (Allocation Mismatch) error.
+    #[default]
+    Disabled,
+    /// The allocation is moved to the new address of the same ip.
+    SameIp,
+    /// The allocation is moved to the new address of the same user.
+    SameUser,
+}
+
+impl From<AddressRebind> for turn::AddressRebind {
+    fn from(value: AddressRebind) -> Self {
+        match value {
+            AddressRebind::Disabled => Self::Disabled,
+            AddressRebind::SameIp => Self::SameIp,
+            AddressRebind::SameUser => Self::SameUser,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MalformedAction {
+    /// Malformed packets are only counted.
+    #[default]
+    Drop,
+    /// Malformed packets are counted and logged, at most once per
+    /// `log_interval` for each source.
+    Log,
+    /// Like `log`, and the source is banned for `ban_duration` when it sends
+    /// more than `ban_threshold` malformed packets within a minute.
+    Ban,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FingerprintCheck {
+    /// The FINGERPRINT is not checked.
+    #[default]
+    None,
+    /// The stun messages whose FINGERPRINT does not match are dropped.
+    Verify,
+    /// Like `verify`, and the stun messages without a FINGERPRINT are
+    /// dropped as well.
+    Require,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Malformed {
+    /// malformed packet action
+    ///
+    /// Possible values are "drop", "log" and "ban". Packets that can not be
+    /// decoded are always dropped and counted per source ip.
+    #[serde(default)]
+    pub action: MalformedAction,
+    /// malformed packet log interval
+    ///
+    /// In seconds, a source is logged at most once within this interval, the
+    /// log contains the number of malformed packets since the last log.
+    #[serde(default = "Malformed::log_interval")]
+    pub log_interval: u64,
+    /// malformed packet ban threshold
+    ///
+    /// The number of malformed packets within a minute after which the
+    /// source is banned.
+    #[serde(default = "Malformed::ban_threshold")]
+    pub ban_threshold: u64,
+    /// malformed packet ban duration
+    ///
+    /// In seconds, all packets of a banned source are dropped without being
+    /// decoded.
+    #[serde(default = "Malformed::ban_duration")]
+    pub ban_duration: u64,
+    /// fingerprint check
+    ///
+    /// Possible values are "none", "verify" and "require". The FINGERPRINT
+    /// of the stun messages is checked before their attributes are parsed
+    /// and their message integrity is computed, the dropped messages are
+    /// malformed packets.
+    #[serde(default)]
+    pub fingerprint: FingerprintCheck,
+}
+
+impl Malformed {
+    fn log_interval() -> u64 {
+        10
+    }
+
+    fn ban_threshold() -> u64 {
+        100
+    }
+
+    fn ban_duration() -> u64 {
+        300
+    }
+}
+
+impl Default for Malformed {
+    fn default() -> Self {
+        Self {
+            action: MalformedAction::default(),
+            log_interval: Self::log_interval(),
+            ban_threshold: Self::ban_threshold(),
+            ban_duration: Self::ban_duration(),
+            fingerprint: FingerprintCheck::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Health {
+    /// health probe interval
+    ///
+    /// The interval in seconds at which the turn server sends binding
+    /// requests to its own listeners to check that the data plane is alive.
+    #[serde(default = "Health::probe_interval")]
+    pub probe_interval: u64,
+    /// health probe timeout
+    ///
+    /// The time in seconds to wait for the response of a probe.
+    #[serde(default = "Health::probe_timeout")]
+    pub probe_timeout: u64,
+    /// liveness threshold
+    ///
+    /// The turn server is considered not alive if no probe round has
+    /// succeeded within this number of seconds.
+    #[serde(default = "Health::liveness_threshold")]
+    pub liveness_threshold: u64,
+    /// minimum free ports
+    ///
+    /// The turn server is considered not ready when the number of free ports
+    /// in the port pool is not greater than this value.
+    #[serde(default)]
+    pub min_free_ports: u16,
+    /// startup self test
+    ///
+    /// Send a binding request to each listener and its external address at
+    /// startup, and refuse to start if any of them is not reachable or the
+    /// XOR-MAPPED-ADDRESS is reflected wrongly.
+    #[serde(default)]
+    pub self_test: bool,
+}
+
+impl Health {
+    fn probe_interval() -> u64 {
+        10
+    }
+
+    fn probe_timeout() -> u64 {
+        3
+    }
+
+    fn liveness_threshold() -> u64 {
+        30
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            probe_interval: Self::probe_interval(),
+            probe_timeout: Self::probe_timeout(),
+            liveness_threshold: Self::liveness_threshold(),
+            min_free_ports: 0,
+            self_test: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub turn: Turn,
+    #[serde(default)]
+    pub api: Api,
+    #[serde(default)]
+    pub log: Log,
+    #[serde(default)]
+    pub auth: Auth,
+    #[serde(default)]
+    pub audit: Audit,
+    #[serde(default)]
+    pub privacy: Privacy,
+    #[serde(default)]
+    pub health: Health,
+    #[serde(default)]
+    pub tcp: Tcp,
+    #[serde(default)]
+    pub malformed: Malformed,
+    #[serde(default)]
+    pub admission: Admission,
+    #[serde(default)]
+    pub reflection: Reflection,
+    #[serde(default)]
+    pub commands: Commands,
+    #[serde(default)]
+    pub plugin: Plugin,
+    #[serde(default)]
+    pub anonymous: Anonymous,
+    #[serde(default)]
+    pub statsd: Statsd,
+    #[serde(default)]
+    pub influxdb: Influxdb,
+    #[serde(default)]
+    pub alerts: Alerts,
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    #[serde(default)]
+    pub persistence: Persistence,
+    #[serde(default)]
+    pub tags: Tags,
+    #[serde(default)]
+    pub usage: Usage,
+    #[serde(default)]
+    pub geoip: GeoIp,
+    #[serde(default)]
+    pub integrity: Integrity,
+    #[serde(default)]
+    pub ingress: Ingress,
+    #[serde(default)]
+    pub buffers: Buffers,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    about = env!("CARGO_PKG_DESCRIPTION"),
+    version = env!("CARGO_PKG_VERSION"),
+    author = env!("CARGO_PKG_AUTHORS"),
+)]
+struct Cli {
+    /// Specify the configuration file path
+    ///
+    /// Example: --config /etc/turn-rs/config.toml
+    #[arg(long, short)]
+    config: Option<String>,
+    /// Static user password
+    ///
+    /// Example: --auth-static-credentials test=test
+    #[arg(long, value_parser = Cli::parse_credential)]
+    auth_static_credentials: Option<Vec<(String, String)>>,
+    /// Static authentication key value (string) that applies only to the TURN
+    /// REST API
+    #[arg(long)]
+    auth_static_auth_secret: Option<String>,
+    /// An enum representing the available verbosity levels of the logger
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(LogLevel),
+    )]
+    log_level: Option<LogLevel>,
+    /// Append all logs to this file
+    ///
+    /// Example: --log-file /var/log/turn-rs/turn-server.log
+    #[arg(long)]
+    log_file: Option<String>,
+    /// Append warnings and errors to this file
+    #[arg(long)]
+    log_error_file: Option<String>,
+    /// This option specifies the http server binding address used to control
+    /// the turn server
+    #[arg(long)]
+    api_bind: Option<SocketAddr>,
+    /// This option is used to specify the http address of the hooks service
+    ///
+    /// Example: --api-hooks http://localhost:8080/turn
+    #[arg(long)]
+    api_hooks: Option<String>,
+    /// TURN server realm
+    #[arg(long)]
+    turn_realm: Option<String>,
+    /// TURN server listen interfaces
+    ///
+    /// Example: --turn-interfaces udp@127.0.0.1:3478/127.0.0.1:3478
+    #[arg(long)]
+    turn_interfaces: Option<Vec<Interface>>,
+    /// Append administrative actions and security events to this file
+    ///
+    /// Example: --audit-file /var/log/turn-rs/audit.log
+    #[arg(long)]
+    audit_file: Option<String>,
+    /// Anonymize client addresses in logs
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(Anonymize),
+    )]
+    privacy_log: Option<Anonymize>,
+    /// Probe own listeners at startup and exit if they are not reachable
+    #[arg(long)]
+    health_self_test: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    // [username]:[password]
+    fn parse_credential(s: &str) -> Result<(String, String), anyhow::Error> {
+        let (username, password) = s
+            .split('=')
+            .collect_tuple()
+            .ok_or_else(|| anyhow!("invalid credential str: {}", s))?;
+        Ok((username.to_string(), password.to_string()))
+    }
+}
+
+impl Config {
+    /// Load configure from config file and command line parameters.
+    ///
+    /// Load command line parameters, if the configuration file path is
+    /// specified, the configuration is read from the configuration file,
+    /// otherwise the default configuration is used.
+    pub fn load() -> anyhow::Result<Self> {
+        Ok(Self::load_with_command()?.0)
+    }
+
+    /// Load configure, and the tool that is requested on the command line
+    /// instead of starting the server.
+    pub fn load_with_command() -> anyhow::Result<(Self, Option<Command>)> {
+        let mut cli = Cli::parse();
+        let command = cli.command.take();
+        let mut config = toml::from_str::<Self>(
+            &cli.config
+                .and_then(|path| read_to_string(path).ok())
+                .unwrap_or("".to_string()),
+        )?;
+
+        // Command line arguments have a high priority and override configuration file
+        // options; here they are used to replace the configuration parsed out of the
+        // configuration file.
+        {
+            if let Some(credentials) = cli.auth_static_credentials {
+                for (k, v) in credentials {
+                    config.auth.static_credentials.insert(k, v);
+                }
+            }
+
+            if let Some(secret) = cli.auth_static_auth_secret {
+                config.auth.static_auth_secret.replace(secret);
+            }
+
+            if let Some(level) = cli.log_level {
+                config.log.level = level;
+            }
+
+            if let Some(file) = cli.log_file {
+                config.log.file.replace(file);
+            }
+
+            if let Some(file) = cli.log_error_file {
+                config.log.error_file.replace(file);
+            }
+
+            if let Some(bind) = cli.api_bind {
+                config.api.bind = bind;
+            }
+
+            if let Some(hooks) = cli.api_hooks {
+                config.api.hooks.replace(hooks);
+            }
+
+            if let Some(realm) = cli.turn_realm {
+                config.turn.realm = realm;
+            }
+
+            if let Some(interfaces) = cli.turn_interfaces {
+                for interface in interfaces {
+                    config.turn.interfaces.push(interface);
+                }
+            }
+
+            if let Some(file) = cli.audit_file {
+                config.audit.file.replace(file);
+            }
+
+            if let Some(anonymize) = cli.privacy_log {
+                config.privacy.log = anonymize;
+            }
+
+            if cli.health_self_test {
+                config.health.self_test = true;
+            }
+        }
+
+        // Filters out transport protocols that are not enabled.
+        {
+            let mut interfaces = Vec::with_capacity(config.turn.interfaces.len());
+
+            {
+                for it in &config.turn.interfaces {
+                    #[cfg(feature = "udp")]
+                    if it.transport == Transport::UDP {
+                        interfaces.push(it.clone());
+                    }
+
+                    #[cfg(feature = "tcp")]
+                    if it.transport == Transport::TCP {
+                        interfaces.push(it.clone());
+                    }
+                }
+            }
+
+            config.turn.interfaces = interfaces;
+        }
+
+        if config.turn.port_range.is_empty() {
+            return Err(anyhow!("invalid port range: {:?}", config.turn.port_range));
         }
 
-        Ok(config)
+        Ok((config, command))
     }
 }
@@ -0,0 +1,406 @@
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use parking_lot::RwLock;
+use serde::Serialize;
+
+use crate::config;
+
+// The metadata of the database follows the last occurrence of the marker.
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+// The pointers are followed at most this deep, so that a corrupt database
+// can not recurse without bounds.
+const MAX_DEPTH: usize = 32;
+
+/// A value of the data section of the database, the types that are not used
+/// for the annotations are not decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Uint(u64),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    Other,
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(it) => Some(it),
+            _ => None,
+        }
+    }
+
+    pub fn as_uint(&self) -> Option<u64> {
+        match self {
+            Self::Uint(it) => Some(*it),
+            _ => None,
+        }
+    }
+}
+
+fn read_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |value, it| (value << 8) | *it as u64)
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8]> {
+        self.0
+            .get(offset..offset + len)
+            .ok_or_else(|| anyhow!("data out of bounds: offset={}", offset))
+    }
+
+    /// Decode the value at the offset, returns the value and the offset that
+    /// follows it.
+    fn decode(&self, offset: usize, depth: usize) -> Result<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("data nested too deep: offset={}", offset));
+        }
+
+        let ctrl = self.bytes(offset, 1)?[0];
+        let mut offset = offset + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            let high = (ctrl & 0x07) as usize;
+            let len = ((ctrl >> 3) & 0x03) as usize + 1;
+            let low = read_uint(self.bytes(offset, len)?) as usize;
+            let pointer = match len {
+                1 => (high << 8) | low,
+                2 => ((high << 16) | low) + 2048,
+                3 => ((high << 24) | low) + 526336,
+                _ => low,
+            };
+
+            let (value, _) = self.decode(pointer, depth + 1)?;
+            return Ok((value, offset + len));
+        }
+
+        if kind == 0 {
+            kind = 7 + self.bytes(offset, 1)?[0];
+            offset += 1;
+        }
+
+        let mut size = (ctrl & 0x1f) as usize;
+        if size >= 29 {
+            let len = size - 28;
+            let extra = read_uint(self.bytes(offset, len)?) as usize;
+            size = [29, 285, 65821][len - 1] + extra;
+            offset += len;
+        }
+
+        Ok(match kind {
+            2 => (
+                Value::String(std::str::from_utf8(self.bytes(offset, size)?)?.to_string()),
+                offset + size,
+            ),
+            5 | 6 | 9 if size <= 8 => (Value::Uint(read_uint(self.bytes(offset, size)?)), offset + size),
+            3 | 4 | 5 | 6 | 8 | 9 | 10 | 15 => (Value::Other, offset + size),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err(anyhow!("map key is not a string: offset={}", offset));
+                    };
+
+                    entries.push((key, value));
+                    offset = next;
+                }
+
+                (Value::Map(entries), offset)
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (value, next) = self.decode(offset, depth + 1)?;
+                    items.push(value);
+                    offset = next;
+                }
+
+                (Value::Array(items), offset)
+            }
+            14 => (Value::Other, offset),
+            _ => return Err(anyhow!("unknown data type: type={}, offset={}", kind, offset)),
+        })
+    }
+}
+
+/// A MaxMind database, in the MaxMind DB format of the GeoIP2 and GeoLite2
+/// databases.
+pub struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    // The node that the ipv4 addresses start at in an ipv6 database.
+    ipv4_start: usize,
+    data_start: usize,
+    data_end: usize,
+}
+
+impl Database {
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|it| it == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("metadata of the database not found"))?;
+
+        let (metadata, _) = Decoder(&data[marker + METADATA_MARKER.len()..]).decode(0, 0)?;
+        let field = |key: &str| {
+            metadata
+                .get(key)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| anyhow!("metadata of the database has no {}", key))
+        };
+
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(anyhow!("unsupported record size: {}", record_size));
+        }
+
+        // The search tree is followed by 16 bytes of zeros and the data section.
+        let data_start = node_count * record_size / 4 + 16;
+        if data_start > marker {
+            return Err(anyhow!("search tree of the database out of bounds"));
+        }
+
+        let mut database = Self {
+            data_end: marker,
+            ipv4_start: 0,
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        };
+
+        if ip_version == 6 {
+            for _ in 0..96 {
+                if database.ipv4_start >= node_count {
+                    break;
+                }
+
+                database.ipv4_start = database.record(database.ipv4_start, 0)?;
+            }
+        }
+
+        Ok(database)
+    }
+
+    fn record(&self, node: usize, bit: u8) -> Result<usize> {
+        let size = self.record_size / 4;
+        let bytes = self
+            .data
+            .get(node * size..node * size + size)
+            .ok_or_else(|| anyhow!("node out of bounds: node={}", node))?;
+
+        Ok(match (self.record_size, bit) {
+            (24, 0) => read_uint(&bytes[0..3]),
+            (24, _) => read_uint(&bytes[3..6]),
+            (28, 0) => ((bytes[3] as u64 & 0xf0) << 20) | read_uint(&bytes[0..3]),
+            (28, _) => ((bytes[3] as u64 & 0x0f) << 24) | read_uint(&bytes[4..7]),
+            (_, 0) => read_uint(&bytes[0..4]),
+            (_, _) => read_uint(&bytes[4..8]),
+        } as usize)
+    }
+
+    /// Look up the data of the network that the ip belongs to.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>> {
+        let (bytes, mut node) = match (ip, self.ip_version) {
+            (IpAddr::V4(it), 6) => (it.octets().to_vec(), self.ipv4_start),
+            (IpAddr::V4(it), _) => (it.octets().to_vec(), 0),
+            (IpAddr::V6(it), 6) => (it.octets().to_vec(), 0),
+            (IpAddr::V6(it), _) => match it.to_ipv4_mapped() {
+                Some(it) => (it.octets().to_vec(), 0),
+                None => return Ok(None),
+            },
+        };
+
+        for i in 0..bytes.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+
+            node = self.record(node, (bytes[i / 8] >> (7 - i % 8)) & 1)?;
+        }
+
+        if node <= self.node_count {
+            return Ok(None);
+        }
+
+        let offset = (node - self.node_count)
+            .checked_sub(16)
+            .ok_or_else(|| anyhow!("record out of bounds: record={}", node))?;
+        let (value, _) = Decoder(&self.data[self.data_start..self.data_end]).decode(offset, 0)?;
+        Ok(Some(value))
+    }
+}
+
+/// The location of a client.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    /// The ISO 3166-1 code of the country.
+    pub country: Option<String>,
+    /// The number of the autonomous system.
+    pub asn: Option<u64>,
+    /// The organization of the autonomous system.
+    pub organization: Option<String>,
+}
+
+impl Location {
+    fn merge(&mut self, value: &Value) {
+        let text = |it: Option<&Value>| it.and_then(Value::as_str).map(str::to_string);
+
+        if self.country.is_none() {
+            self.country = text(value.get("country").and_then(|it| it.get("iso_code")))
+                .or_else(|| text(value.get("registered_country").and_then(|it| it.get("iso_code"))));
+        }
+
+        if self.asn.is_none() {
+            self.asn = value.get("autonomous_system_number").and_then(Value::as_uint);
+        }
+
+        if self.organization.is_none() {
+            self.organization = text(value.get("autonomous_system_organization"));
+        }
+    }
+}
+
+struct Source {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    database: Database,
+}
+
+struct Inner {
+    config: config::GeoIp,
+    sources: RwLock<Vec<Source>>,
+}
+
+/// The geoip annotations of the clients.
+///
+/// The country and the autonomous system of a client are looked up in the
+/// databases of the config, such as GeoLite2-Country and GeoLite2-ASN, the
+/// first database that has a field provides it. The databases are reloaded
+/// when their files are modified.
+#[derive(Clone)]
+pub struct GeoIp(Arc<Inner>);
+
+impl GeoIp {
+    pub fn new(config: config::GeoIp) -> Result<Self> {
+        let mut sources = Vec::with_capacity(config.databases.len());
+        for path in &config.databases {
+            let path = PathBuf::from(path);
+            sources.push(Source {
+                modified: modified_time(&path),
+                database: Database::from_bytes(fs::read(&path)?)?,
+                path,
+            });
+        }
+
+        Ok(Self(Arc::new(Inner {
+            sources: RwLock::new(sources),
+            config,
+        })))
+    }
+
+    /// Whether there are databases to look up.
+    pub fn is_enabled(&self) -> bool {
+        !self.0.config.databases.is_empty()
+    }
+
+    /// Look up the location of the ip, the fields are empty if the databases
+    /// do not know the ip.
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let mut location = Location::default();
+        for source in self.0.sources.read().iter() {
+            match source.database.lookup(ip) {
+                Ok(Some(value)) => location.merge(&value),
+                Ok(None) => (),
+                Err(e) => {
+                    log::warn!("failed to look up geoip database: path={:?}, err={}", source.path, e);
+                }
+            }
+        }
+
+        location
+    }
+
+    /// Check the files at the reload interval, and reload the databases that
+    /// are modified.
+    pub fn start_reloader(&self) {
+        if self.0.config.reload_interval == 0 || !self.is_enabled() {
+            return;
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(this.0.config.reload_interval));
+
+            loop {
+                interval.tick().await;
+
+                let this = this.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || this.reload()).await {
+                    log::error!("failed to reload geoip database: err={}", e);
+                }
+            }
+        });
+    }
+
+    /// Reload the databases whose files are modified since they were loaded,
+    /// the previous database is kept if the file can not be loaded. Returns
+    /// whether a database was reloaded.
+    pub fn reload(&self) -> Result<bool> {
+        let mut reloaded = false;
+        let count = self.0.sources.read().len();
+        for index in 0..count {
+            let (path, modified) = {
+                let sources = self.0.sources.read();
+                let path = sources[index].path.clone();
+                let modified = modified_time(&path);
+                if sources[index].modified == modified {
+                    continue;
+                }
+
+                (path, modified)
+            };
+
+            // The time is updated before the file is read, so that a broken file
+            // is only reported once.
+            self.0.sources.write()[index].modified = modified;
+
+            let database = Database::from_bytes(fs::read(&path)?)?;
+            self.0.sources.write()[index].database = database;
+            reloaded = true;
+
+            log::info!("geoip database reloaded: path={:?}", path);
+        }
+
+        Ok(reloaded)
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|it| it.modified()).ok()
+}
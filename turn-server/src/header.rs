@@ -0,0 +1,216 @@
+use std::{
+    io::{Error, Result},
+    mem::{size_of, zeroed},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::{AsRawFd, RawFd},
+    ptr,
+};
+
+use tokio::{io::Interest, net::UdpSocket};
+
+use crate::server::IpHeader;
+
+/// Ask the kernel to report the time to live and the traffic class of the
+/// received datagrams, so that they are returned by [`recv_from`].
+///
+/// The socket still works if this fails, the fields of the received
+/// datagrams are then unknown and the relayed datagrams are sent with the
+/// defaults of the socket.
+pub fn enable(socket: &UdpSocket) -> Result<()> {
+    let options = if socket.local_addr()?.is_ipv4() {
+        [
+            (libc::IPPROTO_IP, libc::IP_RECVTTL),
+            (libc::IPPROTO_IP, libc::IP_RECVTOS),
+        ]
+    } else {
+        [
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT),
+            (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS),
+        ]
+    };
+
+    for (level, name) in options {
+        let value: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Receive a datagram and the ip header fields that the kernel reported for
+/// it.
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> Result<(usize, SocketAddr, IpHeader)> {
+    let fd = socket.as_raw_fd();
+    socket.async_io(Interest::READABLE, || recvmsg(fd, buf)).await
+}
+
+/// Send a datagram with the ip header fields, the fields that are not set
+/// are left to the defaults of the socket.
+pub async fn send_to(socket: &UdpSocket, buf: &[u8], target: SocketAddr, header: IpHeader) -> Result<usize> {
+    if header == IpHeader::default() {
+        return socket.send_to(buf, target).await;
+    }
+
+    let fd = socket.as_raw_fd();
+    socket
+        .async_io(Interest::WRITABLE, || sendmsg(fd, buf, target, header))
+        .await
+}
+
+fn recvmsg(fd: RawFd, buf: &mut [u8]) -> Result<(usize, SocketAddr, IpHeader)> {
+    let mut name: libc::sockaddr_storage = unsafe { zeroed() };
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = size_of::<[u64; 16]>() as _;
+
+    let size = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_DONTWAIT) };
+    if size < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let addr = to_socket_addr(&name).ok_or_else(|| Error::other("unknown address family"))?;
+
+    let mut header = IpHeader::default();
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let (level, kind, data) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type, libc::CMSG_DATA(cmsg)) };
+
+        // The type of service is a byte, the others are integers.
+        match (level, kind) {
+            (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                header.ttl = Some(unsafe { ptr::read_unaligned(data as *const libc::c_int) } as u8);
+            }
+            (libc::IPPROTO_IP, libc::IP_TOS) => {
+                header.ecn = Some(unsafe { *data } & 0b11);
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                header.ecn = Some(unsafe { ptr::read_unaligned(data as *const libc::c_int) } as u8 & 0b11);
+            }
+            _ => (),
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    Ok((size as usize, addr, header))
+}
+
+fn sendmsg(fd: RawFd, buf: &[u8], target: SocketAddr, header: IpHeader) -> Result<usize> {
+    let (mut name, namelen) = to_sockaddr(target);
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let (level, ttl, tos) = if target.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TTL, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT, libc::IPV6_TCLASS)
+    };
+
+    let fields = [(ttl, header.ttl), (tos, header.ecn)];
+    let space = fields
+        .iter()
+        .filter(|(_, it)| it.is_some())
+        .map(|_| unsafe { libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) } as usize)
+        .sum::<usize>();
+
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = namelen;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    for (kind, value) in fields {
+        let Some(value) = value else {
+            continue;
+        };
+
+        unsafe {
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = kind;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<libc::c_int>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, value as libc::c_int);
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    let size = unsafe { libc::sendmsg(fd, &msg, libc::MSG_DONTWAIT) };
+    if size < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(size as usize)
+}
+
+pub(crate) fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let it = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            it.sin_family = libc::AF_INET as libc::sa_family_t;
+            it.sin_port = addr.port().to_be();
+            it.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let it = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            it.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            it.sin6_port = addr.port().to_be();
+            it.sin6_addr.s6_addr = addr.ip().octets();
+            it.sin6_flowinfo = addr.flowinfo();
+            it.sin6_scope_id = addr.scope_id();
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, len as libc::socklen_t)
+}
@@ -0,0 +1,261 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::BytesMut;
+use rand::{thread_rng, RngCore};
+use stun::{attribute::XorMappedAddress, Decoder, Kind, MessageWriter, Method, Payload};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    config::{Config, Transport},
+    proxy::LOCAL_V2,
+};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+/// Replace the unspecified address that the listener is bound to with the
+/// loopback address, so that the listener can be reached locally.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::health::local_address;
+///
+/// assert_eq!(
+///     local_address("0.0.0.0:3478".parse().unwrap()),
+///     "127.0.0.1:3478".parse().unwrap()
+/// );
+///
+/// assert_eq!(
+///     local_address("[::]:3478".parse().unwrap()),
+///     "[::1]:3478".parse().unwrap()
+/// );
+///
+/// assert_eq!(
+///     local_address("192.168.1.2:3478".parse().unwrap()),
+///     "192.168.1.2:3478".parse().unwrap()
+/// );
+/// ```
+pub fn local_address(bind: SocketAddr) -> SocketAddr {
+    if !bind.ip().is_unspecified() {
+        return bind;
+    }
+
+    SocketAddr::new(
+        if bind.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        } else {
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        },
+        bind.port(),
+    )
+}
+
+/// Send a stun binding request to the target and return the local address of
+/// the probing socket together with the XOR-MAPPED-ADDRESS of the response.
+///
+/// With `proxy_protocol` the tcp connection starts with the PROXY protocol
+/// header of a local connection, as the health checks of a load balancer do.
+pub async fn probe(
+    transport: Transport,
+    target: SocketAddr,
+    duration: Duration,
+    proxy_protocol: bool,
+) -> Result<(SocketAddr, SocketAddr)> {
+    let mut token = [0u8; 12];
+    thread_rng().fill_bytes(&mut token);
+
+    let mut bytes = BytesMut::with_capacity(1500);
+    MessageWriter::new(Method::Binding(Kind::Request), &token, &mut bytes).flush(None)?;
+
+    let mut buf = [0u8; 1500];
+    let (local, size) = timeout(duration, async {
+        Ok::<_, anyhow::Error>(match transport {
+            Transport::UDP => {
+                let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+                socket.connect(target).await?;
+                socket.send(&bytes).await?;
+
+                let size = socket.recv(&mut buf).await?;
+                (socket.local_addr()?, size)
+            }
+            Transport::TCP => {
+                let mut socket = TcpStream::connect(target).await?;
+                if proxy_protocol {
+                    socket.write_all(&LOCAL_V2).await?;
+                }
+
+                socket.write_all(&bytes).await?;
+
+                // The stun header is 20 bytes and contains the length of the message.
+                socket.read_exact(&mut buf[..20]).await?;
+                let size = Decoder::message_size(&buf, true)?;
+                ensure!(size <= buf.len(), "response is too large");
+
+                socket.read_exact(&mut buf[20..size]).await?;
+                (socket.local_addr()?, size)
+            }
+        })
+    })
+    .await
+    .map_err(|_| anyhow!("binding request timeout"))??;
+
+    let mut decoder = Decoder::default();
+    let message = match decoder.decode(&buf[..size])? {
+        Payload::Message(it) => it,
+        _ => return Err(anyhow!("response is not a stun message")),
+    };
+
+    ensure!(message.token == token.as_slice(), "response token does not match");
+    ensure!(
+        message.method == Method::Binding(Kind::Response),
+        "response is not a binding response"
+    );
+
+    let mapped = message
+        .get::<XorMappedAddress>()
+        .ok_or_else(|| anyhow!("response does not contain XOR-MAPPED-ADDRESS"))?;
+
+    Ok((local, mapped))
+}
+
+/// Probe every listener and its external address once, and verify that the
+/// XOR-MAPPED-ADDRESS is reflected correctly.
+///
+/// This is used to fail fast at startup when the listeners are not reachable,
+/// usually because the external address or the NAT/firewall is configured
+/// wrongly.
+pub async fn self_test(config: &Config) -> Result<()> {
+    let duration = Duration::from_secs(config.health.probe_timeout);
+
+    for it in &config.turn.interfaces {
+        let target = local_address(it.bind);
+        let (local, mapped) = probe(it.transport, target, duration, it.proxy_protocol)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "self test failed, listener is not reachable locally: transport={:?}, bind={}, err={}",
+                    it.transport,
+                    it.bind,
+                    e
+                )
+            })?;
+
+        ensure!(
+            local == mapped,
+            "self test failed, XOR-MAPPED-ADDRESS is reflected wrongly: transport={:?}, bind={}, expected={}, \
+             mapped={}",
+            it.transport,
+            it.bind,
+            local,
+            mapped
+        );
+
+        if it.external != target {
+            // The external address of a proxied interface is the load balancer, which
+            // sends the header itself.
+            let (_, mapped) = probe(it.transport, it.external, duration, false).await.map_err(|e| {
+                anyhow!(
+                    "self test failed, external address is not reachable, check the external address and the \
+                     NAT/firewall configuration: transport={:?}, external={}, err={}",
+                    it.transport,
+                    it.external,
+                    e
+                )
+            })?;
+
+            log::info!(
+                "self test: transport={:?}, external={}, mapped={}",
+                it.transport,
+                it.external,
+                mapped
+            );
+        }
+
+        log::info!("self test passed: transport={:?}, bind={}", it.transport, it.bind);
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct State {
+    listening: AtomicBool,
+    started: AtomicU64,
+    probe_success: AtomicU64,
+}
+
+/// Health state of the turn server.
+///
+/// Readiness reflects whether all listeners are bound and whether the port
+/// pool still has enough free ports, liveness reflects whether the data plane
+/// still answers the internal binding probes.
+#[derive(Clone, Default)]
+pub struct Health(Arc<State>);
+
+impl Health {
+    /// Mark all listeners as bound.
+    pub fn set_listening(&self) {
+        self.0.listening.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.0.listening.load(Ordering::Relaxed)
+    }
+
+    /// Seconds since the last successful probe round, or since the probe was
+    /// started if no round has succeeded yet.
+    pub fn probe_elapsed(&self) -> u64 {
+        let last = self
+            .0
+            .probe_success
+            .load(Ordering::Relaxed)
+            .max(self.0.started.load(Ordering::Relaxed));
+
+        now().saturating_sub(last)
+    }
+
+    /// Periodically send binding requests to every listener, a probe round
+    /// succeeds when all listeners respond.
+    pub fn start_probe(&self, config: Arc<Config>) {
+        let this = self.clone();
+        this.0.started.store(now(), Ordering::Relaxed);
+
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(config.health.probe_interval);
+            let duration = Duration::from_secs(config.health.probe_timeout);
+
+            loop {
+                let mut success = true;
+                for it in &config.turn.interfaces {
+                    if let Err(e) = probe(it.transport, local_address(it.bind), duration, it.proxy_protocol).await {
+                        log::warn!("health probe failed: bind={}, err={}", it.bind, e);
+                        success = false;
+                    }
+                }
+
+                if success {
+                    this.0.probe_success.store(now(), Ordering::Relaxed);
+                }
+
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+}
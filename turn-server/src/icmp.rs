@@ -0,0 +1,182 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem::{size_of, zeroed},
+    net::SocketAddr,
+    os::fd::{AsRawFd, RawFd},
+    ptr,
+    sync::Arc,
+};
+
+use bytes::BytesMut;
+use rand::{thread_rng, RngCore};
+use stun::{
+    attribute::{Icmp, IcmpError, XorPeerAddress},
+    MessageWriter, Method,
+};
+use tokio::{io::Interest, net::UdpSocket};
+use turn::{sessions::Endpoint, Observer, ResponseMethod, Service, SessionAddr};
+
+use crate::{header::to_socket_addr, router::Router};
+
+const SO_EE_ORIGIN_ICMP: u8 = 2;
+const SO_EE_ORIGIN_ICMP6: u8 = 3;
+
+/// Ask the kernel to queue the ICMP errors of the socket, so that they can
+/// be read with [`recv`].
+pub fn enable(socket: &UdpSocket) -> Result<()> {
+    let (level, name) = if socket.local_addr()?.is_ipv4() {
+        (libc::SOL_IP, libc::IP_RECVERR)
+    } else {
+        (libc::SOL_IPV6, libc::IPV6_RECVERR)
+    };
+
+    let value: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Receive the next ICMP error of the socket, returns the destination of the
+/// datagram that caused the error and the error.
+pub async fn recv(socket: &UdpSocket) -> Result<(SocketAddr, IcmpError)> {
+    loop {
+        let fd = socket.as_raw_fd();
+        if let Some(it) = socket.async_io(Interest::ERROR, || recv_error(fd)).await? {
+            return Ok(it);
+        }
+    }
+}
+
+/// Read one message from the error queue of the socket, the errors that did
+/// not come from an ICMP message are skipped.
+fn recv_error(fd: RawFd) -> Result<Option<(SocketAddr, IcmpError)>> {
+    let mut name: libc::sockaddr_storage = unsafe { zeroed() };
+    let mut control = [0u64; 64];
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { zeroed() };
+    msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = size_of::<[u64; 64]>() as _;
+
+    if unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let Some(destination) = to_socket_addr(&name) else {
+        return Ok(None);
+    };
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if matches!(
+            (header.cmsg_level, header.cmsg_type),
+            (libc::SOL_IP, libc::IP_RECVERR) | (libc::SOL_IPV6, libc::IPV6_RECVERR)
+        ) {
+            let err: libc::sock_extended_err =
+                unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err) };
+
+            if err.ee_origin == SO_EE_ORIGIN_ICMP || err.ee_origin == SO_EE_ORIGIN_ICMP6 {
+                return Ok(Some((
+                    destination,
+                    IcmpError {
+                        kind: err.ee_type,
+                        code: err.ee_code,
+                        data: err.ee_info,
+                    },
+                )));
+            }
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    Ok(None)
+}
+
+/// Forward the ICMP errors of the interface socket to the clients, as RFC
+/// 8656 describes for the ICMP packets received on a relayed address.
+///
+/// The datagram that caused the error was relayed from the sessions that are
+/// permitted to relay to its destination, each of them gets a Data
+/// indication that carries an ICMP attribute instead of a DATA attribute,
+/// with the relayed address of the destination as the peer address.
+pub fn serve<T>(socket: Arc<UdpSocket>, external: SocketAddr, service: Service<T>, router: Router)
+where
+    T: Clone + Observer + 'static,
+{
+    if let Err(e) = enable(&socket) {
+        log::warn!("failed to receive icmp errors: interface={}, err={}", external, e);
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut bytes = BytesMut::with_capacity(256);
+        let mut token = [0u8; 12];
+
+        loop {
+            let (destination, error) = match recv(&socket).await {
+                Ok(it) => it,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::error!("failed to receive icmp error: interface={}, err={}", external, e);
+                    break;
+                }
+            };
+
+            let sources = service.get_sessions().get_relay_sources(&Endpoint {
+                address: destination,
+                endpoint: external,
+            });
+
+            for (addr, port) in sources {
+                thread_rng().fill_bytes(&mut token);
+
+                {
+                    let mut message = MessageWriter::new(Method::DataIndication, &token, &mut bytes);
+                    message.append::<XorPeerAddress>(SocketAddr::new(addr.interface.ip(), port));
+                    message.append::<Icmp>(error);
+                    if message.flush(None).is_err() {
+                        continue;
+                    }
+                }
+
+                send(&socket, &router, external, &addr, &bytes).await;
+            }
+        }
+    });
+}
+
+async fn send(socket: &UdpSocket, router: &Router, external: SocketAddr, addr: &SessionAddr, bytes: &[u8]) {
+    if addr.interface == external {
+        // An undeliverable indication is not reported again.
+        let _ = socket.send_to(bytes, addr.address).await;
+    } else {
+        router.send(
+            &addr.interface,
+            ResponseMethod::Stun(Method::DataIndication),
+            &addr.address,
+            bytes,
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client, ClientBuilder};
+use stun::Transport;
+use turn::{Observer, Service};
+
+use crate::{config, statistics::Statistics};
+
+// The snapshots that are kept while the database can not be written, the
+// oldest snapshots are dropped beyond it.
+const MAX_PENDING: usize = 1000;
+
+fn escape(value: &str, chars: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || chars.contains(&c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Encode a point in the line protocol, the fields are integers and the
+/// timestamp is in nanoseconds.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::influxdb::line;
+///
+/// assert_eq!(
+///     line("turn", &[("host", "a b"), ("transport", "udp")], &[("sessions", 1), ("channels", 0)], 10),
+///     "turn,host=a\\ b,transport=udp sessions=1i,channels=0i 10"
+/// );
+/// ```
+pub fn line(measurement: &str, tags: &[(&str, &str)], fields: &[(&str, u64)], timestamp: u128) -> String {
+    let mut line = escape(measurement, &[',', ' ']);
+    for (key, value) in tags {
+        line.push(',');
+        line.push_str(&escape(key, &[',', '=', ' ']));
+        line.push('=');
+        line.push_str(&escape(value, &[',', '=', ' ']));
+    }
+
+    for (i, (key, value)) in fields.iter().enumerate() {
+        line.push(if i == 0 { ' ' } else { ',' });
+        line.push_str(&escape(key, &[',', '=', ' ']));
+        line.push('=');
+        line.push_str(&format!("{}i", value));
+    }
+
+    line.push(' ');
+    line.push_str(&timestamp.to_string());
+    line
+}
+
+/// The delay before the next write after a failed write.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use turn_server::influxdb::Backoff;
+///
+/// let mut backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(30));
+///
+/// assert_eq!(backoff.failure(), Duration::from_secs(10));
+/// assert_eq!(backoff.failure(), Duration::from_secs(20));
+/// assert_eq!(backoff.failure(), Duration::from_secs(30));
+///
+/// backoff.reset();
+/// assert_eq!(backoff.failure(), Duration::from_secs(10));
+/// ```
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, failures: 0 }
+    }
+
+    /// Record a failed write, returns the delay before the next write, which
+    /// is doubled after every failure up to the maximum.
+    pub fn failure(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(self.max.max(self.base));
+
+        self.failures = self.failures.saturating_add(1);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/// Take a snapshot of the metrics, the sessions and the port pool are
+/// written to the measurement, and the traffic of each transport since the
+/// start of the server to the measurement with the `_traffic` suffix.
+fn snapshot<T>(config: &config::Influxdb, service: &Service<T>, statistics: &Statistics) -> String
+where
+    T: Clone + Observer + 'static,
+{
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+
+    let mut tags = config
+        .tags
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect::<Vec<_>>();
+
+    // The points are written faster when their tags are sorted.
+    tags.sort();
+
+    let sessions = service.get_sessions();
+    let counters = sessions.counters();
+    let mut lines = vec![line(
+        &config.measurement,
+        &tags,
+        &[
+            ("sessions", counters.sessions as u64),
+            ("allocations", counters.allocated as u64),
+            ("permissions", counters.permissions as u64),
+            ("channels", counters.channels as u64),
+            ("port_capacity", sessions.capacity() as u64),
+            ("port_available", sessions.available() as u64),
+        ],
+        timestamp,
+    )];
+
+    let measurement = format!("{}_traffic", config.measurement);
+    for (transport, name) in [(Transport::UDP, "udp"), (Transport::TCP, "tcp")] {
+        let counts = statistics.transport(transport);
+        let mut tags = tags.clone();
+        tags.push(("transport", name));
+        tags.sort();
+
+        lines.push(line(
+            &measurement,
+            &tags,
+            &[
+                ("received_bytes", counts.received_bytes),
+                ("send_bytes", counts.send_bytes),
+                ("received_pkts", counts.received_pkts),
+                ("send_pkts", counts.send_pkts),
+                ("error_pkts", counts.error_pkts),
+            ],
+            timestamp,
+        ));
+    }
+
+    lines.join("\n")
+}
+
+async fn write(client: &Client, config: &config::Influxdb, url: &str, body: String) -> Result<()> {
+    let mut req = client.post(url).body(body);
+    if let Some(token) = &config.token {
+        req = req.header("Authorization", format!("Token {}", token));
+    }
+
+    let res = req.send().await?;
+    if !res.status().is_success() {
+        return Err(anyhow!("status={}", res.status()));
+    }
+
+    Ok(())
+}
+
+/// Push the snapshots of the metrics to the write url of the config.
+///
+/// A snapshot is taken at the interval of the config, and the snapshots are
+/// written in batches of the batch size. The snapshots of a failed write are
+/// kept and written with the next batch, and the writes are delayed with an
+/// exponential backoff until the database accepts them again. The snapshots
+/// are only pushed when the url is set.
+pub fn start<T>(config: config::Influxdb, service: Service<T>, statistics: Statistics) -> Result<()>
+where
+    T: Clone + Observer + 'static,
+{
+    let Some(url) = config.url.clone() else {
+        return Ok(());
+    };
+
+    let client = ClientBuilder::new().timeout(Duration::from_secs(10)).build()?;
+
+    tokio::spawn(async move {
+        let period = Duration::from_secs(config.interval.max(1));
+        let mut backoff = Backoff::new(period, Duration::from_secs(config.max_backoff));
+        let mut interval = tokio::time::interval(period);
+        let mut pending = VecDeque::with_capacity(config.batch_size);
+        let mut retry_at = None;
+
+        loop {
+            interval.tick().await;
+
+            if pending.len() >= MAX_PENDING {
+                pending.pop_front();
+            }
+
+            pending.push_back(snapshot(&config, &service, &statistics));
+            if pending.len() < config.batch_size.max(1) {
+                continue;
+            }
+
+            if retry_at.is_some_and(|it| Instant::now() < it) {
+                continue;
+            }
+
+            let body = pending.iter().map(String::as_str).collect::<Vec<_>>().join("\n");
+            match write(&client, &config, &url, body).await {
+                Ok(_) => {
+                    pending.clear();
+                    backoff.reset();
+                    retry_at = None;
+                }
+                Err(e) => {
+                    let delay = backoff.failure();
+                    retry_at = Some(Instant::now() + delay);
+
+                    log::warn!(
+                        "failed to write influxdb snapshots: pending={}, retry={:?}, err={}",
+                        pending.len(),
+                        delay,
+                        e
+                    );
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -1,32 +1,150 @@
+pub mod admission;
+pub mod alerts;
+pub mod anonymous;
+pub mod audit;
+pub mod buffers;
+pub mod commands;
 pub mod config;
+pub mod geoip;
+#[cfg(all(feature = "udp", target_os = "linux"))]
+pub mod header;
+pub mod health;
+#[cfg(all(feature = "udp", target_os = "linux"))]
+pub mod icmp;
+#[cfg(feature = "influxdb")]
+pub mod influxdb;
+pub mod logger;
+pub mod malformed;
+pub mod memory;
 pub mod observer;
+pub mod persistence;
+#[cfg(feature = "wasm")]
+pub mod plugin;
+pub mod proxy;
 pub mod publicly;
+pub mod reflection;
 pub mod router;
 pub mod server;
 pub mod statistics;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+pub mod tags;
+pub mod tools;
+pub mod usage;
 
 use std::sync::Arc;
 
-use turn::Service;
+use stun::{Kind, Method};
+use turn::{integrity::IntegrityPool, Service};
 
-use self::{config::Config, observer::Observer, statistics::Statistics};
+use self::{
+    admission::AdmissionController, alerts::Alerts, audit::Audit, buffers::SocketBuffers, config::Config,
+    health::Health, malformed::MalformedFilter, memory::MemoryBudget, observer::Observer, persistence::Persistence,
+    reflection::ReflectionGuard, router::Router, statistics::Statistics, usage::Usage,
+};
 
 /// In order to let the integration test directly use the turn-server crate and
 /// start the server, a function is opened to replace the main function to
 /// directly start the server.
 pub async fn startup(config: Arc<Config>) -> anyhow::Result<()> {
     let statistics = Statistics::default();
-    let service = Service::new(
+    let audit = Audit::new(&config)?;
+    let alerts = Alerts::default();
+    let persistence = Persistence::load(&config.persistence)?;
+    let usage = Usage::new(config.usage.clone(), config.turn.realm.clone(), statistics.clone());
+    let observer = Observer::new(
+        config.clone(),
+        statistics.clone(),
+        audit.clone(),
+        alerts.clone(),
+        usage.clone(),
+    )
+    .await?;
+    if let Some(auth) = persistence.auth() {
+        observer.replace_auth(auth).await;
+    }
+
+    let mut service = Service::with_port_range(
         config.turn.realm.clone(),
         config.turn.get_externals(),
-        Observer::new(config.clone(), statistics.clone()).await?,
+        config.turn.port_range.clone(),
+        observer,
     );
 
-    server::start(&config, &statistics, &service).await?;
+    service.get_sessions().set_fair_share(config.turn.fair_share);
+    service
+        .get_sessions()
+        .set_duplicate_allocate(config.turn.duplicate_allocate.into());
+    service
+        .get_sessions()
+        .set_address_rebind(config.turn.address_rebind.into());
+    service.get_sessions().set_maintenance(config.maintenance.into());
+    service.get_sessions().set_nonce_secret(persistence.nonce_secret());
+    if config.reflection.is_enabled() {
+        service.add_interceptor(ReflectionGuard::new(config.reflection));
+    }
+
+    if config.integrity.workers > 0 {
+        service.set_integrity_pool(IntegrityPool::new(config.integrity.workers, config.integrity.queue));
+    }
+
+    let router = Router::default();
+    let budget = MemoryBudget::default();
+    // The controller is always registered, so that new sessions can be denied
+    // through the api, the limits are only sampled if there are any.
+    let admission = AdmissionController::new(config.admission, router.clone(), budget.clone(), audit.clone());
+    if config.admission.is_enabled() {
+        admission.start_sampler(service.get_sessions());
+    }
+
+    service.register(Method::Allocate(Kind::Request), admission.clone());
+
+    let health = Health::default();
+    let malformed = MalformedFilter::new(config.malformed, config.privacy.log, audit.clone());
+    let buffers = SocketBuffers::new(config.buffers);
+    server::start(&config, &statistics, &service, &router, &malformed, &budget, &buffers).await?;
+    buffers.start();
+    if config.health.self_test {
+        health::self_test(&config).await?;
+    }
+
+    health.set_listening();
+    alerts.start(config.alerts.clone(), config.turn.realm.clone(), service.clone())?;
+    usage.start()?;
 
     #[cfg(feature = "api")]
     {
-        publicly::api::start_server(config, service, statistics).await?;
+        health.start_probe(config.clone());
+        statistics.start_sampler(config.maintenance.stats_interval, config.maintenance.jitter);
+
+        #[cfg(feature = "statsd")]
+        statsd::start(config.statsd.clone(), service.clone(), statistics.clone()).await?;
+
+        #[cfg(not(feature = "statsd"))]
+        if config.statsd.address.is_some() {
+            log::warn!("the statsd exporter is ignored, the server is built without the statsd feature");
+        }
+
+        #[cfg(feature = "influxdb")]
+        influxdb::start(config.influxdb.clone(), service.clone(), statistics.clone())?;
+
+        #[cfg(not(feature = "influxdb"))]
+        if config.influxdb.url.is_some() {
+            log::warn!("the influxdb push is ignored, the server is built without the influxdb feature");
+        }
+
+        publicly::api::start_server(
+            config,
+            service,
+            statistics,
+            audit,
+            health,
+            malformed,
+            persistence,
+            admission,
+            buffers,
+        )
+        .await?;
     }
 
     // The turn server is non-blocking after it runs and needs to be kept from
@@ -0,0 +1,533 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use parking_lot::Mutex;
+use simple_logger::SimpleLogger;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::{Log as LogConfig, LogRotation};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+/// A log file that is rotated when it exceeds the size limit or when the
+/// rotation period changes.
+///
+/// The rotated file is renamed with the unix timestamp of the rotation as
+/// suffix, for example `turn-server.log.1700000000`, and is optionally
+/// compressed with gzip in a background thread. Only the newest `max_files`
+/// rotated files are kept.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::{config::Log, logger::RotatingFile};
+///
+/// let dir = std::env::temp_dir().join("turn-server-rotating-file");
+/// let _ = std::fs::remove_dir_all(&dir);
+/// std::fs::create_dir_all(&dir).unwrap();
+///
+/// let path = dir.join("turn-server.log");
+/// let config = Log {
+///     max_size: 10,
+///     ..Default::default()
+/// };
+///
+/// let mut file = RotatingFile::new(path.to_str().unwrap(), &config).unwrap();
+/// file.write(b"0123456789").unwrap();
+/// assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+///
+/// file.write(b"abc").unwrap();
+/// assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+/// ```
+pub struct RotatingFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    period: u64,
+    rotation: LogRotation,
+    max_size: u64,
+    max_files: usize,
+    compress: bool,
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, config: &LogConfig) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self {
+            size: file.metadata()?.len(),
+            period: config.rotation.period(now()),
+            writer: BufWriter::new(file),
+            rotation: config.rotation,
+            max_size: config.max_size,
+            max_files: config.max_files,
+            compress: config.compress,
+            path,
+        })
+    }
+
+    pub fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let period = self.rotation.period(now());
+        if period != self.period || (self.max_size > 0 && self.size + line.len() as u64 > self.max_size) {
+            self.period = period;
+
+            // An empty file has nothing worth keeping, writing simply continues in it.
+            if self.size > 0 {
+                self.rotate()?;
+            }
+        }
+
+        self.writer.write_all(line)?;
+        self.writer.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", now()));
+
+        let rotated = PathBuf::from(rotated);
+        fs::rename(&self.path, &rotated)?;
+
+        self.writer = BufWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        self.size = 0;
+
+        // Compressing a large file can take a while, which should not block the
+        // caller of the logger.
+        let path = self.path.clone();
+        let compress = self.compress;
+        let max_files = self.max_files;
+        std::thread::spawn(move || {
+            if compress {
+                if let Err(e) = compress_file(&rotated) {
+                    eprintln!("failed to compress rotated log file, err={}", e);
+                }
+            }
+
+            if let Err(e) = remove_expired_files(&path, max_files) {
+                eprintln!("failed to remove expired log files, err={}", e);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".gz");
+
+    let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+    io::copy(&mut BufReader::new(File::open(path)?), &mut encoder)?;
+    encoder.finish()?;
+
+    fs::remove_file(path)
+}
+
+fn remove_expired_files(path: &Path, max_files: usize) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(it) if !it.as_os_str().is_empty() => it,
+        _ => Path::new("."),
+    };
+
+    let prefix = match path.file_name().and_then(|it| it.to_str()) {
+        Some(it) => format!("{}.", it),
+        None => return Ok(()),
+    };
+
+    // Rotated files are suffixed with the rotation timestamp, sorting by it puts
+    // the newest files first.
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if let Some(suffix) = name.strip_prefix(&prefix) {
+                if let Ok(timestamp) = suffix.trim_end_matches(".gz").parse::<u64>() {
+                    files.push((timestamp, entry.path()));
+                }
+            }
+        }
+    }
+
+    files.sort_by_key(|it| std::cmp::Reverse(it.0));
+    for (_, path) in files.into_iter().skip(max_files) {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+/// The logger of the turn server.
+///
+/// All records are written to stdout unless disabled, the access log file
+/// and syslog receive all records, and the error log file receives only
+/// warnings and errors.
+pub struct Logger {
+    level: LevelFilter,
+    stdout: Option<SimpleLogger>,
+    syslog: Option<syslog::Syslog>,
+    access: Option<Mutex<RotatingFile>>,
+    error: Option<Mutex<RotatingFile>>,
+}
+
+impl Logger {
+    pub fn new(config: &LogConfig) -> anyhow::Result<Self> {
+        let level = config.level.as_level().to_level_filter();
+
+        Ok(Self {
+            stdout: if config.stdout {
+                Some(SimpleLogger::new().with_level(level))
+            } else {
+                None
+            },
+            access: if let Some(path) = &config.file {
+                Some(Mutex::new(RotatingFile::new(path, config)?))
+            } else {
+                None
+            },
+            error: if let Some(path) = &config.error_file {
+                Some(Mutex::new(RotatingFile::new(path, config)?))
+            } else {
+                None
+            },
+            syslog: if let Some(it) = &config.syslog {
+                Some(syslog::Syslog::new(it)?)
+            } else {
+                None
+            },
+            level,
+        })
+    }
+
+    /// Install the logger as the global logger.
+    pub fn init(self) -> anyhow::Result<()> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))?;
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if let Some(stdout) = &self.stdout {
+            stdout.log(record);
+        }
+
+        if let Some(syslog) = &self.syslog {
+            // The tls connection of syslog logs through this logger as well, which
+            // would deadlock while the connection is locked.
+            if !record.target().starts_with("rustls") {
+                syslog.send(record.level(), record.target(), &record.args().to_string());
+            }
+        }
+
+        if self.access.is_none() && self.error.is_none() {
+            return;
+        }
+
+        let line = format!(
+            "{} {:<5} [{}] {}\n",
+            OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_else(|_| now().to_string()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        if let Some(file) = &self.access {
+            if let Err(e) = file.lock().write(line.as_bytes()) {
+                eprintln!("failed to write log file, err={}", e);
+            }
+        }
+
+        if record.level() <= Level::Warn {
+            if let Some(file) = &self.error {
+                if let Err(e) = file.lock().write(line.as_bytes()) {
+                    eprintln!("failed to write error log file, err={}", e);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(stdout) = &self.stdout {
+            stdout.flush();
+        }
+    }
+}
+
+/// Syslog output target.
+///
+/// Messages are formatted according to
+/// [rfc5424](https://datatracker.ietf.org/doc/html/rfc5424) and sent to a
+/// local syslog daemon over a unix datagram socket, or to a remote syslog
+/// server over udp, tcp or tls. Messages sent over tcp and tls use the octet
+/// counting framing of [rfc6587](https://datatracker.ietf.org/doc/html/rfc6587)
+/// and [rfc5425](https://datatracker.ietf.org/doc/html/rfc5425).
+pub mod syslog {
+    use std::{
+        fs::{read_to_string, File},
+        io::{self, BufReader, Write},
+        net::{TcpStream, UdpSocket},
+        sync::Arc,
+        time::Duration,
+    };
+
+    #[cfg(unix)]
+    use std::os::unix::net::UnixDatagram;
+
+    use log::Level;
+    use parking_lot::Mutex;
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+    use crate::config::{Syslog as SyslogConfig, SyslogTransport};
+
+    enum Connection {
+        #[cfg(unix)]
+        Unix(UnixDatagram),
+        Udp(UdpSocket),
+        Tcp(TcpStream),
+        Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+    }
+
+    impl Connection {
+        fn connect(config: &SyslogConfig, tls: Option<&Arc<ClientConfig>>) -> io::Result<Self> {
+            let address = config.get_address();
+
+            Ok(match config.transport {
+                #[cfg(unix)]
+                SyslogTransport::Unix => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket.connect(address)?;
+                    Self::Unix(socket)
+                }
+                #[cfg(not(unix))]
+                SyslogTransport::Unix => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "unix syslog socket is not supported on this platform",
+                    ))
+                }
+                SyslogTransport::Udp => {
+                    let socket = UdpSocket::bind(if address.starts_with('[') {
+                        "[::]:0"
+                    } else {
+                        "0.0.0.0:0"
+                    })?;
+
+                    socket.connect(address)?;
+                    Self::Udp(socket)
+                }
+                SyslogTransport::Tcp => Self::Tcp(Self::connect_tcp(address)?),
+                SyslogTransport::Tls => {
+                    let host = address
+                        .rsplit_once(':')
+                        .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']'))
+                        .unwrap_or(address);
+
+                    let name = ServerName::try_from(host.to_string())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+                    let tls = tls.ok_or_else(|| io::Error::other("tls is not initialized"))?;
+                    let conn = ClientConnection::new(tls.clone(), name).map_err(io::Error::other)?;
+                    Self::Tls(Box::new(StreamOwned::new(conn, Self::connect_tcp(address)?)))
+                }
+            })
+        }
+
+        fn connect_tcp(address: &str) -> io::Result<TcpStream> {
+            let stream = TcpStream::connect(address)?;
+            stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        }
+
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            match self {
+                #[cfg(unix)]
+                Self::Unix(socket) => socket.send(message).map(|_| ()),
+                Self::Udp(socket) => socket.send(message).map(|_| ()),
+                Self::Tcp(stream) => Self::send_framed(stream, message),
+                Self::Tls(stream) => Self::send_framed(stream.as_mut(), message),
+            }
+        }
+
+        // Octet counting framing, the message is prefixed with its length.
+        fn send_framed<W: Write>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+            writer.write_all(format!("{} ", message.len()).as_bytes())?;
+            writer.write_all(message)?;
+            writer.flush()
+        }
+    }
+
+    /// Syslog writer.
+    ///
+    /// The connection is established lazily, and is re-established on the
+    /// next message when sending fails.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use log::Level;
+    /// use turn_server::{
+    ///     config::{Syslog as SyslogConfig, SyslogFacility, SyslogTransport},
+    ///     logger::syslog::Syslog,
+    /// };
+    ///
+    /// let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    /// let syslog = Syslog::new(&SyslogConfig {
+    ///     transport: SyslogTransport::Udp,
+    ///     address: Some(socket.local_addr().unwrap().to_string()),
+    ///     facility: SyslogFacility::Local0,
+    ///     ..Default::default()
+    /// })
+    /// .unwrap();
+    ///
+    /// syslog.send(Level::Warn, "turn_server", "hello");
+    ///
+    /// let mut buf = [0u8; 1024];
+    /// let size = socket.recv(&mut buf).unwrap();
+    /// let message = std::str::from_utf8(&buf[..size]).unwrap();
+    ///
+    /// // local0 (16) * 8 + warning (4)
+    /// assert!(message.starts_with("<132>1 "));
+    /// assert!(message.contains(" turn-server "));
+    /// assert!(message.ends_with(" turn_server - hello"));
+    /// ```
+    pub struct Syslog {
+        config: SyslogConfig,
+        hostname: String,
+        procid: u32,
+        tls: Option<Arc<ClientConfig>>,
+        connection: Mutex<Option<Connection>>,
+    }
+
+    impl Syslog {
+        pub fn new(config: &SyslogConfig) -> anyhow::Result<Self> {
+            Ok(Self {
+                tls: if config.transport == SyslogTransport::Tls {
+                    Some(Arc::new(tls_config(config)?))
+                } else {
+                    None
+                },
+                hostname: hostname(),
+                procid: std::process::id(),
+                connection: Mutex::new(None),
+                config: config.clone(),
+            })
+        }
+
+        /// Send a message to syslog.
+        ///
+        /// The message id is the log target, which makes it possible to filter
+        /// the messages of each module on the syslog server.
+        pub fn send(&self, level: Level, msgid: &str, message: &str) {
+            let severity = match level {
+                Level::Error => 3,
+                Level::Warn => 4,
+                Level::Info => 6,
+                Level::Debug | Level::Trace => 7,
+            };
+
+            let message = format!(
+                "<{}>1 {} {} {} {} {} - {}",
+                self.config.facility as u8 * 8 + severity,
+                OffsetDateTime::now_utc()
+                    .format(&Rfc3339)
+                    .unwrap_or_else(|_| "-".to_string()),
+                self.hostname,
+                self.config.app_name,
+                self.procid,
+                // MSGID is limited to 32 printable ascii characters and must not contain
+                // spaces.
+                msgid
+                    .chars()
+                    .filter(|it| it.is_ascii_graphic())
+                    .take(32)
+                    .collect::<String>(),
+                message
+            );
+
+            let mut connection = self.connection.lock();
+
+            // Try once with the existing connection, and once more with a new
+            // connection if the existing one is broken.
+            for _ in 0..2 {
+                if connection.is_none() {
+                    match Connection::connect(&self.config, self.tls.as_ref()) {
+                        Ok(it) => {
+                            connection.replace(it);
+                        }
+                        Err(e) => {
+                            eprintln!("failed to connect syslog, err={}", e);
+                            return;
+                        }
+                    }
+                }
+
+                if let Some(conn) = connection.as_mut() {
+                    match conn.send(message.as_bytes()) {
+                        Ok(_) => return,
+                        Err(e) => {
+                            eprintln!("failed to send syslog, err={}", e);
+                            connection.take();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn tls_config(config: &SyslogConfig) -> anyhow::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        if let Some(path) = &config.ca {
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots.add(cert?)?;
+            }
+        } else {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        Ok(
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    fn hostname() -> String {
+        std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| read_to_string("/proc/sys/kernel/hostname").ok())
+            .or_else(|| read_to_string("/etc/hostname").ok())
+            .map(|it| it.trim().to_string())
+            .filter(|it| !it.is_empty())
+            .unwrap_or_else(|| "-".to_string())
+    }
+}
@@ -4,12 +4,17 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 use std::sync::Arc;
 
-use turn_server::config::Config;
+use turn_server::{config::Config, logger::Logger, tools};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Arc::new(Config::load()?);
-    simple_logger::init_with_level(config.log.level.as_level())?;
+    let (config, command) = Config::load_with_command()?;
+    if let Some(command) = command {
+        return tools::run(command).await;
+    }
+
+    let config = Arc::new(config);
+    Logger::new(&config.log)?.init()?;
 
     if config.turn.interfaces.is_empty() {
         log::warn!(
@@ -0,0 +1,410 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use parking_lot::{Mutex, RwLock};
+use serde_json::json;
+use stun::{util::verify_fingerprint, Decoder, PacketClass};
+
+use crate::{
+    audit::{Actor, Audit},
+    config::{Anonymize, FingerprintCheck, Malformed, MalformedAction},
+};
+
+// The ban threshold applies to the malformed packets within this window.
+const WINDOW: Duration = Duration::from_secs(60);
+
+// The classes of the packets that are discarded before they are decoded.
+const DISCARDED: [PacketClass; 4] = [
+    PacketClass::Truncated,
+    PacketClass::Unknown,
+    PacketClass::BadCookie,
+    PacketClass::BadLength,
+];
+
+/// The most sources that are tracked. Scanning traffic can come from a lot of
+/// sources, the sources that are idle for a window and not banned are purged
+/// at most once per window when the limit is reached, and the new sources
+/// beyond it are not tracked until there is room again, their packets are
+/// still dropped.
+pub const MAX_SOURCES: usize = 65536;
+
+struct Source {
+    window: Instant,
+    count: u64,
+    total: u64,
+    logged: Option<Instant>,
+    suppressed: u64,
+}
+
+/// The malformed packet counters of a source ip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedSource {
+    pub ip: IpAddr,
+    pub total: u64,
+    pub banned: bool,
+}
+
+struct Inner {
+    config: Malformed,
+    anonymize: Anonymize,
+    audit: Audit,
+    sources: Mutex<AHashMap<IpAddr, Source>>,
+    // The time the idle sources were last purged, it is locked with the
+    // sources.
+    purged: Mutex<Option<Instant>>,
+    bans: RwLock<AHashMap<IpAddr, Instant>>,
+    banned: AtomicUsize,
+    fingerprint_rejected: AtomicU64,
+    discarded: [AtomicU64; DISCARDED.len()],
+}
+
+/// Applies the malformed packet policy.
+///
+/// Packets that can not be decoded are reported here, and are counted per
+/// source ip. Depending on the action, the source is logged at most once per
+/// interval, and is banned when it sends too many malformed packets. The
+/// bans are recorded in the audit log.
+#[derive(Clone)]
+pub struct MalformedFilter(Arc<Inner>);
+
+impl MalformedFilter {
+    pub fn new(config: Malformed, anonymize: Anonymize, audit: Audit) -> Self {
+        Self(Arc::new(Inner {
+            sources: Default::default(),
+            purged: Default::default(),
+            bans: Default::default(),
+            banned: AtomicUsize::new(0),
+            fingerprint_rejected: AtomicU64::new(0),
+            discarded: Default::default(),
+            anonymize,
+            audit,
+            config,
+        }))
+    }
+
+    /// Whether the packets of the source ip are dropped without being
+    /// decoded.
+    ///
+    /// There are no bans most of the time, so this does not take the lock in
+    /// that case.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        if self.0.banned.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+
+        let until = if let Some(it) = self.0.bans.read().get(&ip) {
+            *it
+        } else {
+            return false;
+        };
+
+        if until > Instant::now() {
+            #[cfg(feature = "prometheus")]
+            {
+                crate::statistics::prometheus::METRICS.banned_packets.inc();
+            }
+
+            return true;
+        }
+
+        if self.0.bans.write().remove(&ip).is_some() {
+            self.0.banned.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        false
+    }
+
+    /// Report a packet that can not be decoded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{
+    ///     audit::Audit,
+    ///     config::{Anonymize, Malformed, MalformedAction},
+    ///     malformed::*,
+    /// };
+    ///
+    /// let filter = MalformedFilter::new(
+    ///     Malformed {
+    ///         action: MalformedAction::Ban,
+    ///         ban_threshold: 2,
+    ///         ..Default::default()
+    ///     },
+    ///     Anonymize::None,
+    ///     Audit::default(),
+    /// );
+    ///
+    /// let addr = "127.0.0.1:8080".parse().unwrap();
+    ///
+    /// filter.report(addr);
+    /// filter.report(addr);
+    /// assert!(!filter.is_banned(addr.ip()));
+    ///
+    /// filter.report(addr);
+    /// assert!(filter.is_banned(addr.ip()));
+    /// assert_eq!(
+    ///     filter.sources(),
+    ///     vec![MalformedSource {
+    ///         ip: addr.ip(),
+    ///         total: 3,
+    ///         banned: true,
+    ///     }]
+    /// );
+    /// ```
+    pub fn report(&self, addr: SocketAddr) {
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS.malformed_packets.inc();
+        }
+
+        let config = &self.0.config;
+        let now = Instant::now();
+
+        let mut sources = self.0.sources.lock();
+        if sources.len() >= MAX_SOURCES && !sources.contains_key(&addr.ip()) {
+            let mut purged = self.0.purged.lock();
+            if purged.map(|it| now.duration_since(it) >= WINDOW).unwrap_or(true) {
+                *purged = Some(now);
+
+                let bans = self.0.bans.read();
+                sources.retain(|ip, it| now.duration_since(it.window) < WINDOW || bans.contains_key(ip));
+            }
+
+            if sources.len() >= MAX_SOURCES {
+                return;
+            }
+        }
+
+        let source = sources.entry(addr.ip()).or_insert_with(|| Source {
+            window: now,
+            count: 0,
+            total: 0,
+            logged: None,
+            suppressed: 0,
+        });
+
+        if now.duration_since(source.window) >= WINDOW {
+            source.window = now;
+            source.count = 0;
+        }
+
+        source.count += 1;
+        source.total += 1;
+
+        if config.action == MalformedAction::Drop {
+            return;
+        }
+
+        // Only one line is logged for each source within the interval, the number of
+        // packets in between is carried by the next line.
+        let interval = Duration::from_secs(config.log_interval);
+        if source
+            .logged
+            .map(|it| now.duration_since(it) >= interval)
+            .unwrap_or(true)
+        {
+            log::warn!(
+                "malformed packet: addr={}, count={}",
+                self.0.anonymize.apply(addr),
+                source.suppressed + 1,
+            );
+
+            source.logged = Some(now);
+            source.suppressed = 0;
+        } else {
+            source.suppressed += 1;
+        }
+
+        if config.action == MalformedAction::Ban && source.count > config.ban_threshold {
+            let until = now + Duration::from_secs(config.ban_duration);
+            if self.0.bans.write().insert(addr.ip(), until).is_none() {
+                self.0.banned.fetch_add(1, Ordering::Relaxed);
+
+                log::warn!(
+                    "malformed packet source banned: addr={}, duration={}",
+                    self.0.anonymize.apply(addr),
+                    config.ban_duration,
+                );
+
+                self.0.audit.record(
+                    Actor::Server,
+                    "malformed_ban",
+                    json!({
+                        "address": self.0.audit.anonymize(addr),
+                        "duration": config.ban_duration,
+                    }),
+                );
+            }
+        }
+    }
+
+    /// Whether the packet is discarded by the demultiplexer, because its
+    /// header is neither a valid stun message nor a plausible channel data.
+    /// The discarded packets are counted by class and reported as malformed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use stun::PacketClass;
+    /// use turn_server::{
+    ///     audit::Audit,
+    ///     config::{Anonymize, Malformed},
+    ///     malformed::*,
+    /// };
+    ///
+    /// let filter = MalformedFilter::new(Malformed::default(), Anonymize::None, Audit::default());
+    /// let addr = "127.0.0.1:8080".parse().unwrap();
+    ///
+    /// assert!(!filter.reject_class(addr, &[0x40, 0x00, 0x00, 0x00]));
+    /// assert!(filter.reject_class(addr, &[0x80, 0x60, 0x00, 0x00]));
+    /// assert!(filter.reject_class(addr, &[0x00; 20]));
+    /// assert!(filter.reject_class(addr, &[0x00; 2]));
+    ///
+    /// assert_eq!(
+    ///     filter.discarded(),
+    ///     vec![
+    ///         (PacketClass::Truncated, 1),
+    ///         (PacketClass::Unknown, 1),
+    ///         (PacketClass::BadCookie, 1),
+    ///         (PacketClass::BadLength, 0),
+    ///     ]
+    /// );
+    /// assert_eq!(filter.sources()[0].total, 3);
+    /// ```
+    pub fn reject_class(&self, addr: SocketAddr, bytes: &[u8]) -> bool {
+        let class = Decoder::classify(bytes);
+        if class.is_valid() {
+            return false;
+        }
+
+        if let Some(index) = DISCARDED.iter().position(|it| *it == class) {
+            self.0.discarded[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        discarded(class);
+        self.report(addr);
+        true
+    }
+
+    /// The number of packets discarded by the demultiplexer for every class.
+    pub fn discarded(&self) -> Vec<(PacketClass, u64)> {
+        DISCARDED
+            .iter()
+            .zip(self.0.discarded.iter())
+            .map(|(class, count)| (*class, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Whether the packet is dropped by the fingerprint check of the config,
+    /// before it is decoded. The dropped packets are reported as malformed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use bytes::BytesMut;
+    /// use stun::{util, Kind, MessageWriter, Method};
+    /// use turn_server::{
+    ///     audit::Audit,
+    ///     config::{Anonymize, FingerprintCheck, Malformed},
+    ///     malformed::*,
+    /// };
+    ///
+    /// let filter = MalformedFilter::new(
+    ///     Malformed {
+    ///         fingerprint: FingerprintCheck::Require,
+    ///         ..Default::default()
+    ///     },
+    ///     Anonymize::None,
+    ///     Audit::default(),
+    /// );
+    ///
+    /// let addr = "127.0.0.1:8080".parse().unwrap();
+    ///
+    /// let mut bytes = BytesMut::new();
+    /// MessageWriter::new(Method::Binding(Kind::Request), &[0u8; 12], &mut bytes)
+    ///     .flush(Some(&util::long_term_credential_digest("a", "b", "c")))
+    ///     .unwrap();
+    ///
+    /// assert!(!filter.reject_fingerprint(addr, &bytes));
+    ///
+    /// // The channel data has no fingerprint.
+    /// assert!(!filter.reject_fingerprint(addr, &[0x40, 0x00, 0x00, 0x00]));
+    ///
+    /// assert!(filter.reject_fingerprint(addr, &bytes[..20]));
+    /// assert_eq!(filter.fingerprint_rejected(), 1);
+    /// assert_eq!(filter.sources()[0].total, 1);
+    /// ```
+    pub fn reject_fingerprint(&self, addr: SocketAddr, bytes: &[u8]) -> bool {
+        let check = self.0.config.fingerprint;
+
+        // The first two bits of a stun message are zero.
+        if check == FingerprintCheck::None || bytes.first().map(|it| it >> 6 != 0).unwrap_or(true) {
+            return false;
+        }
+
+        let reason = match verify_fingerprint(bytes) {
+            Some(true) => return false,
+            None if check == FingerprintCheck::Verify => return false,
+            Some(false) => "mismatch",
+            None => "missing",
+        };
+
+        rejected(reason);
+        self.0.fingerprint_rejected.fetch_add(1, Ordering::Relaxed);
+        self.report(addr);
+        true
+    }
+
+    /// The number of packets dropped by the fingerprint check.
+    pub fn fingerprint_rejected(&self) -> u64 {
+        self.0.fingerprint_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Get the counters of all sources that have sent malformed packets.
+    pub fn sources(&self) -> Vec<MalformedSource> {
+        let now = Instant::now();
+
+        // The sources are always locked before the bans.
+        let sources = self.0.sources.lock();
+        let bans = self.0.bans.read();
+
+        sources
+            .iter()
+            .map(|(ip, it)| MalformedSource {
+                banned: bans.get(ip).map(|until| *until > now).unwrap_or(false),
+                total: it.total,
+                ip: *ip,
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn rejected(reason: &str) {
+    crate::statistics::prometheus::METRICS
+        .fingerprint_rejected
+        .with_label_values(&[reason])
+        .inc();
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn rejected(_: &str) {}
+
+#[cfg(feature = "prometheus")]
+fn discarded(class: PacketClass) {
+    crate::statistics::prometheus::METRICS
+        .discarded_packets
+        .with_label_values(&[class.as_str()])
+        .inc();
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn discarded(_: PacketClass) {}
@@ -0,0 +1,91 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use turn::sessions::Counters;
+
+// The approximate sizes in bytes of the entries of the session tables,
+// including the overhead of the maps and of the strings they own.
+const SESSION_SIZE: usize = 320;
+const NONCE_SIZE: usize = 96;
+const PORT_SIZE: usize = 64;
+const PERMISSION_SIZE: usize = 48;
+const CHANNEL_SIZE: usize = 96;
+
+#[derive(Default)]
+struct Inner {
+    buffers: AtomicUsize,
+    used: AtomicUsize,
+    exceeded: AtomicBool,
+}
+
+/// The approximate memory used by the router and the sessions.
+///
+/// The memory is the sum of the relayed packets waiting in the router, the
+/// receive buffers of the tcp connections, and an estimate of the session
+/// tables from the number of their entries. It is updated by the sampler of
+/// the admission controller, new allocations are refused while it exceeds
+/// the budget, and the idle tcp connections release their buffers until
+/// they receive data again.
+#[derive(Clone, Default)]
+pub struct MemoryBudget(Arc<Inner>);
+
+impl MemoryBudget {
+    /// Count a buffer that is allocated.
+    pub fn allocate(&self, size: usize) {
+        self.0.buffers.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Count a buffer that is released.
+    pub fn release(&self, size: usize) {
+        self.0.buffers.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// Update the memory used with the counters of the sessions and the bytes
+    /// queued in the router, returns the memory used in bytes. A budget of 0
+    /// is never exceeded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::sessions::Counters;
+    /// use turn_server::memory::MemoryBudget;
+    ///
+    /// let budget = MemoryBudget::default();
+    /// budget.allocate(2048);
+    ///
+    /// assert_eq!(budget.update(&Counters::default(), 1000, 4096), 3048);
+    /// assert!(!budget.is_exceeded());
+    ///
+    /// assert_eq!(budget.update(&Counters::default(), 4000, 4096), 6048);
+    /// assert!(budget.is_exceeded());
+    ///
+    /// budget.release(2048);
+    /// assert_eq!(budget.update(&Counters::default(), 4000, 0), 4000);
+    /// assert!(!budget.is_exceeded());
+    /// ```
+    pub fn update(&self, counters: &Counters, queued_bytes: usize, budget: usize) -> usize {
+        let used = self.0.buffers.load(Ordering::Relaxed)
+            + queued_bytes
+            + counters.sessions * SESSION_SIZE
+            + counters.nonces * NONCE_SIZE
+            + counters.ports * PORT_SIZE
+            + counters.permissions * PERMISSION_SIZE
+            + counters.channels * CHANNEL_SIZE;
+
+        self.0.used.store(used, Ordering::Relaxed);
+        self.0.exceeded.store(budget > 0 && used > budget, Ordering::Relaxed);
+        used
+    }
+
+    /// The memory used in bytes at the last update.
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether the memory used exceeded the budget at the last update.
+    pub fn is_exceeded(&self) -> bool {
+        self.0.exceeded.load(Ordering::Relaxed)
+    }
+}
@@ -1,30 +1,100 @@
-use std::{future::Future, sync::Arc};
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::{config::Config, statistics::Statistics};
+use crate::{
+    alerts::Alerts,
+    anonymous::AnonymousRelay,
+    audit::{Actor, Audit},
+    commands::CommandHooks,
+    config::{Auth, Config, Mechanism},
+    geoip::{GeoIp, Location},
+    statistics::Statistics,
+    tags::TagStore,
+    usage::Usage,
+};
 
 #[cfg(feature = "hooks")]
 use crate::publicly::hooks::HooksService;
 
-#[cfg(feature = "hooks")]
-use serde_json::json;
+#[cfg(feature = "wasm")]
+use crate::plugin::Plugin;
 
 use anyhow::Result;
 use base64::{prelude::BASE64_STANDARD, Engine};
-use turn::SessionAddr;
+use parking_lot::RwLock;
+use serde_json::json;
+use turn::{AuthFailure, CloseReason, Credential, DuplicateAllocate, Operation, SessionAddr, SessionTags};
+
+/// The credential backends, they can be replaced through the api at runtime.
+struct Credentials {
+    auth: RwLock<Arc<Auth>>,
+    // Every lookup holds a read guard, so that the backends are only replaced
+    // once the lookups that are still using the previous ones have finished.
+    lookups: tokio::sync::RwLock<()>,
+}
 
 #[derive(Clone)]
 pub struct Observer {
     config: Arc<Config>,
+    credentials: Arc<Credentials>,
+    anonymous: AnonymousRelay,
+    tags: TagStore,
+    usage: Usage,
+    geoip: GeoIp,
+    alerts: Alerts,
+    audit: Audit,
+    commands: CommandHooks,
     #[cfg(feature = "hooks")]
     hooks: Arc<HooksService>,
+    #[cfg(feature = "wasm")]
+    plugin: Option<Plugin>,
     #[cfg(feature = "api")]
     statistics: Statistics,
 }
 
 impl Observer {
     #[allow(unused_variables)]
-    pub async fn new(config: Arc<Config>, statistics: Statistics) -> Result<Self> {
+    pub async fn new(
+        config: Arc<Config>,
+        statistics: Statistics,
+        audit: Audit,
+        alerts: Alerts,
+        usage: Usage,
+    ) -> Result<Self> {
+        #[cfg(feature = "wasm")]
+        let plugin = match &config.plugin.path {
+            Some(path) => {
+                let plugin = Plugin::new(path, config.plugin.clone())?;
+                plugin.start_reloader();
+                Some(plugin)
+            }
+            None => None,
+        };
+
+        #[cfg(not(feature = "wasm"))]
+        if config.plugin.path.is_some() {
+            log::warn!("the wasm plugin is ignored, the server is built without the wasm feature");
+        }
+
+        let geoip = GeoIp::new(config.geoip.clone())?;
+        geoip.start_reloader();
+
         Ok(Self {
+            #[cfg(feature = "wasm")]
+            plugin,
+            geoip,
+            alerts,
+            audit,
+            usage,
+            commands: CommandHooks::new(config.commands.clone()),
+            anonymous: AnonymousRelay::new(config.anonymous.clone()),
+            tags: TagStore::new(config.tags.clone()),
+            credentials: Arc::new(Credentials {
+                auth: RwLock::new(Arc::new(config.auth.clone())),
+                lookups: Default::default(),
+            }),
             #[cfg(feature = "hooks")]
             hooks: Arc::new(HooksService::new(config.clone())?),
             #[cfg(feature = "api")]
@@ -32,42 +102,278 @@ impl Observer {
             config,
         })
     }
+
+    /// Look up the credential in one of the mechanisms, returns `None` if the
+    /// mechanism does not recognize the username.
+    #[allow(unused_variables)]
+    async fn lookup(
+        &self,
+        mechanism: Mechanism,
+        auth: &Auth,
+        addr: &SessionAddr,
+        username: &str,
+        claim_all: bool,
+    ) -> Option<Option<Credential>> {
+        let untagged = |password| Credential {
+            tags: SessionTags::new(),
+            password,
+        };
+
+        match mechanism {
+            Mechanism::Static => auth
+                .static_credentials
+                .get(username)
+                .cloned()
+                .map(|it| Some(untagged(it))),
+            Mechanism::Plugin => {
+                #[cfg(feature = "wasm")]
+                {
+                    if let Some(it) = self.plugin.as_ref().and_then(|it| it.get_password(addr, username)) {
+                        return Some(Some(untagged(it)));
+                    }
+                }
+
+                None
+            }
+            Mechanism::Secret => {
+                let secret = auth.static_auth_secret.as_ref()?;
+                if !claim_all && !is_ephemeral_credential(username) {
+                    return None;
+                }
+
+                // Because (TURN REST api) this RFC does not mandate the format of the username,
+                // only suggested values, the username is only checked for expiration when it
+                // starts with a timestamp.
+                if is_expired_credential(username) {
+                    return Some(None);
+                }
+
+                Some(encode_password(secret, username).map(untagged))
+            }
+            Mechanism::Hooks => {
+                #[cfg(feature = "hooks")]
+                {
+                    if let Some(it) = self.hooks.get_credential(addr, username).await {
+                        return Some(Some(it));
+                    }
+                }
+
+                None
+            }
+        }
+    }
+
+    /// The usage accounting of the tenants.
+    pub fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// The location of the client, there is no location without the geoip
+    /// databases.
+    // Record the operation refused by the authorization in the audit log.
+    fn refused(&self, addr: &SessionAddr, name: &str, operation: Operation<'_>, reason: &str) {
+        let mut detail = json!({
+            "operation": operation.as_str(),
+            "reason": reason,
+        });
+
+        match operation {
+            Operation::Allocate => (),
+            Operation::CreatePermission(ports) => {
+                detail["ports"] = json!(ports);
+            }
+            Operation::ChannelBind(port, channel) => {
+                detail["port"] = json!(port);
+                detail["channel"] = json!(channel);
+            }
+        }
+
+        self.audit
+            .record(Actor::Client(addr, name), "operation_refused", detail);
+    }
+
+    pub fn locate(&self, addr: &SessionAddr) -> Option<Location> {
+        self.geoip.is_enabled().then(|| self.geoip.lookup(addr.address.ip()))
+    }
+
+    /// Replace the credential backends.
+    ///
+    /// The lookups that are in flight finish with the previous backends
+    /// before this returns, the new lookups wait for the replacement. The
+    /// sessions that are already authenticated keep their cached keys until
+    /// they are checked again with `Sessions::revalidate`.
+    pub async fn replace_auth(&self, auth: Auth) {
+        let _drain = self.credentials.lookups.write().await;
+        *self.credentials.auth.write() = Arc::new(auth);
+
+        log::info!("auth replaced");
+    }
 }
 
 impl turn::Observer for Observer {
-    fn get_password(&self, addr: &SessionAddr, username: &str) -> impl Future<Output = Option<String>> + Send {
-        async move {
-            log::info!(
-                "auth: address={:?}, interface={:?}, username={:?}",
-                addr.address,
-                addr.interface,
-                username,
-            );
+    /// get credential
+    ///
+    /// The tags of the credential are kept within the limits of the config
+    /// and are carried by the events of the session until it is closed.
+    async fn get_credential(&self, addr: &SessionAddr, username: &str) -> Option<Credential> {
+        log::info!(
+            "auth: address={}, interface={:?}, username={:?}",
+            self.config.privacy.log.apply(addr.address),
+            addr.interface,
+            username,
+        );
+
+        let _lookup = self.credentials.lookups.read().await;
+        let auth = self.credentials.auth.read().clone();
+
+        // Without a chain, the secret claims every username, as the http hooks are
+        // not used when the secret is set.
+        let (mechanisms, claim_all) = if auth.mechanisms.is_empty() {
+            (&Mechanism::DEFAULT[..], true)
+        } else {
+            (&auth.mechanisms[..], false)
+        };
 
-            // Match the static authentication information first.
-            if let Some(it) = self.config.auth.static_credentials.get(username) {
-                return Some(it.clone());
+        for mechanism in mechanisms {
+            if let Some(it) = self.lookup(*mechanism, &auth, addr, username, claim_all).await {
+                return it.map(|mut it| {
+                    it.tags = self.tags.limit(it.tags);
+                    self.tags.insert(addr, &it.tags);
+                    it
+                });
             }
+        }
 
-            // Try again to match the static authentication key.
-            if let Some(it) = &self.config.auth.static_auth_secret {
-                // Because (TURN REST api) this RFC does not mandate the format of the username,
-                // only suggested values. In principle, the RFC also indicates that the
-                // timestamp part of username can be set at will, so the timestamp is not
-                // verified here, and the external web service guarantees its security by
-                // itself.
-                return encode_password(it, username);
+        None
+    }
+
+    /// trusted source
+    ///
+    /// The sources in the trusted subnets may allocate without credentials.
+    fn is_trusted(&self, addr: &SessionAddr) -> bool {
+        self.anonymous.is_trusted(addr.address.ip())
+    }
+
+    /// authorize operation
+    ///
+    /// The operations are refused by the policy hook of the wasm plugin, the
+    /// refusals are recorded in the audit log.
+    #[allow(unused_variables)]
+    fn authorize(&self, addr: &SessionAddr, name: &str, operation: Operation<'_>) -> bool {
+        #[cfg(feature = "wasm")]
+        {
+            if let Some(plugin) = &self.plugin {
+                if !plugin.authorize(addr, name, operation) {
+                    log::info!(
+                        "operation refused: address={}, interface={:?}, username={:?}, kind={}",
+                        self.config.privacy.log.apply(addr.address),
+                        addr.interface,
+                        name,
+                        operation.as_str(),
+                    );
+
+                    self.refused(addr, name, operation, "plugin");
+                    return false;
+                }
             }
+        }
 
-            #[cfg(feature = "hooks")]
+        true
+    }
+
+    /// allocation quota
+    ///
+    /// The allocations without credentials are refused over their quotas,
+    /// the refusals are recorded in the audit log.
+    fn allocation_quota(&self, addr: &SessionAddr, name: &str) -> bool {
+        if !name.is_empty() {
+            return true;
+        }
+
+        if !self.anonymous.reserve(addr) {
+            log::warn!(
+                "anonymous allocation refused, too many allocations: address={}, interface={:?}",
+                self.config.privacy.log.apply(addr.address),
+                addr.interface,
+            );
+
+            #[cfg(feature = "prometheus")]
             {
-                if let Some(it) = self.hooks.get_password(addr, username).await {
-                    return Some(it);
-                }
+                crate::statistics::prometheus::METRICS.anonymous_refused.inc();
             }
 
-            None
+            self.refused(addr, name, Operation::Allocate, "anonymous-quota");
+            return false;
         }
+
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS
+                .anonymous_allocated
+                .set(self.anonymous.allocations() as i64);
+        }
+
+        true
+    }
+
+    /// authentication failed
+    ///
+    /// Triggered when a request carries credentials that can not be
+    /// verified, the request is rejected with the error code that matches
+    /// the reason.
+    #[allow(clippy::let_underscore_future)]
+    fn auth_failed(&self, addr: &SessionAddr, name: &str, reason: AuthFailure) {
+        // An ephemeral credential of the TURN REST api is refused as an unknown user
+        // when it has expired, tell them apart for the operators.
+        let reason = if reason == AuthFailure::UnknownUser
+            && self.credentials.auth.read().static_auth_secret.is_some()
+            && is_expired_credential(name)
+        {
+            "expired-credential"
+        } else {
+            reason.as_str()
+        };
+
+        log::warn!(
+            "auth failed: address={}, interface={:?}, username={:?}, reason={}",
+            self.config.privacy.log.apply(addr.address),
+            addr.interface,
+            name,
+            reason
+        );
+
+        self.audit
+            .record(Actor::Client(addr, name), "auth_failed", json!({ "reason": reason }));
+        self.alerts.auth_failed();
+
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS
+                .auth_failed
+                .with_label_values(&[reason])
+                .inc();
+        }
+
+        let mut event = json!({
+            "kind": "auth_failed",
+            "session": {
+                "address": self.config.privacy.hooks.apply(addr.address),
+                "interface": addr.interface,
+            },
+            "username": name,
+            "reason": reason,
+        });
+
+        if let Some(location) = self.locate(addr) {
+            event["location"] = json!(location);
+        }
+
+        #[cfg(feature = "hooks")]
+        {
+            self.hooks.emit(event.clone());
+        }
+
+        self.commands.emit(event);
     }
 
     /// allocate request
@@ -87,13 +393,15 @@ impl turn::Observer for Observer {
     /// Known Port range) to discourage clients from using TURN to run
     /// standard services.
     #[allow(clippy::let_underscore_future)]
-    fn allocated(&self, addr: &SessionAddr, name: &str, port: u16) {
+    fn allocated(&self, addr: &SessionAddr, name: &str, port: u16, lifetime: u32, clamped: bool) {
         log::info!(
-            "allocate: address={:?}, interface={:?}, username={:?}, port={}",
-            addr.address,
+            "allocate: address={}, interface={:?}, username={:?}, port={}, lifetime={}, clamped={}",
+            self.config.privacy.log.apply(addr.address),
             addr.interface,
             name,
-            port
+            port,
+            lifetime,
+            clamped
         );
 
         #[cfg(feature = "api")]
@@ -101,16 +409,162 @@ impl turn::Observer for Observer {
             self.statistics.register(*addr);
         }
 
+        let tags = self.tags.get(addr);
+        self.usage.allocated(addr, &tags);
+
+        #[cfg(feature = "prometheus")]
+        {
+            if let Some(tag) = self.tags.label(&tags) {
+                crate::statistics::prometheus::METRICS
+                    .tagged_allocations
+                    .with_label_values(&[&tag])
+                    .inc();
+            }
+        }
+
+        let mut event = json!({
+            "kind": "allocated",
+            "session": {
+                "address": self.config.privacy.hooks.apply(addr.address),
+                "interface": addr.interface,
+            },
+            "username": name,
+            "port": port,
+            "lifetime": lifetime,
+            "clamped": clamped,
+            "tags": tags,
+        });
+
+        if let Some(location) = self.locate(addr) {
+            #[cfg(feature = "prometheus")]
+            {
+                crate::statistics::prometheus::METRICS
+                    .country_allocations
+                    .with_label_values(&[location.country.as_deref().unwrap_or("unknown")])
+                    .inc();
+            }
+
+            event["location"] = json!(location);
+        }
+
+        #[cfg(feature = "hooks")]
+        {
+            self.hooks.emit(event.clone());
+        }
+
+        self.commands.emit(event);
+    }
+
+    /// port pool exhausted
+    ///
+    /// Triggered when an allocate request is rejected with a 508
+    /// (Insufficient Capacity) error because there are no free relay ports
+    /// left in the port pool.
+    #[allow(clippy::let_underscore_future)]
+    fn port_exhausted(&self, addr: &SessionAddr, name: &str) {
+        log::warn!(
+            "port exhausted: address={}, interface={:?}, username={:?}",
+            self.config.privacy.log.apply(addr.address),
+            addr.interface,
+            name
+        );
+
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS.port_exhausted.inc();
+        }
+
         #[cfg(feature = "hooks")]
         {
             self.hooks.emit(json!({
-                "kind": "allocated",
+                "kind": "port_exhausted",
                 "session": {
-                    "address": addr.address,
+                    "address": self.config.privacy.hooks.apply(addr.address),
+                    "interface": addr.interface,
+                },
+                "username": name,
+            }));
+        }
+    }
+
+    /// duplicate allocate request
+    ///
+    /// Triggered when an allocate request is received on a 5-tuple that
+    /// already has an allocation, with the policy that was applied to it.
+    #[allow(clippy::let_underscore_future)]
+    fn duplicate_allocate(&self, addr: &SessionAddr, name: &str, port: u16, policy: DuplicateAllocate) {
+        log::warn!(
+            "duplicate allocate: address={}, interface={:?}, username={:?}, port={}, policy={}",
+            self.config.privacy.log.apply(addr.address),
+            addr.interface,
+            name,
+            port,
+            policy.as_str()
+        );
+
+        #[cfg(feature = "hooks")]
+        {
+            self.hooks.emit(json!({
+                "kind": "duplicate_allocate",
+                "session": {
+                    "address": self.config.privacy.hooks.apply(addr.address),
                     "interface": addr.interface,
                 },
                 "username": name,
                 "port": port,
+                "policy": policy.as_str(),
+            }));
+        }
+    }
+
+    /// address changed
+    ///
+    /// Triggered when an authenticated request that needs an allocation
+    /// arrives from an address without one, while the user has an
+    /// allocation on the interface from the other address. The statistics,
+    /// the usage and the tags of a rebound session move with it.
+    #[allow(clippy::let_underscore_future)]
+    fn address_changed(&self, from: &SessionAddr, to: &SessionAddr, name: &str, rebound: bool) {
+        log::warn!(
+            "address changed: from={}, to={}, interface={:?}, username={:?}, rebound={}",
+            self.config.privacy.log.apply(from.address),
+            self.config.privacy.log.apply(to.address),
+            to.interface,
+            name,
+            rebound
+        );
+
+        if rebound {
+            #[cfg(feature = "api")]
+            {
+                self.statistics.rebind(from, *to);
+            }
+
+            self.usage.rebind(from, to);
+
+            let tags = self.tags.remove(from);
+            self.tags.insert(to, &tags);
+        }
+
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS
+                .address_changes
+                .with_label_values(&[if rebound { "rebound" } else { "rejected" }])
+                .inc();
+        }
+
+        #[cfg(feature = "hooks")]
+        {
+            self.hooks.emit(json!({
+                "kind": "address_changed",
+                "session": {
+                    "address": self.config.privacy.hooks.apply(to.address),
+                    "interface": to.interface,
+                },
+                "previous": self.config.privacy.hooks.apply(from.address),
+                "username": name,
+                "rebound": rebound,
             }));
         }
     }
@@ -148,8 +602,8 @@ impl turn::Observer for Observer {
     #[allow(clippy::let_underscore_future)]
     fn channel_bind(&self, addr: &SessionAddr, name: &str, channel: u16) {
         log::info!(
-            "channel bind: address={:?}, interface={:?}, username={:?}, channel={}",
-            addr.address,
+            "channel bind: address={}, interface={:?}, username={:?}, channel={}",
+            self.config.privacy.log.apply(addr.address),
             addr.interface,
             name,
             channel
@@ -160,11 +614,49 @@ impl turn::Observer for Observer {
             self.hooks.emit(json!({
                 "kind": "channel_bind",
                 "session": {
-                    "address": addr.address,
+                    "address": self.config.privacy.hooks.apply(addr.address),
+                    "interface": addr.interface,
+                },
+                "username": name,
+                "channel": channel,
+            }));
+        }
+    }
+
+    /// channel stale
+    ///
+    /// Triggered when no data has been relayed to the session over a bound
+    /// channel for longer than the channel idle timeout, and again once data
+    /// is relayed over it again.
+    #[allow(clippy::let_underscore_future)]
+    fn channel_stale(&self, addr: &SessionAddr, name: &str, channel: u16, stale: bool) {
+        log::warn!(
+            "channel stale: address={}, interface={:?}, username={:?}, channel={}, stale={}",
+            self.config.privacy.log.apply(addr.address),
+            addr.interface,
+            name,
+            channel,
+            stale
+        );
+
+        #[cfg(feature = "prometheus")]
+        {
+            if stale {
+                crate::statistics::prometheus::METRICS.stale_channels.inc();
+            }
+        }
+
+        #[cfg(feature = "hooks")]
+        {
+            self.hooks.emit(json!({
+                "kind": "channel_stale",
+                "session": {
+                    "address": self.config.privacy.hooks.apply(addr.address),
                     "interface": addr.interface,
                 },
                 "username": name,
                 "channel": channel,
+                "stale": stale,
             }));
         }
     }
@@ -205,14 +697,14 @@ impl turn::Observer for Observer {
     /// There are no mandatory attributes in the success response.
     ///
     /// > NOTE: A server need not do anything special to implement
-    /// idempotency of CreatePermission requests over UDP using the
-    /// "stateless stack approach".  Retransmitted CreatePermission
-    /// requests will simply refresh the permissions.
+    /// > idempotency of CreatePermission requests over UDP using the
+    /// > "stateless stack approach".  Retransmitted CreatePermission
+    /// > requests will simply refresh the permissions.
     #[allow(clippy::let_underscore_future)]
     fn create_permission(&self, addr: &SessionAddr, name: &str, ports: &[u16]) {
         log::info!(
-            "create permission: address={:?}, interface={:?}, username={:?}, ports={:?}",
-            addr.address,
+            "create permission: address={}, interface={:?}, username={:?}, ports={:?}",
+            self.config.privacy.log.apply(addr.address),
             addr.interface,
             name,
             ports
@@ -223,7 +715,7 @@ impl turn::Observer for Observer {
             self.hooks.emit(json!({
                 "kind": "create_permission",
                 "session": {
-                    "address": addr.address,
+                    "address": self.config.privacy.hooks.apply(addr.address),
                     "interface": addr.interface,
                 },
                 "username": name,
@@ -251,17 +743,17 @@ impl turn::Observer for Observer {
     /// Subsequent processing depends on the "desired lifetime" value:
     ///
     /// * If the "desired lifetime" is zero, then the request succeeds and
-    /// the allocation is deleted.
+    ///   the allocation is deleted.
     ///
     /// * If the "desired lifetime" is non-zero, then the request succeeds
-    /// and the allocation's time-to-expiry is set to the "desired
-    /// lifetime".
+    ///   and the allocation's time-to-expiry is set to the "desired
+    ///   lifetime".
     ///
     /// If the request succeeds, then the server sends a success response
     /// containing:
     ///
     /// * A LIFETIME attribute containing the current value of the time-to-
-    /// expiry timer.
+    ///   expiry timer.
     ///
     /// NOTE: A server need not do anything special to implement
     /// idempotency of Refresh requests over UDP using the "stateless
@@ -272,13 +764,14 @@ impl turn::Observer for Observer {
     /// allocation has already been deleted, but the client will treat
     /// this as equivalent to a success response (see below).
     #[allow(clippy::let_underscore_future)]
-    fn refresh(&self, addr: &SessionAddr, name: &str, lifetime: u32) {
+    fn refresh(&self, addr: &SessionAddr, name: &str, lifetime: u32, clamped: bool) {
         log::info!(
-            "refresh: address={:?}, interface={:?}, username={:?}, lifetime={}",
-            addr.address,
+            "refresh: address={}, interface={:?}, username={:?}, lifetime={}, clamped={}",
+            self.config.privacy.log.apply(addr.address),
             addr.interface,
             name,
-            lifetime
+            lifetime,
+            clamped
         );
 
         #[cfg(feature = "hooks")]
@@ -286,11 +779,12 @@ impl turn::Observer for Observer {
             self.hooks.emit(json!({
                 "kind": "refresh",
                 "session": {
-                    "address": addr.address,
+                    "address": self.config.privacy.hooks.apply(addr.address),
                     "interface": addr.interface,
                 },
                 "username": name,
                 "lifetime": lifetime,
+                "clamped": clamped,
             }));
         }
     }
@@ -301,33 +795,107 @@ impl turn::Observer for Observer {
     /// session life cycle has expired, external active deletion, or active
     /// exit of the session.
     #[allow(clippy::let_underscore_future)]
-    fn closed(&self, addr: &SessionAddr, name: &str) {
+    fn closed(&self, addr: &SessionAddr, name: &str, reason: CloseReason) {
         log::info!(
-            "closed: address={:?}, interface={:?}, username={:?}",
-            addr.address,
+            "closed: address={}, interface={:?}, username={:?}, reason={}",
+            self.config.privacy.log.apply(addr.address),
             addr.interface,
-            name
+            name,
+            reason.as_str()
         );
 
+        // The relayed bytes of the session are counted before it is removed from
+        // the statistics.
+        self.usage.closed(addr);
+
         #[cfg(feature = "api")]
         {
-            self.statistics.unregister(&addr);
+            self.statistics.unregister(addr);
+        }
+
+        if name.is_empty() {
+            self.anonymous.release(addr);
+
+            #[cfg(feature = "prometheus")]
+            {
+                crate::statistics::prometheus::METRICS
+                    .anonymous_allocated
+                    .set(self.anonymous.allocations() as i64);
+            }
+        }
+
+        let mut event = json!({
+            "kind": "closed",
+            "session": {
+                "address": self.config.privacy.hooks.apply(addr.address),
+                "interface": addr.interface,
+            },
+            "username": name,
+            "reason": reason.as_str(),
+            "tags": self.tags.remove(addr),
+        });
+
+        if let Some(location) = self.locate(addr) {
+            event["location"] = json!(location);
         }
 
         #[cfg(feature = "hooks")]
         {
-            self.hooks.emit(json!({
-                "kind": "closed",
-                "session": {
-                    "address": addr.address,
-                    "interface": addr.interface,
-                },
-                "username": name,
-            }));
+            self.hooks.emit(event.clone());
         }
+
+        self.commands.emit(event);
     }
 }
 
+/// Whether the username is an ephemeral credential of the TURN REST api that
+/// has expired, the username is `timestamp:userid` or just `timestamp`, where
+/// the timestamp is the expiration time in unix seconds.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::observer::is_expired_credential;
+///
+/// assert!(is_expired_credential("1:user"));
+/// assert!(is_expired_credential("1"));
+/// assert!(!is_expired_credential("99999999999:user"));
+/// assert!(!is_expired_credential("user"));
+/// assert!(!is_expired_credential("user:1"));
+/// ```
+pub fn is_expired_credential(username: &str) -> bool {
+    let timestamp = match username.split(':').next().map(str::parse::<u64>) {
+        Some(Ok(it)) => it,
+        _ => return false,
+    };
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs() > timestamp)
+        .unwrap_or(false)
+}
+
+/// Whether the username has the suggested format of the TURN REST api, which
+/// starts with the expiration timestamp.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::observer::is_ephemeral_credential;
+///
+/// assert!(is_ephemeral_credential("1700000000:user"));
+/// assert!(is_ephemeral_credential("1700000000"));
+/// assert!(!is_ephemeral_credential("user"));
+/// assert!(!is_ephemeral_credential("user:1700000000"));
+/// ```
+pub fn is_ephemeral_credential(username: &str) -> bool {
+    username
+        .split(':')
+        .next()
+        .map(|it| it.parse::<u64>().is_ok())
+        .unwrap_or(false)
+}
+
 // https://datatracker.ietf.org/doc/html/draft-uberti-behave-turn-rest-00#section-2.2
 fn encode_password(key: &str, username: &str) -> Option<String> {
     Some(
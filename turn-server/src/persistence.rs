@@ -0,0 +1,159 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use parking_lot::Mutex;
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{self, Auth};
+
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    #[serde(default)]
+    nonce_secret: Option<String>,
+    #[serde(default)]
+    auth: Option<Auth>,
+}
+
+/// The state of the server that is kept across restarts.
+///
+/// The secret that the nonces are signed with is generated when the file is
+/// created, and the auth that is replaced through the api is written to the
+/// file, so that a restarted server accepts the nonces that the clients
+/// cached before the restart, instead of challenging all of them at once,
+/// and keeps the shared secrets of the ephemeral credentials that were
+/// loaded at runtime. The auth of the file takes the place of the auth of
+/// the config, remove the file to go back to the config.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::{config, persistence::Persistence};
+///
+/// let path = std::env::temp_dir().join(format!("turn-server-{}.json", std::process::id()));
+/// let config = config::Persistence {
+///     file: Some(path.to_string_lossy().to_string()),
+/// };
+///
+/// let persistence = Persistence::load(&config).unwrap();
+/// let secret = persistence.nonce_secret();
+/// assert!(secret.is_some());
+/// assert!(persistence.auth().is_none());
+///
+/// persistence.set_auth(&config::Auth {
+///     static_auth_secret: Some("secret".to_string()),
+///     ..Default::default()
+/// });
+///
+/// // The state of the restarted server.
+/// let persistence = Persistence::load(&config).unwrap();
+/// assert_eq!(persistence.nonce_secret(), secret);
+/// assert_eq!(
+///     persistence.auth().unwrap().static_auth_secret.as_deref(),
+///     Some("secret")
+/// );
+///
+/// std::fs::remove_file(path).unwrap();
+///
+/// // Nothing is kept without the file.
+/// assert!(Persistence::load(&Default::default()).unwrap().nonce_secret().is_none());
+/// ```
+#[derive(Clone, Default)]
+pub struct Persistence {
+    path: Option<Arc<PathBuf>>,
+    nonce_secret: Option<[u8; 32]>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Persistence {
+    /// Load the state from the file of the config, the file is created with
+    /// a new secret if it does not exist.
+    pub fn load(config: &config::Persistence) -> Result<Self> {
+        let Some(path) = &config.file else {
+            return Ok(Self::default());
+        };
+
+        let path = PathBuf::from(path);
+        let mut state = if path.exists() {
+            serde_json::from_str::<State>(&fs::read_to_string(&path)?)?
+        } else {
+            State::default()
+        };
+
+        let nonce_secret = if let Some(it) = &state.nonce_secret {
+            BASE64_STANDARD
+                .decode(it)?
+                .try_into()
+                .map_err(|_| anyhow!("the nonce secret of the state file is not 32 bytes"))?
+        } else {
+            let mut secret = [0u8; 32];
+            thread_rng().fill_bytes(&mut secret);
+
+            state.nonce_secret = Some(BASE64_STANDARD.encode(secret));
+            save(&path, &state)?;
+            secret
+        };
+
+        Ok(Self {
+            path: Some(Arc::new(path)),
+            nonce_secret: Some(nonce_secret),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// The secret that the nonces are signed with, there is no secret
+    /// without the file.
+    pub fn nonce_secret(&self) -> Option<[u8; 32]> {
+        self.nonce_secret
+    }
+
+    /// The auth that was last replaced through the api.
+    pub fn auth(&self) -> Option<Auth> {
+        self.state.lock().auth.clone()
+    }
+
+    /// Keep the auth that is replaced through the api.
+    ///
+    /// This does nothing without the file. A failure to write the file does
+    /// not interrupt the caller and is only reported through the error log.
+    pub fn set_auth(&self, auth: &Auth) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let mut state = self.state.lock();
+        state.auth = Some(auth.clone());
+        if let Err(e) = save(path, &state) {
+            log::error!("failed to write state file: path={:?}, err={}", path, e);
+        }
+    }
+}
+
+// The file holds secrets, so it is only readable by the owner, and it is
+// replaced at once so that a crash does not leave half of it behind.
+fn save(path: &Path, state: &State) -> Result<()> {
+    let temp = path.with_extension("tmp");
+
+    let mut options = OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&temp)?;
+    file.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+    file.sync_all()?;
+
+    fs::rename(&temp, path)?;
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use parking_lot::RwLock;
+use serde_json::{json, Value};
+use turn::{Operation, SessionAddr};
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::config;
+
+struct Inner {
+    engine: Engine,
+    config: config::Plugin,
+    path: PathBuf,
+    // The module and the modified time of the file it was loaded from.
+    module: RwLock<(Module, Option<SystemTime>)>,
+}
+
+/// The wasm plugin host of the auth and policy hooks.
+///
+/// Every call of a hook runs in a new instance of the module, so that the
+/// calls do not share any state, and each instance is limited in fuel and
+/// memory. The module can not import anything, it exports its `memory` and
+/// an `alloc(len: i32) -> i32` function, which returns the pointer of a
+/// buffer of the length in the memory, and the hooks:
+///
+/// * `get_password(ptr: i32, len: i32) -> i64`, the input is the session
+///   and the username as json, it returns the pointer of the password in the
+///   high 32 bits and the length in the low 32 bits, or a negative number
+///   if the username is not known to the plugin.
+///
+/// * `authorize(ptr: i32, len: i32) -> i32`, the input is the session, the
+///   username and the operation as json, it returns 0 to allow the operation.
+///
+/// Both hooks are optional. A hook that traps refuses the credentials or the
+/// operation.
+#[derive(Clone)]
+pub struct Plugin(Arc<Inner>);
+
+impl Plugin {
+    /// Load the plugin from a wasm or wat file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::{Operation, SessionAddr};
+    /// use turn_server::{config, plugin::Plugin};
+    ///
+    /// let path = std::env::temp_dir().join("turn-server-plugin-example.wat");
+    /// std::fs::write(
+    ///     &path,
+    ///     r#"(module
+    ///         (memory (export "memory") 1)
+    ///         (data (i32.const 16) "secret")
+    ///         (func (export "alloc") (param i32) (result i32) i32.const 1024)
+    ///         (func (export "get_password") (param i32 i32) (result i64)
+    ///             i64.const 0x1000000006)
+    ///         (func (export "authorize") (param i32 i32) (result i32)
+    ///             ;; refuses everything that is not an allocate request.
+    ///             (i32.ne (i32.load8_u (i32.const 1033)) (i32.const 0x61))))"#,
+    /// )
+    /// .unwrap();
+    ///
+    /// let plugin = Plugin::new(path.to_str().unwrap(), config::Plugin::default()).unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// assert_eq!(plugin.get_password(&addr, "test"), Some("secret".to_string()));
+    /// assert!(plugin.authorize(&addr, "test", Operation::Allocate));
+    /// assert!(!plugin.authorize(&addr, "test", Operation::ChannelBind(50000, 0x4000)));
+    /// ```
+    pub fn new(path: &str, config: config::Plugin) -> Result<Self> {
+        let engine = {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            Engine::new(&config)?
+        };
+
+        let path = PathBuf::from(path);
+        let modified = modified_time(&path);
+        let module = Module::from_file(&engine, &path)?;
+
+        Ok(Self(Arc::new(Inner {
+            module: RwLock::new((module, modified)),
+            engine,
+            config,
+            path,
+        })))
+    }
+
+    /// Check the file at the reload interval, and reload the module when the
+    /// file is modified.
+    pub fn start_reloader(&self) {
+        if self.0.config.reload_interval == 0 {
+            return;
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(this.0.config.reload_interval));
+
+            loop {
+                interval.tick().await;
+
+                let this = this.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || this.reload()).await {
+                    log::error!("failed to reload wasm plugin: err={}", e);
+                }
+            }
+        });
+    }
+
+    /// Reload the module if the file is modified since it was loaded, the
+    /// previous module is kept if the file can not be loaded.
+    pub fn reload(&self) -> Result<bool> {
+        let modified = modified_time(&self.0.path);
+        if self.0.module.read().1 == modified {
+            return Ok(false);
+        }
+
+        // The time is updated before the module is compiled, so that a broken
+        // file is only reported once.
+        self.0.module.write().1 = modified;
+
+        let module = Module::from_file(&self.0.engine, &self.0.path)?;
+        self.0.module.write().0 = module;
+
+        log::info!("wasm plugin reloaded: path={:?}", self.0.path);
+        Ok(true)
+    }
+
+    /// Get the password of the username from the plugin.
+    pub fn get_password(&self, addr: &SessionAddr, username: &str) -> Option<String> {
+        let input = json!({
+            "session": {
+                "address": addr.address,
+                "interface": addr.interface,
+            },
+            "username": username,
+        });
+
+        self.try_get_password(&input).unwrap_or_else(|e| {
+            log::error!("wasm plugin get_password failed: username={:?}, err={}", username, e);
+            None
+        })
+    }
+
+    /// Whether the plugin allows the operation.
+    pub fn authorize(&self, addr: &SessionAddr, username: &str, operation: Operation<'_>) -> bool {
+        let mut input = json!({
+            "kind": operation.as_str(),
+            "session": {
+                "address": addr.address,
+                "interface": addr.interface,
+            },
+            "username": username,
+        });
+
+        match operation {
+            Operation::Allocate => (),
+            Operation::CreatePermission(ports) => {
+                input["ports"] = json!(ports);
+            }
+            Operation::ChannelBind(port, channel) => {
+                input["port"] = json!(port);
+                input["channel"] = json!(channel);
+            }
+        }
+
+        self.try_authorize(&input).unwrap_or_else(|e| {
+            log::error!(
+                "wasm plugin authorize failed: username={:?}, kind={}, err={}",
+                username,
+                operation.as_str(),
+                e
+            );
+
+            false
+        })
+    }
+
+    fn try_get_password(&self, input: &Value) -> Result<Option<String>> {
+        let (mut store, instance) = self.instantiate()?;
+        if instance.get_func(&mut store, "get_password").is_none() {
+            return Ok(None);
+        }
+
+        let func = instance.get_typed_func::<(i32, i32), i64>(&mut store, "get_password")?;
+        let (memory, ptr, len) = write_input(&mut store, &instance, input)?;
+        let ret = func.call(&mut store, (ptr, len))?;
+        if ret < 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((ret >> 32) as usize, (ret & 0xffff_ffff) as usize);
+        let bytes = memory
+            .data(&store)
+            .get(ptr..ptr + len)
+            .ok_or_else(|| anyhow!("password out of bounds"))?;
+
+        Ok(Some(std::str::from_utf8(bytes)?.to_string()))
+    }
+
+    fn try_authorize(&self, input: &Value) -> Result<bool> {
+        let (mut store, instance) = self.instantiate()?;
+        if instance.get_func(&mut store, "authorize").is_none() {
+            return Ok(true);
+        }
+
+        let func = instance.get_typed_func::<(i32, i32), i32>(&mut store, "authorize")?;
+        let (_, ptr, len) = write_input(&mut store, &instance, input)?;
+        Ok(func.call(&mut store, (ptr, len))? == 0)
+    }
+
+    fn instantiate(&self) -> Result<(Store<StoreLimits>, Instance)> {
+        let module = self.0.module.read().0.clone();
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.0.config.max_memory << 20)
+            .build();
+
+        let mut store = Store::new(&self.0.engine, limits);
+        store.limiter(|it| it);
+        store.set_fuel(self.0.config.max_fuel)?;
+
+        let instance = Instance::new(&mut store, &module, &[])?;
+        Ok((store, instance))
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|it| it.modified()).ok()
+}
+
+/// Write the input into a buffer allocated by the instance, returns the
+/// memory, the pointer and the length.
+fn write_input(store: &mut Store<StoreLimits>, instance: &Instance, input: &Value) -> Result<(Memory, i32, i32)> {
+    let input = input.to_string();
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| anyhow!("memory is not exported"))?;
+
+    let alloc = instance.get_typed_func::<i32, i32>(&mut *store, "alloc")?;
+    let ptr = alloc.call(&mut *store, input.len() as i32)?;
+    memory.write(&mut *store, ptr as usize, input.as_bytes())?;
+
+    Ok((memory, ptr, input.len() as i32))
+}
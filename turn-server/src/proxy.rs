@@ -0,0 +1,194 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, ensure, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The version 1 header is a single line of at most 107 bytes.
+const MAX_V1_SIZE: usize = 107;
+
+/// The largest header that is accepted, the version 2 headers can carry
+/// extensions after the addresses.
+pub const MAX_HEADER_SIZE: usize = 4096;
+
+/// The version 2 header of the LOCAL command, which a health check of the
+/// proxy sends so that the address of its connection is kept.
+pub const LOCAL_V2: [u8; 16] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x20, 0x00, 0x00, 0x00,
+];
+
+/// The PROXY protocol header that a load balancer sends at the start of a
+/// connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Header {
+    /// The connection is relayed by the proxy for the client of the address.
+    Proxied(SocketAddr),
+    /// The connection is made by the proxy itself, such as a health check,
+    /// or the address of the client is unknown. The address of the
+    /// connection is kept.
+    Local,
+}
+
+/// Parse the PROXY protocol header of the version 1 or 2 at the start of the
+/// bytes, returns the header and its size, or `None` if more bytes are
+/// needed.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::proxy::*;
+///
+/// let bytes = b"PROXY TCP4 203.0.113.7 10.0.0.2 51234 3478\r\n\x00\x01";
+/// assert_eq!(
+///     parse(bytes).unwrap(),
+///     Some((Header::Proxied("203.0.113.7:51234".parse().unwrap()), 44))
+/// );
+///
+/// assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), Some((Header::Local, 15)));
+/// assert_eq!(parse(b"PROXY TCP6 2001:db8").unwrap(), None);
+/// assert!(parse(b"PROXY TCP4 203.0.113.7 2001:db8::1 1 2\r\n").is_err());
+///
+/// let mut bytes = vec![
+///     0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, 0x11, 0x00, 0x0c,
+///     203, 0, 113, 7, 10, 0, 0, 2, 0xc8, 0x22, 0x0d, 0x96,
+/// ];
+///
+/// assert_eq!(parse(&bytes[..20]).unwrap(), None);
+/// assert_eq!(
+///     parse(&bytes).unwrap(),
+///     Some((Header::Proxied("203.0.113.7:51234".parse().unwrap()), 28))
+/// );
+///
+/// assert_eq!(parse(&LOCAL_V2).unwrap(), Some((Header::Local, 16)));
+///
+/// bytes[12] = 0x11;
+/// assert!(parse(&bytes).is_err());
+/// assert!(parse(b"\x00\x01\x00\x00").is_err());
+/// ```
+pub fn parse(bytes: &[u8]) -> Result<Option<(Header, usize)>> {
+    if bytes.starts_with(&SIGNATURE) {
+        return parse_v2(bytes);
+    }
+
+    if bytes.starts_with(b"PROXY ") {
+        return parse_v1(bytes);
+    }
+
+    if SIGNATURE.starts_with(bytes) || b"PROXY ".starts_with(bytes) {
+        return Ok(None);
+    }
+
+    Err(anyhow!("not a proxy protocol header"))
+}
+
+/// Read the header at the start of the stream. It is read exactly, so that
+/// the bytes that follow it are left in the stream.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::proxy::*;
+///
+/// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+///
+/// let mut stream = &b"PROXY TCP6 2001:db8::7 2001:db8::2 51234 3478\r\n\x00\x01"[..];
+/// let header = runtime.block_on(read(&mut stream)).unwrap();
+/// assert_eq!(header, Header::Proxied("[2001:db8::7]:51234".parse().unwrap()));
+/// assert_eq!(stream, b"\x00\x01");
+///
+/// let mut stream = &[&LOCAL_V2[..], b"\x00\x01"].concat()[..];
+/// assert_eq!(runtime.block_on(read(&mut stream)).unwrap(), Header::Local);
+/// assert_eq!(stream, b"\x00\x01");
+///
+/// let mut stream = &b"\x00\x01\x00\x00\x21\x12\xa4\x42\x00\x00\x00\x00\x00\x00\x00\x00"[..];
+/// assert!(runtime.block_on(read(&mut stream)).is_err());
+/// ```
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Header> {
+    // The shortest header is `PROXY UNKNOWN\r\n`.
+    let mut bytes = vec![0u8; 15];
+    reader.read_exact(&mut bytes).await?;
+
+    loop {
+        if let Some((header, _)) = parse(&bytes)? {
+            return Ok(header);
+        }
+
+        // The size of the version 2 header is known from its first 16 bytes, the
+        // version 1 header is read until its line break.
+        let size = if bytes.len() >= 16 && bytes.starts_with(&SIGNATURE) {
+            16 + u16::from_be_bytes([bytes[14], bytes[15]]) as usize
+        } else {
+            bytes.len() + 1
+        };
+
+        let offset = bytes.len();
+        bytes.resize(size, 0);
+        reader.read_exact(&mut bytes[offset..]).await?;
+    }
+}
+
+fn parse_v1(bytes: &[u8]) -> Result<Option<(Header, usize)>> {
+    let Some(end) = bytes.windows(2).position(|it| it == b"\r\n") else {
+        ensure!(bytes.len() < MAX_V1_SIZE, "proxy protocol header is too long");
+        return Ok(None);
+    };
+
+    ensure!(end + 2 <= MAX_V1_SIZE, "proxy protocol header is too long");
+
+    let line = std::str::from_utf8(&bytes[6..end])?;
+    let header = match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["UNKNOWN", ..] => Header::Local,
+        [family @ ("TCP4" | "TCP6"), source, destination, port, _] => {
+            let source: IpAddr = source.parse()?;
+            let destination: IpAddr = destination.parse()?;
+            ensure!(
+                source.is_ipv4() == (*family == "TCP4") && destination.is_ipv4() == source.is_ipv4(),
+                "proxy protocol addresses do not match the family: {}",
+                line
+            );
+
+            Header::Proxied(SocketAddr::new(source, port.parse()?))
+        }
+        _ => return Err(anyhow!("invalid proxy protocol header: {}", line)),
+    };
+
+    Ok(Some((header, end + 2)))
+}
+
+fn parse_v2(bytes: &[u8]) -> Result<Option<(Header, usize)>> {
+    if bytes.len() < 16 {
+        return Ok(None);
+    }
+
+    ensure!(
+        bytes[12] >> 4 == 2,
+        "unknown proxy protocol version: {}",
+        bytes[12] >> 4
+    );
+
+    let size = 16 + u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+    ensure!(size <= MAX_HEADER_SIZE, "proxy protocol header is too long");
+    if bytes.len() < size {
+        return Ok(None);
+    }
+
+    let addresses = &bytes[16..size];
+    let header = match (bytes[12] & 0x0f, bytes[13] >> 4) {
+        (0, _) => Header::Local,
+        (1, 1) if addresses.len() >= 12 => Header::Proxied(SocketAddr::new(
+            Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?).into(),
+            u16::from_be_bytes([addresses[8], addresses[9]]),
+        )),
+        (1, 2) if addresses.len() >= 36 => Header::Proxied(SocketAddr::new(
+            Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?).into(),
+            u16::from_be_bytes([addresses[32], addresses[33]]),
+        )),
+        // The unspecified and the unix families carry no address of the client.
+        (1, 0 | 3) => Header::Local,
+        (1, _) => return Err(anyhow!("invalid proxy protocol addresses")),
+        (command, _) => return Err(anyhow!("unknown proxy protocol command: {}", command)),
+    };
+
+    Ok(Some((header, size)))
+}
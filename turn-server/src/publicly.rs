@@ -13,44 +13,216 @@ static NONCE: Lazy<String> = Lazy::new(|| {
 
 #[cfg(feature = "api")]
 pub mod api {
-    use std::{net::SocketAddr, sync::Arc, time::Instant};
+    use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Instant};
+
+    use ahash::AHashMap;
 
     use axum::{
-        extract::{Query, State},
+        extract::{ConnectInfo, Query, State},
         http::HeaderValue,
         middleware,
         response::{IntoResponse, Response},
-        routing::{delete, get},
+        routing::{delete, get, put},
         Json, Router,
     };
 
     use reqwest::StatusCode;
     use serde::Deserialize;
-    use serde_json::json;
+    use serde_json::{json, Value};
+    use stun::Transport;
     use tokio::net::TcpListener;
-    use turn::{PortAllocatePools, Service, SessionAddr};
+    use turn::{CloseReason, Service, SessionAddr};
 
     use super::NONCE;
-    use crate::{config::Config, observer::Observer, statistics::Statistics};
+    use crate::{
+        admission::AdmissionController,
+        audit::{Actor, Audit},
+        buffers::SocketBuffers,
+        config::{Auth, Config, Subnet},
+        health::Health,
+        malformed::MalformedFilter,
+        observer::Observer,
+        persistence::Persistence,
+        statistics::{Counts, Statistics},
+    };
+
+    /// The average relay rates within the last 1, 5 and 15 minutes.
+    fn rates(statistics: &Statistics, addr: Option<&SessionAddr>) -> Value {
+        let rate = |minutes| {
+            let rate = statistics.rate(addr, minutes);
+            json!({
+                "bytes": rate.bytes,
+                "pkts": rate.pkts,
+            })
+        };
+
+        json!({
+            "1m": rate(1),
+            "5m": rate(5),
+            "15m": rate(15),
+        })
+    }
+
+    /// The traffic counts of the server as json.
+    fn traffic(counts: Counts<u64>) -> Value {
+        json!({
+            "received_bytes": counts.received_bytes,
+            "send_bytes": counts.send_bytes,
+            "received_pkts": counts.received_pkts,
+            "send_pkts": counts.send_pkts,
+            "error_pkts": counts.error_pkts,
+        })
+    }
 
     struct AppState {
         config: Arc<Config>,
         service: Service<Observer>,
         statistics: Statistics,
+        audit: Audit,
+        health: Health,
+        malformed: MalformedFilter,
+        persistence: Persistence,
+        admission: AdmissionController,
+        buffers: SocketBuffers,
         uptime: Instant,
     }
 
+    #[derive(Deserialize)]
+    struct MaintenanceBody {
+        deny_new_sessions: bool,
+    }
+
     #[derive(Deserialize)]
     struct SessionQueryFilter {
         address: SocketAddr,
         interface: SocketAddr,
     }
 
-    impl Into<SessionAddr> for SessionQueryFilter {
-        fn into(self) -> SessionAddr {
+    #[derive(Deserialize)]
+    struct PeerQueryFilter {
+        port: u16,
+    }
+
+    #[derive(Deserialize)]
+    struct ChannelQueryFilter {
+        port: u16,
+        channel: u16,
+    }
+
+    #[derive(Deserialize)]
+    struct UserQueryFilter {
+        username: String,
+    }
+
+    #[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    enum SessionSort {
+        #[default]
+        Address,
+        Username,
+        Created,
+        Expires,
+    }
+
+    #[derive(Deserialize, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    enum SortOrder {
+        #[default]
+        Asc,
+        Desc,
+    }
+
+    #[derive(Deserialize)]
+    struct SessionsQueryFilter {
+        username: Option<String>,
+        realm: Option<String>,
+        subnet: Option<Subnet>,
+        min_age: Option<u64>,
+        #[serde(default)]
+        sort: SessionSort,
+        #[serde(default)]
+        order: SortOrder,
+        limit: Option<usize>,
+        cursor: Option<String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    enum SortValue {
+        None,
+        Text(String),
+        Number(u64),
+    }
+
+    /// The position of a session in a sorted listing, the address breaks the
+    /// ties so that the order is stable between pages.
+    ///
+    /// The cursor is written as `address,interface,value`, the value is last
+    /// because usernames can contain commas.
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct SortKey(SortValue, SocketAddr, SocketAddr);
+
+    impl SortKey {
+        fn to_cursor(&self) -> String {
+            let value = match &self.0 {
+                SortValue::None => String::new(),
+                SortValue::Text(it) => it.clone(),
+                SortValue::Number(it) => it.to_string(),
+            };
+
+            format!("{},{},{}", self.1, self.2, value)
+        }
+
+        fn from_cursor(cursor: &str, sort: SessionSort) -> Option<Self> {
+            let mut items = cursor.splitn(3, ',');
+            let address = items.next()?.parse().ok()?;
+            let interface = items.next()?.parse().ok()?;
+            let value = items.next()?;
+
+            Some(Self(
+                match sort {
+                    SessionSort::Address => SortValue::None,
+                    SessionSort::Username => SortValue::Text(value.to_string()),
+                    SessionSort::Created | SessionSort::Expires => SortValue::Number(value.parse().ok()?),
+                },
+                address,
+                interface,
+            ))
+        }
+    }
+
+    #[derive(Deserialize, Default, PartialEq, Eq)]
+    #[serde(rename_all = "lowercase")]
+    enum TopGroup {
+        #[default]
+        Session,
+        Username,
+    }
+
+    #[derive(Deserialize)]
+    struct TopQueryFilter {
+        #[serde(default = "TopQueryFilter::window")]
+        window: usize,
+        #[serde(default = "TopQueryFilter::limit")]
+        limit: usize,
+        #[serde(default)]
+        by: TopGroup,
+    }
+
+    impl TopQueryFilter {
+        fn window() -> usize {
+            1
+        }
+
+        fn limit() -> usize {
+            10
+        }
+    }
+
+    impl From<SessionQueryFilter> for SessionAddr {
+        fn from(val: SessionQueryFilter) -> Self {
             SessionAddr {
-                address: self.address,
-                interface: self.interface,
+                address: val.address,
+                interface: val.interface,
             }
         }
     }
@@ -64,16 +236,29 @@ pub mod api {
     /// any means of authentication, and sensitive information and dangerous
     /// operations can be obtained through this service, please do not expose it
     /// directly to an unsafe environment.
+    #[allow(clippy::too_many_arguments)]
     pub async fn start_server(
         config: Arc<Config>,
         service: Service<Observer>,
         statistics: Statistics,
+        audit: Audit,
+        health: Health,
+        malformed: MalformedFilter,
+        persistence: Persistence,
+        admission: AdmissionController,
+        buffers: SocketBuffers,
     ) -> anyhow::Result<()> {
         let state = Arc::new(AppState {
             config: config.clone(),
             uptime: Instant::now(),
             service,
             statistics,
+            audit,
+            health,
+            malformed,
+            persistence,
+            admission,
+            buffers,
         });
 
         #[allow(unused_mut)]
@@ -82,27 +267,88 @@ pub mod api {
                 "/info",
                 get(|State(app_state): State<Arc<AppState>>| async move {
                     let sessions = app_state.service.get_sessions();
+                    let integrity = app_state.service.get_integrity_pool();
                     Json(json!({
                         "software": concat!(env!("CARGO_PKG_NAME"), ":", env!("CARGO_PKG_VERSION")),
                         "uptime": app_state.uptime.elapsed().as_secs(),
                         "interfaces": app_state.config.turn.interfaces,
-                        "port_capacity": PortAllocatePools::capacity(),
+                        "port_capacity": sessions.capacity(),
                         "port_allocated": sessions.allocated(),
+                        "port_available": sessions.available(),
+                        "deny_new_sessions": app_state.admission.is_denying_new_sessions(),
+                        "integrity_queued": integrity.map(|it| it.queued()).unwrap_or(0),
+                        "integrity_shed": integrity.map(|it| it.shed()).unwrap_or(0),
+                        "integrity_shed_sessions": integrity.map(|it| it.shed_sessions()).unwrap_or(0),
+                        "fingerprint_rejected": app_state.malformed.fingerprint_rejected(),
+                        "discarded_packets": app_state
+                            .malformed
+                            .discarded()
+                            .into_iter()
+                            .map(|(class, count)| (class.as_str(), count))
+                            .collect::<BTreeMap<_, _>>(),
+                        "socket_buffers": app_state.buffers.get(),
                     }))
                 }),
             )
+            .route(
+                "/healthz",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    let probe_elapsed = state.health.probe_elapsed();
+                    let alive = probe_elapsed <= state.config.health.liveness_threshold;
+
+                    (
+                        if alive {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        },
+                        Json(json!({
+                            "alive": alive,
+                            "probe_elapsed": probe_elapsed,
+                        })),
+                    )
+                }),
+            )
+            .route(
+                "/readyz",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    let listening = state.health.is_listening();
+                    let free_ports = state.service.get_sessions().available();
+                    let ports = free_ports > state.config.health.min_free_ports as usize;
+
+                    (
+                        if listening && ports {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        },
+                        Json(json!({
+                            "ready": listening && ports,
+                            "listening": listening,
+                            "free_ports": free_ports,
+                        })),
+                    )
+                }),
+            )
             .route(
                 "/session",
                 get(
                     |Query(query): Query<SessionQueryFilter>, State(state): State<Arc<AppState>>| async move {
-                        if let Some(session) = state.service.get_sessions().get_session(&query.into()).get_ref() {
+                        let addr = query.into();
+                        if let Some(session) = state.service.get_sessions().get_session(&addr).get_ref() {
                             Json(json!({
                                 "username": session.auth.username,
                                 "password": session.auth.password,
-                                "permissions": session.permissions,
+                                "realm": state.config.turn.realm,
+                                "transport": session.allocate.ingress.map(|it| it.as_str()),
+                                "permissions": session.permissions.iter().map(|it| it.port).collect::<Vec<_>>(),
                                 "channels": session.allocate.channels,
                                 "port": session.allocate.port,
+                                "generation": session.allocate.generation,
+                                "created": session.created,
                                 "expires": session.expires,
+                                "tags": session.tags,
+                                "location": state.service.get_observer().locate(&addr),
                             }))
                             .into_response()
                         } else {
@@ -111,6 +357,401 @@ pub mod api {
                     },
                 ),
             )
+            .route(
+                "/sessions",
+                get(
+                    |Query(query): Query<SessionsQueryFilter>, State(state): State<Arc<AppState>>| async move {
+                        let cursor = match query.cursor.as_deref() {
+                            None => None,
+                            Some(it) => match SortKey::from_cursor(it, query.sort) {
+                                None => return StatusCode::BAD_REQUEST.into_response(),
+                                some => some,
+                            },
+                        };
+
+                        // There is only one realm for the turn server.
+                        if let Some(realm) = &query.realm {
+                            if realm != &state.config.turn.realm {
+                                return Json(Vec::<Value>::new()).into_response();
+                            }
+                        }
+
+                        let sessions = state.service.get_sessions();
+                        let now = sessions.now();
+
+                        let mut items = sessions
+                            .iter_sessions()
+                            .filter(|(addr, session)| {
+                                query
+                                    .username
+                                    .as_ref()
+                                    .map(|it| session.auth.username.starts_with(it.as_str()))
+                                    .unwrap_or(true)
+                                    && query.subnet.map(|it| it.contains(addr.address.ip())).unwrap_or(true)
+                                    && query
+                                        .min_age
+                                        .map(|it| now.saturating_sub(session.created) >= it)
+                                        .unwrap_or(true)
+                            })
+                            .map(|(addr, session)| {
+                                let value = match query.sort {
+                                    SessionSort::Address => SortValue::None,
+                                    SessionSort::Username => SortValue::Text(session.auth.username.clone()),
+                                    SessionSort::Created => SortValue::Number(session.created),
+                                    SessionSort::Expires => SortValue::Number(session.expires),
+                                };
+
+                                (SortKey(value, addr.address, addr.interface), addr, session)
+                            })
+                            .filter(|(key, ..)| match &cursor {
+                                None => true,
+                                Some(cursor) if query.order == SortOrder::Asc => key > cursor,
+                                Some(cursor) => key < cursor,
+                            })
+                            .collect::<Vec<_>>();
+
+                        items.sort_by(|a, b| a.0.cmp(&b.0));
+                        if query.order == SortOrder::Desc {
+                            items.reverse();
+                        }
+
+                        // The cursor of the next page is the key of the last session in this
+                        // page, and is only returned when there are more sessions.
+                        let mut next = None;
+                        if let Some(limit) = query.limit {
+                            if items.len() > limit {
+                                items.truncate(limit);
+                                next = items.last().map(|(key, ..)| key.to_cursor());
+                            }
+                        }
+
+                        let mut res = Json(
+                            items
+                                .into_iter()
+                                .map(|(_, addr, session)| {
+                                    json!({
+                                        "address": addr.address,
+                                        "interface": addr.interface,
+                                        "username": session.auth.username,
+                                        "realm": state.config.turn.realm,
+                                        "transport": session.allocate.ingress.map(|it| it.as_str()),
+                                        "permissions": session.permissions.iter().map(|it| it.port).collect::<Vec<_>>(),
+                                        "channels": session.allocate.channels,
+                                        "port": session.allocate.port,
+                                        "generation": session.allocate.generation,
+                                        "created": session.created,
+                                        "expires": session.expires,
+                                        "tags": session.tags,
+                                        "location": state.service.get_observer().locate(&addr),
+                                    })
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                        .into_response();
+
+                        if let Some(next) = next.and_then(|it| HeaderValue::from_str(&it).ok()) {
+                            res.headers_mut().insert("Next-Cursor", next);
+                        }
+
+                        res
+                    },
+                ),
+            )
+            .route(
+                "/peer/sessions",
+                get(
+                    |Query(query): Query<PeerQueryFilter>, State(state): State<Arc<AppState>>| async move {
+                        let sessions = state.service.get_sessions();
+                        let peer = if let Some(it) = sessions.get_port_session(query.port) {
+                            it
+                        } else {
+                            return StatusCode::NOT_FOUND.into_response();
+                        };
+
+                        let username = |addr: &SessionAddr| {
+                            sessions.get_session(addr).get_ref().map(|it| it.auth.username.clone())
+                        };
+
+                        Json(json!({
+                            "peer": {
+                                "address": peer.address,
+                                "interface": peer.interface,
+                                "username": username(&peer),
+                            },
+                            "sessions": sessions
+                                .get_peer_sessions(&peer)
+                                .into_iter()
+                                .map(|(addr, port)| {
+                                    json!({
+                                        "address": addr.address,
+                                        "interface": addr.interface,
+                                        "username": username(&addr),
+                                        "port": port,
+                                    })
+                                })
+                                .collect::<Vec<_>>(),
+                        }))
+                        .into_response()
+                    },
+                ),
+            )
+            .route(
+                "/channel",
+                get(
+                    |Query(query): Query<ChannelQueryFilter>, State(state): State<Arc<AppState>>| async move {
+                        let sessions = state.service.get_sessions();
+                        let binding = if let Some(it) = sessions.get_channel_binding(query.port, query.channel) {
+                            it
+                        } else {
+                            return StatusCode::NOT_FOUND.into_response();
+                        };
+
+                        let username = |addr: &SessionAddr| {
+                            sessions.get_session(addr).get_ref().map(|it| it.auth.username.clone())
+                        };
+
+                        Json(json!({
+                            "session": {
+                                "address": binding.session.address,
+                                "interface": binding.session.interface,
+                                "username": username(&binding.session),
+                            },
+                            "peer": {
+                                "address": binding.peer.address,
+                                "interface": binding.peer.interface,
+                                "username": username(&binding.peer),
+                                "port": binding.peer_port,
+                            },
+                            "idle": binding.idle,
+                            "stale": binding.stale,
+                        }))
+                        .into_response()
+                    },
+                ),
+            )
+            .route(
+                "/user",
+                delete(
+                    |ConnectInfo(admin): ConnectInfo<SocketAddr>,
+                     Query(query): Query<UserQueryFilter>,
+                     State(state): State<Arc<AppState>>| async move {
+                        let count = state
+                            .service
+                            .get_sessions()
+                            .remove_user(&query.username, CloseReason::Removed);
+
+                        state.audit.record(
+                            Actor::Admin(admin),
+                            "kick_user",
+                            json!({
+                                "username": query.username,
+                                "sessions": count,
+                            }),
+                        );
+
+                        Json(json!({
+                            "sessions": count,
+                        }))
+                    },
+                ),
+            )
+            .route(
+                "/auth",
+                put(
+                    |ConnectInfo(admin): ConnectInfo<SocketAddr>,
+                     State(state): State<Arc<AppState>>,
+                     Json(auth): Json<Auth>| async move {
+                        let static_credentials = auth.static_credentials.len();
+                        let static_auth_secret = auth.static_auth_secret.is_some();
+
+                        // The replaced auth is kept across restarts.
+                        state.persistence.set_auth(&auth);
+
+                        // The cached keys of the sessions were derived from the previous
+                        // backends, check them against the new ones.
+                        state.service.get_observer().replace_auth(auth).await;
+                        let count = state.service.get_sessions().revalidate().await;
+
+                        state.audit.record(
+                            Actor::Admin(admin),
+                            "replace_auth",
+                            json!({
+                                "static_credentials": static_credentials,
+                                "static_auth_secret": static_auth_secret,
+                                "sessions": count,
+                            }),
+                        );
+
+                        Json(json!({
+                            "sessions": count,
+                        }))
+                    },
+                ),
+            )
+            .route(
+                "/maintenance",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    Json(json!({
+                        "deny_new_sessions": state.admission.is_denying_new_sessions(),
+                    }))
+                })
+                .put(
+                    |ConnectInfo(admin): ConnectInfo<SocketAddr>,
+                     State(state): State<Arc<AppState>>,
+                     Json(body): Json<MaintenanceBody>| async move {
+                        state.admission.set_deny_new_sessions(body.deny_new_sessions);
+                        state.audit.record(
+                            Actor::Admin(admin),
+                            "maintenance",
+                            json!({
+                                "deny_new_sessions": body.deny_new_sessions,
+                            }),
+                        );
+
+                        log::info!(
+                            "new sessions {}",
+                            if body.deny_new_sessions { "denied" } else { "accepted" }
+                        );
+                        StatusCode::OK
+                    },
+                ),
+            )
+            .route(
+                "/statistics",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    let counts = state.statistics.total();
+                    Json(json!({
+                        "received_bytes": counts.received_bytes,
+                        "send_bytes": counts.send_bytes,
+                        "received_pkts": counts.received_pkts,
+                        "send_pkts": counts.send_pkts,
+                        "error_pkts": counts.error_pkts,
+                        "rates": rates(&state.statistics, None),
+                    }))
+                }),
+            )
+            .route(
+                "/stats.json",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    let sessions = state.service.get_sessions();
+                    let counters = sessions.counters();
+                    let probe_elapsed = state.health.probe_elapsed();
+
+                    Json(json!({
+                        "software": concat!(env!("CARGO_PKG_NAME"), ":", env!("CARGO_PKG_VERSION")),
+                        "uptime": state.uptime.elapsed().as_secs(),
+                        "sessions": counters.sessions,
+                        "allocations": counters.allocated,
+                        "permissions": counters.permissions,
+                        "channels": counters.channels,
+                        "port_capacity": sessions.capacity(),
+                        "port_available": sessions.available(),
+                        "traffic": {
+                            "total": traffic(state.statistics.total()),
+                            "udp": traffic(state.statistics.transport(Transport::UDP)),
+                            "tcp": traffic(state.statistics.transport(Transport::TCP)),
+                        },
+                        "health": {
+                            "alive": probe_elapsed <= state.config.health.liveness_threshold,
+                            "listening": state.health.is_listening(),
+                            "probe_elapsed": probe_elapsed,
+                        },
+                    }))
+                }),
+            )
+            .route(
+                "/usage",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    let usage = state.service.get_observer().usage();
+
+                    Json(json!({
+                        "current": usage.report(),
+                        "last": usage.last(),
+                    }))
+                }),
+            )
+            .route(
+                "/malformed",
+                get(|State(state): State<Arc<AppState>>| async move {
+                    let mut sources = state.malformed.sources();
+                    sources.sort_by_key(|it| std::cmp::Reverse(it.total));
+
+                    Json(
+                        sources
+                            .into_iter()
+                            .map(|it| {
+                                json!({
+                                    "ip": it.ip,
+                                    "total": it.total,
+                                    "banned": it.banned,
+                                })
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                }),
+            )
+            .route(
+                "/top",
+                get(
+                    |Query(query): Query<TopQueryFilter>, State(state): State<Arc<AppState>>| async move {
+                        if ![1, 5, 15].contains(&query.window) {
+                            return StatusCode::BAD_REQUEST.into_response();
+                        }
+
+                        let sessions = state.service.get_sessions();
+                        let items = state
+                            .statistics
+                            .bandwidth(query.window)
+                            .into_iter()
+                            .filter_map(|(addr, bytes)| {
+                                let username = sessions.get_session(&addr).get_ref()?.auth.username.clone();
+                                Some((addr, username, bytes))
+                            });
+
+                        if query.by == TopGroup::Session {
+                            return Json(
+                                items
+                                    .take(query.limit)
+                                    .map(|(addr, username, bytes)| {
+                                        json!({
+                                            "address": addr.address,
+                                            "interface": addr.interface,
+                                            "username": username,
+                                            "bytes": bytes,
+                                        })
+                                    })
+                                    .collect::<Vec<_>>(),
+                            )
+                            .into_response();
+                        }
+
+                        let mut users: AHashMap<String, (usize, u64)> = AHashMap::new();
+                        for (_, username, bytes) in items {
+                            let user = users.entry(username).or_default();
+                            user.0 += 1;
+                            user.1 += bytes;
+                        }
+
+                        let mut users = users.into_iter().collect::<Vec<_>>();
+                        users.sort_by_key(|(_, (_, bytes))| std::cmp::Reverse(*bytes));
+
+                        Json(
+                            users
+                                .into_iter()
+                                .take(query.limit)
+                                .map(|(username, (sessions, bytes))| {
+                                    json!({
+                                        "username": username,
+                                        "sessions": sessions,
+                                        "bytes": bytes,
+                                    })
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                        .into_response()
+                    },
+                ),
+            )
             .route(
                 "/session/statistics",
                 get(
@@ -123,6 +764,7 @@ pub mod api {
                                 "received_pkts": counts.received_pkts,
                                 "send_pkts": counts.send_pkts,
                                 "error_pkts": counts.error_pkts,
+                                "rates": rates(&state.statistics, Some(&addr)),
                             }))
                             .into_response()
                         } else {
@@ -134,8 +776,24 @@ pub mod api {
             .route(
                 "/session",
                 delete(
-                    |Query(query): Query<SessionQueryFilter>, State(state): State<Arc<AppState>>| async move {
-                        if state.service.get_sessions().refresh(&query.into(), 0) {
+                    |ConnectInfo(admin): ConnectInfo<SocketAddr>,
+                     Query(query): Query<SessionQueryFilter>,
+                     State(state): State<Arc<AppState>>| async move {
+                        let addr: SessionAddr = query.into();
+                        let ok = state.service.get_sessions().remove_session(&addr, CloseReason::Removed);
+                        state.audit.record(
+                            Actor::Admin(admin),
+                            "kick",
+                            json!({
+                                "session": {
+                                    "address": state.audit.anonymize(addr.address),
+                                    "interface": addr.interface,
+                                },
+                                "success": ok,
+                            }),
+                        );
+
+                        if ok {
                             StatusCode::OK
                         } else {
                             StatusCode::EXPECTATION_FAILED
@@ -146,14 +804,22 @@ pub mod api {
 
         #[cfg(feature = "prometheus")]
         {
-            use crate::statistics::prometheus::generate_metrics;
+            use crate::statistics::prometheus::{generate_metrics, METRICS};
             use axum::http::header::CONTENT_TYPE;
 
             let mut metrics_bytes = Vec::with_capacity(4096);
 
             app = app.route(
                 "/metrics",
-                get(|| async move {
+                get(|State(state): State<Arc<AppState>>| async move {
+                    // The port pool is owned by the sessions, sample it when the metrics are
+                    // scraped instead of tracking every allocation.
+                    {
+                        let sessions = state.service.get_sessions();
+                        METRICS.port_capacity.set(sessions.capacity() as i64);
+                        METRICS.port_available.set(sessions.available() as i64);
+                    }
+
                     metrics_bytes.clear();
 
                     if generate_metrics(&mut metrics_bytes).is_err() {
@@ -179,7 +845,11 @@ pub mod api {
             .with_state(state);
 
         log::info!("api server listening={:?}", &config.api.bind);
-        axum::serve(TcpListener::bind(config.api.bind).await?, app).await?;
+        axum::serve(
+            TcpListener::bind(config.api.bind).await?,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
 
         Ok(())
     }
@@ -189,15 +859,25 @@ pub mod api {
 pub mod hooks {
     use std::{sync::Arc, time::Duration};
 
-    use axum::http::{HeaderMap, HeaderValue};
+    use axum::http::{header::CONTENT_TYPE, HeaderMap, HeaderValue};
     use reqwest::{Client, ClientBuilder};
+    use serde::Deserialize;
     use serde_json::Value;
     use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-    use turn::SessionAddr;
+    use turn::{Credential, SessionAddr, SessionTags};
 
     use super::NONCE;
     use crate::config::Config;
 
+    /// The json response of the password request, which carries the tags of
+    /// the session along with the password.
+    #[derive(Deserialize)]
+    struct PasswordResponse {
+        password: String,
+        #[serde(default)]
+        tags: SessionTags,
+    }
+
     pub struct HooksService {
         client: Arc<Client>,
         tx: UnboundedSender<Value>,
@@ -239,7 +919,10 @@ pub mod hooks {
 
         // There are no matching static entries, get the password from an external hook
         // service.
-        pub async fn get_password(&self, addr: &SessionAddr, username: &str) -> Option<String> {
+        //
+        // The response is either the password in plain text, or a json object
+        // with the password and the tags of the session.
+        pub async fn get_credential(&self, addr: &SessionAddr, username: &str) -> Option<Credential> {
             if let Some(server) = &self.config.api.hooks {
                 if let Ok(res) = self
                     .client
@@ -250,8 +933,33 @@ pub mod hooks {
                     .send()
                     .await
                 {
-                    if let Ok(password) = res.text().await {
-                        return Some(password);
+                    let is_json = res
+                        .headers()
+                        .get(CONTENT_TYPE)
+                        .and_then(|it| it.to_str().ok())
+                        .is_some_and(|it| it.starts_with("application/json"));
+
+                    if is_json {
+                        match res.json::<PasswordResponse>().await {
+                            Ok(it) => {
+                                return Some(Credential {
+                                    password: it.password,
+                                    tags: it.tags,
+                                })
+                            }
+                            Err(e) => {
+                                log::error!(
+                                    "invalid password response of hooks server: username={:?}, err={}",
+                                    username,
+                                    e
+                                );
+                            }
+                        }
+                    } else if let Ok(password) = res.text().await {
+                        return Some(Credential {
+                            password,
+                            tags: SessionTags::new(),
+                        });
                     }
                 }
             }
@@ -0,0 +1,129 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use stun::Payload;
+use turn::{
+    operations::{Interceptor, Response},
+    ResponseMethod, SessionAddr,
+};
+
+use crate::config::Reflection;
+
+// The error responses are limited within this window.
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Spoofed requests can come from a lot of sources, the sources that are idle
+// for a window are purged when there are more than this.
+const MAX_SOURCES: usize = 65536;
+
+struct Source {
+    window: Instant,
+    count: u32,
+}
+
+/// Prevents the server from being used as a reflector.
+///
+/// The source address of a udp request can be spoofed, and the response is
+/// then sent to the victim. Error responses are not authenticated, so the
+/// number of error responses per source ip is limited, and the requests that
+/// are too small to be legitimate are dropped.
+pub struct ReflectionGuard {
+    config: Reflection,
+    sources: Mutex<AHashMap<IpAddr, Source>>,
+}
+
+impl ReflectionGuard {
+    pub fn new(config: Reflection) -> Self {
+        Self {
+            sources: Default::default(),
+            config,
+        }
+    }
+
+    /// Whether an error response can be sent to the source ip.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{config::Reflection, reflection::ReflectionGuard};
+    ///
+    /// let guard = ReflectionGuard::new(Reflection {
+    ///     max_error_responses: 2,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// let ip = "127.0.0.1".parse().unwrap();
+    ///
+    /// assert!(guard.allow_error(ip));
+    /// assert!(guard.allow_error(ip));
+    /// assert!(!guard.allow_error(ip));
+    /// assert!(guard.allow_error("127.0.0.2".parse().unwrap()));
+    /// ```
+    pub fn allow_error(&self, ip: IpAddr) -> bool {
+        if self.config.max_error_responses == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut sources = self.sources.lock();
+        if sources.len() >= MAX_SOURCES {
+            sources.retain(|_, it| now.duration_since(it.window) < WINDOW);
+        }
+
+        let source = sources.entry(ip).or_insert_with(|| Source { window: now, count: 0 });
+        if now.duration_since(source.window) >= WINDOW {
+            source.window = now;
+            source.count = 0;
+        }
+
+        if source.count >= self.config.max_error_responses {
+            return false;
+        }
+
+        source.count += 1;
+        true
+    }
+}
+
+#[cfg(feature = "prometheus")]
+fn dropped(reason: &str) {
+    crate::statistics::prometheus::METRICS
+        .reflection_dropped
+        .with_label_values(&[reason])
+        .inc();
+}
+
+#[cfg(not(feature = "prometheus"))]
+fn dropped(_: &str) {}
+
+impl Interceptor for ReflectionGuard {
+    fn before(&self, _: &SessionAddr, payload: &Payload<'_>) -> bool {
+        if let Payload::Message(message) = payload {
+            if message.size() < self.config.min_request_size {
+                dropped("request-size");
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn after(&self, addr: &SessionAddr, response: &mut Option<Response<'_>>) {
+        let is_error = matches!(
+            response,
+            Some(Response {
+                method: ResponseMethod::Stun(method),
+                ..
+            }) if method.is_error()
+        );
+
+        if is_error && !self.allow_error(addr.address.ip()) {
+            dropped("error-rate");
+            response.take();
+        }
+    }
+}
@@ -1,15 +1,49 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use ahash::AHashMap;
 use parking_lot::RwLock;
 use tokio::sync::mpsc::*;
 use turn::ResponseMethod;
 
-type Receiver = UnboundedSender<(Vec<u8>, ResponseMethod, SocketAddr)>;
+type Packet = (Vec<u8>, ResponseMethod, SocketAddr);
+
+/// The packets and the bytes that are forwarded to a socket but not
+/// received yet.
+#[derive(Default)]
+struct Queue {
+    packets: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+/// The receiving side of a route.
+///
+/// It counts the packets that are forwarded to the socket but not received
+/// yet, which is the queue depth of the route.
+pub struct Receiver {
+    receiver: UnboundedReceiver<Packet>,
+    queued: Arc<Queue>,
+}
+
+impl Receiver {
+    pub async fn recv(&mut self) -> Option<Packet> {
+        let packet = self.receiver.recv().await?;
+        self.queued.packets.fetch_sub(1, Ordering::Relaxed);
+        self.queued.bytes.fetch_sub(packet.0.len(), Ordering::Relaxed);
+        Some(packet)
+    }
+}
+
+type Sender = (UnboundedSender<Packet>, Arc<Queue>);
 
 /// Handles packet forwarding between transport protocols.
 #[derive(Clone)]
-pub struct Router(Arc<RwLock<AHashMap<SocketAddr, Receiver>>>);
+pub struct Router(Arc<RwLock<AHashMap<SocketAddr, Sender>>>);
 
 impl Default for Router {
     fn default() -> Self {
@@ -43,10 +77,12 @@ impl Router {
     ///     assert_eq!(ret.2, addr);
     /// }
     /// ```
-    pub fn get_receiver(&self, interface: SocketAddr) -> UnboundedReceiver<(Vec<u8>, ResponseMethod, SocketAddr)> {
+    pub fn get_receiver(&self, interface: SocketAddr) -> Receiver {
         let (sender, receiver) = unbounded_channel();
-        self.0.write().insert(interface, sender);
-        receiver
+        let queued = Arc::new(Queue::default());
+
+        self.0.write().insert(interface, (sender, queued.clone()));
+        Receiver { receiver, queued }
     }
 
     /// Send data to router.
@@ -80,9 +116,12 @@ impl Router {
         let mut is_destroy = false;
 
         {
-            if let Some(sender) = self.0.read().get(interface) {
+            if let Some((sender, queued)) = self.0.read().get(interface) {
                 if sender.send((data.to_vec(), method, *addr)).is_err() {
                     is_destroy = true;
+                } else {
+                    queued.packets.fetch_add(1, Ordering::Relaxed);
+                    queued.bytes.fetch_add(data.len(), Ordering::Relaxed);
                 }
             }
         }
@@ -92,6 +131,70 @@ impl Router {
         }
     }
 
+    /// The number of packets that are forwarded but not received yet, summed
+    /// over all routes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::net::SocketAddr;
+    /// use turn::ResponseMethod;
+    /// use turn_server::router::*;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+    ///     let router = Router::default();
+    ///     let mut receiver = router.get_receiver(addr);
+    ///
+    ///     router.send(&addr, ResponseMethod::ChannelData, &addr, &[1, 2, 3]);
+    ///     router.send(&addr, ResponseMethod::ChannelData, &addr, &[1, 2, 3]);
+    ///     assert_eq!(router.queued(), 2);
+    ///
+    ///     receiver.recv().await.unwrap();
+    ///     assert_eq!(router.queued(), 1);
+    /// }
+    /// ```
+    pub fn queued(&self) -> usize {
+        self.0
+            .read()
+            .values()
+            .map(|(_, queued)| queued.packets.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The bytes of the packets that are forwarded but not received yet,
+    /// summed over all routes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::net::SocketAddr;
+    /// use turn::ResponseMethod;
+    /// use turn_server::router::*;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+    ///     let router = Router::default();
+    ///     let mut receiver = router.get_receiver(addr);
+    ///
+    ///     router.send(&addr, ResponseMethod::ChannelData, &addr, &[1, 2, 3]);
+    ///     router.send(&addr, ResponseMethod::ChannelData, &addr, &[1, 2]);
+    ///     assert_eq!(router.queued_bytes(), 5);
+    ///
+    ///     receiver.recv().await.unwrap();
+    ///     assert_eq!(router.queued_bytes(), 2);
+    /// }
+    /// ```
+    pub fn queued_bytes(&self) -> usize {
+        self.0
+            .read()
+            .values()
+            .map(|(_, queued)| queued.bytes.load(Ordering::Relaxed))
+            .sum()
+    }
+
     /// delete socket.
     ///
     /// # Example
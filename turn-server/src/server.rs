@@ -1,20 +1,154 @@
 use crate::{
-    config::{Config, Interface},
+    buffers::SocketBuffers,
+    config::{Anonymize, Config, Ingress, Interface, Tcp},
+    malformed::MalformedFilter,
+    memory::MemoryBudget,
     router::Router,
     statistics::Statistics,
 };
 
-use std::net::SocketAddr;
+use std::{future::Future, io, net::SocketAddr};
 
-use turn::{Observer, Service};
+use turn::{operations::CredentialMechanism, Observer, Service};
+
+/// The fields of the ip header of a datagram that are carried over to the
+/// datagram that relays it, as RFC 8656 recommends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IpHeader {
+    /// The time to live of ipv4 or the hop limit of ipv6.
+    pub ttl: Option<u8>,
+    /// The ECN bits of the type of service or the traffic class.
+    pub ecn: Option<u8>,
+}
+
+impl IpHeader {
+    /// The header of the datagram that relays a datagram with this header,
+    /// the time to live is decremented, returns None if the datagram must
+    /// be dropped because the time to live is exhausted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::server::IpHeader;
+    ///
+    /// let header = IpHeader {
+    ///     ttl: Some(64),
+    ///     ecn: Some(0b10),
+    /// };
+    ///
+    /// assert_eq!(
+    ///     header.relayed(),
+    ///     Some(IpHeader {
+    ///         ttl: Some(63),
+    ///         ecn: Some(0b10),
+    ///     })
+    /// );
+    ///
+    /// assert_eq!(IpHeader::default().relayed(), Some(IpHeader::default()));
+    /// assert_eq!(IpHeader { ttl: Some(1), ecn: None }.relayed(), None);
+    /// ```
+    pub fn relayed(self) -> Option<Self> {
+        Some(Self {
+            ttl: match self.ttl {
+                Some(ttl) if ttl <= 1 => return None,
+                ttl => ttl.map(|it| it - 1),
+            },
+            ecn: self.ecn,
+        })
+    }
+}
+
+/// The datagram socket of an udp interface.
+///
+/// The udp server only sends and receives through this trait, so that other
+/// datapaths, such as io_uring, AF_XDP or an in-memory socket for tests, can
+/// be used with [`serve_datagram`] without changing the processing.
+pub trait DatagramSocket: Send + Sync + 'static {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Receive a datagram, returns the size and the source address.
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// Send a datagram to the target address.
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Receive a datagram with the fields of its ip header, the fields are
+    /// unknown by default.
+    fn recv_with_header(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr, IpHeader)>> + Send {
+        async {
+            let (size, addr) = self.recv_from(buf).await?;
+            Ok((size, addr, IpHeader::default()))
+        }
+    }
+
+    /// Send a datagram with the fields of its ip header, the fields are
+    /// ignored by default.
+    #[allow(unused_variables)]
+    fn send_with_header(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        header: IpHeader,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        self.send_to(buf, target)
+    }
+}
+
+impl DatagramSocket for tokio::net::UdpSocket {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        tokio::net::UdpSocket::recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+        tokio::net::UdpSocket::send_to(self, buf, target)
+    }
+
+    #[cfg(all(feature = "udp", target_os = "linux"))]
+    fn recv_with_header(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr, IpHeader)>> + Send {
+        crate::header::recv_from(self, buf)
+    }
+
+    #[cfg(all(feature = "udp", target_os = "linux"))]
+    fn send_with_header(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+        header: IpHeader,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        crate::header::send_to(self, buf, target, header)
+    }
+}
+
+/// The size of the receive buffers and the largest datagram that is accepted
+/// and relayed, when it is not configured for the interface.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 2048;
 
 #[allow(unused)]
 struct ServerStartOptions<T> {
     bind: SocketAddr,
     external: SocketAddr,
+    credential: CredentialMechanism,
+    max_datagram_size: usize,
     service: Service<T>,
     router: Router,
     statistics: Statistics,
+    anonymize: Anonymize,
+    tcp: Tcp,
+    ingress: Ingress,
+    malformed: MalformedFilter,
+    budget: MemoryBudget,
+    buffers: SocketBuffers,
+    proxy_protocol: bool,
 }
 
 #[allow(unused)]
@@ -26,17 +160,25 @@ trait Server {
 
 #[cfg(feature = "udp")]
 mod udp {
-    use super::{Server as ServerExt, ServerStartOptions};
-    use crate::statistics::Stats;
+    use super::{DatagramSocket, IpHeader, Server as ServerExt, ServerStartOptions};
+    use crate::{
+        config::Ingress,
+        malformed::MalformedFilter,
+        router::Router,
+        statistics::{Statistics, StatisticsReporter, Stats},
+    };
 
-    use std::{io::ErrorKind::ConnectionReset, ops::Deref, sync::Arc};
+    use std::{collections::VecDeque, io, net::SocketAddr, ops::Deref, sync::Arc, time::Instant};
 
     use once_cell::sync::Lazy;
     use stun::Transport;
     use tokio::net::UdpSocket;
-    use turn::{Observer, ResponseMethod, SessionAddr};
+    use turn::{
+        operations::{CredentialMechanism, IngressTransport, TransportContext},
+        Observer, Operationer, ResponseMethod, Service, SessionAddr,
+    };
 
-    static NUM_CPUS: Lazy<usize> = Lazy::new(|| num_cpus::get());
+    static NUM_CPUS: Lazy<usize> = Lazy::new(num_cpus::get);
 
     /// udp socket process thread.
     ///
@@ -50,137 +192,552 @@ mod udp {
             ServerStartOptions {
                 bind,
                 external,
+                credential,
+                max_datagram_size,
                 service,
                 router,
                 statistics,
+                malformed,
+                ingress,
+                buffers,
+                ..
             }: ServerStartOptions<T>,
         ) -> Result<(), anyhow::Error>
         where
             T: Clone + Observer + 'static,
         {
             let socket = Arc::new(UdpSocket::bind(bind).await?);
-            let local_addr = socket.local_addr()?;
 
-            tokio::spawn(async move {
-                for _ in 0..*NUM_CPUS.deref() {
-                    let socket = socket.clone();
-                    let router = router.clone();
-                    let reporter = statistics.get_reporter(Transport::UDP);
-                    let mut operationer = service.get_operationer(external, external);
+            #[cfg(target_os = "linux")]
+            {
+                crate::icmp::serve(socket.clone(), external, service.clone(), router.clone());
+
+                if let Err(e) = crate::header::enable(&socket) {
+                    log::warn!(
+                        "failed to preserve ip headers of relayed datagrams: interface={}, err={}",
+                        external,
+                        e
+                    );
+                }
+            }
 
-                    let mut session_addr = SessionAddr {
-                        address: external,
-                        interface: external,
-                    };
+            // The ip headers, the icmp errors and the drops of the socket are read through
+            // linux, on the other platforms the relayed datagrams are sent with the defaults
+            // of the socket, the icmp errors are not forwarded and only the buffer sizes of
+            // the config are set.
+            #[cfg(not(target_os = "linux"))]
+            log::info!(
+                "ip headers, icmp errors and socket drops are not supported on this platform: interface={}",
+                external
+            );
 
-                    tokio::spawn(async move {
-                        let mut buf = vec![0u8; 2048];
-
-                        loop {
-                            // Note: An error will also be reported when the remote host is
-                            // shut down, which is not processed yet, but a
-                            // warning will be issued.
-                            let (size, addr) = match socket.recv_from(&mut buf).await {
-                                Err(e) if e.kind() != ConnectionReset => break,
-                                Ok(s) => s,
-                                _ => continue,
-                            };
+            if let Err(e) = buffers.register(external, socket.clone()) {
+                log::warn!("failed to set udp socket buffers: interface={}, err={}", external, e);
+            }
 
-                            session_addr.address = addr;
+            serve(
+                socket,
+                external,
+                Limits {
+                    credential,
+                    max_datagram_size,
+                    ingress,
+                },
+                service,
+                router,
+                statistics,
+                malformed,
+            )?;
 
-                            reporter.send(
-                                &session_addr,
-                                &[Stats::ReceivedBytes(size as u32), Stats::ReceivedPkts(1)],
-                            );
+            log::info!(
+                "turn server listening: bind={}, external={}, transport=UDP",
+                bind,
+                external,
+            );
 
-                            // The stun message requires at least 4 bytes. (currently the
-                            // smallest stun message is channel data,
-                            // excluding content)
-                            if size >= 4 {
-                                if let Ok(Some(res)) = operationer.route(&buf[..size], addr).await {
-                                    let target = res.relay.as_ref().unwrap_or(&addr);
-                                    if let Some(ref endpoint) = res.endpoint {
-                                        router.send(endpoint, res.method, target, res.bytes);
-                                    } else {
-                                        if let Err(e) = socket.send_to(res.bytes, target).await {
-                                            if e.kind() != ConnectionReset {
-                                                break;
-                                            }
-                                        }
+            Ok(())
+        }
+    }
 
-                                        reporter.send(
-                                            &session_addr,
-                                            &[Stats::SendBytes(res.bytes.len() as u32), Stats::SendPkts(1)],
-                                        );
+    /// Whether the error of the socket is caused by a remote host, such as
+    /// the ICMP errors of a previous datagram, rather than by the socket.
+    fn is_remote_error(e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+        )
+    }
 
-                                        if let ResponseMethod::Stun(method) = res.method {
-                                            if method.is_error() {
-                                                reporter.send(&session_addr, &[Stats::ErrorPkts(1)]);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    });
+    /// The settings of the interface that the datagrams are checked with.
+    pub struct Limits {
+        pub credential: CredentialMechanism,
+        pub max_datagram_size: usize,
+        pub ingress: Ingress,
+    }
+
+    /// Count a datagram that is dropped because it is larger than the maximum
+    /// datagram size, the direction is "received" or "relayed".
+    #[allow(unused_variables)]
+    fn oversize(direction: &str) {
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS
+                .oversize_dropped
+                .with_label_values(&["udp", direction])
+                .inc();
+        }
+    }
+
+    /// Count a stun message that is dropped because the control queue of the
+    /// receive task is full.
+    fn control_dropped() {
+        #[cfg(feature = "prometheus")]
+        {
+            crate::statistics::prometheus::METRICS.control_dropped.inc();
+        }
+    }
+
+    /// Whether the message is relayed data, a channel data or a send
+    /// indication, rather than a stun message that controls the sessions.
+    fn is_data(bytes: &[u8]) -> bool {
+        bytes[0] >> 6 == 1 || bytes[..2] == [0x00, 0x16]
+    }
+
+    /// The state of a receive task of the socket.
+    struct Worker<T, S>
+    where
+        T: Observer + 'static,
+    {
+        operationer: Operationer<T>,
+        reporter: StatisticsReporter,
+        session_addr: SessionAddr,
+        socket: Arc<S>,
+        router: Router,
+        malformed: MalformedFilter,
+        max_datagram_size: usize,
+    }
+
+    impl<T, S> Worker<T, S>
+    where
+        T: Clone + Observer + 'static,
+        S: DatagramSocket,
+    {
+        /// Process a message and send the response, returns false if the
+        /// socket is broken.
+        async fn process(&mut self, bytes: &[u8], addr: SocketAddr, header: IpHeader) -> bool {
+            self.session_addr.address = addr;
+
+            let started = Instant::now();
+            let ret = self.operationer.route(bytes, addr).await;
+            if ret.is_err() {
+                self.malformed.report(addr);
+            }
+
+            let Ok(Some(res)) = ret else {
+                return true;
+            };
+
+            if res.relay.is_some() && res.bytes.len() > self.max_datagram_size {
+                oversize("relayed");
+                return true;
+            }
+
+            let target = res.relay.as_ref().unwrap_or(&addr);
+            if let Some(ref endpoint) = res.endpoint {
+                self.router.send(endpoint, res.method, target, res.bytes);
+                self.reporter.observe(res.method, started);
+                return true;
+            }
+
+            // The responses are sent with the defaults of the socket, the
+            // datagrams relayed to the peers carry over the header.
+            let header = match res.relay {
+                Some(_) => match header.relayed() {
+                    Some(it) => it,
+                    None => return true,
+                },
+                None => IpHeader::default(),
+            };
+
+            if let Err(e) = self.socket.send_with_header(res.bytes, *target, header).await {
+                if !is_remote_error(&e) {
+                    return false;
                 }
+            }
+
+            self.reporter.send(
+                &self.session_addr,
+                &[Stats::SendBytes(res.bytes.len() as u32), Stats::SendPkts(1)],
+            );
+
+            self.reporter.observe(res.method, started);
+            if let ResponseMethod::Stun(method) = res.method {
+                if method.is_error() {
+                    self.reporter.send(&self.session_addr, &[Stats::ErrorPkts(1)]);
+                }
+            }
+
+            true
+        }
+    }
 
-                {
-                    let mut session_addr = SessionAddr {
+    /// Serve the interface with the socket.
+    pub fn serve<T, S>(
+        socket: Arc<S>,
+        external: SocketAddr,
+        Limits {
+            credential,
+            max_datagram_size,
+            ingress,
+        }: Limits,
+        service: Service<T>,
+        router: Router,
+        statistics: Statistics,
+        malformed: MalformedFilter,
+    ) -> Result<(), anyhow::Error>
+    where
+        T: Clone + Observer + 'static,
+        S: DatagramSocket,
+    {
+        let local_addr = socket.local_addr()?;
+        let mut transport = TransportContext::new(IngressTransport::Udp, local_addr);
+        transport.credential = credential;
+
+        tokio::spawn(async move {
+            for _ in 0..*NUM_CPUS.deref() {
+                let mut worker = Worker {
+                    operationer: service.get_operationer(external, external, transport.clone()),
+                    reporter: statistics.get_reporter(Transport::UDP),
+                    session_addr: SessionAddr {
                         address: external,
                         interface: external,
-                    };
+                    },
+                    socket: socket.clone(),
+                    router: router.clone(),
+                    malformed: malformed.clone(),
+                    max_datagram_size,
+                };
+
+                tokio::spawn(async move {
+                    // One more byte than the maximum, so that a larger datagram is
+                    // detected rather than silently truncated.
+                    let mut buf = vec![0u8; max_datagram_size + 1];
+
+                    // The stun messages that wait behind the relayed data, and the number of data
+                    // messages that were processed since the queue had a turn.
+                    let mut control: VecDeque<(Vec<u8>, SocketAddr, IpHeader)> =
+                        VecDeque::with_capacity(ingress.control_queue);
+                    let mut credit = 0;
+
+                    loop {
+                        // The queue has a turn after the weight of data messages, or as soon as no
+                        // datagram is waiting.
+                        let received = if !control.is_empty() && credit >= ingress.data_weight {
+                            None
+                        } else {
+                            tokio::select! {
+                                biased;
+                                ret = worker.socket.recv_with_header(&mut buf) => Some(ret),
+                                _ = std::future::ready(()), if !control.is_empty() => None,
+                            }
+                        };
 
-                    let reporter = statistics.get_reporter(Transport::UDP);
-                    let mut receiver = router.get_receiver(external);
-                    while let Some((bytes, _, addr)) = receiver.recv().await {
-                        session_addr.address = addr;
+                        let Some(ret) = received else {
+                            for _ in 0..ingress.control_weight.max(1) {
+                                let Some((bytes, addr, header)) = control.pop_front() else {
+                                    break;
+                                };
 
-                        if let Err(e) = socket.send_to(&bytes, addr).await {
-                            if e.kind() != ConnectionReset {
-                                break;
+                                if !worker.process(&bytes, addr, header).await {
+                                    return;
+                                }
                             }
-                        } else {
-                            reporter.send(
-                                &session_addr,
-                                &[Stats::SendBytes(bytes.len() as u32), Stats::SendPkts(1)],
-                            );
+
+                            credit = 0;
+                            continue;
+                        };
+
+                        // Note: An error will also be reported when the remote host is
+                        // shut down, which is not processed yet, but a
+                        // warning will be issued.
+                        let (size, addr, header) = match ret {
+                            Err(e) if !is_remote_error(&e) => break,
+                            Ok(s) => s,
+                            _ => continue,
+                        };
+
+                        // The packets of banned sources are dropped before they are decoded.
+                        if worker.malformed.is_banned(addr.ip()) {
+                            continue;
+                        }
+
+                        if size > max_datagram_size {
+                            oversize("received");
+                            continue;
+                        }
+
+                        worker.session_addr.address = addr;
+                        worker.reporter.send(
+                            &worker.session_addr,
+                            &[Stats::ReceivedBytes(size as u32), Stats::ReceivedPkts(1)],
+                        );
+
+                        // The packets that are not a stun message or a plausible channel data, and
+                        // the stun messages without a valid fingerprint, are dropped before they are
+                        // decoded.
+                        if worker.malformed.reject_class(addr, &buf[..size])
+                            || worker.malformed.reject_fingerprint(addr, &buf[..size])
+                        {
+                            continue;
+                        }
+
+                        if ingress.control_queue > 0 && !is_data(&buf[..size]) {
+                            if control.len() >= ingress.control_queue {
+                                control_dropped();
+                                continue;
+                            }
+
+                            control.push_back((buf[..size].to_vec(), addr, header));
+                            continue;
+                        }
+
+                        credit += 1;
+                        if !worker.process(&buf[..size], addr, header).await {
+                            break;
                         }
                     }
+                });
+            }
+
+            {
+                let mut session_addr = SessionAddr {
+                    address: external,
+                    interface: external,
+                };
+
+                let reporter = statistics.get_reporter(Transport::UDP);
+                let mut receiver = router.get_receiver(external);
+                while let Some((bytes, _, addr)) = receiver.recv().await {
+                    session_addr.address = addr;
+
+                    if bytes.len() > max_datagram_size {
+                        oversize("relayed");
+                        continue;
+                    }
 
-                    router.remove(&external);
+                    if let Err(e) = socket.send_to(&bytes, addr).await {
+                        if !is_remote_error(&e) {
+                            break;
+                        }
+                    } else {
+                        reporter.send(
+                            &session_addr,
+                            &[Stats::SendBytes(bytes.len() as u32), Stats::SendPkts(1)],
+                        );
+                    }
                 }
 
-                log::error!("udp server close: interface={:?}", local_addr);
-            });
+                router.remove(&external);
+            }
 
-            log::info!(
-                "turn server listening: bind={}, external={}, transport=UDP",
-                bind,
-                external,
-            );
+            log::error!("udp server close: interface={:?}", local_addr);
+        });
 
-            Ok(())
-        }
+        Ok(())
     }
 }
 
 #[cfg(feature = "tcp")]
 mod tcp {
     use super::{Server as ServerExt, ServerStartOptions};
-    use crate::statistics::Stats;
+    use crate::{
+        config::{Anonymize, Subnet},
+        memory::MemoryBudget,
+        proxy::{self, Header},
+        statistics::Stats,
+    };
 
     use std::{
+        net::{IpAddr, SocketAddr},
         ops::{Deref, DerefMut},
         sync::Arc,
+        time::{Duration, Instant},
     };
 
+    use ahash::AHashMap;
     use stun::{Decoder, Transport};
-    use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpListener, sync::Mutex};
-    use turn::{Observer, ResponseMethod, SessionAddr};
+    use tokio::{
+        io::AsyncReadExt,
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+        sync::{
+            mpsc::{unbounded_channel, UnboundedReceiver},
+            Mutex,
+        },
+        time::timeout,
+    };
+    use turn::{
+        operations::{IngressTransport, TransportContext},
+        CloseReason, Observer, ResponseMethod, SessionAddr,
+    };
 
     static ZERO_BYTES: [u8; 8] = [0u8; 8];
 
+    // A connection that has not sent its PROXY protocol header within this time
+    // is closed.
+    const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Accept the connections of the listener, the connections are returned
+    /// with the address of the client.
+    ///
+    /// If the interface expects the PROXY protocol, the address of the client
+    /// is read from the header of the connection. The headers are read on
+    /// separate tasks, so that a slow client does not hold up the others,
+    /// and only the trusted proxies may send them.
+    fn accept(
+        listener: TcpListener,
+        proxy_protocol: bool,
+        trusted: Vec<Subnet>,
+        anonymize: Anonymize,
+    ) -> UnboundedReceiver<(TcpStream, SocketAddr)> {
+        let (sender, receiver) = unbounded_channel();
+
+        tokio::spawn(async move {
+            let local_addr = listener.local_addr().ok();
+
+            // Accept all connections on the current listener, but exit the entire
+            // process when an error occurs.
+            while let Ok((mut socket, address)) = listener.accept().await {
+                if !proxy_protocol {
+                    if sender.send((socket, address)).is_err() {
+                        break;
+                    }
+
+                    continue;
+                }
+
+                let ip = address.ip();
+                if !ip.is_loopback() && !trusted.is_empty() && !trusted.iter().any(|it| it.contains(ip)) {
+                    log::warn!(
+                        "tcp socket refused, untrusted proxy: addr={}, interface={:?}",
+                        anonymize.apply(address),
+                        local_addr,
+                    );
+
+                    continue;
+                }
+
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let header = match timeout(PROXY_HEADER_TIMEOUT, proxy::read(&mut socket)).await {
+                        Ok(Ok(it)) => it,
+                        Ok(Err(e)) => {
+                            log::warn!(
+                                "tcp socket refused, invalid proxy protocol header: addr={}, interface={:?}, err={}",
+                                anonymize.apply(address),
+                                local_addr,
+                                e
+                            );
+
+                            return;
+                        }
+                        Err(_) => {
+                            log::warn!(
+                                "tcp socket refused, proxy protocol header timeout: addr={}, interface={:?}",
+                                anonymize.apply(address),
+                                local_addr,
+                            );
+
+                            return;
+                        }
+                    };
+
+                    let _ = sender.send((
+                        socket,
+                        match header {
+                            Header::Proxied(it) => it,
+                            Header::Local => address,
+                        },
+                    ));
+                });
+            }
+        });
+
+        receiver
+    }
+
+    #[derive(Default)]
+    struct ConnectionCounts {
+        total: usize,
+        peers: AHashMap<IpAddr, usize>,
+    }
+
+    /// The connection limits of a tcp listener.
+    ///
+    /// The number of connections is counted in total and per source ip, a
+    /// connection is counted until the guard returned by `acquire` is dropped.
+    #[derive(Clone)]
+    struct Connections {
+        max: usize,
+        max_per_ip: usize,
+        counts: Arc<parking_lot::Mutex<ConnectionCounts>>,
+    }
+
+    impl Connections {
+        fn new(max: usize, max_per_ip: usize) -> Self {
+            Self {
+                counts: Default::default(),
+                max_per_ip,
+                max,
+            }
+        }
+
+        /// Count a new connection, returns None if any limit is exceeded, 0
+        /// means unlimited.
+        fn acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+            let mut counts = self.counts.lock();
+            if self.max > 0 && counts.total >= self.max {
+                return None;
+            }
+
+            let count = counts.peers.get(&ip).copied().unwrap_or(0);
+            if self.max_per_ip > 0 && count >= self.max_per_ip {
+                return None;
+            }
+
+            counts.peers.insert(ip, count + 1);
+            counts.total += 1;
+
+            Some(ConnectionGuard {
+                connections: self.clone(),
+                ip,
+            })
+        }
+    }
+
+    struct ConnectionGuard {
+        connections: Connections,
+        ip: IpAddr,
+    }
+
+    impl Drop for ConnectionGuard {
+        fn drop(&mut self) {
+            let mut counts = self.connections.counts.lock();
+            counts.total -= 1;
+
+            if let Some(count) = counts.peers.get_mut(&self.ip) {
+                *count -= 1;
+
+                if *count == 0 {
+                    counts.peers.remove(&self.ip);
+                }
+            }
+        }
+    }
+
     /// An emulated double buffer queue, this is used when reading data over
     /// TCP.
     ///
@@ -201,19 +758,8 @@ mod tcp {
     struct ExchangeBuffer {
         buffers: [(Vec<u8>, usize /* len */); 2],
         index: usize,
-    }
-
-    impl Default for ExchangeBuffer {
-        #[rustfmt::skip]
-        fn default() -> Self {
-            Self {
-                index: 0,
-                buffers: [
-                    (vec![0u8; 2048], 0),
-                    (vec![0u8; 2048], 0),
-                ],
-            }
-        }
+        size: usize,
+        budget: MemoryBudget,
     }
 
     impl Deref for ExchangeBuffer {
@@ -233,11 +779,53 @@ mod tcp {
         }
     }
 
+    impl Drop for ExchangeBuffer {
+        fn drop(&mut self) {
+            self.shrink();
+        }
+    }
+
     impl ExchangeBuffer {
+        fn new(size: usize, budget: MemoryBudget) -> Self {
+            let mut this = Self {
+                buffers: [(Vec::new(), 0), (Vec::new(), 0)],
+                index: 0,
+                budget,
+                size,
+            };
+
+            this.grow();
+            this
+        }
+
+        /// Allocate the buffers if they were released.
+        fn grow(&mut self) {
+            if self.buffers[0].0.is_empty() {
+                self.budget.allocate(self.size * 2);
+                for (buffer, _) in &mut self.buffers {
+                    *buffer = vec![0u8; self.size];
+                }
+            }
+        }
+
+        /// Release the buffers, they must not have any unconsumed data.
+        fn shrink(&mut self) {
+            if !self.buffers[0].0.is_empty() {
+                self.budget.release(self.size * 2);
+                for (buffer, _) in &mut self.buffers {
+                    *buffer = Vec::new();
+                }
+            }
+        }
+
         fn len(&self) -> usize {
             self.buffers[self.index].1
         }
 
+        fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
         /// The buffer does not automatically advance the cursor as BytesMut
         /// does, and you need to manually advance the length of the data
         /// written.
@@ -288,9 +876,17 @@ mod tcp {
             ServerStartOptions {
                 bind,
                 external,
+                credential,
+                max_datagram_size,
                 service,
                 router,
                 statistics,
+                anonymize,
+                tcp,
+                malformed,
+                budget,
+                proxy_protocol,
+                ..
             }: ServerStartOptions<T>,
         ) -> Result<(), anyhow::Error>
         where
@@ -299,22 +895,57 @@ mod tcp {
             let listener = TcpListener::bind(bind).await?;
             let local_addr = listener.local_addr()?;
 
+            let connections = Connections::new(tcp.max_connections, tcp.max_connections_per_ip);
+            let idle_timeout = (tcp.idle_timeout > 0).then(|| Duration::from_secs(tcp.idle_timeout));
+            let mut accepted = accept(listener, proxy_protocol, tcp.proxy_trusted, anonymize);
+
             tokio::spawn(async move {
-                // Accept all connections on the current listener, but exit the entire
-                // process when an error occurs.
-                while let Ok((socket, address)) = listener.accept().await {
+                while let Some((socket, address)) = accepted.recv().await {
+                    if malformed.is_banned(address.ip()) {
+                        continue;
+                    }
+
+                    // Connections over the limits are closed as soon as the socket is
+                    // dropped.
+                    let guard = match connections.acquire(address.ip()) {
+                        Some(it) => it,
+                        None => {
+                            log::warn!(
+                                "tcp socket refused, too many connections: addr={}, interface={:?}",
+                                anonymize.apply(address),
+                                local_addr,
+                            );
+
+                            continue;
+                        }
+                    };
+
                     let router = router.clone();
+                    let budget = budget.clone();
+                    let malformed = malformed.clone();
                     let reporter = statistics.get_reporter(Transport::TCP);
                     let mut receiver = router.get_receiver(address);
-                    let mut operationer = service.get_operationer(address, external);
+                    let mut operationer = service.get_operationer(address, external, {
+                        let mut transport = TransportContext::new(IngressTransport::Tcp, local_addr);
+                        transport.credential = credential;
+                        transport
+                    });
 
-                    log::info!("tcp socket accept: addr={:?}, interface={:?}", address, local_addr,);
+                    log::info!(
+                        "tcp socket accept: addr={}, interface={:?}",
+                        anonymize.apply(address),
+                        local_addr,
+                    );
 
                     // Disable the Nagle algorithm.
                     // because to maintain real-time, any received data should be processed
                     // as soon as possible.
                     if let Err(e) = socket.set_nodelay(true) {
-                        log::error!("tcp socket set nodelay failed!: addr={}, err={}", address, e);
+                        log::error!(
+                            "tcp socket set nodelay failed!: addr={}, err={}",
+                            anonymize.apply(address),
+                            e
+                        );
                     }
 
                     let session_addr = SessionAddr {
@@ -356,16 +987,41 @@ mod tcp {
 
                     let sessions = service.get_sessions();
                     tokio::spawn(async move {
-                        let mut buffer = ExchangeBuffer::default();
+                        let mut buffer = ExchangeBuffer::new(max_datagram_size, budget.clone());
+                        let mut reason = CloseReason::Disconnected;
+
+                        'a: loop {
+                            // Over the memory budget, an idle connection releases its
+                            // buffers while it waits for data.
+                            let read = async {
+                                if buffer.is_empty() && budget.is_exceeded() {
+                                    buffer.shrink();
+                                    reader.readable().await?;
+                                }
+
+                                buffer.grow();
+                                reader.read(&mut buffer).await
+                            };
+
+                            let ret = match idle_timeout {
+                                None => read.await,
+                                Some(duration) => match timeout(duration, read).await {
+                                    Ok(ret) => ret,
+                                    Err(_) => {
+                                        reason = CloseReason::IdleTimeout;
+                                        break;
+                                    }
+                                },
+                            };
 
-                        'a: while let Ok(size) = reader.read(&mut buffer).await {
                             // When the received message is 0, it means that the socket
                             // has been closed.
-                            if size == 0 {
-                                break;
-                            } else {
-                                reporter.send(&session_addr, &[Stats::ReceivedBytes(size as u32)]);
-                                buffer.advance(size);
+                            match ret {
+                                Ok(0) | Err(_) => break,
+                                Ok(size) => {
+                                    reporter.send(&session_addr, &[Stats::ReceivedBytes(size as u32)]);
+                                    buffer.advance(size);
+                                }
                             }
 
                             // The minimum length of a stun message will not be less
@@ -389,6 +1045,7 @@ mod tcp {
                                         // Limit the maximum length of messages to 2048, this is to prevent buffer
                                         // overflow attacks.
                                         if s > 2048 {
+                                            malformed.report(address);
                                             break 'a;
                                         }
 
@@ -402,7 +1059,18 @@ mod tcp {
                                     }
                                 };
 
+                                // The stream can not be framed after a packet that is not a stun message or
+                                // a plausible channel data.
                                 let chunk = buffer.split(size);
+                                if malformed.reject_class(address, chunk) {
+                                    break 'a;
+                                }
+
+                                if malformed.reject_fingerprint(address, chunk) {
+                                    continue;
+                                }
+
+                                let started = Instant::now();
                                 if let Ok(ret) = operationer.route(chunk, address).await {
                                     if let Some(res) = ret {
                                         if let Some(ref inerface) = res.endpoint {
@@ -412,6 +1080,8 @@ mod tcp {
                                                 res.relay.as_ref().unwrap_or(&address),
                                                 res.bytes,
                                             );
+
+                                            reporter.observe(res.method, started);
                                         } else {
                                             if writer.lock().await.write_all(res.bytes).await.is_err() {
                                                 break 'a;
@@ -422,6 +1092,7 @@ mod tcp {
                                                 &[Stats::SendBytes(res.bytes.len() as u32), Stats::SendPkts(1)],
                                             );
 
+                                            reporter.observe(res.method, started);
                                             if let ResponseMethod::Stun(method) = res.method {
                                                 if method.is_error() {
                                                     reporter.send(&session_addr, &[Stats::ErrorPkts(1)]);
@@ -430,20 +1101,26 @@ mod tcp {
                                         }
                                     }
                                 } else {
+                                    malformed.report(address);
                                     break 'a;
                                 }
                             }
                         }
 
-                        // When the tcp connection is closed, the procedure to close the session is
-                        // process directly once, avoiding the connection being disconnected
-                        // directly without going through the closing
-                        // process.
-                        sessions.refresh(&session_addr, 0);
+                        // When the tcp connection is closed or idle for too long, the session
+                        // is closed directly, avoiding the allocation being left behind
+                        // until it expires.
+                        sessions.remove_session(&session_addr, reason);
 
                         router.remove(&address);
-
-                        log::info!("tcp socket disconnect: addr={:?}, interface={:?}", address, local_addr);
+                        drop(guard);
+
+                        log::info!(
+                            "tcp socket disconnect: addr={}, interface={:?}, reason={}",
+                            anonymize.apply(address),
+                            local_addr,
+                            reason.as_str(),
+                        );
                     });
                 }
 
@@ -461,29 +1138,83 @@ mod tcp {
     }
 }
 
+/// Serve an udp interface with a socket that is not bound by the server.
+///
+/// The socket replaces the one that is bound for an udp interface of the
+/// configuration, the interface should not be configured as well.
+#[cfg(feature = "udp")]
+pub fn serve_datagram<T, S>(
+    socket: S,
+    external: SocketAddr,
+    statistics: &Statistics,
+    service: &Service<T>,
+    router: &Router,
+    malformed: &MalformedFilter,
+) -> anyhow::Result<()>
+where
+    T: Clone + Observer + 'static,
+    S: DatagramSocket,
+{
+    udp::serve(
+        std::sync::Arc::new(socket),
+        external,
+        udp::Limits {
+            credential: CredentialMechanism::LongTerm,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            ingress: Ingress::default(),
+        },
+        service.clone(),
+        router.clone(),
+        statistics.clone(),
+        malformed.clone(),
+    )?;
+
+    log::info!("turn server listening: external={}, transport=UDP", external);
+    Ok(())
+}
+
 /// start turn server.
 ///
 /// create a specified number of threads,
 /// each thread processes udp data separately.
-pub async fn start<T>(config: &Config, statistics: &Statistics, service: &Service<T>) -> anyhow::Result<()>
+pub async fn start<T>(
+    config: &Config,
+    statistics: &Statistics,
+    service: &Service<T>,
+    router: &Router,
+    malformed: &MalformedFilter,
+    budget: &MemoryBudget,
+    buffers: &SocketBuffers,
+) -> anyhow::Result<()>
 where
     T: Clone + Observer + 'static,
 {
     #[allow(unused)]
     use crate::config::Transport;
 
-    let router = Router::default();
     for Interface {
         transport,
         external,
         bind,
+        credential,
+        max_datagram_size,
+        proxy_protocol,
     } in config.turn.interfaces.iter().cloned()
     {
         #[allow(unused)]
         let options = ServerStartOptions {
+            anonymize: config.privacy.log,
+            tcp: config.tcp.clone(),
+            ingress: config.ingress,
+            malformed: malformed.clone(),
+            budget: budget.clone(),
+            buffers: buffers.clone(),
             statistics: statistics.clone(),
             service: service.clone(),
             router: router.clone(),
+            credential: credential.into(),
+            max_datagram_size,
+            proxy_protocol,
             external,
             bind,
         };
@@ -1,24 +1,42 @@
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use ahash::AHashMap;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use rand::{thread_rng, Rng};
 use stun::Transport;
-use turn::SessionAddr;
+use turn::{ResponseMethod, SessionAddr};
 
 /// [issue](https://github.com/mycrl/turn-rs/issues/101)
 ///
 /// Integrated Prometheus Metrics Exporter
 pub mod prometheus {
+    use std::time::Duration;
+
     use anyhow::Result;
     use once_cell::sync::Lazy;
-    use prometheus::{register_int_counter, register_int_gauge, Encoder, IntCounter, IntGauge, TextEncoder};
+    use prometheus::{
+        register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder,
+        HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    };
 
     use super::{Counts, Number, Stats};
 
-    use stun::Transport;
+    use stun::{Kind, Method, Transport};
+    use turn::ResponseMethod;
+
+    // Most requests are processed in microseconds, but requests that need to get
+    // the password from the hooks service can take up to the timeout of the http
+    // client.
+    const LATENCY_BUCKETS: &[f64] = &[
+        0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+    ];
 
     // The `register_int_counter` macro would be too long if written out in full,
     // with too many line breaks after formatting, and this is wrapped directly into
@@ -32,7 +50,7 @@ pub mod prometheus {
         };
     }
 
-    pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::default());
+    pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
 
     /// # Example
     ///
@@ -73,9 +91,30 @@ pub mod prometheus {
     /// Summarized metrics data for Global/TCP/UDP.
     pub struct Metrics {
         pub allocated: IntGauge,
+        pub port_capacity: IntGauge,
+        pub port_available: IntGauge,
+        pub port_exhausted: IntCounter,
+        pub stale_channels: IntCounter,
+        pub auth_failed: IntCounterVec,
+        pub malformed_packets: IntCounter,
+        pub banned_packets: IntCounter,
+        pub fingerprint_rejected: IntCounterVec,
+        pub discarded_packets: IntCounterVec,
+        pub allocate_refused: IntCounterVec,
+        pub anonymous_allocated: IntGauge,
+        pub anonymous_refused: IntCounter,
+        pub tagged_allocations: IntCounterVec,
+        pub country_allocations: IntCounterVec,
+        pub reflection_dropped: IntCounterVec,
+        pub oversize_dropped: IntCounterVec,
+        pub control_dropped: IntCounter,
+        pub socket_drops: IntCounterVec,
+        pub address_changes: IntCounterVec,
         pub total: Counts<IntCounter>,
         pub tcp: Counts<IntCounter>,
         pub udp: Counts<IntCounter>,
+        pub request_duration: HistogramVec,
+        pub relay_duration: HistogramVec,
     }
 
     impl Default for Metrics {
@@ -91,9 +130,150 @@ pub mod prometheus {
                 tcp: Counts::new("tcp")?,
                 udp: Counts::new("udp")?,
                 allocated: register_int_gauge!("allocated", "The number of allocated ports, count = 16383")?,
+                port_capacity: register_int_gauge!("port_capacity", "The total number of ports in the port pool")?,
+                port_available: register_int_gauge!("port_available", "The number of free ports in the port pool")?,
+                port_exhausted: register_int_counter!(
+                    "port_exhausted_total",
+                    "The number of allocate requests rejected because the port pool is exhausted"
+                )?,
+                stale_channels: register_int_counter!(
+                    "stale_channels_total",
+                    "The number of bound channels that no data was relayed over for the channel idle timeout"
+                )?,
+                auth_failed: register_int_counter_vec!(
+                    "auth_failed_total",
+                    "The number of requests whose credentials can not be verified",
+                    &["reason"]
+                )?,
+                malformed_packets: register_int_counter!(
+                    "malformed_packets_total",
+                    "The number of packets that can not be decoded"
+                )?,
+                banned_packets: register_int_counter!(
+                    "banned_packets_total",
+                    "The number of packets dropped because the source is banned"
+                )?,
+                discarded_packets: register_int_counter_vec!(
+                    "discarded_packets_total",
+                    "The number of packets discarded before decoding because they are not a stun message or a plausible channel data",
+                    &["class"]
+                )?,
+                fingerprint_rejected: register_int_counter_vec!(
+                    "fingerprint_rejected_total",
+                    "The number of stun messages dropped before decoding because their fingerprint is wrong or missing",
+                    &["reason"]
+                )?,
+                allocate_refused: register_int_counter_vec!(
+                    "allocate_refused_total",
+                    "The number of allocate requests refused because the server is overloaded or denies new sessions",
+                    &["reason"]
+                )?,
+                anonymous_allocated: register_int_gauge!(
+                    "anonymous_allocated",
+                    "The number of allocations without credentials from the trusted subnets"
+                )?,
+                anonymous_refused: register_int_counter!(
+                    "anonymous_refused_total",
+                    "The number of allocations without credentials refused over the quotas"
+                )?,
+                tagged_allocations: register_int_counter_vec!(
+                    "tagged_allocations_total",
+                    "The number of allocations by the value of the metrics label of the session tags",
+                    &["tag"]
+                )?,
+                country_allocations: register_int_counter_vec!(
+                    "country_allocations_total",
+                    "The number of allocations by the country of the client, see geoip.databases",
+                    &["country"]
+                )?,
+                reflection_dropped: register_int_counter_vec!(
+                    "reflection_dropped_total",
+                    "The number of requests and error responses dropped to prevent reflection",
+                    &["reason"]
+                )?,
+                oversize_dropped: register_int_counter_vec!(
+                    "oversize_dropped_total",
+                    "The number of datagrams dropped because they are larger than the maximum datagram size",
+                    &["transport", "direction"]
+                )?,
+                control_dropped: register_int_counter!(
+                    "control_dropped_total",
+                    "The number of stun messages dropped because the control queue of the udp receive task is full"
+                )?,
+                socket_drops: register_int_counter_vec!(
+                    "socket_drops_total",
+                    "The number of datagrams dropped by the kernel because the receive buffer of the udp socket is full",
+                    &["interface"]
+                )?,
+                address_changes: register_int_counter_vec!(
+                    "address_changes_total",
+                    "The number of sessions whose requests arrived from a new address while their allocation is on the previous one",
+                    &["result"]
+                )?,
+                request_duration: register_histogram_vec!(
+                    "request_duration_seconds",
+                    "The processing time of stun requests",
+                    &["transport", "method", "result"],
+                    LATENCY_BUCKETS.to_vec()
+                )?,
+                relay_duration: register_histogram_vec!(
+                    "relay_duration_seconds",
+                    "The forwarding time of relayed data",
+                    &["transport", "method"],
+                    LATENCY_BUCKETS.to_vec()
+                )?,
             })
         }
 
+        /// Record the time taken to process a message, from receiving it to
+        /// sending out the response or forwarding it to the peer.
+        ///
+        /// # Example
+        ///
+        /// ```
+        /// use std::time::Duration;
+        /// use stun::{Kind, Method, Transport};
+        /// use turn::ResponseMethod;
+        /// use turn_server::statistics::prometheus::*;
+        ///
+        /// let method = ResponseMethod::Stun(Method::Allocate(Kind::Error));
+        /// METRICS.observe(Transport::UDP, method, Duration::from_millis(1));
+        ///
+        /// let histogram = METRICS
+        ///     .request_duration
+        ///     .with_label_values(&["udp", "allocate", "error"]);
+        /// assert_eq!(histogram.get_sample_count(), 1);
+        /// ```
+        pub fn observe(&self, transport: Transport, method: ResponseMethod, elapsed: Duration) {
+            let transport = if transport == Transport::TCP { "tcp" } else { "udp" };
+            let (name, kind) = match method {
+                ResponseMethod::ChannelData => {
+                    self.relay_duration
+                        .with_label_values(&[transport, "channel_data"])
+                        .observe(elapsed.as_secs_f64());
+                    return;
+                }
+                ResponseMethod::Stun(Method::SendIndication | Method::DataIndication) => {
+                    self.relay_duration
+                        .with_label_values(&[transport, "indication"])
+                        .observe(elapsed.as_secs_f64());
+                    return;
+                }
+                ResponseMethod::Stun(Method::Binding(kind)) => ("binding", kind),
+                ResponseMethod::Stun(Method::Allocate(kind)) => ("allocate", kind),
+                ResponseMethod::Stun(Method::CreatePermission(kind)) => ("create_permission", kind),
+                ResponseMethod::Stun(Method::ChannelBind(kind)) => ("channel_bind", kind),
+                ResponseMethod::Stun(Method::Refresh(kind)) => ("refresh", kind),
+                ResponseMethod::Stun(method @ Method::Other(_)) => {
+                    ("other", if method.is_error() { Kind::Error } else { Kind::Response })
+                }
+            };
+
+            self.request_duration
+                .with_label_values(&[transport, name, if kind == Kind::Error { "error" } else { "success" }])
+                .observe(elapsed.as_secs_f64());
+        }
+
         /// # Example
         ///
         /// ```
@@ -153,6 +333,7 @@ impl Number for Count {
 }
 
 /// Worker independent statisticsing statistics
+#[derive(Default)]
 pub struct Counts<T> {
     pub received_bytes: T,
     pub send_bytes: T,
@@ -198,24 +379,96 @@ impl<T: Number> Counts<T> {
     }
 }
 
+impl Counts<Count> {
+    fn load(&self) -> Counts<u64> {
+        Counts {
+            received_bytes: self.received_bytes.get(),
+            received_pkts: self.received_pkts.get(),
+            send_bytes: self.send_bytes.get(),
+            send_pkts: self.send_pkts.get(),
+            error_pkts: self.error_pkts.get(),
+        }
+    }
+}
+
+/// The relayed bytes and packets, counted in both directions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    pub bytes: u64,
+    pub pkts: u64,
+}
+
+impl From<&Counts<Count>> for Traffic {
+    fn from(counts: &Counts<Count>) -> Self {
+        Self {
+            bytes: counts.received_bytes.get() + counts.send_bytes.get(),
+            pkts: counts.received_pkts.get() + counts.send_pkts.get(),
+        }
+    }
+}
+
+/// The average relay rate within a window, in bytes and packets per second.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub bytes: f64,
+    pub pkts: f64,
+}
+
+struct Sample {
+    time: Instant,
+    total: Traffic,
+    sessions: AHashMap<SessionAddr, Traffic>,
+}
+
 /// worker cluster statistics
 #[derive(Clone)]
-pub struct Statistics(Arc<RwLock<AHashMap<SessionAddr, Counts<Count>>>>);
+pub struct Statistics {
+    map: Arc<RwLock<AHashMap<SessionAddr, Counts<Count>>>>,
+    total: Arc<Counts<Count>>,
+    udp: Arc<Counts<Count>>,
+    tcp: Arc<Counts<Count>>,
+    // The traffic of the server and every session, sampled once per interval,
+    // the newest sample is at the back.
+    samples: Arc<Mutex<VecDeque<Sample>>>,
+    // The seconds between the samples.
+    interval: Arc<AtomicU64>,
+}
 
 impl Default for Statistics {
     #[cfg(feature = "api")]
     fn default() -> Self {
-        Self(Arc::new(RwLock::new(AHashMap::with_capacity(1024))))
+        Self {
+            map: Arc::new(RwLock::new(AHashMap::with_capacity(1024))),
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(16))),
+            interval: Arc::new(AtomicU64::new(Self::DEFAULT_INTERVAL)),
+            total: Default::default(),
+            udp: Default::default(),
+            tcp: Default::default(),
+        }
     }
 
     // There's no need to take up so much memory when you don't have stats enabled.
     #[cfg(not(feature = "api"))]
     fn default() -> Self {
-        Self(Default::default())
+        Self {
+            map: Default::default(),
+            samples: Default::default(),
+            interval: Arc::new(AtomicU64::new(Self::DEFAULT_INTERVAL)),
+            total: Default::default(),
+            udp: Default::default(),
+            tcp: Default::default(),
+        }
     }
 }
 
 impl Statistics {
+    const DEFAULT_INTERVAL: u64 = 60;
+
+    /// The number of samples that span the minutes.
+    fn steps(&self, minutes: usize) -> usize {
+        (minutes * 60).div_ceil(self.interval.load(Ordering::Relaxed).max(1) as usize)
+    }
+
     /// get signal sender
     ///
     /// The signal sender can notify the statisticsing instance to update
@@ -241,7 +494,12 @@ impl Statistics {
     /// ```
     pub fn get_reporter(&self, transport: Transport) -> StatisticsReporter {
         StatisticsReporter {
-            map: self.0.clone(),
+            map: self.map.clone(),
+            total: self.total.clone(),
+            transport_total: match transport {
+                Transport::UDP => self.udp.clone(),
+                Transport::TCP => self.tcp.clone(),
+            },
             transport,
         }
     }
@@ -271,7 +529,7 @@ impl Statistics {
             self::prometheus::METRICS.allocated.inc();
         }
 
-        self.0.write().insert(
+        self.map.write().insert(
             addr,
             Counts {
                 received_bytes: Count::default(),
@@ -311,7 +569,34 @@ impl Statistics {
             self::prometheus::METRICS.allocated.dec();
         }
 
-        self.0.write().remove(addr);
+        self.map.write().remove(addr);
+    }
+
+    /// Move the statistics of a session to its new address, see
+    /// `Sessions::rebind`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::*;
+    /// use turn_server::statistics::*;
+    ///
+    /// let statistics = Statistics::default();
+    /// let addr = |address: &str| SessionAddr {
+    ///     address: address.parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// statistics.register(addr("127.0.0.1:8080"));
+    /// statistics.rebind(&addr("127.0.0.1:8080"), addr("127.0.0.1:9090"));
+    /// assert_eq!(statistics.get(&addr("127.0.0.1:8080")).is_some(), false);
+    /// assert_eq!(statistics.get(&addr("127.0.0.1:9090")).is_some(), true);
+    /// ```
+    pub fn rebind(&self, from: &SessionAddr, to: SessionAddr) {
+        let mut map = self.map.write();
+        if let Some(counts) = map.remove(from) {
+            map.insert(to, counts);
+        }
     }
 
     /// Obtain a list of statistics from statisticsing
@@ -336,13 +621,186 @@ impl Statistics {
     /// assert_eq!(statistics.get(&addr).is_some(), true);
     /// ```
     pub fn get(&self, addr: &SessionAddr) -> Option<Counts<u64>> {
-        self.0.read().get(addr).map(|counts| Counts {
-            received_bytes: counts.received_bytes.get(),
-            received_pkts: counts.received_pkts.get(),
-            send_bytes: counts.send_bytes.get(),
-            send_pkts: counts.send_pkts.get(),
-            error_pkts: counts.error_pkts.get(),
-        })
+        self.map.read().get(addr).map(|counts| counts.load())
+    }
+
+    /// Get the statistics of the server, including the sessions that have
+    /// been closed.
+    pub fn total(&self) -> Counts<u64> {
+        self.total.load()
+    }
+
+    /// Get the statistics of the server on the transport, including the
+    /// sessions that have been closed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use stun::Transport;
+    /// use turn_server::statistics::*;
+    ///
+    /// let statistics = Statistics::default();
+    ///
+    /// assert_eq!(statistics.transport(Transport::UDP).received_bytes, 0);
+    /// assert_eq!(statistics.transport(Transport::TCP).send_pkts, 0);
+    /// ```
+    pub fn transport(&self, transport: Transport) -> Counts<u64> {
+        match transport {
+            Transport::UDP => self.udp.load(),
+            Transport::TCP => self.tcp.load(),
+        }
+    }
+
+    /// Take a sample of the traffic of the server and every session, only the
+    /// samples of the last 15 minutes are kept.
+    pub fn sample(&self) {
+        let sample = Sample {
+            time: Instant::now(),
+            total: Traffic::from(self.total.as_ref()),
+            sessions: self
+                .map
+                .read()
+                .iter()
+                .map(|(addr, counts)| (*addr, Traffic::from(counts)))
+                .collect(),
+        };
+
+        // Enough samples to look back 15 minutes from the newest sample.
+        let max = self.steps(15) + 1;
+        let mut samples = self.samples.lock();
+        while samples.len() >= max {
+            samples.pop_front();
+        }
+
+        samples.push_back(sample);
+    }
+
+    /// Sample the traffic at the interval in seconds, up to `jitter` seconds
+    /// are added at random to every interval.
+    pub fn start_sampler(&self, interval: u64, jitter: u64) {
+        let interval = interval.max(1);
+        self.interval.store(interval, Ordering::Relaxed);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let jitter = if jitter > 0 {
+                    thread_rng().gen_range(0..=jitter)
+                } else {
+                    0
+                };
+                tokio::time::sleep(Duration::from_secs(interval + jitter)).await;
+                this.sample();
+            }
+        });
+    }
+
+    /// Get the bytes relayed by each session within the last `minutes`
+    /// minutes of samples, sorted from the largest to the smallest.
+    ///
+    /// The bytes of sessions created within the window, or of all sessions if
+    /// there are not enough samples yet, are counted from the start of the
+    /// session.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::*;
+    /// use turn_server::statistics::*;
+    ///
+    /// let statistics = Statistics::default();
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// assert!(statistics.bandwidth(1).is_empty());
+    ///
+    /// statistics.register(addr.clone());
+    /// statistics.sample();
+    /// assert_eq!(statistics.bandwidth(1), vec![(addr, 0)]);
+    ///
+    /// statistics.unregister(&addr);
+    /// statistics.sample();
+    /// assert!(statistics.bandwidth(1).is_empty());
+    /// ```
+    pub fn bandwidth(&self, minutes: usize) -> Vec<(SessionAddr, u64)> {
+        let samples = self.samples.lock();
+        let newest = match samples.back() {
+            Some(it) => it,
+            None => return Vec::new(),
+        };
+
+        let baseline = samples
+            .len()
+            .checked_sub(self.steps(minutes) + 1)
+            .map(|index| &samples[index]);
+        let mut items = newest
+            .sessions
+            .iter()
+            .map(|(addr, traffic)| {
+                let base = baseline.and_then(|it| it.sessions.get(addr)).map(|it| it.bytes);
+                (*addr, traffic.bytes.saturating_sub(base.unwrap_or(0)))
+            })
+            .collect::<Vec<_>>();
+
+        items.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        items
+    }
+
+    /// Get the average relay rate of a session, or of the server if the
+    /// session is not specified, within the last `minutes` minutes of samples.
+    ///
+    /// If there are not enough samples yet, the rate is averaged from the
+    /// oldest sample. The rate is zero if there are less than two samples or
+    /// the session does not exist.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::*;
+    /// use turn_server::statistics::*;
+    ///
+    /// let statistics = Statistics::default();
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// statistics.register(addr.clone());
+    /// statistics.sample();
+    /// assert_eq!(statistics.rate(None, 1), Rate::default());
+    ///
+    /// statistics.sample();
+    /// assert_eq!(statistics.rate(Some(&addr), 5), Rate::default());
+    /// ```
+    pub fn rate(&self, addr: Option<&SessionAddr>, minutes: usize) -> Rate {
+        let samples = self.samples.lock();
+        if samples.len() < 2 {
+            return Rate::default();
+        }
+
+        let newest = &samples[samples.len() - 1];
+        let baseline = &samples[samples.len() - 1 - self.steps(minutes).min(samples.len() - 1)];
+        let elapsed = newest.time.duration_since(baseline.time).as_secs_f64();
+        if elapsed == 0.0 {
+            return Rate::default();
+        }
+
+        let (current, base) = match addr {
+            None => (newest.total, baseline.total),
+            Some(addr) => match newest.sessions.get(addr) {
+                Some(it) => (*it, baseline.sessions.get(addr).copied().unwrap_or_default()),
+                None => return Rate::default(),
+            },
+        };
+
+        Rate {
+            bytes: current.bytes.saturating_sub(base.bytes) as f64 / elapsed,
+            pkts: current.pkts.saturating_sub(base.pkts) as f64 / elapsed,
+        }
     }
 }
 
@@ -355,10 +813,27 @@ impl Statistics {
 #[allow(unused)]
 pub struct StatisticsReporter {
     map: Arc<RwLock<AHashMap<SessionAddr, Counts<Count>>>>,
+    total: Arc<Counts<Count>>,
+    transport_total: Arc<Counts<Count>>,
     transport: Transport,
 }
 
 impl StatisticsReporter {
+    /// Record the time taken to process a message, this only takes effect when
+    /// prometheus or statsd is enabled.
+    #[allow(unused_variables)]
+    pub fn observe(&self, method: ResponseMethod, started: Instant) {
+        #[cfg(feature = "prometheus")]
+        {
+            self::prometheus::METRICS.observe(self.transport, method, started.elapsed());
+        }
+
+        #[cfg(feature = "statsd")]
+        {
+            crate::statsd::TIMINGS.observe(self.transport, method, started.elapsed());
+        }
+    }
+
     #[allow(unused_variables)]
     pub fn send(&self, addr: &SessionAddr, reports: &[Stats]) {
         #[cfg(feature = "api")]
@@ -370,6 +845,11 @@ impl StatisticsReporter {
                 }
             }
 
+            for item in reports {
+                self.total.add(item);
+                self.transport_total.add(item);
+            }
+
             if let Some(counts) = self.map.read().get(addr) {
                 for item in reports {
                     counts.add(item);
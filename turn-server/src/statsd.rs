@@ -0,0 +1,306 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use stun::{Kind, Method, Transport};
+use tokio::net::{lookup_host, UdpSocket};
+use turn::{Observer, ResponseMethod, Service};
+
+use crate::{
+    config,
+    statistics::{Counts, Statistics},
+};
+
+// The payloads are kept within the usual path mtu, so that they are not
+// fragmented.
+const MAX_PAYLOAD_SIZE: usize = 1432;
+
+// The number of timings that are kept between two pushes, the timings beyond
+// it are sampled.
+const MAX_TIMINGS: u64 = 1024;
+
+pub static TIMINGS: Lazy<Timings> = Lazy::new(Timings::default);
+
+/// The time taken to process a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub transport: Transport,
+    pub method: &'static str,
+    pub error: bool,
+    pub elapsed: Duration,
+}
+
+/// The timings of the requests between two pushes.
+///
+/// At most 1024 timings are kept, the pushed timings carry the rate at which
+/// they were sampled, so that the agent can scale the counts of the
+/// timings.
+#[derive(Default)]
+pub struct Timings {
+    observed: AtomicU64,
+    samples: Mutex<Vec<Timing>>,
+}
+
+impl Timings {
+    /// Record the time taken to process a message, the relayed messages are
+    /// not timed.
+    pub fn observe(&self, transport: Transport, method: ResponseMethod, elapsed: Duration) {
+        let (method, kind) = match method {
+            ResponseMethod::ChannelData | ResponseMethod::Stun(Method::SendIndication | Method::DataIndication) => {
+                return;
+            }
+            ResponseMethod::Stun(Method::Binding(kind)) => ("binding", kind),
+            ResponseMethod::Stun(Method::Allocate(kind)) => ("allocate", kind),
+            ResponseMethod::Stun(Method::CreatePermission(kind)) => ("create_permission", kind),
+            ResponseMethod::Stun(Method::ChannelBind(kind)) => ("channel_bind", kind),
+            ResponseMethod::Stun(Method::Refresh(kind)) => ("refresh", kind),
+            ResponseMethod::Stun(method @ Method::Other(_)) => {
+                ("other", if method.is_error() { Kind::Error } else { Kind::Response })
+            }
+        };
+
+        if self.observed.fetch_add(1, Ordering::Relaxed) < MAX_TIMINGS {
+            self.samples.lock().push(Timing {
+                error: kind == Kind::Error,
+                transport,
+                method,
+                elapsed,
+            });
+        }
+    }
+
+    /// Take the timings since the last call, and the rate at which they
+    /// were sampled.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// use stun::{Kind, Method, Transport};
+    /// use turn::ResponseMethod;
+    /// use turn_server::statsd::*;
+    ///
+    /// let timings = Timings::default();
+    /// let elapsed = Duration::from_millis(1);
+    ///
+    /// timings.observe(Transport::UDP, ResponseMethod::Stun(Method::Allocate(Kind::Error)), elapsed);
+    /// timings.observe(Transport::UDP, ResponseMethod::ChannelData, elapsed);
+    ///
+    /// let (samples, rate) = timings.take();
+    /// assert_eq!(
+    ///     samples,
+    ///     vec![Timing {
+    ///         transport: Transport::UDP,
+    ///         method: "allocate",
+    ///         error: true,
+    ///         elapsed,
+    ///     }]
+    /// );
+    ///
+    /// assert_eq!(rate, 1.0);
+    /// assert!(timings.take().0.is_empty());
+    /// ```
+    pub fn take(&self) -> (Vec<Timing>, f64) {
+        let mut samples = self.samples.lock();
+        let observed = self.observed.swap(0, Ordering::Relaxed);
+        let rate = if observed > samples.len() as u64 {
+            samples.len() as f64 / observed as f64
+        } else {
+            1.0
+        };
+
+        (std::mem::take(&mut *samples), rate)
+    }
+}
+
+/// Encode a metric in the DogStatsD format.
+///
+/// # Example
+///
+/// ```
+/// use turn_server::statsd::encode;
+///
+/// let tags = vec!["env:test".to_string()];
+///
+/// assert_eq!(encode("turn", "sessions", 1, "g", 1.0, &tags, &[]), "turn.sessions:1|g|#env:test");
+/// assert_eq!(
+///     encode("turn", "request_duration", 0.5, "ms", 0.25, &[], &[("method", "allocate")]),
+///     "turn.request_duration:0.5|ms|@0.25|#method:allocate"
+/// );
+/// ```
+pub fn encode(
+    prefix: &str,
+    name: &str,
+    value: impl Display,
+    kind: &str,
+    rate: f64,
+    tags: &[String],
+    labels: &[(&str, &str)],
+) -> String {
+    let mut line = if prefix.is_empty() {
+        format!("{}:{}|{}", name, value, kind)
+    } else {
+        format!("{}.{}:{}|{}", prefix, name, value, kind)
+    };
+
+    if rate < 1.0 {
+        line.push_str(&format!("|@{}", rate));
+    }
+
+    let tags = tags
+        .iter()
+        .cloned()
+        .chain(labels.iter().map(|(k, v)| format!("{}:{}", k, v)))
+        .collect::<Vec<_>>();
+
+    if !tags.is_empty() {
+        line.push_str("|#");
+        line.push_str(&tags.join(","));
+    }
+
+    line
+}
+
+/// The metrics of one push, split into payloads that fit in a datagram.
+struct Payloads<'a> {
+    config: &'a config::Statsd,
+    payloads: Vec<String>,
+}
+
+impl<'a> Payloads<'a> {
+    fn new(config: &'a config::Statsd) -> Self {
+        Self {
+            payloads: vec![String::new()],
+            config,
+        }
+    }
+
+    fn push(&mut self, name: &str, value: impl Display, kind: &str, rate: f64, labels: &[(&str, &str)]) {
+        let line = encode(&self.config.prefix, name, value, kind, rate, &self.config.tags, labels);
+
+        // The last payload always exists.
+        let payload = self.payloads.last_mut().unwrap();
+        if !payload.is_empty() && payload.len() + line.len() + 1 > MAX_PAYLOAD_SIZE {
+            self.payloads.push(line);
+        } else {
+            if !payload.is_empty() {
+                payload.push('\n');
+            }
+
+            payload.push_str(&line);
+        }
+    }
+
+    fn counts(&mut self, counts: &Counts<u64>, previous: &Counts<u64>, transport: &str) {
+        let labels = [("transport", transport)];
+        for (name, value, previous) in [
+            ("received_bytes", counts.received_bytes, previous.received_bytes),
+            ("send_bytes", counts.send_bytes, previous.send_bytes),
+            ("received_pkts", counts.received_pkts, previous.received_pkts),
+            ("send_pkts", counts.send_pkts, previous.send_pkts),
+            ("error_pkts", counts.error_pkts, previous.error_pkts),
+        ] {
+            self.push(name, value.saturating_sub(previous), "c", 1.0, &labels);
+        }
+    }
+}
+
+/// Push the metrics to the statsd agent of the config.
+///
+/// The gauges of the sessions and the port pool, and the counters of the
+/// traffic of each transport since the last push, are pushed at the interval
+/// of the config, together with the timings of the requests that were
+/// processed since the last push. The metrics are only pushed when the
+/// address is set.
+pub async fn start<T>(config: config::Statsd, service: Service<T>, statistics: Statistics) -> Result<()>
+where
+    T: Clone + Observer + 'static,
+{
+    let Some(address) = &config.address else {
+        return Ok(());
+    };
+
+    let address = lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("statsd address is not resolved: {}", address))?;
+
+    let socket = UdpSocket::bind(if address.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    })
+    .await?;
+
+    socket.connect(address).await?;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        let mut previous = [Counts::default(), Counts::default()];
+
+        loop {
+            interval.tick().await;
+
+            let mut payloads = Payloads::new(&config);
+            let sessions = service.get_sessions();
+            let counters = sessions.counters();
+            for (name, value) in [
+                ("sessions", counters.sessions),
+                ("allocations", counters.allocated),
+                ("permissions", counters.permissions),
+                ("channels", counters.channels),
+                ("ports.capacity", sessions.capacity()),
+                ("ports.available", sessions.available()),
+            ] {
+                payloads.push(name, value, "g", 1.0, &[]);
+            }
+
+            for (i, (transport, name)) in [(Transport::UDP, "udp"), (Transport::TCP, "tcp")]
+                .into_iter()
+                .enumerate()
+            {
+                let counts = statistics.transport(transport);
+                payloads.counts(&counts, &previous[i], name);
+                previous[i] = counts;
+            }
+
+            let (timings, rate) = TIMINGS.take();
+            for timing in timings {
+                payloads.push(
+                    "request_duration",
+                    timing.elapsed.as_secs_f64() * 1000.0,
+                    "ms",
+                    rate,
+                    &[
+                        (
+                            "transport",
+                            if timing.transport == Transport::TCP {
+                                "tcp"
+                            } else {
+                                "udp"
+                            },
+                        ),
+                        ("method", timing.method),
+                        ("result", if timing.error { "error" } else { "success" }),
+                    ],
+                );
+            }
+
+            for payload in payloads.payloads {
+                if let Err(e) = socket.send(payload.as_bytes()).await {
+                    log::warn!("failed to push statsd metrics: address={}, err={}", address, e);
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use ahash::{AHashMap, AHashSet};
+use parking_lot::{Mutex, RwLock};
+use turn::{SessionAddr, SessionTags};
+
+use crate::config::Tags;
+
+struct Inner {
+    config: Tags,
+    sessions: RwLock<AHashMap<SessionAddr, SessionTags>>,
+    label_values: Mutex<AHashSet<String>>,
+}
+
+/// The tags that the auth backends attach to the sessions.
+///
+/// The tags are kept within the limits of the config when the session is
+/// authenticated, and are kept until the session is closed, so that the
+/// events of the session carry them.
+#[derive(Clone)]
+pub struct TagStore(Arc<Inner>);
+
+impl TagStore {
+    pub fn new(config: Tags) -> Self {
+        Self(Arc::new(Inner {
+            sessions: Default::default(),
+            label_values: Default::default(),
+            config,
+        }))
+    }
+
+    /// Drop the tags beyond the limits of the config.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::{config::Tags, tags::TagStore};
+    ///
+    /// let store = TagStore::new(Tags {
+    ///     max_tags: 2,
+    ///     max_length: 8,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// let tags = store.limit(
+    ///     [("a", "1"), ("b", "a long value"), ("c", "3"), ("d", "4")]
+    ///         .into_iter()
+    ///         .map(|(k, v)| (k.to_string(), v.to_string()))
+    ///         .collect(),
+    /// );
+    ///
+    /// assert_eq!(tags.keys().collect::<Vec<_>>(), ["a", "c"]);
+    /// ```
+    pub fn limit(&self, tags: SessionTags) -> SessionTags {
+        let max = self.0.config.max_length;
+
+        tags.into_iter()
+            .filter(|(k, v)| !k.is_empty() && k.len() <= max && v.len() <= max)
+            .take(self.0.config.max_tags)
+            .collect()
+    }
+
+    /// Keep the tags of the session until it is closed.
+    pub fn insert(&self, addr: &SessionAddr, tags: &SessionTags) {
+        if !tags.is_empty() {
+            self.0.sessions.write().insert(*addr, tags.clone());
+        }
+    }
+
+    /// The tags of the session, there are no tags for the sessions that the
+    /// auth backends did not tag.
+    pub fn get(&self, addr: &SessionAddr) -> SessionTags {
+        self.0.sessions.read().get(addr).cloned().unwrap_or_default()
+    }
+
+    pub fn remove(&self, addr: &SessionAddr) -> SessionTags {
+        self.0.sessions.write().remove(addr).unwrap_or_default()
+    }
+
+    /// The value of the metrics label of the tags, returns `None` if there
+    /// is no metrics label or the tags do not have it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn::SessionTags;
+    /// use turn_server::{config::Tags, tags::TagStore};
+    ///
+    /// let store = TagStore::new(Tags {
+    ///     metrics_label: Some("tenant".to_string()),
+    ///     max_label_values: 1,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// let tenant = |it: &str| SessionTags::from([("tenant".to_string(), it.to_string())]);
+    ///
+    /// assert_eq!(store.label(&tenant("acme")).as_deref(), Some("acme"));
+    /// assert_eq!(store.label(&tenant("acme")).as_deref(), Some("acme"));
+    /// assert_eq!(store.label(&tenant("other-tenant")).as_deref(), Some("other"));
+    /// assert_eq!(store.label(&SessionTags::new()), None);
+    /// ```
+    pub fn label(&self, tags: &SessionTags) -> Option<String> {
+        let value = tags.get(self.0.config.metrics_label.as_ref()?)?;
+
+        // The number of series of the metrics is bounded by the number of the
+        // distinct values, the values beyond the limit share a single series.
+        let mut values = self.0.label_values.lock();
+        if values.contains(value) {
+            return Some(value.clone());
+        }
+
+        if values.len() < self.0.config.max_label_values {
+            values.insert(value.clone());
+            return Some(value.clone());
+        }
+
+        Some("other".to_string())
+    }
+}
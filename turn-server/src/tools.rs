@@ -0,0 +1,896 @@
+use std::{net::SocketAddr, time::Duration};
+
+use clap::Subcommand;
+
+/// The tools that are run instead of the server.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Decode and print stun messages
+    ///
+    /// Example: turn-server decode 000100002112a4420102030405060708090a0b0c
+    Decode {
+        /// The messages in hex, spaces and colons are ignored, the messages
+        /// are read from stdin, one per line, if none are given
+        messages: Vec<String>,
+        /// Decode the udp packets of a pcap file instead
+        ///
+        /// Example: --pcap capture.pcap
+        #[arg(long)]
+        pcap: Option<String>,
+    },
+    /// Send a binding request and print the reflexive address
+    ///
+    /// Example: turn-server binding-test 127.0.0.1:3478
+    BindingTest {
+        /// The address of the stun server
+        server: SocketAddr,
+        /// The timeout of each response in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        timeout: u64,
+    },
+    /// Allocate two relays, and send data from one to the other and back
+    ///
+    /// Example: turn-server allocate-test 127.0.0.1:3478 --username test
+    /// --password test
+    AllocateTest {
+        /// The address of the turn server
+        server: SocketAddr,
+        /// The username of the long-term credential
+        #[arg(long)]
+        username: String,
+        /// The password of the long-term credential
+        #[arg(long)]
+        password: String,
+        /// The timeout of each response in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        timeout: u64,
+    },
+    /// Simulate concurrent clients that relay media to each other
+    ///
+    /// Example: turn-server bench 127.0.0.1:3478 --username test --password
+    /// test --clients 100
+    Bench {
+        /// The address of the turn server
+        server: SocketAddr,
+        /// The username of the long-term credential
+        #[arg(long)]
+        username: String,
+        /// The password of the long-term credential
+        #[arg(long)]
+        password: String,
+        /// The number of clients, the clients are paired, so an odd number
+        /// is rounded up
+        #[arg(long, default_value_t = 10)]
+        clients: usize,
+        /// The number of packets per second sent by each client
+        #[arg(long, default_value_t = 50)]
+        rate: u64,
+        /// The payload size of the packets in bytes
+        #[arg(long, default_value_t = 160)]
+        size: usize,
+        /// The duration of the media in seconds
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+        /// The timeout of each response in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        timeout: u64,
+    },
+}
+
+pub async fn run(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Decode { messages, pcap } => decode::run(messages, pcap),
+        Command::BindingTest { server, timeout } => probe::binding_test(server, timeout).await,
+        Command::AllocateTest {
+            server,
+            username,
+            password,
+            timeout,
+        } => probe::allocate_test(server, &username, &password, timeout).await,
+        Command::Bench {
+            server,
+            username,
+            password,
+            clients,
+            rate,
+            size,
+            duration,
+            timeout,
+        } => {
+            bench::run(bench::Options {
+                timeout: Duration::from_millis(timeout),
+                duration: Duration::from_secs(duration),
+                clients: clients.div_ceil(2) * 2,
+                rate: rate.max(1),
+                size: size.max(bench::HEADER_SIZE),
+                username,
+                password,
+                server,
+            })
+            .await
+        }
+    }
+}
+
+pub mod probe {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::{anyhow, ensure};
+    use bytes::BytesMut;
+    use rand::{thread_rng, RngCore};
+    use stun::{
+        attribute::{
+            ChannelNumber, Data, ErrorCode, ErrorKind, Lifetime, Nonce, Realm, ReqeestedTransport, Transport, UserName,
+            XorMappedAddress, XorPeerAddress, XorRelayedAddress,
+        },
+        ChannelData, Decoder, Kind, MessageReader, MessageWriter, Method, Payload,
+    };
+    use tokio::{net::UdpSocket, time::timeout};
+
+    #[derive(Default)]
+    struct Credential {
+        username: String,
+        realm: String,
+        nonce: String,
+        digest: [u8; 16],
+    }
+
+    /// A minimal turn client over udp.
+    pub struct Client {
+        socket: Arc<UdpSocket>,
+        server: SocketAddr,
+        timeout: Duration,
+        token: [u8; 12],
+        decoder: Decoder,
+        send_bytes: BytesMut,
+        recv_bytes: Vec<u8>,
+        credential: Credential,
+    }
+
+    impl Client {
+        pub async fn new(server: SocketAddr, timeout: Duration) -> anyhow::Result<Self> {
+            let bind: SocketAddr = if server.is_ipv4() {
+                "0.0.0.0:0".parse()?
+            } else {
+                "[::]:0".parse()?
+            };
+
+            let socket = UdpSocket::bind(bind).await?;
+            socket.connect(server).await?;
+
+            Ok(Self {
+                socket: Arc::new(socket),
+                send_bytes: BytesMut::with_capacity(1500),
+                recv_bytes: vec![0u8; 1500],
+                decoder: Decoder::default(),
+                credential: Credential::default(),
+                token: [0u8; 12],
+                timeout,
+                server,
+            })
+        }
+
+        /// Write a new message with a new transaction id, the credential is
+        /// appended if the client is authenticated.
+        async fn request(
+            &mut self,
+            method: Method,
+            append: impl FnOnce(&mut MessageWriter<'_>),
+        ) -> anyhow::Result<MessageReader<'_>> {
+            thread_rng().fill_bytes(&mut self.token);
+
+            {
+                let mut message = MessageWriter::new(method, &self.token, &mut self.send_bytes);
+                append(&mut message);
+
+                if self.credential.username.is_empty() {
+                    message.flush(None)?;
+                } else {
+                    message.append::<UserName>(&self.credential.username);
+                    message.append::<Realm>(&self.credential.realm);
+                    message.append::<Nonce>(&self.credential.nonce);
+                    message.flush(Some(&self.credential.digest))?;
+                }
+            }
+
+            self.socket.send(&self.send_bytes).await?;
+            self.recv().await
+        }
+
+        async fn recv(&mut self) -> anyhow::Result<MessageReader<'_>> {
+            let size = timeout(self.timeout, self.socket.recv(&mut self.recv_bytes))
+                .await
+                .map_err(|_| anyhow!("timeout"))??;
+
+            match self.decoder.decode(&self.recv_bytes[..size])? {
+                Payload::Message(message) => Ok(message),
+                Payload::ChannelData(_) => Err(anyhow!("unexpected channel data")),
+            }
+        }
+
+        fn peer(&self, port: u16) -> SocketAddr {
+            SocketAddr::new(self.server.ip(), port)
+        }
+
+        /// Returns the reflexive address.
+        pub async fn binding(&mut self) -> anyhow::Result<SocketAddr> {
+            let message = self.request(Method::Binding(Kind::Request), |_| ()).await?;
+
+            ensure!(message.method == Method::Binding(Kind::Response), "binding failed");
+            message
+                .get::<XorMappedAddress>()
+                .ok_or_else(|| anyhow!("no XOR-MAPPED-ADDRESS in the response"))
+        }
+
+        /// Returns the relayed address, the nonce and the realm of the first
+        /// 401 response are used for the credential.
+        pub async fn allocate(&mut self, username: &str, password: &str) -> anyhow::Result<SocketAddr> {
+            let append = |message: &mut MessageWriter<'_>| {
+                message.append::<ReqeestedTransport>(Transport::UDP);
+            };
+
+            let (realm, nonce) = {
+                let message = self.request(Method::Allocate(Kind::Request), append).await?;
+                let error = message
+                    .get::<ErrorCode>()
+                    .ok_or_else(|| anyhow!("the server does not require authentication"))?;
+
+                ensure!(
+                    error.code == ErrorKind::Unauthorized as u16,
+                    "allocate failed: {} {}",
+                    code(error.code),
+                    error.message
+                );
+
+                (
+                    message.get::<Realm>().unwrap_or_default().to_string(),
+                    message.get::<Nonce>().unwrap_or_default().to_string(),
+                )
+            };
+
+            self.credential = Credential {
+                digest: stun::util::long_term_credential_digest(username, password, &realm),
+                username: username.to_string(),
+                realm,
+                nonce,
+            };
+
+            let digest = self.credential.digest;
+            let message = self.request(Method::Allocate(Kind::Request), append).await?;
+            if let Some(error) = message.get::<ErrorCode>() {
+                return Err(anyhow!("allocate failed: {} {}", code(error.code), error.message));
+            }
+
+            message.integrity(&digest)?;
+            message
+                .get::<XorRelayedAddress>()
+                .ok_or_else(|| anyhow!("no XOR-RELAYED-ADDRESS in the response"))
+        }
+
+        pub async fn create_permission(&mut self, port: u16) -> anyhow::Result<()> {
+            let peer = self.peer(port);
+            let message = self
+                .request(Method::CreatePermission(Kind::Request), |message| {
+                    message.append::<XorPeerAddress>(peer);
+                })
+                .await?;
+
+            if let Some(error) = message.get::<ErrorCode>() {
+                return Err(anyhow!(
+                    "create permission failed: {} {}",
+                    code(error.code),
+                    error.message
+                ));
+            }
+
+            Ok(())
+        }
+
+        pub async fn send(&mut self, port: u16, data: &[u8]) -> anyhow::Result<()> {
+            thread_rng().fill_bytes(&mut self.token);
+
+            let peer = self.peer(port);
+            {
+                let mut message = MessageWriter::new(Method::SendIndication, &self.token, &mut self.send_bytes);
+                message.append::<XorPeerAddress>(peer);
+                message.append::<Data>(data);
+                message.flush(None)?;
+            }
+
+            self.socket.send(&self.send_bytes).await?;
+            Ok(())
+        }
+
+        pub async fn recv_data(&mut self) -> anyhow::Result<Vec<u8>> {
+            let message = self.recv().await?;
+
+            ensure!(message.method == Method::DataIndication, "not a data indication");
+            message
+                .get::<Data>()
+                .map(|it| it.to_vec())
+                .ok_or_else(|| anyhow!("no DATA in the indication"))
+        }
+
+        pub async fn channel_bind(&mut self, port: u16, channel: u16) -> anyhow::Result<()> {
+            let peer = self.peer(port);
+            let message = self
+                .request(Method::ChannelBind(Kind::Request), |message| {
+                    message.append::<ChannelNumber>(channel);
+                    message.append::<XorPeerAddress>(peer);
+                })
+                .await?;
+
+            if let Some(error) = message.get::<ErrorCode>() {
+                return Err(anyhow!("channel bind failed: {} {}", code(error.code), error.message));
+            }
+
+            Ok(())
+        }
+
+        /// Encode channel data into the send buffer, and return the encoded
+        /// bytes.
+        pub fn channel_data(&mut self, number: u16, bytes: &[u8]) -> &[u8] {
+            ChannelData { number, bytes }.encode(&mut self.send_bytes);
+            &self.send_bytes
+        }
+
+        /// The socket connected to the server, for sending and receiving
+        /// channel data without the client.
+        pub fn socket(&self) -> Arc<UdpSocket> {
+            self.socket.clone()
+        }
+
+        /// Release the allocation.
+        pub async fn release(&mut self) -> anyhow::Result<()> {
+            let message = self
+                .request(Method::Refresh(Kind::Request), |message| {
+                    message.append::<Lifetime>(0);
+                })
+                .await?;
+
+            if let Some(error) = message.get::<ErrorCode>() {
+                return Err(anyhow!("refresh failed: {} {}", code(error.code), error.message));
+            }
+
+            Ok(())
+        }
+    }
+
+    // The error code is encoded as the class and the number.
+    fn code(code: u16) -> u16 {
+        (code >> 8) * 100 + (code & 0xff)
+    }
+
+    fn report(step: &str, started: Instant, detail: impl std::fmt::Display) {
+        println!(
+            "{:<20} {:>8.2}ms  {}",
+            step,
+            started.elapsed().as_secs_f64() * 1000.0,
+            detail
+        );
+    }
+
+    pub async fn binding_test(server: SocketAddr, timeout: u64) -> anyhow::Result<()> {
+        let mut client = Client::new(server, Duration::from_millis(timeout)).await?;
+
+        let started = Instant::now();
+        let mapped = client.binding().await?;
+        report("binding", started, format!("reflexive={}", mapped));
+
+        Ok(())
+    }
+
+    pub async fn allocate_test(server: SocketAddr, username: &str, password: &str, timeout: u64) -> anyhow::Result<()> {
+        let timeout = Duration::from_millis(timeout);
+        let mut a = Client::new(server, timeout).await?;
+        let mut b = Client::new(server, timeout).await?;
+
+        let started = Instant::now();
+        let relay_a = a.allocate(username, password).await?;
+        let relay_b = b.allocate(username, password).await?;
+        report("allocate", started, format!("relays={}, {}", relay_a, relay_b));
+
+        let started = Instant::now();
+        a.create_permission(relay_b.port()).await?;
+        b.create_permission(relay_a.port()).await?;
+        report("create permission", started, "ok");
+
+        let payload = b"turn-server allocate-test";
+
+        let started = Instant::now();
+        a.send(relay_b.port(), payload).await?;
+        ensure!(b.recv_data().await? == payload, "the relayed data does not match");
+        b.send(relay_a.port(), payload).await?;
+        ensure!(a.recv_data().await? == payload, "the relayed data does not match");
+        report("round trip", started, format!("bytes={}", payload.len()));
+
+        let started = Instant::now();
+        a.release().await?;
+        b.release().await?;
+        report("release", started, "ok");
+
+        Ok(())
+    }
+}
+
+pub mod decode {
+    use std::{
+        fs::read,
+        io::stdin,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    };
+
+    use anyhow::anyhow;
+    use serde_json::{json, Value};
+    use stun::{Decoder, Payload};
+
+    /// Render a stun message or channel data as json.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::tools::decode::render;
+    ///
+    /// let value = render(&[0x40, 0x00, 0x00, 0x02, 0x01, 0x02, 0x00, 0x00]).unwrap();
+    /// assert_eq!(value, serde_json::json!({ "channel": 0x4000, "size": 2 }));
+    ///
+    /// let value = render(&[
+    ///     0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42, 0x72, 0x6d, 0x49, 0x42,
+    ///     0x72, 0x52, 0x64, 0x48, 0x57, 0x62, 0x4b, 0x2b,
+    /// ])
+    /// .unwrap();
+    ///
+    /// assert_eq!(value["method"], "binding");
+    /// assert_eq!(value["class"], "request");
+    /// assert!(render(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    /// ```
+    pub fn render(bytes: &[u8]) -> anyhow::Result<Value> {
+        if bytes.len() < 4 {
+            return Err(anyhow!("too short"));
+        }
+
+        let mut decoder = Decoder::default();
+        Ok(match decoder.decode(bytes)? {
+            Payload::Message(message) => serde_json::to_value(&message)?,
+            // The data can be followed by padding, so the size is taken from the
+            // header.
+            Payload::ChannelData(data) => json!({
+                "channel": data.number,
+                "size": u16::from_be_bytes([bytes[2], bytes[3]]),
+            }),
+        })
+    }
+
+    /// Parse a message in hex, spaces and colons are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::tools::decode::parse_hex;
+    ///
+    /// assert_eq!(parse_hex("00:01 ff").unwrap(), vec![0x00, 0x01, 0xff]);
+    /// assert!(parse_hex("0").is_err());
+    /// assert!(parse_hex("zz").is_err());
+    /// ```
+    pub fn parse_hex(value: &str) -> anyhow::Result<Vec<u8>> {
+        let digits = value
+            .chars()
+            .filter(|it| !it.is_whitespace() && *it != ':')
+            .collect::<Vec<_>>();
+
+        if digits.len() % 2 != 0 {
+            return Err(anyhow!("odd number of hex digits"));
+        }
+
+        digits
+            .chunks(2)
+            .map(|it| {
+                let byte = it.iter().collect::<String>();
+                u8::from_str_radix(&byte, 16).map_err(|_| anyhow!("invalid hex: {}", byte))
+            })
+            .collect()
+    }
+
+    /// A udp packet read from a capture.
+    #[derive(Debug, PartialEq, Eq)]
+    pub struct Packet<'a> {
+        pub source: SocketAddr,
+        pub destination: SocketAddr,
+        pub payload: &'a [u8],
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+    }
+
+    /// Get the udp packet in an ip packet, other protocols are skipped.
+    fn udp(ip: &[u8]) -> Option<Packet<'_>> {
+        let (source, destination, udp) = match ip.first()? >> 4 {
+            4 => {
+                let size = ((ip[0] & 0x0f) as usize) * 4;
+                if *ip.get(9)? != 17 {
+                    return None;
+                }
+
+                let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+                let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+                (
+                    IpAddr::V4(Ipv4Addr::from(source)),
+                    IpAddr::V4(Ipv4Addr::from(destination)),
+                    ip.get(size..)?,
+                )
+            }
+            6 => {
+                // Extension headers are not followed.
+                if *ip.get(6)? != 17 {
+                    return None;
+                }
+
+                let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+                let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+                (
+                    IpAddr::V6(Ipv6Addr::from(source)),
+                    IpAddr::V6(Ipv6Addr::from(destination)),
+                    ip.get(40..)?,
+                )
+            }
+            _ => return None,
+        };
+
+        let size = (u16_at(udp, 4)? as usize).min(udp.len());
+        Some(Packet {
+            source: SocketAddr::new(source, u16_at(udp, 0)?),
+            destination: SocketAddr::new(destination, u16_at(udp, 2)?),
+            payload: udp.get(8..size)?,
+        })
+    }
+
+    /// Get the ip packet in a frame of the link type of the capture.
+    fn ip(link_type: u32, frame: &[u8]) -> Option<&[u8]> {
+        match link_type {
+            // BSD loopback
+            0 => frame.get(4..),
+            // Ethernet, with an optional 802.1Q tag.
+            1 => match u16_at(frame, 12)? {
+                0x8100 => frame.get(18..),
+                _ => frame.get(14..),
+            },
+            // Raw ip
+            101 => Some(frame),
+            // Linux cooked capture v1 and v2
+            113 => frame.get(16..),
+            276 => frame.get(20..),
+            _ => None,
+        }
+    }
+
+    /// Read the udp packets of a pcap file, only the classic pcap format is
+    /// supported.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use turn_server::tools::decode::udp_packets;
+    ///
+    /// let mut pcap = vec![
+    ///     0xd4, 0xc3, 0xb2, 0xa1, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ///     0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x65, 0x00, 0x00, 0x00,
+    /// ];
+    ///
+    /// let packet = [
+    ///     0x45, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x00, 0x40, 0x11, 0x00, 0x00,
+    ///     0x7f, 0x00, 0x00, 0x01, 0x7f, 0x00, 0x00, 0x02, 0x1f, 0x90, 0x0d, 0x96,
+    ///     0x00, 0x0a, 0x00, 0x00, 0x01, 0x02,
+    /// ];
+    ///
+    /// pcap.extend_from_slice(&[0; 8]);
+    /// pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    /// pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    /// pcap.extend_from_slice(&packet);
+    ///
+    /// let packets = udp_packets(&pcap).unwrap();
+    /// assert_eq!(packets.len(), 1);
+    /// assert_eq!(packets[0].source, "127.0.0.1:8080".parse().unwrap());
+    /// assert_eq!(packets[0].destination, "127.0.0.2:3478".parse().unwrap());
+    /// assert_eq!(packets[0].payload, &[0x01, 0x02]);
+    /// ```
+    pub fn udp_packets(pcap: &[u8]) -> anyhow::Result<Vec<Packet<'_>>> {
+        let magic = pcap.get(0..4).ok_or_else(|| anyhow!("not a pcap file"))?;
+
+        // The microsecond and the nanosecond formats only differ in the
+        // timestamps.
+        let read_u32: fn(&[u8]) -> u32 = match magic {
+            [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => |it| u32::from_le_bytes(it.try_into().unwrap()),
+            [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => |it| u32::from_be_bytes(it.try_into().unwrap()),
+            _ => return Err(anyhow!("not a pcap file, pcapng is not supported")),
+        };
+
+        let link_type = read_u32(pcap.get(20..24).ok_or_else(|| anyhow!("truncated pcap header"))?);
+
+        let mut packets = Vec::new();
+        let mut offset = 24;
+        while let Some(header) = pcap.get(offset..offset + 16) {
+            let size = read_u32(&header[8..12]) as usize;
+            let frame = pcap
+                .get(offset + 16..offset + 16 + size)
+                .ok_or_else(|| anyhow!("truncated pcap record"))?;
+
+            if let Some(packet) = ip(link_type, frame).and_then(udp) {
+                packets.push(packet);
+            }
+
+            offset += 16 + size;
+        }
+
+        Ok(packets)
+    }
+
+    fn print(value: &Value) -> anyhow::Result<()> {
+        println!("{}", serde_json::to_string_pretty(value)?);
+        Ok(())
+    }
+
+    pub fn run(messages: Vec<String>, pcap: Option<String>) -> anyhow::Result<()> {
+        if let Some(path) = pcap {
+            let pcap = read(&path)?;
+
+            // Packets of other protocols on the same ports are skipped.
+            for (index, packet) in udp_packets(&pcap)?.iter().enumerate() {
+                if let Ok(mut value) = render(packet.payload) {
+                    value["packet"] = json!(index + 1);
+                    value["source"] = json!(packet.source.to_string());
+                    value["destination"] = json!(packet.destination.to_string());
+                    print(&value)?;
+                }
+            }
+
+            return Ok(());
+        }
+
+        let messages = if messages.is_empty() {
+            stdin().lines().collect::<Result<Vec<_>, _>>()?
+        } else {
+            messages
+        };
+
+        for message in messages.iter().filter(|it| !it.trim().is_empty()) {
+            match parse_hex(message).and_then(|it| render(&it)) {
+                Ok(value) => print(&value)?,
+                Err(e) => eprintln!("invalid message: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub mod bench {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use anyhow::anyhow;
+    use stun::{ChannelData, Decoder, Payload};
+    use tokio::{
+        net::UdpSocket,
+        task::JoinSet,
+        time::{interval, timeout, MissedTickBehavior},
+    };
+
+    use super::probe::Client;
+
+    /// The payload starts with the sequence number and the send time.
+    pub const HEADER_SIZE: usize = 16;
+
+    const CHANNEL: u16 = 0x4000;
+
+    pub struct Options {
+        pub server: SocketAddr,
+        pub username: String,
+        pub password: String,
+        pub clients: usize,
+        pub rate: u64,
+        pub size: usize,
+        pub duration: Duration,
+        pub timeout: Duration,
+    }
+
+    #[derive(Default)]
+    struct Report {
+        setup: Vec<Duration>,
+        latency: Vec<Duration>,
+        sent: u64,
+        received: u64,
+    }
+
+    /// The value at the percentile of the sorted values.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use turn_server::tools::bench::percentile;
+    ///
+    /// let values = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+    ///
+    /// assert_eq!(percentile(&values, 0.5), Duration::from_millis(51));
+    /// assert_eq!(percentile(&values, 0.99), Duration::from_millis(99));
+    /// assert_eq!(percentile(&values, 1.0), Duration::from_millis(100));
+    /// assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    /// ```
+    pub fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+        if sorted.is_empty() {
+            return Duration::ZERO;
+        }
+
+        let index = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+        sorted[index]
+    }
+
+    /// Allocate a pair of clients that are bound to each other by a channel.
+    async fn setup(options: &Options) -> anyhow::Result<(Client, Client, Duration)> {
+        let started = Instant::now();
+
+        let mut a = Client::new(options.server, options.timeout).await?;
+        let mut b = Client::new(options.server, options.timeout).await?;
+        let relay_a = a.allocate(&options.username, &options.password).await?;
+        let relay_b = b.allocate(&options.username, &options.password).await?;
+
+        a.channel_bind(relay_b.port(), CHANNEL).await?;
+        b.channel_bind(relay_a.port(), CHANNEL).await?;
+
+        Ok((a, b, started.elapsed()))
+    }
+
+    /// Send channel data at the rate until the deadline, the payload carries
+    /// the sequence number and the time since the epoch.
+    async fn send(
+        mut client: Client,
+        options: Arc<Options>,
+        epoch: Instant,
+        deadline: Instant,
+    ) -> anyhow::Result<(Client, u64)> {
+        let mut payload = vec![0u8; options.size];
+        let mut ticker = interval(Duration::from_secs(1) / options.rate as u32);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+        let mut sequence = 0u64;
+        while Instant::now() < deadline {
+            ticker.tick().await;
+
+            payload[0..8].copy_from_slice(&sequence.to_be_bytes());
+            payload[8..16].copy_from_slice(&(epoch.elapsed().as_nanos() as u64).to_be_bytes());
+
+            let bytes = client.channel_data(CHANNEL, &payload).to_vec();
+            client.socket().send(&bytes).await?;
+            sequence += 1;
+        }
+
+        Ok((client, sequence))
+    }
+
+    /// Receive channel data until the deadline and a grace period for the
+    /// packets in flight.
+    async fn recv(socket: Arc<UdpSocket>, epoch: Instant, deadline: Instant, grace: Duration) -> Vec<Duration> {
+        let mut latency = Vec::new();
+        let mut decoder = Decoder::default();
+        let mut bytes = vec![0u8; 2048];
+
+        loop {
+            let remaining = (deadline + grace).saturating_duration_since(Instant::now());
+            let size = match timeout(remaining, socket.recv(&mut bytes)).await {
+                Ok(Ok(size)) => size,
+                _ => break,
+            };
+
+            if let Ok(Payload::ChannelData(ChannelData { bytes, .. })) = decoder.decode(&bytes[..size]) {
+                if let Some(sent) = bytes.get(8..16) {
+                    let sent = Duration::from_nanos(u64::from_be_bytes(sent.try_into().unwrap()));
+                    latency.push(epoch.elapsed().saturating_sub(sent));
+                }
+            }
+        }
+
+        latency
+    }
+
+    pub async fn run(options: Options) -> anyhow::Result<()> {
+        let options = Arc::new(options);
+        let mut report = Report::default();
+
+        println!(
+            "clients={}, rate={}/s, size={}, duration={}s",
+            options.clients,
+            options.rate,
+            options.size,
+            options.duration.as_secs()
+        );
+
+        let mut pairs = JoinSet::new();
+        for _ in 0..options.clients / 2 {
+            let options = options.clone();
+            pairs.spawn(async move { setup(&options).await });
+        }
+
+        let mut clients = Vec::with_capacity(options.clients);
+        let mut failed = 0;
+        while let Some(ret) = pairs.join_next().await {
+            match ret? {
+                Ok((a, b, elapsed)) => {
+                    report.setup.push(elapsed);
+                    clients.push(a);
+                    clients.push(b);
+                }
+                Err(e) => {
+                    failed += 1;
+                    eprintln!("setup failed: {}", e);
+                }
+            }
+        }
+
+        if clients.is_empty() {
+            return Err(anyhow!("no client is allocated"));
+        }
+
+        let epoch = Instant::now();
+        let deadline = epoch + options.duration;
+
+        // The socket of each client is shared by the sending and the receiving
+        // tasks, so that the sending is paced independently.
+        let mut receivers = JoinSet::new();
+        let mut senders = JoinSet::new();
+        for client in clients {
+            receivers.spawn(recv(client.socket(), epoch, deadline, options.timeout));
+            senders.spawn(send(client, options.clone(), epoch, deadline));
+        }
+
+        let mut clients = Vec::new();
+        while let Some(ret) = senders.join_next().await {
+            let (client, sent) = ret??;
+            report.sent += sent;
+            clients.push(client);
+        }
+
+        while let Some(ret) = receivers.join_next().await {
+            let latency = ret?;
+            report.received += latency.len() as u64;
+            report.latency.extend(latency);
+        }
+
+        for client in clients.iter_mut() {
+            let _ = client.release().await;
+        }
+
+        report.setup.sort();
+        report.latency.sort();
+
+        let print = |name: &str, values: &[Duration]| {
+            println!(
+                "{:<8} p50={:.2}ms p90={:.2}ms p99={:.2}ms max={:.2}ms",
+                name,
+                percentile(values, 0.5).as_secs_f64() * 1000.0,
+                percentile(values, 0.9).as_secs_f64() * 1000.0,
+                percentile(values, 0.99).as_secs_f64() * 1000.0,
+                percentile(values, 1.0).as_secs_f64() * 1000.0,
+            );
+        };
+
+        println!("setup    pairs={}, failed={}", report.setup.len(), failed);
+        print("setup", &report.setup);
+        println!(
+            "media    sent={}, received={}, loss={:.2}%",
+            report.sent,
+            report.received,
+            report.sent.saturating_sub(report.received) as f64 * 100.0 / report.sent.max(1) as f64
+        );
+        print("latency", &report.latency);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ahash::AHashMap;
+use anyhow::Result;
+use parking_lot::Mutex;
+use reqwest::ClientBuilder;
+use serde::Serialize;
+use turn::{SessionAddr, SessionTags};
+
+use crate::{config, statistics::Statistics};
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The usage of a tenant within a window.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TenantUsage {
+    /// The relayed bytes in both directions.
+    pub bytes: u64,
+    /// The number of allocations.
+    pub allocations: u64,
+    /// The peak number of concurrent allocations.
+    pub peak: u64,
+    #[serde(skip)]
+    current: u64,
+}
+
+/// The usage of the tenants within a window, the times are in seconds since
+/// the unix epoch.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub realm: String,
+    pub start: u64,
+    pub end: u64,
+    pub tenants: BTreeMap<String, TenantUsage>,
+}
+
+struct Window {
+    start: u64,
+    tenants: BTreeMap<String, TenantUsage>,
+}
+
+struct Inner {
+    config: config::Usage,
+    realm: String,
+    statistics: Statistics,
+    // The tenant of every allocation, and the relayed bytes of the session that
+    // are already counted. It is always locked before the window.
+    sessions: Mutex<AHashMap<SessionAddr, (String, u64)>>,
+    window: Mutex<Window>,
+    last: Mutex<Option<Report>>,
+}
+
+/// The usage accounting of the tenants.
+///
+/// The relayed bytes, the allocations and the peak number of concurrent
+/// allocations are aggregated by tenant over the windows of the config. The
+/// tenant of a session is the value of the tenant tag that the auth backend
+/// attached to it, or the realm. The report of every window is posted to the
+/// webhook of the config, for usage-based billing.
+///
+/// # Example
+///
+/// ```
+/// use turn::{SessionAddr, SessionTags};
+/// use turn_server::{config, statistics::Statistics, usage::Usage};
+///
+/// let usage = Usage::new(
+///     config::Usage {
+///         tenant_tag: Some("tenant".to_string()),
+///         ..Default::default()
+///     },
+///     "localhost".to_string(),
+///     Statistics::default(),
+/// );
+///
+/// let addr = |port: u16| SessionAddr {
+///     address: format!("127.0.0.1:{}", port).parse().unwrap(),
+///     interface: "127.0.0.1:3478".parse().unwrap(),
+/// };
+///
+/// let tags = SessionTags::from([("tenant".to_string(), "acme".to_string())]);
+///
+/// usage.allocated(&addr(1000), &tags);
+/// usage.allocated(&addr(1001), &tags);
+/// usage.allocated(&addr(1002), &SessionTags::new());
+/// usage.closed(&addr(1000));
+///
+/// let report = usage.report();
+/// assert_eq!(report.tenants["acme"].allocations, 2);
+/// assert_eq!(report.tenants["acme"].peak, 2);
+/// assert_eq!(report.tenants["localhost"].allocations, 1);
+///
+/// // The next window starts with the allocations that are still open.
+/// usage.roll();
+/// assert_eq!(usage.last().unwrap().tenants["acme"].peak, 2);
+///
+/// let report = usage.report();
+/// assert_eq!(report.tenants["acme"].allocations, 0);
+/// assert_eq!(report.tenants["acme"].peak, 1);
+/// ```
+#[derive(Clone)]
+pub struct Usage(Arc<Inner>);
+
+impl Usage {
+    pub fn new(config: config::Usage, realm: String, statistics: Statistics) -> Self {
+        Self(Arc::new(Inner {
+            sessions: Default::default(),
+            last: Default::default(),
+            window: Mutex::new(Window {
+                start: now(),
+                tenants: BTreeMap::new(),
+            }),
+            statistics,
+            config,
+            realm,
+        }))
+    }
+
+    /// Count the allocation of the session with the tags of the session.
+    pub fn allocated(&self, addr: &SessionAddr, tags: &SessionTags) {
+        let mut tenant = self
+            .0
+            .config
+            .tenant_tag
+            .as_ref()
+            .and_then(|it| tags.get(it))
+            .unwrap_or(&self.0.realm)
+            .clone();
+
+        let mut sessions = self.0.sessions.lock();
+        let mut window = self.0.window.lock();
+
+        // The number of tenants is bounded, the tenants beyond the limit share
+        // a single entry.
+        if !window.tenants.contains_key(&tenant) && window.tenants.len() >= self.0.config.max_tenants {
+            tenant = "other".to_string();
+        }
+
+        let usage = window.tenants.entry(tenant.clone()).or_default();
+        usage.allocations += 1;
+
+        // A session that allocates again after its allocation was replaced is
+        // still a single concurrent allocation.
+        if !sessions.contains_key(addr) {
+            usage.current += 1;
+            usage.peak = usage.peak.max(usage.current);
+            sessions.insert(*addr, (tenant, 0));
+        }
+    }
+
+    /// Count the relayed bytes of the session that is closed, it is called
+    /// before the session is removed from the statistics.
+    pub fn closed(&self, addr: &SessionAddr) {
+        let mut sessions = self.0.sessions.lock();
+        let Some((tenant, counted)) = sessions.remove(addr) else {
+            return;
+        };
+
+        let bytes = self.bytes(addr);
+        if let Some(usage) = self.0.window.lock().tenants.get_mut(&tenant) {
+            usage.bytes += bytes.saturating_sub(counted);
+            usage.current = usage.current.saturating_sub(1);
+        }
+    }
+
+    /// Move the session to its new address, it is called after its
+    /// statistics are moved.
+    pub fn rebind(&self, from: &SessionAddr, to: &SessionAddr) {
+        let mut sessions = self.0.sessions.lock();
+        if let Some(it) = sessions.remove(from) {
+            sessions.insert(*to, it);
+        }
+    }
+
+    /// The usage of the current window until now.
+    pub fn report(&self) -> Report {
+        self.collect(false)
+    }
+
+    /// The report of the last window that was completed.
+    pub fn last(&self) -> Option<Report> {
+        self.0.last.lock().clone()
+    }
+
+    /// Complete the current window and start the next one, returns the
+    /// report of the completed window.
+    pub fn roll(&self) -> Report {
+        let report = self.collect(true);
+        self.0.last.lock().replace(report.clone());
+        report
+    }
+
+    /// Complete the windows at the interval of the config, and post their
+    /// reports to the webhook if it is set.
+    pub fn start(&self) -> Result<()> {
+        let client = ClientBuilder::new().timeout(Duration::from_secs(10)).build()?;
+        let this = self.clone();
+
+        tokio::spawn(async move {
+            let period = Duration::from_secs(this.0.config.window.max(1));
+            let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+            loop {
+                interval.tick().await;
+
+                let report = this.roll();
+                if let Some(webhook) = &this.0.config.webhook {
+                    if let Err(e) = client.post(webhook).json(&report).send().await {
+                        log::error!(
+                            "failed to post usage report to webhook: start={}, err={}",
+                            report.start,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn bytes(&self, addr: &SessionAddr) -> u64 {
+        self.0
+            .statistics
+            .get(addr)
+            .map(|it| it.received_bytes + it.send_bytes)
+            .unwrap_or(0)
+    }
+
+    // Add the bytes that the open sessions relayed since they were last counted
+    // to the window, and start the next window if `roll` is set.
+    fn collect(&self, roll: bool) -> Report {
+        let mut sessions = self.0.sessions.lock();
+        let mut window = self.0.window.lock();
+
+        for (addr, (tenant, counted)) in sessions.iter_mut() {
+            // The statistics of a session start over when it allocates again.
+            let bytes = self.bytes(addr);
+            if let Some(usage) = window.tenants.get_mut(tenant) {
+                usage.bytes += bytes.saturating_sub(*counted);
+            }
+
+            *counted = bytes;
+        }
+
+        let end = now();
+        let report = Report {
+            realm: self.0.realm.clone(),
+            tenants: window.tenants.clone(),
+            start: window.start,
+            end,
+        };
+
+        if roll {
+            window.start = end;
+            window.tenants.retain(|_, it| it.current > 0);
+            for usage in window.tenants.values_mut() {
+                *usage = TenantUsage {
+                    current: usage.current,
+                    peak: usage.current,
+                    ..Default::default()
+                };
+            }
+        }
+
+        report
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use parking_lot::{Condvar, Mutex};
+use stun::SignedMessage;
+
+/// The priority of a verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// The request of a session that already has an allocation, such as a
+    /// refresh or a create permission request.
+    Session,
+    /// The request of a session that has no allocation yet, such as a new
+    /// allocate request.
+    New,
+}
+
+#[derive(Default)]
+struct Slot(Mutex<(Option<bool>, Option<Waker>)>);
+
+impl Slot {
+    fn complete(&self, ok: bool) {
+        let mut slot = self.0.lock();
+        slot.0 = Some(ok);
+
+        if let Some(waker) = slot.1.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The pending result of a verification, resolves to whether the message
+/// integrity matches the key.
+pub struct Verification(Arc<Slot>);
+
+impl Future for Verification {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.0 .0.lock();
+        if let Some(ok) = slot.0 {
+            return Poll::Ready(ok);
+        }
+
+        slot.1 = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+struct Job {
+    signed: SignedMessage,
+    key: Vec<u8>,
+    slot: Arc<Slot>,
+}
+
+impl Job {
+    fn run(self) {
+        self.slot.complete(self.signed.verify(&self.key).is_ok());
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    sessions: VecDeque<Job>,
+    new: VecDeque<Job>,
+    closed: bool,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    condvar: Condvar,
+    capacity: usize,
+    shed: AtomicU64,
+    shed_sessions: AtomicU64,
+}
+
+/// The worker threads that check the message integrity of the requests.
+///
+/// The requests of the sessions that already have an allocation and the
+/// requests of the new sessions are queued separately, and the workers take
+/// the requests of the existing sessions first. Both queues are bounded and
+/// a request is shed when its queue is full, so that the hmac cost of a
+/// flood stays off the threads that forward the relayed data. The priority
+/// only comes from the source address, which can be spoofed over udp, so
+/// the requests of the existing sessions are never checked on the calling
+/// thread either.
+///
+/// # Test
+///
+/// ```
+/// use stun::*;
+/// use mycrl_turn::integrity::*;
+///
+/// let buffer = [
+///     0x00u8, 0x03, 0x00, 0x50, 0x21, 0x12, 0xa4, 0x42, 0x64, 0x4f, 0x5a,
+///     0x78, 0x6a, 0x56, 0x33, 0x62, 0x4b, 0x52, 0x33, 0x31, 0x00, 0x19, 0x00,
+///     0x04, 0x11, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x05, 0x70, 0x61, 0x6e,
+///     0x64, 0x61, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x09, 0x72, 0x61, 0x73,
+///     0x70, 0x62, 0x65, 0x72, 0x72, 0x79, 0x00, 0x00, 0x00, 0x00, 0x15, 0x00,
+///     0x10, 0x31, 0x63, 0x31, 0x33, 0x64, 0x32, 0x62, 0x32, 0x34, 0x35, 0x62,
+///     0x33, 0x61, 0x37, 0x33, 0x34, 0x00, 0x08, 0x00, 0x14, 0xd6, 0x78, 0x26,
+///     0x99, 0x0e, 0x15, 0x56, 0x15, 0xe5, 0xf4, 0x24, 0x74, 0xe2, 0x3c, 0x26,
+///     0xc5, 0xb1, 0x03, 0xb2, 0x6d,
+/// ];
+///
+/// let mut attributes = Attributes::default();
+/// let message = MessageReader::decode(&buffer[..], &mut attributes).unwrap();
+/// let digest = util::long_term_credential_digest("panda", "panda", "raspberry");
+///
+/// let pool = IntegrityPool::new(1, 16);
+/// let verification = pool
+///     .verify(message.signed().unwrap(), digest.to_vec(), Priority::New)
+///     .unwrap();
+/// assert!(pollster::block_on(verification));
+///
+/// let verification = pool
+///     .verify(message.signed().unwrap(), vec![0u8; 16], Priority::Session)
+///     .unwrap();
+/// assert!(!pollster::block_on(verification));
+/// assert_eq!(pool.shed(), 0);
+/// assert_eq!(pool.shed_sessions(), 0);
+/// ```
+pub struct IntegrityPool(Arc<Shared>);
+
+impl IntegrityPool {
+    /// Start the workers, every queue holds at most `capacity` requests.
+    /// There is at least one worker.
+    pub fn new(workers: usize, capacity: usize) -> Self {
+        let shared = Arc::new(Shared {
+            queues: Default::default(),
+            condvar: Condvar::new(),
+            shed: AtomicU64::new(0),
+            shed_sessions: AtomicU64::new(0),
+            capacity,
+        });
+
+        for index in 0..workers.max(1) {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("turn-integrity-{}", index))
+                .spawn(move || loop {
+                    let job = {
+                        let mut queues = shared.queues.lock();
+                        loop {
+                            if let Some(job) = queues.sessions.pop_front() {
+                                break job;
+                            }
+
+                            if let Some(job) = queues.new.pop_front() {
+                                break job;
+                            }
+
+                            // The queued requests are still checked after the pool is
+                            // dropped, so that no verification is left pending.
+                            if queues.closed {
+                                return;
+                            }
+
+                            shared.condvar.wait(&mut queues);
+                        }
+                    };
+
+                    job.run();
+                })
+                .expect("failed to spawn the integrity worker");
+        }
+
+        Self(shared)
+    }
+
+    /// Queue the check of the message integrity with the key, returns
+    /// `None` if the request is shed because its queue is full.
+    pub fn verify(
+        &self,
+        signed: SignedMessage,
+        key: Vec<u8>,
+        priority: Priority,
+    ) -> Option<Verification> {
+        let slot = Arc::new(Slot::default());
+        let job = Job {
+            slot: slot.clone(),
+            signed,
+            key,
+        };
+
+        {
+            let mut queues = self.0.queues.lock();
+            let queue = match priority {
+                Priority::Session => &mut queues.sessions,
+                Priority::New => &mut queues.new,
+            };
+
+            if queue.len() < self.0.capacity {
+                queue.push_back(job);
+                self.0.condvar.notify_one();
+                return Some(Verification(slot));
+            }
+        }
+
+        match priority {
+            Priority::Session => &self.0.shed_sessions,
+            Priority::New => &self.0.shed,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+
+        None
+    }
+
+    /// The number of requests waiting to be checked.
+    pub fn queued(&self) -> usize {
+        let queues = self.0.queues.lock();
+        queues.sessions.len() + queues.new.len()
+    }
+
+    /// The number of requests of new sessions that were shed.
+    pub fn shed(&self) -> u64 {
+        self.0.shed.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests of the existing sessions that were shed.
+    pub fn shed_sessions(&self) -> u64 {
+        self.0.shed_sessions.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for IntegrityPool {
+    fn drop(&mut self) {
+        self.0.queues.lock().closed = true;
+        self.0.condvar.notify_all();
+    }
+}
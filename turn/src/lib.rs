@@ -1,14 +1,24 @@
+pub mod integrity;
 pub mod operations;
 pub mod sessions;
 
-use self::operations::ServiceContext;
+use self::{
+    integrity::IntegrityPool,
+    operations::{Interceptor, Processor, Processors, ServiceContext, TransportContext},
+};
 
 pub use self::{
-    operations::{Operationer, ResponseMethod},
-    sessions::{PortAllocatePools, Session, SessionAddr, Sessions},
+    operations::{AuthFailure, Operationer, OwnedResponse, ResponseMethod},
+    sessions::{
+        AddressRebind, Clock, CloseReason, Credential, DuplicateAllocate, Maintenance, Permission,
+        PortAllocatePools, RelayPort, Session, SessionAddr, SessionTags, Sessions,
+        DEFAULT_PORT_RANGE,
+    },
 };
 
-use std::{future::Future, net::SocketAddr, sync::Arc};
+use std::{future::Future, net::SocketAddr, ops::Range, sync::Arc};
+
+use stun::{Method, StunError};
 
 #[rustfmt::skip]
 static SOFTWARE: &str = concat!(
@@ -16,6 +26,27 @@ static SOFTWARE: &str = concat!(
     env!("CARGO_PKG_VERSION")
 );
 
+/// An operation of an authenticated session that the observer can refuse,
+/// see [`Observer::authorize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation<'a> {
+    Allocate,
+    /// The relay ports of the peers.
+    CreatePermission(&'a [u16]),
+    /// The relay port of the peer and the channel number.
+    ChannelBind(u16, u16),
+}
+
+impl Operation<'_> {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allocate => "allocate",
+            Self::CreatePermission(_) => "create_permission",
+            Self::ChannelBind(..) => "channel_bind",
+        }
+    }
+}
+
 #[allow(unused)]
 pub trait Observer: Send + Sync {
     fn get_password(
@@ -26,6 +57,62 @@ pub trait Observer: Send + Sync {
         async { None }
     }
 
+    /// get credential
+    ///
+    /// The password of the user and the tags that are attached to the
+    /// session, such as the tenant or the plan of the user. The tags are kept
+    /// with the session until it is closed. By default it is the password of
+    /// [`Observer::get_password`] without tags.
+    fn get_credential(
+        &self,
+        addr: &SessionAddr,
+        username: &str,
+    ) -> impl Future<Output = Option<Credential>> + Send {
+        async {
+            self.get_password(addr, username)
+                .await
+                .map(|password| Credential {
+                    password,
+                    tags: SessionTags::new(),
+                })
+        }
+    }
+
+    /// trusted source
+    ///
+    /// Whether the source may use the relay without credentials. The requests
+    /// of a trusted source that do not carry MESSAGE-INTEGRITY are accepted as
+    /// the anonymous user, whose username is empty, and are answered without
+    /// MESSAGE-INTEGRITY.
+    fn is_trusted(&self, addr: &SessionAddr) -> bool {
+        false
+    }
+
+    /// authorize operation
+    ///
+    /// Called after the credentials of an allocate, create permission or
+    /// channel bind request are verified, the request is rejected with a 403
+    /// (Forbidden) error if the operation is refused.
+    fn authorize(&self, addr: &SessionAddr, username: &str, operation: Operation<'_>) -> bool {
+        true
+    }
+
+    /// allocation quota
+    ///
+    /// Called after an allocate request is authorized, the request is
+    /// rejected with a 486 (Allocation Quota Reached) error if the user has
+    /// no quota left for another allocation.
+    fn allocation_quota(&self, addr: &SessionAddr, username: &str) -> bool {
+        true
+    }
+
+    /// authentication failed
+    ///
+    /// Triggered when a request carries credentials that can not be
+    /// verified, the request is rejected with the error code that matches
+    /// the reason.
+    fn auth_failed(&self, addr: &SessionAddr, username: &str, reason: AuthFailure) {}
+
     /// allocate request
     ///
     /// [rfc8489](https://tools.ietf.org/html/rfc8489)
@@ -42,7 +129,53 @@ pub trait Observer: Send + Sync {
     /// server SHOULD NOT allocate ports in the range 0 - 1023 (the Well-
     /// Known Port range) to discourage clients from using TURN to run
     /// standard services.
-    fn allocated(&self, addr: &SessionAddr, username: &str, port: u16) {}
+    ///
+    /// The lifetime is the one granted in the response, the requested
+    /// lifetime is clamped to the default and the maximum lifetime, and
+    /// `clamped` is whether it differs from the requested lifetime.
+    fn allocated(
+        &self,
+        addr: &SessionAddr,
+        username: &str,
+        port: u16,
+        lifetime: u32,
+        clamped: bool,
+    ) {
+    }
+
+    /// port pool exhausted
+    ///
+    /// Triggered when an allocate request is rejected with a 508
+    /// (Insufficient Capacity) error because there are no free relay ports
+    /// left in the port pool.
+    fn port_exhausted(&self, addr: &SessionAddr, username: &str) {}
+
+    /// duplicate allocate request
+    ///
+    /// Triggered when an allocate request is received on a 5-tuple that
+    /// already has an allocation on the port, the policy is what was done
+    /// with it: rejected with a 437 (Allocation Mismatch) error, or the
+    /// allocation was released so that a new one is allocated.
+    fn duplicate_allocate(
+        &self,
+        addr: &SessionAddr,
+        username: &str,
+        port: u16,
+        policy: DuplicateAllocate,
+    ) {
+    }
+
+    /// address changed
+    ///
+    /// Triggered when an authenticated request that needs an allocation
+    /// arrives from an address without one, while the user has an
+    /// allocation on the interface from the other address, which is what a
+    /// NAT rebinding or an ECMP re-hash of an anycast address looks like.
+    /// `rebound` is whether the allocation was moved to the new address
+    /// under the address rebind policy, otherwise the request is rejected
+    /// with a 438 (Stale Nonce) error.
+    fn address_changed(&self, from: &SessionAddr, to: &SessionAddr, username: &str, rebound: bool) {
+    }
 
     /// channel binding request
     ///
@@ -76,6 +209,15 @@ pub trait Observer: Send + Sync {
     /// retransmission.
     fn channel_bind(&self, addr: &SessionAddr, username: &str, channel: u16) {}
 
+    /// channel stale
+    ///
+    /// Triggered when no data has been relayed to the session over a bound
+    /// channel for longer than the channel idle timeout of the maintenance,
+    /// and again with `stale` set to false once data is relayed over it
+    /// again. This is independent of the lifetime of the allocation, which
+    /// the client can keep refreshing while the media path is dead.
+    fn channel_stale(&self, addr: &SessionAddr, username: &str, channel: u16, stale: bool) {}
+
     /// create permission request
     ///
     /// [rfc8489](https://tools.ietf.org/html/rfc8489)
@@ -112,9 +254,9 @@ pub trait Observer: Send + Sync {
     /// There are no mandatory attributes in the success response.
     ///
     /// > NOTE: A server need not do anything special to implement
-    /// idempotency of CreatePermission requests over UDP using the
-    /// "stateless stack approach".  Retransmitted CreatePermission
-    /// requests will simply refresh the permissions.
+    /// > idempotency of CreatePermission requests over UDP using the
+    /// > "stateless stack approach".  Retransmitted CreatePermission
+    /// > requests will simply refresh the permissions.
     fn create_permission(&self, addr: &SessionAddr, username: &str, ports: &[u16]) {}
 
     /// refresh request
@@ -136,17 +278,17 @@ pub trait Observer: Send + Sync {
     /// Subsequent processing depends on the "desired lifetime" value:
     ///
     /// * If the "desired lifetime" is zero, then the request succeeds and
-    /// the allocation is deleted.
+    ///   the allocation is deleted.
     ///
     /// * If the "desired lifetime" is non-zero, then the request succeeds
-    /// and the allocation's time-to-expiry is set to the "desired
-    /// lifetime".
+    ///   and the allocation's time-to-expiry is set to the "desired
+    ///   lifetime".
     ///
     /// If the request succeeds, then the server sends a success response
     /// containing:
     ///
     /// * A LIFETIME attribute containing the current value of the time-to-
-    /// expiry timer.
+    ///   expiry timer.
     ///
     /// NOTE: A server need not do anything special to implement
     /// idempotency of Refresh requests over UDP using the "stateless
@@ -156,21 +298,28 @@ pub trait Observer: Send + Sync {
     /// will cause a 437 (Allocation Mismatch) response if the
     /// allocation has already been deleted, but the client will treat
     /// this as equivalent to a success response (see below).
-    fn refresh(&self, addr: &SessionAddr, username: &str, lifetime: u32) {}
+    ///
+    /// The lifetime is the one granted in the response, the requested
+    /// lifetimes that are longer than the maximum lifetime are clamped to
+    /// it, and `clamped` is whether it differs from the requested lifetime.
+    fn refresh(&self, addr: &SessionAddr, username: &str, lifetime: u32, clamped: bool) {}
 
     /// session closed
     ///
     /// Triggered when the session leaves from the turn. Possible reasons: the
     /// session life cycle has expired, external active deletion, or active
     /// exit of the session.
-    fn closed(&self, addr: &SessionAddr, username: &str) {}
+    fn closed(&self, addr: &SessionAddr, username: &str, reason: CloseReason) {}
 }
 
 /// Turn service.
 #[derive(Clone)]
 pub struct Service<T> {
     interfaces: Arc<Vec<SocketAddr>>,
+    processors: Arc<Processors<T>>,
+    interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
     sessions: Arc<Sessions<T>>,
+    integrity: Option<Arc<IntegrityPool>>,
     realm: Arc<String>,
     observer: T,
 }
@@ -183,6 +332,10 @@ where
         self.sessions.clone()
     }
 
+    pub fn get_observer(&self) -> &T {
+        &self.observer
+    }
+
     /// Create turn service.
     ///
     /// # Test
@@ -198,14 +351,207 @@ where
     /// Service::new("test".to_string(), vec![], ObserverTest);
     /// ```
     pub fn new(realm: String, interfaces: Vec<SocketAddr>, observer: T) -> Self {
+        Self::with_port_range(realm, interfaces, DEFAULT_PORT_RANGE, observer)
+    }
+
+    /// Create turn service that allocates the relay ports from the range.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {}
+    ///
+    /// let service = Service::with_port_range("test".to_string(), vec![], 50000..60000, ObserverTest);
+    /// assert_eq!(service.get_sessions().capacity(), 10000);
+    /// ```
+    pub fn with_port_range(
+        realm: String,
+        interfaces: Vec<SocketAddr>,
+        port_range: Range<u16>,
+        observer: T,
+    ) -> Self {
+        Self::with_clock(realm, interfaces, port_range, Clock::System, observer)
+    }
+
+    /// Create turn service with the clock of the sessions, see
+    /// [`Sessions::with_clock`].
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::Clock, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {}
+    ///
+    /// let service = Service::with_clock(
+    ///     "test".to_string(),
+    ///     vec![],
+    ///     DEFAULT_PORT_RANGE,
+    ///     Clock::Manual,
+    ///     ObserverTest,
+    /// );
+    ///
+    /// service.get_sessions().advance(10);
+    /// assert_eq!(service.get_sessions().now(), 10);
+    /// ```
+    pub fn with_clock(
+        realm: String,
+        interfaces: Vec<SocketAddr>,
+        port_range: Range<u16>,
+        clock: Clock,
+        observer: T,
+    ) -> Self {
         Self {
-            sessions: Sessions::new(observer.clone()),
+            sessions: Sessions::with_clock(observer.clone(), port_range, clock),
+            processors: Default::default(),
+            interceptors: Default::default(),
+            integrity: None,
             interfaces: Arc::new(interfaces),
             realm: Arc::new(realm),
             observer,
         }
     }
 
+    /// Register a processor for the method, the processor overrides the
+    /// built-in processor of the method. The methods that are not supported
+    /// by default are registered as `Method::Other` with their message type.
+    ///
+    /// Only the operationers created after the registration use the
+    /// processor.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use std::net::SocketAddr;
+    ///
+    /// use bytes::BytesMut;
+    /// use mycrl_turn::{operations::*, *};
+    /// use stun::{Kind, MessageReader, MessageWriter, Method};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {}
+    ///
+    /// // Drop all binding requests.
+    /// struct DropBinding;
+    ///
+    /// impl Processor<ObserverTest> for DropBinding {
+    ///     fn process<'c, 'a: 'c>(
+    ///         &'c self,
+    ///         _: Requet<'c, 'a, ObserverTest, MessageReader<'c>>,
+    ///     ) -> ProcessorFuture<'c, 'a> {
+    ///         Box::pin(async { None })
+    ///     }
+    /// }
+    ///
+    /// let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+    /// let transport = TransportContext::new(IngressTransport::Udp, addr);
+    ///
+    /// let mut bytes = BytesMut::new();
+    /// MessageWriter::new(Method::Binding(Kind::Request), &[0u8; 12], &mut bytes)
+    ///     .flush(None)
+    ///     .unwrap();
+    ///
+    /// let mut service = Service::new("test".to_string(), vec![], ObserverTest);
+    /// let mut operationer = service.get_operationer(addr, addr, transport.clone());
+    /// let res = pollster::block_on(operationer.route(&bytes, addr)).unwrap();
+    /// assert_eq!(res.unwrap().method, ResponseMethod::Stun(Method::Binding(Kind::Response)));
+    ///
+    /// service.register(Method::Binding(Kind::Request), DropBinding);
+    /// let mut operationer = service.get_operationer(addr, addr, transport.clone());
+    /// assert!(pollster::block_on(operationer.route(&bytes, addr)).unwrap().is_none());
+    /// ```
+    pub fn register(&mut self, method: Method, processor: impl Processor<T> + 'static) {
+        Arc::make_mut(&mut self.processors).insert(method, Arc::new(processor));
+    }
+
+    /// Append an interceptor to the end of the interceptor chain.
+    ///
+    /// Only the operationers created after the registration use the
+    /// interceptor.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use std::{
+    ///     net::SocketAddr,
+    ///     sync::atomic::{AtomicUsize, Ordering},
+    /// };
+    ///
+    /// use bytes::BytesMut;
+    /// use mycrl_turn::{operations::*, *};
+    /// use stun::{Kind, MessageWriter, Method, Payload};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {}
+    ///
+    /// // Drop every other request.
+    /// #[derive(Default)]
+    /// struct Halve(AtomicUsize);
+    ///
+    /// impl Interceptor for Halve {
+    ///     fn before(&self, _: &SessionAddr, _: &Payload<'_>) -> bool {
+    ///         self.0.fetch_add(1, Ordering::Relaxed) % 2 == 0
+    ///     }
+    /// }
+    ///
+    /// // Remove all responses.
+    /// struct Silence;
+    ///
+    /// impl Interceptor for Silence {
+    ///     fn after(&self, _: &SessionAddr, response: &mut Option<Response<'_>>) {
+    ///         response.take();
+    ///     }
+    /// }
+    ///
+    /// let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+    /// let transport = TransportContext::new(IngressTransport::Udp, addr);
+    ///
+    /// let mut bytes = BytesMut::new();
+    /// MessageWriter::new(Method::Binding(Kind::Request), &[0u8; 12], &mut bytes)
+    ///     .flush(None)
+    ///     .unwrap();
+    ///
+    /// let mut service = Service::new("test".to_string(), vec![], ObserverTest);
+    /// service.add_interceptor(Halve::default());
+    ///
+    /// let mut operationer = service.get_operationer(addr, addr, transport.clone());
+    /// assert!(pollster::block_on(operationer.route(&bytes, addr)).unwrap().is_some());
+    /// assert!(pollster::block_on(operationer.route(&bytes, addr)).unwrap().is_none());
+    ///
+    /// service.add_interceptor(Silence);
+    ///
+    /// let mut operationer = service.get_operationer(addr, addr, transport.clone());
+    /// assert!(pollster::block_on(operationer.route(&bytes, addr)).unwrap().is_none());
+    /// ```
+    pub fn add_interceptor(&mut self, interceptor: impl Interceptor + 'static) {
+        Arc::make_mut(&mut self.interceptors).push(Arc::new(interceptor));
+    }
+
+    /// Check the message integrity of the requests on the workers of the
+    /// pool instead of the thread that received them, see
+    /// [`IntegrityPool`].
+    ///
+    /// Only the operationers created after the pool is set use it.
+    pub fn set_integrity_pool(&mut self, pool: IntegrityPool) {
+        self.integrity = Some(Arc::new(pool));
+    }
+
+    pub fn get_integrity_pool(&self) -> Option<&IntegrityPool> {
+        self.integrity.as_deref()
+    }
+
     /// Get operationer.
     ///
     /// # Test
@@ -213,7 +559,7 @@ where
     /// ```
     /// use std::net::SocketAddr;
     /// use stun::attribute::Transport;
-    /// use mycrl_turn::*;
+    /// use mycrl_turn::{operations::*, *};
     ///
     /// #[derive(Clone)]
     /// struct ObserverTest;
@@ -221,18 +567,100 @@ where
     /// impl Observer for ObserverTest {}
     ///
     /// let addr = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+    /// let transport = TransportContext::new(IngressTransport::Udp, addr);
     /// let service = Service::new("test".to_string(), vec![], ObserverTest);
     ///
-    /// service.get_operationer(addr, addr);
+    /// service.get_operationer(addr, addr, transport);
     /// ```
-    pub fn get_operationer(&self, endpoint: SocketAddr, interface: SocketAddr) -> Operationer<T> {
+    pub fn get_operationer(
+        &self,
+        endpoint: SocketAddr,
+        interface: SocketAddr,
+        transport: TransportContext,
+    ) -> Operationer<T> {
         Operationer::new(ServiceContext {
+            transport,
             interfaces: self.interfaces.clone(),
             observer: self.observer.clone(),
+            processors: self.processors.clone(),
+            interceptors: self.interceptors.clone(),
             sessions: self.sessions.clone(),
+            integrity: self.integrity.clone(),
             realm: self.realm.clone(),
             interface,
             endpoint,
         })
     }
+
+    /// Process a datagram received from the source on the interface, for
+    /// embedders that drive the service from their own io.
+    ///
+    /// The response is sent to the relay address if there is one and to the
+    /// source otherwise, through the endpoint if there is one and through the
+    /// interface otherwise. Creating an operationer per socket or connection
+    /// avoids the per call allocation.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use bytes::BytesMut;
+    /// use stun::{attribute::XorMappedAddress, *};
+    /// use mycrl_turn::{operations::*, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {}
+    ///
+    /// let source = "127.0.0.1:8080".parse().unwrap();
+    /// let interface = "127.0.0.1:3478".parse().unwrap();
+    /// let service = Service::new("test".to_string(), vec![interface], ObserverTest);
+    ///
+    /// let mut bytes = BytesMut::new();
+    /// MessageWriter::new(Method::Binding(Kind::Request), &[0u8; 12], &mut bytes)
+    ///     .flush(None)
+    ///     .unwrap();
+    ///
+    /// let res = pollster::block_on(service.process_bytes(
+    ///     source,
+    ///     interface,
+    ///     TransportContext::new(IngressTransport::Udp, interface),
+    ///     &bytes,
+    /// ))
+    /// .unwrap()
+    /// .unwrap();
+    ///
+    /// assert_eq!(res.method, ResponseMethod::Stun(Method::Binding(Kind::Response)));
+    /// assert_eq!(res.relay, None);
+    ///
+    /// let mut attributes = Attributes::default();
+    /// let message = MessageReader::decode(&res.bytes, &mut attributes).unwrap();
+    /// assert_eq!(message.get::<XorMappedAddress>(), Some(source));
+    /// ```
+    pub async fn process_bytes(
+        &self,
+        source: SocketAddr,
+        interface: SocketAddr,
+        transport: TransportContext,
+        bytes: &[u8],
+    ) -> Result<Option<OwnedResponse>, StunError> {
+        // The decoder needs at least the type and the length.
+        if bytes.len() < 4 {
+            return Err(StunError::InvalidInput);
+        }
+
+        // The sessions of a stream transport are routed by the address of
+        // the connection.
+        let endpoint = if transport.is_stream() {
+            source
+        } else {
+            interface
+        };
+
+        let mut operationer = self.get_operationer(endpoint, interface, transport);
+        Ok(operationer
+            .route(bytes, source)
+            .await?
+            .map(OwnedResponse::from))
+    }
 }
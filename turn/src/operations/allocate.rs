@@ -1,12 +1,12 @@
-use super::{Requet, Response, ResponseMethod};
-use crate::{Observer, SOFTWARE};
+use super::{Requet, Response, ResponseMethod, DEFAULT_LIFETIME, MAX_LIFETIME};
+use crate::{sessions::DuplicateAllocate, Observer, Operation, SOFTWARE};
 
 use std::net::SocketAddr;
 
 use stun::{
     attribute::{
-        Error, ErrorCode, ErrorKind, Lifetime, Nonce, Realm, ReqeestedTransport, Software,
-        XorMappedAddress, XorRelayedAddress,
+        Error, ErrorCode, ErrorKind, IpFamily, Lifetime, Nonce, Realm, ReqeestedTransport,
+        RequestedAddressFamily, Software, Transport, XorMappedAddress, XorRelayedAddress,
     },
     Kind, MessageReader, MessageWriter, Method,
 };
@@ -22,7 +22,7 @@ fn reject<'a, T: Observer>(
             MessageWriter::extend(Method::Allocate(Kind::Error), req.message, req.bytes);
 
         message.append::<ErrorCode>(Error::from(err));
-        message.append::<Nonce>(&req.service.sessions.get_nonce(&req.address).get_ref()?.0);
+        message.append::<Nonce>(&req.service.sessions.get_nonce(req.address).get_ref()?.0);
         message.append::<Realm>(&req.service.realm);
         message.flush(None).ok()?;
     }
@@ -48,8 +48,9 @@ fn reject<'a, T: Observer>(
 #[inline(always)]
 fn resolve<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
-    digest: &[u8; 16],
+    digest: Option<&[u8]>,
     port: u16,
+    lifetime: u32,
 ) -> Option<Response<'a>> {
     {
         let mut message =
@@ -57,9 +58,9 @@ fn resolve<'a, T: Observer>(
 
         message.append::<XorRelayedAddress>(SocketAddr::new(req.service.interface.ip(), port));
         message.append::<XorMappedAddress>(req.address.address);
-        message.append::<Lifetime>(600);
+        message.append::<Lifetime>(lifetime);
         message.append::<Software>(SOFTWARE);
-        message.flush(Some(digest)).ok()?;
+        message.flush(digest).ok()?;
     }
 
     Some(Response {
@@ -89,20 +90,137 @@ fn resolve<'a, T: Observer>(
 pub async fn process<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
 ) -> Option<Response<'a>> {
-    if req.message.get::<ReqeestedTransport>().is_none() {
-        return reject(req, ErrorKind::ServerError);
+    // Only UDP relaying is supported, a request without REQUESTED-TRANSPORT is
+    // malformed, and a protocol number that is not known is not supported.
+    let transport = match req.message.get::<ReqeestedTransport>() {
+        Some(Transport::UDP) => Transport::UDP,
+        Some(Transport::TCP) => return reject(req, ErrorKind::UnsupportedTransportAddress),
+        None if req.message.contains::<ReqeestedTransport>() => {
+            return reject(req, ErrorKind::UnsupportedTransportAddress)
+        }
+        None => return reject(req, ErrorKind::BadRequest),
+    };
+
+    // The relayed transport address is always allocated on the address family
+    // of the interface that received the request.
+    if let Some(family) = req.message.get::<RequestedAddressFamily>() {
+        let supported = if req.service.interface.is_ipv4() {
+            IpFamily::V4
+        } else {
+            IpFamily::V6
+        };
+
+        if family != supported {
+            return reject(req, ErrorKind::AddressFamilyNotSupported);
+        }
     }
 
     let (username, digest) = match req.auth().await {
-        Some(it) => it,
-        None => return reject(req, ErrorKind::Unauthorized),
+        Ok(it) => it,
+        Err(err) => return reject(req, err),
     };
 
-    let port = match req.service.sessions.allocate(req.address) {
+    // An allocation already exists for the 5-tuple.
+    let allocated = req
+        .service
+        .sessions
+        .get_session(req.address)
+        .get_ref()
+        .and_then(|it| Some((it.allocate.port?, it.allocate.token)));
+
+    let mut replaced = None;
+    if let Some((port, token)) = allocated {
+        match req.service.sessions.duplicate_allocate() {
+            DuplicateAllocate::Reject => {
+                req.service.observer.duplicate_allocate(
+                    req.address,
+                    username,
+                    port,
+                    DuplicateAllocate::Reject,
+                );
+
+                return reject(req, ErrorKind::AllocationMismatch);
+            }
+            DuplicateAllocate::Replace => {
+                // A retransmission of the request that allocated the port must not
+                // tear down the allocation that its first copy created.
+                if token.as_ref().map(|it| &it[..]) == Some(req.message.token) {
+                    let lifetime = req
+                        .service
+                        .sessions
+                        .get_session(req.address)
+                        .get_ref()
+                        .map(|it| it.expires.saturating_sub(req.service.sessions.now()) as u32)
+                        .unwrap_or(DEFAULT_LIFETIME);
+
+                    return resolve(req, digest.as_deref(), port, lifetime);
+                }
+
+                replaced = Some(port);
+            }
+        }
+    }
+
+    if !req
+        .service
+        .observer
+        .authorize(req.address, username, Operation::Allocate)
+    {
+        return reject(req, ErrorKind::Forbidden);
+    }
+
+    if !req.service.observer.allocation_quota(req.address, username) {
+        return reject(req, ErrorKind::AllocationQuotaReached);
+    }
+
+    if let Some(port) = replaced {
+        req.service.sessions.release_allocation(req.address);
+        req.service.observer.duplicate_allocate(
+            req.address,
+            username,
+            port,
+            DuplicateAllocate::Replace,
+        );
+    }
+
+    // The session has no port yet, so the allocation can only fail because the
+    // port pool is exhausted, and the client should try another server.
+    let port = match req
+        .service
+        .sessions
+        .allocate_with_transport(req.address, transport)
+    {
         Some(it) => it,
-        None => return reject(req, ErrorKind::AllocationQuotaReached),
+        None => {
+            req.service.observer.port_exhausted(req.address, username);
+            return reject(req, ErrorKind::InsufficientCapacity);
+        }
     };
 
-    req.service.observer.allocated(&req.address, username, port);
-    resolve(req, &digest, port)
+    req.service.sessions.set_allocate_origin(
+        req.address,
+        req.message.token,
+        req.service.transport.transport,
+    );
+
+    // The requested lifetime is granted within the default and the maximum
+    // lifetime.
+    let requested = req.message.get::<Lifetime>();
+    let lifetime = requested
+        .map(|it| it.clamp(DEFAULT_LIFETIME, MAX_LIFETIME))
+        .unwrap_or(DEFAULT_LIFETIME);
+
+    if lifetime != DEFAULT_LIFETIME {
+        req.service.sessions.refresh(req.address, lifetime);
+    }
+
+    req.service.observer.allocated(
+        req.address,
+        username,
+        port,
+        lifetime,
+        requested.is_some_and(|it| it != lifetime),
+    );
+
+    resolve(req, digest.as_deref(), port, lifetime)
 }
@@ -31,7 +31,7 @@ use stun::{
 pub fn process<'a, T: Observer>(req: Requet<'_, 'a, T, MessageReader<'_>>) -> Option<Response<'a>> {
     {
         let mut message =
-            MessageWriter::extend(Method::Binding(Kind::Response), &req.message, req.bytes);
+            MessageWriter::extend(Method::Binding(Kind::Response), req.message, req.bytes);
 
         message.append::<XorMappedAddress>(req.address.address);
         message.append::<MappedAddress>(req.address.address);
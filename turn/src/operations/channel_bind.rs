@@ -1,8 +1,8 @@
 use super::{Requet, Response, ResponseMethod};
-use crate::Observer;
+use crate::{Observer, Operation};
 
 use stun::{
-    attribute::{ChannelNumber, Error, ErrorCode, ErrorKind, Realm, XorPeerAddress},
+    attribute::{ChannelNumber, Error, ErrorCode, ErrorKind, Nonce, Realm, XorPeerAddress},
     Kind, MessageReader, MessageWriter, Method,
 };
 
@@ -17,6 +17,7 @@ fn reject<'a, T: Observer>(
             MessageWriter::extend(Method::ChannelBind(Kind::Error), req.message, req.bytes);
 
         message.append::<ErrorCode>(Error::from(err));
+        message.append::<Nonce>(&req.service.sessions.get_nonce(req.address).get_ref()?.0);
         message.append::<Realm>(&req.service.realm);
         message.flush(None).ok()?;
     }
@@ -33,11 +34,11 @@ fn reject<'a, T: Observer>(
 #[inline(always)]
 fn resolve<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
-    digest: &[u8; 16],
+    digest: Option<&[u8]>,
 ) -> Option<Response<'a>> {
     {
         MessageWriter::extend(Method::ChannelBind(Kind::Response), req.message, req.bytes)
-            .flush(Some(digest))
+            .flush(digest)
             .ok()?;
     }
 
@@ -51,6 +52,11 @@ fn resolve<'a, T: Observer>(
 
 /// process channel binding request
 ///
+/// If the XOR-PEER-ADDRESS attribute contains an address of an address
+/// family that is not the same as that of the relayed transport address
+/// for the allocation, the server MUST generate an error response with
+/// the 443 (Peer Address Family Mismatch) response code.
+///
 /// The server MAY impose restrictions on the IP address and port values
 /// allowed in the XOR-PEER-ADDRESS attribute; if a value is not allowed,
 /// the server rejects the request with a 403 (Forbidden) error.
@@ -87,10 +93,14 @@ pub async fn process<'a, T: Observer>(
         Some(it) => it,
     };
 
-    if !req.verify_ip(&peer) {
+    if !req.verify_family(&peer) {
         return reject(req, ErrorKind::PeerAddressFamilyMismatch);
     }
 
+    if !req.verify_ip(&peer) {
+        return reject(req, ErrorKind::Forbidden);
+    }
+
     let number = match req.message.get::<ChannelNumber>() {
         None => return reject(req, ErrorKind::BadRequest),
         Some(it) => it,
@@ -101,20 +111,33 @@ pub async fn process<'a, T: Observer>(
     }
 
     let (username, digest) = match req.auth().await {
-        None => return reject(req, ErrorKind::Unauthorized),
-        Some(it) => it,
+        Err(err) => return reject(req, err),
+        Ok(it) => it,
     };
 
+    // There is no allocation for the 5-tuple.
+    if !req.is_allocated_or_rebind(username) {
+        return reject(req, ErrorKind::AllocationMismatch);
+    }
+
+    if !req.service.observer.authorize(
+        req.address,
+        username,
+        Operation::ChannelBind(peer.port(), number),
+    ) {
+        return reject(req, ErrorKind::Forbidden);
+    }
+
     if !req
         .service
         .sessions
-        .bind_channel(&req.address, &req.service.endpoint, peer.port(), number)
+        .bind_channel(req.address, &req.service.endpoint, peer.port(), number)
     {
         return reject(req, ErrorKind::Forbidden);
     }
 
     req.service
         .observer
-        .channel_bind(&req.address, username, number);
-    resolve(req, &digest)
+        .channel_bind(req.address, username, number);
+    resolve(req, digest.as_deref())
 }
@@ -1,8 +1,8 @@
 use super::{Requet, Response, ResponseMethod};
-use crate::{Observer, SOFTWARE};
+use crate::{Observer, Operation, SOFTWARE};
 
 use stun::{
-    attribute::{Error, ErrorCode, ErrorKind, Realm, Software, XorPeerAddress},
+    attribute::{Error, ErrorCode, ErrorKind, Nonce, Realm, Software, XorPeerAddress},
     Kind, MessageReader, MessageWriter, Method,
 };
 
@@ -20,6 +20,7 @@ fn reject<'a, T: Observer>(
         );
 
         message.append::<ErrorCode>(Error::from(err));
+        message.append::<Nonce>(&req.service.sessions.get_nonce(req.address).get_ref()?.0);
         message.append::<Realm>(&req.service.realm);
         message.flush(None).ok()?;
     }
@@ -36,7 +37,7 @@ fn reject<'a, T: Observer>(
 #[inline(always)]
 fn resolve<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
-    digest: &[u8; 16],
+    digest: Option<&[u8]>,
 ) -> Option<Response<'a>> {
     {
         let mut message = MessageWriter::extend(
@@ -46,7 +47,7 @@ fn resolve<'a, T: Observer>(
         );
 
         message.append::<Software>(SOFTWARE);
-        message.flush(Some(digest)).ok()?;
+        message.flush(digest).ok()?;
     }
 
     Some(Response {
@@ -100,29 +101,46 @@ pub async fn process<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
 ) -> Option<Response<'a>> {
     let (username, digest) = match req.auth().await {
-        None => return reject(req, ErrorKind::Unauthorized),
-        Some(it) => it,
+        Err(err) => return reject(req, err),
+        Ok(it) => it,
     };
 
+    // There is no allocation for the 5-tuple.
+    if !req.is_allocated_or_rebind(username) {
+        return reject(req, ErrorKind::AllocationMismatch);
+    }
+
     let mut ports = Vec::with_capacity(15);
     for it in req.message.get_all::<XorPeerAddress>() {
-        if !req.verify_ip(&it) {
+        if !req.verify_family(&it) {
             return reject(req, ErrorKind::PeerAddressFamilyMismatch);
         }
 
+        if !req.verify_ip(&it) {
+            return reject(req, ErrorKind::Forbidden);
+        }
+
         ports.push(it.port());
     }
 
+    if !req
+        .service
+        .observer
+        .authorize(req.address, username, Operation::CreatePermission(&ports))
+    {
+        return reject(req, ErrorKind::Forbidden);
+    }
+
     if !req
         .service
         .sessions
-        .create_permission(&req.address, &req.service.endpoint, &ports)
+        .create_permission(req.address, &req.service.endpoint, &ports)
     {
         return reject(req, ErrorKind::Forbidden);
     }
 
     req.service
         .observer
-        .create_permission(&req.address, username, &ports);
-    resolve(req, &digest)
+        .create_permission(req.address, username, &ports);
+    resolve(req, digest.as_deref())
 }
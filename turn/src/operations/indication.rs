@@ -59,18 +59,25 @@ pub fn process<'a, T: Observer>(req: Requet<'_, 'a, T, MessageReader<'_>>) -> Op
     let relay = req
         .service
         .sessions
-        .get_relay_address(&req.address, peer.port())?;
+        .get_relay_address(req.address, peer.port())?;
 
     let local_port = req
         .service
         .sessions
-        .get_session(&req.address)
+        .get_session(req.address)
         .get_ref()?
         .allocate
         .port?;
 
+    // The peer only receives the data of the sessions that it has a
+    // permission for.
+    let peer_addr = req.service.sessions.get_port_session(peer.port())?;
+    if !req.service.sessions.has_permission(&peer_addr, local_port) {
+        return None;
+    }
+
     {
-        let mut message = MessageWriter::extend(Method::DataIndication, &req.message, req.bytes);
+        let mut message = MessageWriter::extend(Method::DataIndication, req.message, req.bytes);
         message.append::<XorPeerAddress>(SocketAddr::new(req.service.interface.ip(), local_port));
         message.append::<Data>(data);
         message.flush(None).ok()?;
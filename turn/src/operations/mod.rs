@@ -7,15 +7,18 @@ pub mod indication;
 pub mod refresh;
 
 use crate::{
+    integrity::{IntegrityPool, Priority},
     sessions::{SessionAddr, Sessions},
     Observer,
 };
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+use ahash::HashMap;
 
 use bytes::BytesMut;
 use stun::{
-    attribute::{Nonce, UserName},
+    attribute::{ErrorKind, MessageIntegrity, Nonce, UserName},
     Decoder, Kind, MessageReader, Method, Payload, StunError,
 };
 
@@ -25,6 +28,184 @@ pub enum ResponseMethod {
     ChannelData,
 }
 
+/// The reason why the authentication of a request failed.
+///
+/// The first request of a session without MESSAGE-INTEGRITY is the normal
+/// challenge of the long-term credential mechanism and is not a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthFailure {
+    /// The observer has no password for the username.
+    UnknownUser,
+    /// The MESSAGE-INTEGRITY does not match the password of the username.
+    BadIntegrity,
+    /// The nonce is expired or was not issued to the address.
+    StaleNonce,
+    /// The username is not the one of the existing session.
+    WrongCredentials,
+}
+
+impl AuthFailure {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::operations::AuthFailure;
+    ///
+    /// assert_eq!(AuthFailure::UnknownUser.as_str(), "unknown-user");
+    /// assert_eq!(AuthFailure::BadIntegrity.as_str(), "bad-integrity");
+    /// assert_eq!(AuthFailure::StaleNonce.as_str(), "stale-nonce");
+    /// assert_eq!(AuthFailure::WrongCredentials.as_str(), "wrong-credentials");
+    /// ```
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnknownUser => "unknown-user",
+            Self::BadIntegrity => "bad-integrity",
+            Self::StaleNonce => "stale-nonce",
+            Self::WrongCredentials => "wrong-credentials",
+        }
+    }
+}
+
+/// The lifetime of an allocation when the client does not request one.
+pub const DEFAULT_LIFETIME: u32 = 600;
+
+/// The longest lifetime that is granted to an allocation, longer requested
+/// lifetimes are clamped to it.
+pub const MAX_LIFETIME: u32 = 3600;
+
+/// The future returned by a processor.
+pub type ProcessorFuture<'c, 'a> = Pin<Box<dyn Future<Output = Option<Response<'a>>> + Send + 'c>>;
+
+/// A handler of a stun method.
+///
+/// Processors are registered in the service by the method of the request, a
+/// registered processor overrides the built-in processor of the method, and
+/// can also handle the methods that are not supported by default, such as
+/// custom indications, which are decoded as `Method::Other` with their
+/// message type. The response is written to `req.bytes`, returning `None`
+/// means that the request is dropped without a response.
+pub trait Processor<T: Observer + 'static>: Send + Sync {
+    fn process<'c, 'a: 'c>(
+        &'c self,
+        req: Requet<'c, 'a, T, MessageReader<'c>>,
+    ) -> ProcessorFuture<'c, 'a>;
+}
+
+/// A middleware around the processing of every request.
+///
+/// Interceptors are called in the order of registration before the request
+/// is processed, and in the reverse order after the request is processed.
+/// They are used for the cross-cutting concerns such as rate limiting,
+/// logging, or rewriting the responses, so that the processors do not have to
+/// handle them.
+#[allow(unused)]
+pub trait Interceptor: Send + Sync {
+    /// Called before the request is processed, returning `false` drops the
+    /// request without a response, and the remaining interceptors are
+    /// skipped.
+    fn before(&self, addr: &SessionAddr, payload: &Payload<'_>) -> bool {
+        true
+    }
+
+    /// Called after the request is processed, the response can be modified or
+    /// removed.
+    fn after(&self, addr: &SessionAddr, response: &mut Option<Response<'_>>) {}
+}
+
+/// The processors registered in the service, by the method of the request.
+pub type Processors<T> = HashMap<Method, Arc<dyn Processor<T>>>;
+
+/// The transport protocol of the listener that received the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IngressTransport {
+    Udp,
+    Tcp,
+    Tls,
+    Dtls,
+}
+
+impl IngressTransport {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::operations::IngressTransport;
+    ///
+    /// assert_eq!(IngressTransport::Udp.as_str(), "udp");
+    /// assert_eq!(IngressTransport::Dtls.as_str(), "dtls");
+    /// ```
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+            Self::Tls => "tls",
+            Self::Dtls => "dtls",
+        }
+    }
+}
+
+/// The credential mechanism of the requests received on a listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CredentialMechanism {
+    /// The long-term credentials, the client is challenged with a nonce and
+    /// the realm, and the key is derived from the username, the realm and the
+    /// password.
+    #[default]
+    LongTerm,
+    /// The short-term credentials of RFC 8489, the key is the password, no
+    /// nonce or realm is used. It is meant for the trusted deployments where
+    /// the credentials are exchanged out of band, such as ICE between
+    /// controlled endpoints.
+    ShortTerm,
+}
+
+/// The metadata of the transport that the requests are received on.
+///
+/// # Test
+///
+/// ```
+/// use mycrl_turn::operations::*;
+///
+/// let transport = TransportContext::new(IngressTransport::Tcp, "127.0.0.1:3478".parse().unwrap());
+/// assert!(transport.is_stream());
+/// assert_eq!(transport.peer_identity, None);
+/// assert_eq!(transport.credential, CredentialMechanism::LongTerm);
+///
+/// let transport = TransportContext::new(IngressTransport::Udp, "127.0.0.1:3478".parse().unwrap());
+/// assert!(!transport.is_stream());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportContext {
+    pub transport: IngressTransport,
+    /// The local address that the listener is bound to.
+    pub listener: SocketAddr,
+    /// The identity of the peer certificate, only for TLS and DTLS.
+    pub peer_identity: Option<String>,
+    /// The negotiated application protocol, only for TLS and DTLS.
+    pub alpn: Option<Vec<u8>>,
+    /// The credential mechanism of the listener.
+    pub credential: CredentialMechanism,
+}
+
+impl TransportContext {
+    pub fn new(transport: IngressTransport, listener: SocketAddr) -> Self {
+        Self {
+            credential: CredentialMechanism::LongTerm,
+            peer_identity: None,
+            alpn: None,
+            transport,
+            listener,
+        }
+    }
+
+    /// Whether the requests are received on a stream transport, where the
+    /// 5-tuple is bound to the connection.
+    pub fn is_stream(&self) -> bool {
+        matches!(
+            self.transport,
+            IngressTransport::Tcp | IngressTransport::Tls
+        )
+    }
+}
+
 /// The context of the service.
 ///
 /// A service corresponds to a Net Endpoint, different sockets have different
@@ -35,6 +216,10 @@ pub struct ServiceContext<T: Observer> {
     pub endpoint: SocketAddr,
     pub interface: SocketAddr,
     pub interfaces: Arc<Vec<SocketAddr>>,
+    pub transport: TransportContext,
+    pub processors: Arc<Processors<T>>,
+    pub interceptors: Arc<Vec<Arc<dyn Interceptor>>>,
+    pub integrity: Option<Arc<IntegrityPool>>,
     pub observer: T,
 }
 
@@ -53,6 +238,71 @@ impl<'a, 'b, T> Requet<'a, 'b, T, MessageReader<'a>>
 where
     T: Observer + 'static,
 {
+    /// Check if the address has the same address family as the relayed
+    /// transport address of the allocation.
+    ///
+    /// The relayed transport address is always allocated on the interface
+    /// that received the request, so the address family of the interface is
+    /// the address family of the allocation.
+    #[inline(always)]
+    pub(crate) fn verify_family(&self, address: &SocketAddr) -> bool {
+        self.service.interface.is_ipv4() == address.is_ipv4()
+    }
+
+    /// Check if the session of the request already has an allocation.
+    #[inline(always)]
+    pub(crate) fn is_allocated(&self) -> bool {
+        self.service
+            .sessions
+            .get_session(self.address)
+            .get_ref()
+            .and_then(|it| it.allocate.port)
+            .is_some()
+    }
+
+    /// Check if the session of the authenticated request has an allocation,
+    /// moving the allocation of the user from its previous address to this
+    /// one if the address changed and the address rebind policy allows it.
+    ///
+    /// The allocation of a stream transport ends with its connection, a new
+    /// connection is never matched to it.
+    #[inline(always)]
+    pub(crate) fn is_allocated_or_rebind(&self, username: &str) -> bool {
+        if self.is_allocated() {
+            return true;
+        }
+
+        let Some(from) = self.find_moved(username) else {
+            return false;
+        };
+
+        let rebound = self
+            .service
+            .sessions
+            .address_rebind()
+            .allows(&from, self.address)
+            && self.service.sessions.rebind(&from, self.address);
+
+        self.service
+            .observer
+            .address_changed(&from, self.address, username, rebound);
+        rebound
+    }
+
+    /// Find the allocation that the client of the request had before its
+    /// address changed, by the nonce of the request.
+    #[inline(always)]
+    fn find_moved(&self, username: &str) -> Option<SessionAddr> {
+        if self.service.transport.is_stream() {
+            return None;
+        }
+
+        let nonce = self.message.get::<Nonce>()?;
+        self.service
+            .sessions
+            .find_moved(self.address, username, nonce)
+    }
+
     /// Check if the ip address belongs to the current turn server.
     #[inline(always)]
     pub(crate) fn verify_ip(&self, address: &SocketAddr) -> bool {
@@ -107,32 +357,172 @@ where
     /// the end of the MESSAGE-INTEGRITY attribute prior to calculating the
     /// HMAC.  Such adjustment is necessary when attributes, such as
     /// FINGERPRINT, appear after MESSAGE-INTEGRITY.
+    ///
+    /// The failures are mapped to the error codes required by the RFCs: a
+    /// request without MESSAGE-INTEGRITY is challenged with 401
+    /// (Unauthorized), a request with MESSAGE-INTEGRITY but without USERNAME
+    /// or NONCE is answered with 400 (Bad Request), an expired or unknown
+    /// nonce is answered with 438 (Stale Nonce) unless it is the nonce of the
+    /// allocation of the user on a previous address of the client and the
+    /// address rebind policy allows moving it, the change of the address is
+    /// reported otherwise once MESSAGE-INTEGRITY is verified, and a
+    /// request of an existing session that uses a different username is
+    /// answered with 441 (Wrong Credentials) once its MESSAGE-INTEGRITY is
+    /// verified with the credentials of that username. The failures of
+    /// requests that carry credentials are reported to the observer.
+    ///
+    /// With the short-term credentials there is nothing to challenge, so a
+    /// request without MESSAGE-INTEGRITY or USERNAME is answered with 400
+    /// (Bad Request), and the nonce is not checked.
+    ///
+    /// A trusted source can send the requests without MESSAGE-INTEGRITY and
+    /// USERNAME, they are accepted as the anonymous user, whose username is
+    /// empty and has no key.
+    ///
+    /// Returns the username and the key of the message integrity.
     #[inline(always)]
-    pub(crate) async fn auth(&self) -> Option<(&'a str, [u8; 16])> {
-        let username = self.message.get::<UserName>()?;
-        let digest = self
-            .service
-            .sessions
-            .get_digest(&self.address, username, self.service.realm.as_str())
-            .await?;
+    pub async fn auth(&self) -> Result<(&'a str, Option<Vec<u8>>), ErrorKind> {
+        let short_term = self.service.transport.credential == CredentialMechanism::ShortTerm;
+        if self.message.get::<MessageIntegrity>().is_none() {
+            if self.message.get::<UserName>().is_none()
+                && self.service.observer.is_trusted(self.address)
+                && self.service.sessions.authenticate_anonymous(self.address)
+            {
+                return Ok(("", None));
+            }
+
+            return Err(if short_term {
+                ErrorKind::BadRequest
+            } else {
+                ErrorKind::Unauthorized
+            });
+        }
+
+        let (username, nonce) = match (self.message.get::<UserName>(), self.message.get::<Nonce>())
+        {
+            (Some(username), nonce) if short_term => (username, nonce),
+            (Some(username), Some(nonce)) => (username, Some(nonce)),
+            _ => return Err(ErrorKind::BadRequest),
+        };
+
+        let failed = |reason: AuthFailure| {
+            self.service
+                .observer
+                .auth_failed(self.address, username, reason);
+        };
+
+        // A nonce that was issued before the server restarted is still accepted
+        // if it is signed with the same secret.
+        if let (false, Some(nonce)) = (short_term, nonce) {
+            self.service.sessions.restore_nonce(self.address, nonce);
+        }
 
-        // if nonce is not empty, check nonce
-        if let Some(nonce) = self.message.get::<Nonce>() {
-            if self
+        // The nonce that was issued to the previous address of a client whose
+        // address changed is accepted if the address rebind policy allows moving
+        // its allocation, otherwise the change is only reported once the request
+        // is verified.
+        let mut refused_move = None;
+        if !short_term
+            && self
                 .service
                 .sessions
-                .get_nonce(&self.address)
-                .get_ref()?
-                .0
-                .as_str()
-                != nonce
+                .get_nonce(self.address)
+                .get_ref()
+                .map(|it| Some(it.0.as_str()) != nonce)
+                .unwrap_or(true)
+        {
+            let Some(from) = self.find_moved(username) else {
+                failed(AuthFailure::StaleNonce);
+                return Err(ErrorKind::StaleNonce);
+            };
+
+            if !self
+                .service
+                .sessions
+                .address_rebind()
+                .allows(&from, self.address)
             {
-                return None;
+                refused_move = Some(from);
             }
         }
 
-        self.message.integrity(&digest).ok()?;
-        Some((username, digest))
+        // The credentials of an existing session can not be changed, the request
+        // is checked with the credentials of its own username first, so that only
+        // a request that proves them is reported as using the wrong credentials.
+        let wrong_credentials = self
+            .service
+            .sessions
+            .get_session(self.address)
+            .get_ref()
+            .is_some_and(|it| it.auth.username != username);
+
+        let realm = self.service.realm.as_str();
+        let auth = if wrong_credentials || refused_move.is_some() {
+            self.service
+                .sessions
+                .lookup_auth(self.address, username, realm)
+                .await
+                .map(|(it, _)| it)
+        } else {
+            self.service
+                .sessions
+                .get_auth(self.address, username, realm)
+                .await
+        };
+
+        let auth = match auth {
+            Some(it) => it,
+            None => {
+                failed(AuthFailure::UnknownUser);
+                return Err(ErrorKind::Unauthorized);
+            }
+        };
+
+        let key = if short_term {
+            auth.password.into_bytes()
+        } else {
+            auth.digest.to_vec()
+        };
+
+        let verified = match &self.service.integrity {
+            Some(pool) => {
+                let priority = if self.is_allocated() {
+                    Priority::Session
+                } else {
+                    Priority::New
+                };
+
+                match self.message.signed() {
+                    Ok(signed) => match pool.verify(signed, key.clone(), priority) {
+                        Some(verification) => verification.await,
+                        None => return Err(ErrorKind::InsufficientCapacity),
+                    },
+                    Err(_) => false,
+                }
+            }
+            None => self.message.integrity(&key).is_ok(),
+        };
+
+        if !verified {
+            failed(AuthFailure::BadIntegrity);
+            return Err(ErrorKind::Unauthorized);
+        }
+
+        if let Some(from) = refused_move {
+            self.service
+                .observer
+                .address_changed(&from, self.address, username, false);
+
+            failed(AuthFailure::StaleNonce);
+            return Err(ErrorKind::StaleNonce);
+        }
+
+        if wrong_credentials {
+            failed(AuthFailure::WrongCredentials);
+            return Err(ErrorKind::WrongCredentials);
+        }
+
+        Ok((username, Some(key)))
     }
 }
 
@@ -144,6 +534,26 @@ pub struct Response<'a> {
     pub endpoint: Option<SocketAddr>,
 }
 
+/// The response of the service that owns its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedResponse {
+    pub method: ResponseMethod,
+    pub bytes: Vec<u8>,
+    pub relay: Option<SocketAddr>,
+    pub endpoint: Option<SocketAddr>,
+}
+
+impl From<Response<'_>> for OwnedResponse {
+    fn from(value: Response<'_>) -> Self {
+        Self {
+            method: value.method,
+            bytes: value.bytes.to_vec(),
+            relay: value.relay,
+            endpoint: value.endpoint,
+        }
+    }
+}
+
 /// process udp message and return message + address
 pub struct Operationer<T>
 where
@@ -289,7 +699,14 @@ where
     ) -> Result<Option<Response<'a>>, StunError> {
         self.address.address = address;
 
-        Ok(match self.decoder.decode(bytes)? {
+        let payload = self.decoder.decode(bytes)?;
+        for interceptor in self.service.interceptors.iter() {
+            if !interceptor.before(&self.address, &payload) {
+                return Ok(None);
+            }
+        }
+
+        let mut res = match payload {
             Payload::ChannelData(channel) => channel_data::process(bytes, Requet {
                 bytes: &mut self.bytes,
                 service: &self.service,
@@ -304,16 +721,28 @@ where
                     message: &message,
                 };
 
-                match req.message.method {
-                    Method::Binding(Kind::Request) => binding::process(req),
-                    Method::Allocate(Kind::Request) => allocate::process(req).await,
-                    Method::CreatePermission(Kind::Request) => create_permission::process(req).await,
-                    Method::ChannelBind(Kind::Request) => channel_bind::process(req).await,
-                    Method::Refresh(Kind::Request) => refresh::process(req).await,
-                    Method::SendIndication => indication::process(req),
-                    _ => None,
+                if let Some(processor) = self.service.processors.get(&req.message.method) {
+                    processor.process(req).await
+                } else {
+                    match req.message.method {
+                        Method::Binding(Kind::Request) => binding::process(req),
+                        Method::Allocate(Kind::Request) => allocate::process(req).await,
+                        Method::CreatePermission(Kind::Request) => create_permission::process(req).await,
+                        Method::ChannelBind(Kind::Request) => channel_bind::process(req).await,
+                        Method::Refresh(Kind::Request) => refresh::process(req).await,
+                        Method::SendIndication => indication::process(req),
+                        _ => None,
+                    }
                 }
             }
-        })
+        };
+
+        // The interceptors are unwound in the reverse order, so that the first
+        // interceptor sees the request first and the response last.
+        for interceptor in self.service.interceptors.iter().rev() {
+            interceptor.after(&self.address, &mut res);
+        }
+
+        Ok(res)
     }
 }
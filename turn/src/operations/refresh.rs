@@ -1,9 +1,9 @@
 use stun::{
-    attribute::{Error, ErrorCode, ErrorKind, Lifetime},
+    attribute::{Error, ErrorCode, ErrorKind, Lifetime, Nonce, Realm},
     Kind, MessageReader, MessageWriter, Method,
 };
 
-use super::{Requet, Response, ResponseMethod};
+use super::{Requet, Response, ResponseMethod, DEFAULT_LIFETIME, MAX_LIFETIME};
 use crate::Observer;
 
 /// return refresh error response
@@ -14,9 +14,11 @@ fn reject<'a, T: Observer>(
 ) -> Option<Response<'a>> {
     {
         let mut message =
-            MessageWriter::extend(Method::Refresh(Kind::Error), &req.message, req.bytes);
+            MessageWriter::extend(Method::Refresh(Kind::Error), req.message, req.bytes);
 
         message.append::<ErrorCode>(Error::from(err));
+        message.append::<Nonce>(&req.service.sessions.get_nonce(req.address).get_ref()?.0);
+        message.append::<Realm>(&req.service.realm);
         message.flush(None).ok()?;
     }
 
@@ -33,14 +35,14 @@ fn reject<'a, T: Observer>(
 pub fn resolve<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
     lifetime: u32,
-    digest: &[u8; 16],
+    digest: Option<&[u8]>,
 ) -> Option<Response<'a>> {
     {
         let mut message =
-            MessageWriter::extend(Method::Refresh(Kind::Response), &req.message, req.bytes);
+            MessageWriter::extend(Method::Refresh(Kind::Response), req.message, req.bytes);
 
         message.append::<Lifetime>(lifetime);
-        message.flush(Some(digest)).ok()?;
+        message.flush(digest).ok()?;
     }
 
     Some(Response {
@@ -93,17 +95,35 @@ pub async fn process<'a, T: Observer>(
     req: Requet<'_, 'a, T, MessageReader<'_>>,
 ) -> Option<Response<'a>> {
     let (username, digest) = match req.auth().await {
-        None => return reject(req, ErrorKind::Unauthorized),
-        Some(it) => it,
+        Err(err) => return reject(req, err),
+        Ok(it) => it,
     };
 
-    let lifetime = req.message.get::<Lifetime>().unwrap_or(600);
-    if !req.service.sessions.refresh(&req.address, lifetime) {
+    // There is no allocation for the 5-tuple.
+    if !req.is_allocated_or_rebind(username) {
         return reject(req, ErrorKind::AllocationMismatch);
     }
 
-    req.service
-        .observer
-        .refresh(&req.address, username, lifetime);
-    resolve(req, lifetime, &digest)
+    // A zero lifetime deletes the allocation, the other lifetimes are granted
+    // within the default and the maximum lifetime.
+    let requested = req.message.get::<Lifetime>();
+    let lifetime = requested
+        .map(|it| match it {
+            0 => 0,
+            it => it.clamp(DEFAULT_LIFETIME, MAX_LIFETIME),
+        })
+        .unwrap_or(DEFAULT_LIFETIME);
+
+    if !req.service.sessions.refresh(req.address, lifetime) {
+        return reject(req, ErrorKind::AllocationMismatch);
+    }
+
+    req.service.observer.refresh(
+        req.address,
+        username,
+        lifetime,
+        requested.is_some_and(|it| it != lifetime),
+    );
+
+    resolve(req, lifetime, digest.as_deref())
 }
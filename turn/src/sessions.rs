@@ -1,21 +1,25 @@
-use crate::Observer;
+use crate::{operations::IngressTransport, Observer};
 
 use std::{
+    collections::BTreeMap,
     hash::Hash,
     net::SocketAddr,
     ops::{Deref, DerefMut, Range},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     thread::{self, sleep},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use ahash::{HashMap, HashMapExt};
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
 use parking_lot::{Mutex, RwLock, RwLockReadGuard};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use stun::util::long_term_credential_digest;
+use stun::{
+    attribute::Transport,
+    util::{hmac_sha1, long_term_credential_digest},
+};
 
 /// Authentication information for the session.
 ///
@@ -28,6 +32,46 @@ pub struct Auth {
     pub digest: [u8; 16],
 }
 
+/// The tags of a session, such as the tenant or the plan of the user.
+pub type SessionTags = BTreeMap<String, String>;
+
+/// The credential of a user that the observer looks up.
+///
+/// # Test
+///
+/// ```
+/// use mycrl_turn::*;
+///
+/// #[derive(Clone)]
+/// struct ObserverTest;
+///
+/// impl Observer for ObserverTest {
+///     async fn get_credential(&self, _: &SessionAddr, _: &str) -> Option<Credential> {
+///         Some(Credential {
+///             password: "test".to_string(),
+///             tags: [("tenant".to_string(), "acme".to_string())].into(),
+///         })
+///     }
+/// }
+///
+/// let addr = SessionAddr {
+///     address: "127.0.0.1:8080".parse().unwrap(),
+///     interface: "127.0.0.1:3478".parse().unwrap(),
+/// };
+///
+/// let sessions = Sessions::new(ObserverTest);
+/// pollster::block_on(sessions.get_auth(&addr, "test", "test")).unwrap();
+///
+/// let lock = sessions.get_session(&addr);
+/// assert_eq!(lock.get_ref().unwrap().tags["tenant"], "acme");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Credential {
+    pub password: String,
+    /// The tags that are attached to the session at authentication.
+    pub tags: SessionTags,
+}
+
 /// Assignment information for the session.
 ///
 /// Sessions are all bound to only one port and one channel.
@@ -35,6 +79,46 @@ pub struct Auth {
 pub struct Allocate {
     pub port: Option<u16>,
     pub channels: Vec<u16>,
+    /// The transport requested by the client, only set once the port is
+    /// allocated.
+    pub transport: Option<Transport>,
+    /// The transaction id of the allocate request that allocated the port,
+    /// so that its retransmissions are recognized.
+    pub token: Option<[u8; 12]>,
+    /// The transport of the listener that the port was allocated on.
+    pub ingress: Option<IngressTransport>,
+    /// The generation of the allocation of the port, see [`RelayPort`].
+    pub generation: Option<u64>,
+}
+
+/// The lifetime of a permission in seconds, the create permission requests
+/// of the session refresh it.
+pub const PERMISSION_LIFETIME: u64 = 300;
+
+/// A permission of the session to receive data from a peer.
+///
+/// The peers are the relay ports of the other sessions, so a permission is
+/// for the relayed transport address of the peer rather than only its ip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permission {
+    /// The relay port of the peer.
+    pub port: u16,
+    /// The time the permission expires.
+    pub expires: u64,
+    /// Whether a channel is bound to the peer, the channels are bound for
+    /// the lifetime of the allocation and so are their permissions.
+    pub bound: bool,
+}
+
+/// The session that a relay port is allocated to.
+///
+/// The ports are recycled when the sessions are closed, the generation is
+/// different for every allocation of a port, so that a reference to a
+/// previous owner of the port can be told apart from the current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayPort {
+    pub session: SessionAddr,
+    pub generation: u64,
 }
 
 /// turn session information.
@@ -46,8 +130,132 @@ pub struct Allocate {
 pub struct Session {
     pub auth: Auth,
     pub allocate: Allocate,
-    pub permissions: Vec<u16>,
+    pub permissions: Vec<Permission>,
     pub expires: u64,
+    pub created: u64,
+    pub tags: SessionTags,
+}
+
+/// The reason why a session was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// The lifetime of the session has expired.
+    Expired,
+    /// The client released the allocation with a refresh request of lifetime
+    /// 0.
+    ClientReleased,
+    /// The session was removed by the server, e.g. kicked through the api.
+    Removed,
+    /// The stream connection carrying the session was closed.
+    Disconnected,
+    /// The stream connection carrying the session was idle for too long.
+    IdleTimeout,
+    /// The credentials of the session are no longer accepted by the
+    /// observer.
+    Revoked,
+}
+
+impl CloseReason {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::sessions::CloseReason;
+    ///
+    /// assert_eq!(CloseReason::Expired.as_str(), "expired");
+    /// assert_eq!(CloseReason::ClientReleased.as_str(), "client-released");
+    /// assert_eq!(CloseReason::Removed.as_str(), "removed");
+    /// assert_eq!(CloseReason::Disconnected.as_str(), "disconnected");
+    /// assert_eq!(CloseReason::IdleTimeout.as_str(), "idle-timeout");
+    /// assert_eq!(CloseReason::Revoked.as_str(), "revoked");
+    /// ```
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Expired => "expired",
+            Self::ClientReleased => "client-released",
+            Self::Removed => "removed",
+            Self::Disconnected => "disconnected",
+            Self::IdleTimeout => "idle-timeout",
+            Self::Revoked => "revoked",
+        }
+    }
+}
+
+/// What is done with an allocate request on a 5-tuple that already has an
+/// allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DuplicateAllocate {
+    /// The request is rejected with a 437 (Allocation Mismatch) error, as
+    /// the rfc requires.
+    #[default]
+    Reject,
+    /// The existing allocation is released and a new one is allocated, for
+    /// the clients that allocate again without releasing. A retransmission
+    /// of the request that allocated the port gets the same port again.
+    Replace,
+}
+
+impl DuplicateAllocate {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::sessions::DuplicateAllocate;
+    ///
+    /// assert_eq!(DuplicateAllocate::Reject.as_str(), "reject");
+    /// assert_eq!(DuplicateAllocate::Replace.as_str(), "replace");
+    /// ```
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Replace => "replace",
+        }
+    }
+}
+
+/// What is done with a request of an address without an allocation, when
+/// the user has an allocation on the interface from another address, such as
+/// after a NAT rebinding or an ECMP re-hash of an anycast address moved the
+/// flow of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AddressRebind {
+    /// The request is rejected with a 438 (Stale Nonce) error, the nonce is
+    /// only valid on the address it was issued to, the change of the
+    /// address is only reported.
+    #[default]
+    Disabled,
+    /// The allocation is moved to the new address if only the port of the
+    /// address changed.
+    SameIp,
+    /// The allocation is moved to the new address of the user, from any ip.
+    SameUser,
+}
+
+impl AddressRebind {
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::sessions::AddressRebind;
+    ///
+    /// assert_eq!(AddressRebind::Disabled.as_str(), "disabled");
+    /// assert_eq!(AddressRebind::SameIp.as_str(), "same-ip");
+    /// assert_eq!(AddressRebind::SameUser.as_str(), "same-user");
+    /// ```
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Disabled => "disabled",
+            Self::SameIp => "same-ip",
+            Self::SameUser => "same-user",
+        }
+    }
+
+    /// Whether the allocation of the address is moved to the new address
+    /// under the policy.
+    pub fn allows(&self, from: &SessionAddr, to: &SessionAddr) -> bool {
+        match self {
+            Self::Disabled => false,
+            Self::SameIp => from.address.ip() == to.address.ip(),
+            Self::SameUser => true,
+        }
+    }
 }
 
 /// The identifier of the session or addr.
@@ -69,6 +277,50 @@ pub struct Endpoint {
     pub endpoint: SocketAddr,
 }
 
+/// A channel bound by a session, and the peer session it is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelBinding {
+    pub session: SessionAddr,
+    pub peer: SessionAddr,
+    pub peer_port: u16,
+    /// The seconds since data was last relayed to the session over the
+    /// channel, or since the channel was bound.
+    pub idle: u64,
+    /// Whether the channel has been idle for longer than the channel idle
+    /// timeout of the maintenance.
+    pub stale: bool,
+}
+
+/// The channel forwarding of a peer, and the traffic that went through it.
+///
+/// The activity is tracked separately from the lifetime of the session, a
+/// client that keeps refreshing its allocation can still have a channel
+/// that no data is relayed over.
+struct ChannelRelay {
+    endpoint: Endpoint,
+    // The session that bound the channel.
+    session: SessionAddr,
+    // The time that data was last relayed over the channel.
+    active: AtomicU64,
+    stale: AtomicBool,
+}
+
+/// The number of entries in each of the bookkeeping tables of the sessions.
+///
+/// Every entry belongs to a session, so all of the counters return to zero
+/// when all of the sessions are closed, anything left is a leak.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    pub sessions: usize,
+    pub nonces: usize,
+    /// The ports taken from the port pool.
+    pub allocated: usize,
+    /// The ports mapped to the session that owns them.
+    pub ports: usize,
+    pub permissions: usize,
+    pub channels: usize,
+}
+
 /// A specially optimised timer.
 ///
 /// This timer does not stack automatically and needs to be stacked externally
@@ -96,92 +348,356 @@ impl Timer {
     }
 }
 
+// The issue time and the signature of a signed nonce in hex.
+const SIGNED_NONCE_SIZE: usize = 24;
+
+// The signed nonces carry the wall time, the time of the sessions starts
+// over with the process.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+// The signature covers the address, so that a nonce is only accepted from the
+// address that it was issued to.
+fn sign_nonce(secret: &[u8], key: &SessionAddr, issued: u64) -> String {
+    let mut nonce = format!("{:08x}", issued as u32);
+    let addrs = format!("{}/{}", key.address, key.interface);
+    if let Ok(mac) = hmac_sha1(secret, &[nonce.as_bytes(), addrs.as_bytes()]) {
+        for it in &mac.into_bytes()[..8] {
+            nonce.push_str(&format!("{:02x}", it));
+        }
+    }
+
+    nonce
+}
+
+/// The source of the time of the sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// The time advances by one second every second.
+    System,
+    /// The time only advances with [`Sessions::advance`].
+    Manual,
+}
+
+/// The cadence of the maintenance of the sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Maintenance {
+    /// The seconds between the sweeps of the expired sessions and nonces.
+    pub sweep_interval: u64,
+    /// The expired sessions and nonces that are removed while holding the
+    /// locks of the tables once, zero removes them all at once.
+    pub sweep_batch_size: usize,
+    /// The seconds that a nonce is valid for before it is rotated.
+    pub nonce_lifetime: u64,
+    /// Up to this number of seconds are added at random to every sweep
+    /// interval, so that the sweeps of many servers are spread out.
+    pub jitter: u64,
+    /// The seconds without relayed data after which a bound channel is
+    /// reported as stale, zero disables the detection.
+    pub channel_idle_timeout: u64,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            sweep_interval: 1,
+            sweep_batch_size: 1024,
+            nonce_lifetime: 600,
+            jitter: 0,
+            channel_idle_timeout: 0,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct State {
     sessions: RwLock<Table<SessionAddr, Session>>,
     port_allocate_pool: Mutex<PortAllocatePools>,
     // Records the sessions corresponding to each assigned port, which will be needed when looking
     // up sessions assigned to this port based on the port number.
-    port_mapping_table: RwLock<Table</* port */ u16, SessionAddr>>,
+    port_mapping_table: RwLock<Table</* port */ u16, RelayPort>>,
     // Records the nonce value for each network connection, which is independent of the session
     // because it can exist before it is authenticated.
     address_nonce_tanle: RwLock<Table<SessionAddr, (String, /* expires */ u64)>>,
+    // The address that each nonce was issued to, which finds the allocation that a client
+    // moved away from by the nonce it still uses.
+    nonce_address_table: RwLock<Table<String, SessionAddr>>,
     // Stores the address to which the session should be forwarded when it sends indication to a
     // port. This is written when permissions are created to allow a certain address to be
     // forwarded to the current session.
     port_relay_table: RwLock<Table<SessionAddr, HashMap</* port */ u16, Endpoint>>>,
     // Indicates to which session the data sent by a session to a channel should be forwarded.
-    channel_relay_table: RwLock<Table<SessionAddr, HashMap</* channel */ u16, Endpoint>>>,
+    channel_relay_table: RwLock<Table<SessionAddr, HashMap</* channel */ u16, ChannelRelay>>>,
 }
 
 pub struct Sessions<T> {
     timer: Timer,
     state: State,
     observer: T,
+    // The percentage of free ports below which the pool is shared fairly
+    // between the source addresses, zero disables it.
+    fair_share: AtomicUsize,
+    maintenance: RwLock<Maintenance>,
+    // The time of the next sweep.
+    next_sweep: AtomicU64,
+    // The generation of the last allocated port.
+    generation: AtomicU64,
+    duplicate_allocate: RwLock<DuplicateAllocate>,
+    address_rebind: RwLock<AddressRebind>,
+    // The secret that the nonces are signed with, the nonces are random when
+    // it is not set.
+    nonce_secret: RwLock<Option<[u8; 32]>>,
 }
 
 impl<T: Observer + 'static> Sessions<T> {
     pub fn new(observer: T) -> Arc<Self> {
+        Self::with_port_range(observer, DEFAULT_PORT_RANGE)
+    }
+
+    /// Create sessions that allocate the relay ports from the range.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_port_range(ObserverTest, 50000..50001);
+    /// assert_eq!(sessions.capacity(), 1);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// assert_eq!(sessions.allocate(&addr), Some(50000));
+    /// ```
+    pub fn with_port_range(observer: T, port_range: Range<u16>) -> Arc<Self> {
+        Self::with_clock(observer, port_range, Clock::System)
+    }
+
+    /// Create sessions with the clock, with a manual clock the time of the
+    /// sessions only moves with [`Sessions::advance`], so that the expiry of
+    /// the sessions can be tested without waiting for it.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::Clock, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_clock(ObserverTest, DEFAULT_PORT_RANGE, Clock::Manual);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// assert_eq!(sessions.get_session(&addr).get_ref().unwrap().expires, 600);
+    /// assert_eq!(sessions.now(), 0);
+    /// ```
+    pub fn with_clock(observer: T, port_range: Range<u16>, clock: Clock) -> Arc<Self> {
         let this = Arc::new(Self {
-            state: State::default(),
+            state: State {
+                port_allocate_pool: Mutex::new(PortAllocatePools::new(port_range)),
+                ..Default::default()
+            },
             timer: Timer::default(),
+            fair_share: AtomicUsize::new(0),
+            maintenance: RwLock::new(Maintenance::default()),
+            next_sweep: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+            duplicate_allocate: RwLock::new(DuplicateAllocate::default()),
+            address_rebind: RwLock::new(AddressRebind::default()),
+            nonce_secret: RwLock::new(None),
             observer,
         });
 
         // This is a background thread that silently handles expiring sessions and
         // cleans up session information when it expires.
-        let this_ = Arc::downgrade(&this);
-        thread::spawn(move || {
-            let mut address = Vec::with_capacity(255);
+        if clock == Clock::System {
+            let this_ = Arc::downgrade(&this);
+            thread::spawn(move || {
+                while let Some(this) = this_.upgrade() {
+                    this.advance(1);
+                    drop(this);
+
+                    // Fixing a second tick.
+                    sleep(Duration::from_secs(1));
+                }
+            });
+        }
 
-            while let Some(this) = this_.upgrade() {
-                // The timer advances one second and gets the current time offset.
-                let now = this.timer.add();
+        this
+    }
 
-                // This is the part that deletes the session information.
-                {
-                    // Finds sessions that have expired.
-                    {
-                        this.state
-                            .sessions
-                            .read()
-                            .iter()
-                            .filter(|(_, v)| v.expires <= now)
-                            .for_each(|(k, _)| address.push(*k));
-                    }
+    /// Advance the time of the sessions by the seconds, and close the
+    /// sessions and drop the nonces that have expired in the meantime.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::{Clock, Counters}, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_clock(ObserverTest, DEFAULT_PORT_RANGE, Clock::Manual);
+    ///
+    /// sessions.get_nonce(&addr);
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// sessions.allocate(&addr).unwrap();
+    /// sessions.refresh(&addr, 60);
+    ///
+    /// sessions.advance(59);
+    /// assert_eq!(sessions.now(), 59);
+    /// assert!(sessions.get_session(&addr).get_ref().is_some());
+    ///
+    /// sessions.advance(1);
+    /// assert!(sessions.get_session(&addr).get_ref().is_none());
+    /// assert_eq!(sessions.counters(), Counters::default());
+    /// ```
+    pub fn advance(&self, seconds: u64) {
+        for _ in 0..seconds {
+            // The timer advances one second and gets the current time offset.
+            let now = self.timer.add();
+            if now < self.next_sweep.load(Ordering::Relaxed) {
+                continue;
+            }
 
-                    // Delete the expired sessions.
-                    if !address.is_empty() {
-                        this.remove_session(&address);
-                        address.clear();
-                    }
-                }
+            let maintenance = *self.maintenance.read();
+            let jitter = if maintenance.jitter > 0 {
+                thread_rng().gen_range(0..=maintenance.jitter)
+            } else {
+                0
+            };
 
-                // Because nonce does not follow session creation, nonce is created for each
-                // addr, so nonce deletion is handled independently.
-                {
-                    this.state
-                        .address_nonce_tanle
-                        .read()
-                        .iter()
-                        .filter(|(_, v)| v.1 <= now)
-                        .for_each(|(k, _)| address.push(*k));
-
-                    if !address.is_empty() {
-                        this.remove_nonce(&address);
-                        address.clear();
-                    }
+            self.next_sweep.store(
+                now + maintenance.sweep_interval.max(1) + jitter,
+                Ordering::Relaxed,
+            );
+            self.sweep(now, maintenance.sweep_batch_size);
+
+            if maintenance.channel_idle_timeout > 0 {
+                self.check_channels(now, maintenance.channel_idle_timeout);
+            }
+        }
+    }
+
+    /// Report the channels that became stale or received data again since
+    /// the last check.
+    fn check_channels(&self, now: u64, idle_timeout: u64) {
+        let mut changes = Vec::new();
+        for relays in self.state.channel_relay_table.read().values() {
+            for (channel, relay) in relays {
+                let stale =
+                    now.saturating_sub(relay.active.load(Ordering::Relaxed)) >= idle_timeout;
+                if relay.stale.swap(stale, Ordering::Relaxed) != stale {
+                    changes.push((relay.session, *channel, stale));
                 }
+            }
+        }
+
+        if changes.is_empty() {
+            return;
+        }
 
-                // Fixing a second tick.
-                sleep(Duration::from_secs(1));
+        let sessions = self.state.sessions.read();
+        for (addr, channel, stale) in changes {
+            if let Some(session) = sessions.get(&addr) {
+                self.observer
+                    .channel_stale(&addr, &session.auth.username, channel, stale);
             }
-        });
+        }
+    }
 
-        this
+    /// Remove the sessions and the nonces that have expired, in batches of
+    /// the size.
+    fn sweep(&self, now: u64, batch_size: usize) {
+        let batch_size = if batch_size == 0 {
+            usize::MAX
+        } else {
+            batch_size
+        };
+
+        // This is the part that deletes the session information.
+        let address = self
+            .state
+            .sessions
+            .read()
+            .iter()
+            .filter(|(_, v)| v.expires <= now)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+
+        for addrs in address.chunks(batch_size) {
+            self.remove_sessions(addrs, CloseReason::Expired);
+        }
+
+        self.expire_permissions(now);
+
+        // Because nonce does not follow session creation, nonce is created for each
+        // addr, so nonce deletion is handled independently.
+        let address = self
+            .state
+            .address_nonce_tanle
+            .read()
+            .iter()
+            .filter(|(_, v)| v.1 <= now)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+
+        for addrs in address.chunks(batch_size) {
+            self.remove_nonces(addrs);
+        }
     }
 
-    fn remove_session(&self, addrs: &[SessionAddr]) {
+    fn remove_sessions(&self, addrs: &[SessionAddr], reason: CloseReason) {
         let mut sessions = self.state.sessions.write();
         let mut port_allocate_pool = self.state.port_allocate_pool.lock();
         let mut port_mapping_table = self.state.port_mapping_table.write();
@@ -189,8 +705,8 @@ impl<T: Observer + 'static> Sessions<T> {
         let mut channel_relay_table = self.state.channel_relay_table.write();
 
         addrs.iter().for_each(|k| {
-            port_relay_table.remove(k);
-            channel_relay_table.remove(k);
+            let relays = port_relay_table.remove(k);
+            let channel_relays = channel_relay_table.remove(k);
 
             if let Some(session) = sessions.remove(k) {
                 // Removes the session-bound port from the port binding table and
@@ -198,19 +714,80 @@ impl<T: Observer + 'static> Sessions<T> {
                 if let Some(port) = session.allocate.port {
                     port_mapping_table.remove(&port);
                     port_allocate_pool.restore(port);
+
+                    detach_relay_port(
+                        k,
+                        port,
+                        &session.permissions,
+                        &session.allocate.channels,
+                        relays,
+                        channel_relays,
+                        &mut sessions,
+                        &port_mapping_table,
+                        &mut port_relay_table,
+                        &mut channel_relay_table,
+                    );
                 }
 
                 // Notifies that the external session has been closed.
-                self.observer.closed(k, &session.auth.username);
+                self.observer.closed(k, &session.auth.username, reason);
             }
         });
     }
 
-    fn remove_nonce(&self, addrs: &[SessionAddr]) {
+    /// Remove the permissions that were not refreshed in time, together with
+    /// the forwarding entries on their peers.
+    fn expire_permissions(&self, now: u64) {
+        let expired = |it: &Permission| !it.bound && it.expires <= now;
+        if !self
+            .state
+            .sessions
+            .read()
+            .values()
+            .any(|it| it.permissions.iter().any(expired))
+        {
+            return;
+        }
+
+        let mut sessions = self.state.sessions.write();
+        let port_mapping_table = self.state.port_mapping_table.read();
+        let mut port_relay_table = self.state.port_relay_table.write();
+
+        for (addr, session) in sessions.iter_mut() {
+            let local_port = session.allocate.port;
+            session.permissions.retain(|it| {
+                if !expired(it) {
+                    return true;
+                }
+
+                let relays = port_mapping_table
+                    .get(&it.port)
+                    .and_then(|peer| port_relay_table.get_mut(&peer.session));
+
+                if let (Some(relays), Some(local_port)) = (relays, local_port) {
+                    if relays
+                        .get(&local_port)
+                        .is_some_and(|it| it.address == addr.address)
+                    {
+                        relays.remove(&local_port);
+                    }
+                }
+
+                false
+            });
+        }
+    }
+
+    fn remove_nonces(&self, addrs: &[SessionAddr]) {
         let mut address_nonce_tanle = self.state.address_nonce_tanle.write();
+        let mut nonce_address_table = self.state.nonce_address_table.write();
 
         addrs.iter().for_each(|k| {
-            address_nonce_tanle.remove(k);
+            if let Some((nonce, _)) = address_nonce_tanle.remove(k) {
+                if nonce_address_table.get(&nonce) == Some(k) {
+                    nonce_address_table.remove(&nonce);
+                }
+            }
         });
     }
 
@@ -305,22 +882,34 @@ impl<T: Observer + 'static> Sessions<T> {
         // If no nonce is created, create a new one.
         {
             if !self.state.address_nonce_tanle.read().contains_key(key) {
-                self.state.address_nonce_tanle.write().insert(
-                    *key,
-                    (
-                        // A random string of length 16.
-                        {
-                            let mut rng = thread_rng();
-                            std::iter::repeat(())
-                                .map(|_| rng.sample(Alphanumeric) as char)
-                                .take(16)
-                                .collect::<String>()
-                                .to_lowercase()
-                        },
-                        // Current time stacks for 600 seconds.
-                        self.timer.get() + 600,
-                    ),
-                );
+                let nonce = if let Some(secret) = *self.nonce_secret.read() {
+                    sign_nonce(&secret, key, unix_time())
+                } else {
+                    // A random string of length 16.
+                    let mut rng = thread_rng();
+                    std::iter::repeat(())
+                        .map(|_| rng.sample(Alphanumeric) as char)
+                        .take(16)
+                        .collect::<String>()
+                        .to_lowercase()
+                };
+
+                let mut address_nonce_tanle = self.state.address_nonce_tanle.write();
+                if !address_nonce_tanle.contains_key(key) {
+                    self.state
+                        .nonce_address_table
+                        .write()
+                        .insert(nonce.clone(), *key);
+
+                    address_nonce_tanle.insert(
+                        *key,
+                        (
+                            nonce,
+                            // The nonce is rotated after its lifetime.
+                            self.timer.get() + self.maintenance.read().nonce_lifetime,
+                        ),
+                    );
+                }
             }
         }
 
@@ -387,46 +976,13 @@ impl<T: Observer + 'static> Sessions<T> {
         username: &str,
         realm: &str,
     ) -> Option<[u8; 16]> {
-        // Already authenticated, get the cached digest directly.
-        {
-            if let Some(it) = self.state.sessions.read().get(addr) {
-                return Some(it.auth.digest);
-            }
-        }
-
-        // Get the current user's password from an external observer and create a
-        // digest.
-        let password = self.observer.get_password(addr, username).await?;
-        let digest = long_term_credential_digest(&username, &password, realm);
-
-        // Record a new session.
-        {
-            self.state.sessions.write().insert(
-                *addr,
-                Session {
-                    permissions: Vec::with_capacity(10),
-                    expires: self.timer.get() + 600,
-                    auth: Auth {
-                        username: username.to_string(),
-                        password,
-                        digest,
-                    },
-                    allocate: Allocate {
-                        channels: Vec::with_capacity(10),
-                        port: None,
-                    },
-                },
-            );
-        }
-
-        Some(digest)
-    }
-
-    pub fn allocated(&self) -> usize {
-        self.state.port_allocate_pool.lock().len()
+        self.get_auth(addr, username, realm)
+            .await
+            .map(|it| it.digest)
     }
 
-    /// Assign a port number to the session.
+    /// Get the authentication information for addr, the password is only
+    /// requested from the observer when addr is not authenticated yet.
     ///
     /// # Test
     ///
@@ -455,61 +1011,1607 @@ impl<T: Observer + 'static> Sessions<T> {
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let digest = [
-    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
-    ///     239,
-    /// ];
-    ///
     /// let sessions = Sessions::new(ObserverTest);
     ///
-    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// assert!(pollster::block_on(sessions.get_auth(&addr, "test1", "test")).is_none());
     ///
+    /// let auth = pollster::block_on(sessions.get_auth(&addr, "test", "test")).unwrap();
+    /// assert_eq!(auth.username, "test");
+    /// assert_eq!(auth.password, "test");
+    /// ```
+    pub async fn get_auth(&self, addr: &SessionAddr, username: &str, realm: &str) -> Option<Auth> {
+        // Already authenticated, get the cached credentials directly.
+        {
+            if let Some(it) = self.state.sessions.read().get(addr) {
+                return Some(it.auth.clone());
+            }
+        }
+
+        let (auth, tags) = self.lookup_auth(addr, username, realm).await?;
+
+        // Record a new session.
+        {
+            self.state
+                .sessions
+                .write()
+                .insert(*addr, self.new_session(auth.clone(), tags));
+        }
+
+        Some(auth)
+    }
+
+    /// Get the password of the user from the external observer and create a
+    /// digest, without recording a session for addr. This is how the
+    /// credentials of a request are checked that uses another username than
+    /// the session of addr.
+    pub(crate) async fn lookup_auth(
+        &self,
+        addr: &SessionAddr,
+        username: &str,
+        realm: &str,
+    ) -> Option<(Auth, SessionTags)> {
+        let credential = self.observer.get_credential(addr, username).await?;
+        let auth = Auth {
+            digest: long_term_credential_digest(username, &credential.password, realm),
+            username: username.to_string(),
+            password: credential.password,
+        };
+
+        Some((auth, credential.tags))
+    }
+
+    /// Authenticate addr as the anonymous user, whose username is empty.
+    /// Returns `false` if addr is already authenticated with credentials.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let other_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// assert!(sessions.authenticate_anonymous(&addr));
+    /// assert!(sessions.authenticate_anonymous(&addr));
+    /// assert_eq!(sessions.get_session(&addr).get_ref().unwrap().auth.username, "");
+    ///
+    /// pollster::block_on(sessions.get_digest(&other_addr, "test", "test"));
+    /// assert!(!sessions.authenticate_anonymous(&other_addr));
+    /// ```
+    pub fn authenticate_anonymous(&self, addr: &SessionAddr) -> bool {
+        let mut sessions = self.state.sessions.write();
+        if let Some(it) = sessions.get(addr) {
+            return it.auth.username.is_empty();
+        }
+
+        sessions.insert(
+            *addr,
+            self.new_session(
+                Auth {
+                    username: String::new(),
+                    password: String::new(),
+                    digest: [0u8; 16],
+                },
+                SessionTags::new(),
+            ),
+        );
+
+        true
+    }
+
+    fn new_session(&self, auth: Auth, tags: SessionTags) -> Session {
+        Session {
+            tags,
+            permissions: Vec::with_capacity(10),
+            expires: self.timer.get() + 600,
+            created: self.timer.get(),
+            allocate: Allocate {
+                channels: Vec::with_capacity(10),
+                transport: None,
+                port: None,
+                token: None,
+                ingress: None,
+                generation: None,
+            },
+            auth,
+        }
+    }
+
+    /// The current time of the sessions in seconds, the `created` and
+    /// `expires` of a session are relative to it.
+    pub fn now(&self) -> u64 {
+        self.timer.get()
+    }
+
+    /// The number of ports allocated from the port pool.
+    pub fn allocated(&self) -> usize {
+        self.state.port_allocate_pool.lock().used()
+    }
+
+    /// The number of free ports left in the port pool.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    /// assert_eq!(sessions.available(), sessions.capacity());
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// sessions.allocate(&addr).unwrap();
+    ///
+    /// assert_eq!(sessions.allocated(), 1);
+    /// assert_eq!(sessions.available(), sessions.capacity() - 1);
+    /// ```
+    pub fn available(&self) -> usize {
+        self.state.port_allocate_pool.lock().available()
+    }
+
+    /// The total number of ports in the port pool.
+    pub fn capacity(&self) -> usize {
+        self.state.port_allocate_pool.lock().capacity()
+    }
+
+    /// Share the port pool fairly between the source addresses when the
+    /// free ports fall below the percentage of the pool.
+    ///
+    /// Below the threshold a source that already holds ports is refused once
+    /// its share reaches the capacity divided by the number of sources that
+    /// hold ports plus one, so that there is always room left for a new
+    /// source. A source that holds no ports is never refused by the share.
+    /// Zero disables it, which is the default.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = |ip: [u8; 4], port: u16| SessionAddr {
+    ///     address: (ip, port).into(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_port_range(ObserverTest, 50000..50010);
+    /// sessions.set_fair_share(50);
+    ///
+    /// // One source takes the ports freely until half of the pool is left.
+    /// for port in 0..7 {
+    ///     let addr = addr([10, 0, 0, 1], port);
+    ///     pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    ///
+    ///     assert_eq!(sessions.allocate(&addr).is_some(), port < 6);
+    /// }
+    ///
+    /// // The rest of the pool is left to the other sources.
+    /// let other = addr([10, 0, 0, 2], 0);
+    /// pollster::block_on(sessions.get_digest(&other, "test", "test"));
+    /// assert!(sessions.allocate(&other).is_some());
+    /// assert_eq!(sessions.allocated(), 7);
+    /// ```
+    pub fn set_fair_share(&self, percent: usize) {
+        self.fair_share.store(percent, Ordering::Relaxed);
+    }
+
+    /// Set the cadence of the maintenance, it takes effect from the next
+    /// sweep and for the nonces that are created afterwards.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::{Clock, Maintenance}, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_clock(ObserverTest, DEFAULT_PORT_RANGE, Clock::Manual);
+    /// sessions.set_maintenance(Maintenance {
+    ///     sweep_interval: 10,
+    ///     nonce_lifetime: 25,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// assert_eq!(sessions.get_nonce(&addr).get_ref().unwrap().1, 25);
+    ///
+    /// // The sweeps are at 1, 11, 21 and 31 seconds, the expired nonce is kept
+    /// // until the next sweep.
+    /// sessions.advance(30);
+    /// assert_eq!(sessions.get_nonce(&addr).get_ref().unwrap().1, 25);
+    ///
+    /// sessions.advance(1);
+    /// assert_eq!(sessions.get_nonce(&addr).get_ref().unwrap().1, 56);
+    /// ```
+    pub fn set_maintenance(&self, maintenance: Maintenance) {
+        *self.maintenance.write() = maintenance;
+    }
+
+    /// Set the secret that the nonces are signed with.
+    ///
+    /// A signed nonce carries the time that it was issued at, so a server
+    /// that is restarted with the same secret accepts the nonces that the
+    /// clients cached before the restart with [`Sessions::restore_nonce`],
+    /// instead of challenging every client again at the same time.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let other = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    /// sessions.set_nonce_secret(Some([1u8; 32]));
+    ///
+    /// let nonce = sessions.get_nonce(&addr).get_ref().unwrap().0.clone();
+    ///
+    /// // The sessions of the restarted server.
+    /// let sessions = Sessions::new(ObserverTest);
+    /// assert!(!sessions.restore_nonce(&addr, &nonce));
+    ///
+    /// sessions.set_nonce_secret(Some([1u8; 32]));
+    /// assert!(!sessions.restore_nonce(&other, &nonce));
+    /// assert!(sessions.restore_nonce(&addr, &nonce));
+    /// assert_eq!(sessions.get_nonce(&addr).get_ref().unwrap().0, nonce);
+    /// ```
+    pub fn set_nonce_secret(&self, secret: Option<[u8; 32]>) {
+        *self.nonce_secret.write() = secret;
+    }
+
+    /// Accept a nonce that was signed with the secret and is still within
+    /// its lifetime, for an address that has no nonce yet, returns whether
+    /// the nonce was accepted.
+    pub fn restore_nonce(&self, key: &SessionAddr, nonce: &str) -> bool {
+        let Some(secret) = *self.nonce_secret.read() else {
+            return false;
+        };
+
+        if nonce.len() != SIGNED_NONCE_SIZE
+            || !nonce.is_ascii()
+            || self.state.address_nonce_tanle.read().contains_key(key)
+        {
+            return false;
+        }
+
+        let Ok(issued) = u64::from_str_radix(&nonce[..8], 16) else {
+            return false;
+        };
+
+        let now = unix_time();
+        let lifetime = self.maintenance.read().nonce_lifetime;
+        if issued > now || now - issued >= lifetime || sign_nonce(&secret, key, issued) != nonce {
+            return false;
+        }
+
+        let mut address_nonce_tanle = self.state.address_nonce_tanle.write();
+        self.state
+            .nonce_address_table
+            .write()
+            .insert(nonce.to_string(), *key);
+
+        address_nonce_tanle.insert(
+            *key,
+            (
+                nonce.to_string(),
+                self.timer.get() + lifetime - (now - issued),
+            ),
+        );
+
+        true
+    }
+
+    /// Set what is done with the allocate requests on the 5-tuples that
+    /// already have an allocation.
+    pub fn set_duplicate_allocate(&self, policy: DuplicateAllocate) {
+        *self.duplicate_allocate.write() = policy;
+    }
+
+    pub fn duplicate_allocate(&self) -> DuplicateAllocate {
+        *self.duplicate_allocate.read()
+    }
+
+    /// Set what is done with the requests of the addresses without an
+    /// allocation whose user has an allocation from another address.
+    pub fn set_address_rebind(&self, policy: AddressRebind) {
+        *self.address_rebind.write() = policy;
+    }
+
+    pub fn address_rebind(&self) -> AddressRebind {
+        *self.address_rebind.read()
+    }
+
+    /// Whether the source of the session has reached its share of the pool.
+    fn exceeds_fair_share(&self, addr: &SessionAddr, available: usize, capacity: usize) -> bool {
+        let threshold = self.fair_share.load(Ordering::Relaxed);
+        if threshold == 0 || available * 100 >= capacity * threshold {
+            return false;
+        }
+
+        let ip = addr.address.ip();
+        let mut held = 0;
+        let mut sources = HashSet::new();
+        for it in self.state.port_mapping_table.read().values() {
+            if it.session.address.ip() == ip {
+                held += 1;
+            }
+
+            sources.insert(it.session.address.ip());
+        }
+
+        held > 0 && held * (sources.len() + 1) >= capacity
+    }
+
+    /// A snapshot of the sizes of the bookkeeping tables.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::{CloseReason, Counters}, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    /// sessions.get_nonce(&addr);
+    ///
+    /// sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    /// sessions.create_permission(&addr, &endpoint, &[peer_port]);
+    /// sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000);
+    ///
+    /// assert_eq!(
+    ///     sessions.counters(),
+    ///     Counters {
+    ///         sessions: 2,
+    ///         nonces: 1,
+    ///         allocated: 2,
+    ///         ports: 2,
+    ///         permissions: 1,
+    ///         channels: 1,
+    ///     }
+    /// );
+    ///
+    /// sessions.remove_session(&addr, CloseReason::ClientReleased);
+    /// sessions.remove_session(&peer_addr, CloseReason::ClientReleased);
+    /// assert_eq!(sessions.counters(), Counters::default());
+    /// ```
+    pub fn counters(&self) -> Counters {
+        Counters {
+            sessions: self.state.sessions.read().len(),
+            nonces: self.state.address_nonce_tanle.read().len(),
+            allocated: self.state.port_allocate_pool.lock().used(),
+            ports: self.state.port_mapping_table.read().len(),
+            permissions: self
+                .state
+                .port_relay_table
+                .read()
+                .values()
+                .map(|it| it.len())
+                .sum(),
+            channels: self
+                .state
+                .channel_relay_table
+                .read()
+                .values()
+                .map(|it| it.len())
+                .sum(),
+        }
+    }
+
+    /// Assign a port number to the session.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         if username == "test" {
+    ///             Some("test".to_string())
+    ///         } else {
+    ///             None
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let digest = [
+    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
+    ///     239,
+    /// ];
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    ///
+    /// {
+    ///     let lock = sessions.get_session(&addr);
+    ///     let session = lock.get_ref().unwrap();
+    ///     assert_eq!(session.auth.username, "test");
+    ///     assert_eq!(session.auth.password, "test");
+    ///     assert_eq!(session.allocate.port, None);
+    ///     assert_eq!(session.allocate.channels.len(), 0);
+    /// }
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
     /// {
     ///     let lock = sessions.get_session(&addr);
     ///     let session = lock.get_ref().unwrap();
     ///     assert_eq!(session.auth.username, "test");
     ///     assert_eq!(session.auth.password, "test");
-    ///     assert_eq!(session.allocate.port, None);
+    ///     assert_eq!(session.allocate.port, Some(port));
     ///     assert_eq!(session.allocate.channels.len(), 0);
     /// }
     ///
+    /// assert!(sessions.allocate(&addr).is_none());
+    /// ```
+    pub fn allocate(&self, addr: &SessionAddr) -> Option<u16> {
+        self.allocate_with_transport(addr, Transport::UDP)
+    }
+
+    /// Assign a port number to the session, and record the transport
+    /// requested by the client.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    /// use stun::attribute::Transport;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// {
+    ///     let lock = sessions.get_session(&addr);
+    ///     assert_eq!(lock.get_ref().unwrap().allocate.transport, None);
+    /// }
+    ///
+    /// sessions.allocate_with_transport(&addr, Transport::UDP).unwrap();
+    /// {
+    ///     let lock = sessions.get_session(&addr);
+    ///     assert_eq!(lock.get_ref().unwrap().allocate.transport, Some(Transport::UDP));
+    /// }
+    /// ```
+    pub fn allocate_with_transport(&self, addr: &SessionAddr, transport: Transport) -> Option<u16> {
+        let mut lock = self.state.sessions.write();
+        let session = lock.get_mut(addr)?;
+
+        // If the port has already been allocated, re-allocation is not allowed.
+        if session.allocate.port.is_some() {
+            return None;
+        }
+
+        // Records the port assigned to the current session and resets the alive time.
+        let port = {
+            let mut pool = self.state.port_allocate_pool.lock();
+            if self.exceeds_fair_share(addr, pool.available(), pool.capacity()) {
+                return None;
+            }
+
+            pool.alloc()?
+        };
+
+        session.expires = self.timer.get() + 600;
+        session.allocate.port = Some(port);
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        session.allocate.transport = Some(transport);
+        session.allocate.generation = Some(generation);
+
+        // Write the allocation port binding table.
+        self.state.port_mapping_table.write().insert(
+            port,
+            RelayPort {
+                session: *addr,
+                generation,
+            },
+        );
+
+        Some(port)
+    }
+
+    /// Create permission for session.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         if username == "test" {
+    ///             Some("test".to_string())
+    ///         } else {
+    ///             None
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let digest = [
+    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
+    ///     239,
+    /// ];
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    ///
+    /// assert!(!sessions.create_permission(&addr, &endpoint, &[port]));
+    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    ///
+    /// assert!(!sessions.create_permission(&peer_addr, &endpoint, &[peer_port]));
+    /// assert!(sessions.create_permission(&peer_addr, &endpoint, &[port]));
+    /// ```
+    pub fn create_permission(
+        &self,
+        addr: &SessionAddr,
+        endpoint: &SocketAddr,
+        ports: &[u16],
+    ) -> bool {
+        self.grant_permissions(addr, endpoint, ports, false)
+    }
+
+    fn grant_permissions(
+        &self,
+        addr: &SessionAddr,
+        endpoint: &SocketAddr,
+        ports: &[u16],
+        bound: bool,
+    ) -> bool {
+        let expires = self.timer.get() + PERMISSION_LIFETIME;
+        let mut sessions = self.state.sessions.write();
+        let mut port_relay_table = self.state.port_relay_table.write();
+        let port_mapping_table = self.state.port_mapping_table.read();
+
+        // Finds information about the current session.
+        let session = if let Some(it) = sessions.get_mut(addr) {
+            it
+        } else {
+            return false;
+        };
+
+        // The port number assigned to the current session.
+        let local_port = if let Some(it) = session.allocate.port {
+            it
+        } else {
+            return false;
+        };
+
+        // You cannot create permissions for yourself.
+        if ports.contains(&local_port) {
+            return false;
+        }
+
+        // Each peer port must be present.
+        let mut peers = Vec::with_capacity(15);
+        for port in ports {
+            if let Some(it) = port_mapping_table.get(port) {
+                peers.push((&it.session, *port));
+            } else {
+                return false;
+            }
+        }
+
+        // Create a port forwarding mapping relationship for each peer session.
+        for (peer, port) in peers {
+            port_relay_table
+                .entry(*peer)
+                .or_insert_with(|| HashMap::with_capacity(20))
+                .insert(
+                    local_port,
+                    Endpoint {
+                        address: addr.address,
+                        endpoint: *endpoint,
+                    },
+                );
+
+            // Do not store the same peer ports to the permission list over and over again,
+            // the existing permissions are refreshed.
+            if let Some(it) = session.permissions.iter_mut().find(|it| it.port == port) {
+                it.expires = expires;
+                it.bound |= bound;
+            } else {
+                session.permissions.push(Permission {
+                    port,
+                    expires,
+                    bound,
+                });
+            }
+        }
+
+        true
+    }
+
+    /// Binding a channel to the session.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         if username == "test" {
+    ///             Some("test".to_string())
+    ///         } else {
+    ///             None
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let digest = [
+    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
+    ///     239,
+    /// ];
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_session(&addr)
+    ///         .get_ref()
+    ///         .unwrap()
+    ///         .allocate
+    ///         .channels
+    ///         .len(),
+    ///     0
+    /// );
+    ///
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_session(&peer_addr)
+    ///         .get_ref()
+    ///         .unwrap()
+    ///         .allocate
+    ///         .channels
+    ///         .len(),
+    ///     0
+    /// );
+    ///
+    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
+    /// assert!(sessions.bind_channel(&peer_addr, &endpoint, port, 0x4000));
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_session(&addr)
+    ///         .get_ref()
+    ///         .unwrap()
+    ///         .allocate
+    ///         .channels,
+    ///     vec![0x4000]
+    /// );
+    ///
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_session(&peer_addr)
+    ///         .get_ref()
+    ///         .unwrap()
+    ///         .allocate
+    ///         .channels,
+    ///     vec![0x4000]
+    /// );
+    /// ```
+    pub fn bind_channel(
+        &self,
+        addr: &SessionAddr,
+        endpoint: &SocketAddr,
+        port: u16,
+        channel: u16,
+    ) -> bool {
+        // Finds the address of the bound opposing port.
+        let peer = if let Some(it) = self.state.port_mapping_table.read().get(&port) {
+            it.session
+        } else {
+            return false;
+        };
+
+        // Records the channel used for the current session.
+        {
+            let mut lock = self.state.sessions.write();
+            let session = if let Some(it) = lock.get_mut(addr) {
+                it
+            } else {
+                return false;
+            };
+
+            if !session.allocate.channels.contains(&channel) {
+                session.allocate.channels.push(channel);
+            } else {
+                return false;
+            }
+        }
+
+        // Binding ports also creates permissions.
+        if !self.grant_permissions(addr, endpoint, &[port], true) {
+            return false;
+        }
+
+        // Create channel forwarding mapping relationships for peers.
+        self.state
+            .channel_relay_table
+            .write()
+            .entry(peer)
+            .or_insert_with(|| HashMap::with_capacity(10))
+            .insert(
+                channel,
+                ChannelRelay {
+                    endpoint: Endpoint {
+                        address: addr.address,
+                        endpoint: *endpoint,
+                    },
+                    session: *addr,
+                    active: AtomicU64::new(self.timer.get()),
+                    stale: AtomicBool::new(false),
+                },
+            );
+
+        true
+    }
+
+    /// Gets the peer of the current session bound channel.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         if username == "test" {
+    ///             Some("test".to_string())
+    ///         } else {
+    ///             None
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let digest = [
+    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
+    ///     239,
+    /// ];
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    ///
+    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
+    /// assert!(sessions.bind_channel(&peer_addr, &endpoint, port, 0x4000));
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_channel_relay_address(&addr, 0x4000)
+    ///         .unwrap()
+    ///         .endpoint,
+    ///     endpoint
+    /// );
+    ///
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_channel_relay_address(&peer_addr, 0x4000)
+    ///         .unwrap()
+    ///         .endpoint,
+    ///     endpoint
+    /// );
+    /// ```
+    pub fn get_channel_relay_address(&self, addr: &SessionAddr, channel: u16) -> Option<Endpoint> {
+        let channel_relay_table = self.state.channel_relay_table.read();
+        let relay = channel_relay_table.get(addr)?.get(&channel)?;
+
+        // This is called for every relayed channel data, the time is only
+        // written once a second so that the cache line is not contended.
+        let now = self.timer.get();
+        if relay.active.load(Ordering::Relaxed) != now {
+            relay.active.store(now, Ordering::Relaxed);
+        }
+
+        Some(relay.endpoint)
+    }
+
+    /// Get the address of the port binding.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         if username == "test" {
+    ///             Some("test".to_string())
+    ///         } else {
+    ///             None
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let digest = [
+    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
+    ///     239,
+    /// ];
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    ///
+    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    /// assert!(sessions.create_permission(&peer_addr, &endpoint, &[port]));
+    ///
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_relay_address(&addr, peer_port)
+    ///         .unwrap()
+    ///         .endpoint,
+    ///     endpoint
+    /// );
+    ///
+    /// assert_eq!(
+    ///     sessions
+    ///         .get_relay_address(&peer_addr, port)
+    ///         .unwrap()
+    ///         .endpoint,
+    ///     endpoint
+    /// );
+    /// ```
+    pub fn get_relay_address(&self, addr: &SessionAddr, port: u16) -> Option<Endpoint> {
+        self.state
+            .port_relay_table
+            .read()
+            .get(addr)?
+            .get(&port)
+            .copied()
+    }
+
+    /// Get the session that the relay port is allocated to.
+    pub fn get_port_session(&self, port: u16) -> Option<SessionAddr> {
+        self.lookup_by_relay_port(port).map(|it| it.session)
+    }
+
+    /// Get the session that the relay port is currently allocated to, and the
+    /// generation of the allocation.
+    ///
+    /// This is how the inbound data of the peers is demultiplexed, a port is
+    /// only mapped to the session that holds it, and the references of the
+    /// peers to a port are removed when the port is released, so that the
+    /// data addressed to a previous owner is never relayed to the session
+    /// that the port is allocated to next.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::CloseReason, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = |port: u16| SessionAddr {
+    ///     address: format!("127.0.0.1:{}", port).parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// // There is only a single port, it is recycled for every allocation.
+    /// let sessions = Sessions::with_port_range(ObserverTest, 50000..50001);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr(8080), "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&addr(8081), "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr(8080)).unwrap();
+    /// let first = sessions.lookup_by_relay_port(port).unwrap();
+    /// assert_eq!(first.session, addr(8080));
+    ///
+    /// sessions.remove_session(&addr(8080), CloseReason::Expired);
+    /// assert_eq!(sessions.lookup_by_relay_port(port), None);
+    ///
+    /// assert_eq!(sessions.allocate(&addr(8081)), Some(port));
+    /// let second = sessions.lookup_by_relay_port(port).unwrap();
+    /// assert_eq!(second.session, addr(8081));
+    /// assert_ne!(second.generation, first.generation);
+    /// ```
+    pub fn lookup_by_relay_port(&self, port: u16) -> Option<RelayPort> {
+        self.state.port_mapping_table.read().get(&port).copied()
+    }
+
+    /// Whether the port is still allocated in the generation.
+    ///
+    /// The references to a relay port that are kept outside of the sessions,
+    /// such as the packets that are waiting to be relayed or the forwarding
+    /// entries of an external data path, are tagged with the generation of
+    /// the port, so that the references to a previous owner of a recycled
+    /// port are dropped instead of being relayed to the next owner.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::CloseReason, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = |port: u16| SessionAddr {
+    ///     address: format!("127.0.0.1:{}", port).parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_port_range(ObserverTest, 50000..50001);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr(8080), "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&addr(8081), "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr(8080)).unwrap();
+    /// let generation = sessions
+    ///     .get_session(&addr(8080))
+    ///     .get_ref()
+    ///     .and_then(|it| it.allocate.generation)
+    ///     .unwrap();
+    ///
+    /// assert!(sessions.is_current(port, generation));
+    ///
+    /// sessions.remove_session(&addr(8080), CloseReason::Expired);
+    /// assert!(!sessions.is_current(port, generation));
+    ///
+    /// // The port is recycled, the previous generation is still stale.
+    /// assert_eq!(sessions.allocate(&addr(8081)), Some(port));
+    /// assert!(!sessions.is_current(port, generation));
+    /// ```
+    pub fn is_current(&self, port: u16, generation: u64) -> bool {
+        self.state
+            .port_mapping_table
+            .read()
+            .get(&port)
+            .is_some_and(|it| it.generation == generation)
+    }
+
+    /// Whether the session has a permission for the peer port that has not
+    /// expired, the data of the peers without a permission is not relayed to
+    /// the session.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::{Clock, Maintenance, PERMISSION_LIFETIME}, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_clock(ObserverTest, DEFAULT_PORT_RANGE, Clock::Manual);
+    /// sessions.set_maintenance(Maintenance {
+    ///     sweep_interval: 1,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
     /// let port = sessions.allocate(&addr).unwrap();
-    /// {
-    ///     let lock = sessions.get_session(&addr);
-    ///     let session = lock.get_ref().unwrap();
-    ///     assert_eq!(session.auth.username, "test");
-    ///     assert_eq!(session.auth.password, "test");
-    ///     assert_eq!(session.allocate.port, Some(port));
-    ///     assert_eq!(session.allocate.channels.len(), 0);
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    ///
+    /// assert!(!sessions.has_permission(&addr, peer_port));
+    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    /// assert!(sessions.has_permission(&addr, peer_port));
+    /// assert!(!sessions.has_permission(&peer_addr, port));
+    /// assert!(sessions.get_relay_address(&peer_addr, port).is_some());
+    ///
+    /// // The permission expires unless it is refreshed.
+    /// sessions.advance(PERMISSION_LIFETIME - 1);
+    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    /// sessions.advance(PERMISSION_LIFETIME - 1);
+    /// assert!(sessions.has_permission(&addr, peer_port));
+    ///
+    /// sessions.advance(1);
+    /// assert!(!sessions.has_permission(&addr, peer_port));
+    /// assert!(sessions.get_relay_address(&peer_addr, port).is_none());
+    /// assert_eq!(sessions.counters().permissions, 0);
+    /// ```
+    pub fn has_permission(&self, addr: &SessionAddr, peer: u16) -> bool {
+        let now = self.timer.get();
+        self.state.sessions.read().get(addr).is_some_and(|session| {
+            session
+                .permissions
+                .iter()
+                .any(|it| it.port == peer && (it.bound || it.expires > now))
+        })
+    }
+
+    /// Find the sessions that are permitted to relay to the endpoint, and the
+    /// relay port of the endpoint that each of them relays to.
+    ///
+    /// This is the reverse of [`Sessions::get_relay_address`], it finds the
+    /// senders of the datagrams that the server failed to deliver to the
+    /// endpoint.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::Endpoint, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    ///
+    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    ///
+    /// // The peer relays to the session, the session does not relay to the peer
+    /// // until the peer permits it.
+    /// let sources = sessions.get_relay_sources(&Endpoint {
+    ///     address: addr.address,
+    ///     endpoint,
+    /// });
+    ///
+    /// assert_eq!(sources, vec![(peer_addr, port)]);
+    /// assert!(sessions
+    ///     .get_relay_sources(&Endpoint {
+    ///         address: peer_addr.address,
+    ///         endpoint,
+    ///     })
+    ///     .is_empty());
+    /// ```
+    pub fn get_relay_sources(&self, endpoint: &Endpoint) -> Vec<(SessionAddr, u16)> {
+        let mut sources = Vec::new();
+        for (addr, relays) in self.state.port_relay_table.read().iter() {
+            for (port, it) in relays {
+                if it == endpoint {
+                    sources.push((*addr, *port));
+                }
+            }
+        }
+
+        sources
+    }
+
+    /// Find the channel bound by the session that the relay port is allocated
+    /// to, and the peer session that the channel is bound to.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::{ChannelBinding, Clock, Maintenance}, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::with_clock(ObserverTest, DEFAULT_PORT_RANGE, Clock::Manual);
+    /// sessions.set_maintenance(Maintenance {
+    ///     channel_idle_timeout: 10,
+    ///     ..Default::default()
+    /// });
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    ///
+    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
+    /// assert_eq!(
+    ///     sessions.get_channel_binding(port, 0x4000),
+    ///     Some(ChannelBinding {
+    ///         session: addr,
+    ///         peer: peer_addr,
+    ///         peer_port,
+    ///         idle: 0,
+    ///         stale: false,
+    ///     })
+    /// );
+    ///
+    /// // Nothing is relayed to the session over the channel.
+    /// sessions.advance(10);
+    /// let binding = sessions.get_channel_binding(port, 0x4000).unwrap();
+    /// assert_eq!((binding.idle, binding.stale), (10, true));
+    ///
+    /// // The peer relays data to the session over the channel.
+    /// assert!(sessions.get_channel_relay_address(&peer_addr, 0x4000).is_some());
+    /// sessions.advance(1);
+    /// let binding = sessions.get_channel_binding(port, 0x4000).unwrap();
+    /// assert_eq!((binding.idle, binding.stale), (1, false));
+    ///
+    /// assert!(sessions.get_channel_binding(port, 0x4001).is_none());
+    /// assert!(sessions.get_channel_binding(peer_port, 0x4000).is_none());
+    /// ```
+    pub fn get_channel_binding(&self, port: u16, channel: u16) -> Option<ChannelBinding> {
+        let sessions = self.state.sessions.read();
+        let port_mapping_table = self.state.port_mapping_table.read();
+        let channel_relay_table = self.state.channel_relay_table.read();
+
+        let addr = &port_mapping_table.get(&port)?.session;
+        let session = sessions.get(addr)?;
+        if !session.allocate.channels.contains(&channel) {
+            return None;
+        }
+
+        // The channel is recorded on the peer side, so look for it in the peers
+        // that the session has permissions for.
+        session.permissions.iter().find_map(|it| {
+            let peer_port = &it.port;
+            let peer = &port_mapping_table.get(peer_port)?.session;
+            let relay = channel_relay_table.get(peer)?.get(&channel)?;
+            if relay.endpoint.address == addr.address {
+                Some(ChannelBinding {
+                    session: *addr,
+                    peer: *peer,
+                    peer_port: *peer_port,
+                    idle: self
+                        .timer
+                        .get()
+                        .saturating_sub(relay.active.load(Ordering::Relaxed)),
+                    stale: relay.stale.load(Ordering::Relaxed),
+                })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the sessions that have created permissions for the peer session,
+    /// together with their relay ports.
+    ///
+    /// The forwarding table is indexed by the peer, so this does not need to
+    /// go through all sessions.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    ///
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
+    /// assert_eq!(sessions.get_port_session(peer_port), Some(peer_addr));
+    ///
+    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    /// assert_eq!(sessions.get_peer_sessions(&peer_addr), vec![(addr, port)]);
+    /// assert!(sessions.get_peer_sessions(&addr).is_empty());
+    ///
+    /// sessions.remove_session(&addr, CloseReason::Removed);
+    /// assert!(sessions.get_peer_sessions(&peer_addr).is_empty());
+    /// ```
+    pub fn get_peer_sessions(&self, peer: &SessionAddr) -> Vec<(SessionAddr, u16)> {
+        let port_relay_table = self.state.port_relay_table.read();
+        let port_mapping_table = self.state.port_mapping_table.read();
+
+        let relays = if let Some(it) = port_relay_table.get(peer) {
+            it
+        } else {
+            return Vec::new();
+        };
+
+        // Only the entries whose port is still allocated to the same session are
+        // valid.
+        relays
+            .iter()
+            .filter_map(|(port, endpoint)| {
+                let addr = port_mapping_table.get(port)?.session;
+                if addr.address == endpoint.address {
+                    Some((addr, *port))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Refresh the session for addr.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         if username == "test" {
+    ///             Some("test".to_string())
+    ///         } else {
+    ///             None
+    ///         }
+    ///     }
     /// }
     ///
-    /// assert!(sessions.allocate(&addr).is_none());
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let digest = [
+    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
+    ///     239,
+    /// ];
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// assert!(sessions.get_session(&addr).get_ref().is_none());
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    ///
+    /// let expires = sessions.get_session(&addr).get_ref().unwrap().expires;
+    /// assert!(expires == 600 || expires == 601 || expires == 602);
+    ///
+    /// assert!(sessions.refresh(&addr, 0));
+    ///
+    /// assert!(sessions.get_session(&addr).get_ref().is_none());
     /// ```
-    pub fn allocate(&self, addr: &SessionAddr) -> Option<u16> {
-        let mut lock = self.state.sessions.write();
-        let session = lock.get_mut(addr)?;
-
-        // If the port has already been allocated, re-allocation is not allowed.
-        if session.allocate.port.is_some() {
-            return None;
+    pub fn refresh(&self, addr: &SessionAddr, lifetime: u32) -> bool {
+        if lifetime > 3600 {
+            return false;
         }
 
-        // Records the port assigned to the current session and resets the alive time.
-        let port = self.state.port_allocate_pool.lock().alloc(None)?;
-        session.expires = self.timer.get() + 600;
-        session.allocate.port = Some(port);
+        if lifetime == 0 {
+            self.remove_session(addr, CloseReason::ClientReleased);
+        } else {
+            if let Some(session) = self.state.sessions.write().get_mut(addr) {
+                session.expires = self.timer.get() + lifetime as u64;
+            } else {
+                return false;
+            }
 
-        // Write the allocation port binding table.
-        self.state.port_mapping_table.write().insert(port, *addr);
-        Some(port)
+            if let Some(nonce) = self.state.address_nonce_tanle.write().get_mut(addr) {
+                nonce.1 = self.timer.get() + lifetime as u64;
+            }
+        }
+
+        true
     }
 
-    /// Create permission for session.
+    /// Close the session for addr, release the allocated port and the
+    /// channels, and notify the observer with the reason.
+    ///
+    /// Returns `false` if there is no session for addr.
     ///
     /// # Test
     ///
     /// ```
-    /// use mycrl_turn::*;
+    /// use mycrl_turn::{sessions::CloseReason, *};
     ///
     /// #[derive(Clone)]
     /// struct ObserverTest;
@@ -528,6 +2630,55 @@ impl<T: Observer + 'static> Sessions<T> {
     ///     }
     /// }
     ///
+    /// let addr = SessionAddr {
+    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    ///
+    /// assert!(!sessions.remove_session(&addr, CloseReason::Removed));
+    ///
+    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// let port = sessions.allocate(&addr).unwrap();
+    /// assert_eq!(sessions.allocated(), 1);
+    ///
+    /// assert!(sessions.remove_session(&addr, CloseReason::Removed));
+    /// assert!(sessions.get_session(&addr).get_ref().is_none());
+    /// assert!(sessions.get_relay_address(&addr, port).is_none());
+    /// assert_eq!(sessions.allocated(), 0);
+    /// ```
+    pub fn remove_session(&self, addr: &SessionAddr, reason: CloseReason) -> bool {
+        let exists = self.state.sessions.read().contains_key(addr);
+
+        self.remove_sessions(&[*addr], reason);
+        self.remove_nonces(&[*addr]);
+        exists
+    }
+
+    /// Release the allocation of the session, the relay port goes back to
+    /// the pool and the permissions and the channels are removed, but the
+    /// session and its credentials are kept so that it can allocate again.
+    /// Returns the released port.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::{sessions::Counters, *};
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
     /// let endpoint = "127.0.0.1:3478".parse().unwrap();
     /// let addr = SessionAddr {
     ///     address: "127.0.0.1:8080".parse().unwrap(),
@@ -539,11 +2690,6 @@ impl<T: Observer + 'static> Sessions<T> {
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let digest = [
-    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
-    ///     239,
-    /// ];
-    ///
     /// let sessions = Sessions::new(ObserverTest);
     ///
     /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
@@ -551,80 +2697,145 @@ impl<T: Observer + 'static> Sessions<T> {
     ///
     /// let port = sessions.allocate(&addr).unwrap();
     /// let peer_port = sessions.allocate(&peer_addr).unwrap();
-    ///
-    /// assert!(!sessions.create_permission(&addr, &endpoint, &[port]));
     /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
+    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
     ///
-    /// assert!(!sessions.create_permission(&peer_addr, &endpoint, &[peer_port]));
-    /// assert!(sessions.create_permission(&peer_addr, &endpoint, &[port]));
+    /// assert_eq!(sessions.release_allocation(&addr), Some(port));
+    /// assert_eq!(sessions.release_allocation(&addr), None);
+    /// assert!(sessions.get_relay_address(&peer_addr, port).is_none());
+    /// assert_eq!(sessions.counters().permissions, 0);
+    /// assert_eq!(sessions.counters().channels, 0);
+    /// assert_eq!(sessions.allocated(), 1);
+    ///
+    /// // The session is kept and can allocate again.
+    /// assert!(sessions.allocate(&addr).is_some());
+    /// assert_eq!(sessions.allocated(), 2);
     /// ```
-    pub fn create_permission(
-        &self,
-        addr: &SessionAddr,
-        endpoint: &SocketAddr,
-        ports: &[u16],
-    ) -> bool {
+    pub fn release_allocation(&self, addr: &SessionAddr) -> Option<u16> {
         let mut sessions = self.state.sessions.write();
+        let session = sessions.get_mut(addr)?;
+        let port = session.allocate.port.take()?;
+        let permissions = std::mem::take(&mut session.permissions);
+        let channels = std::mem::take(&mut session.allocate.channels);
+        session.allocate.transport = None;
+        session.allocate.token = None;
+        session.allocate.generation = None;
+
+        // The locks are taken in the same order as when the sessions are
+        // removed.
+        self.state.port_allocate_pool.lock().restore(port);
+
+        let mut port_mapping_table = self.state.port_mapping_table.write();
         let mut port_relay_table = self.state.port_relay_table.write();
-        let port_mapping_table = self.state.port_mapping_table.read();
+        let mut channel_relay_table = self.state.channel_relay_table.write();
 
-        // Finds information about the current session.
-        let session = if let Some(it) = sessions.get_mut(addr) {
-            it
-        } else {
-            return false;
-        };
+        port_mapping_table.remove(&port);
+        let relays = port_relay_table.remove(addr);
+        let channel_relays = channel_relay_table.remove(addr);
+
+        detach_relay_port(
+            addr,
+            port,
+            &permissions,
+            &channels,
+            relays,
+            channel_relays,
+            &mut sessions,
+            &port_mapping_table,
+            &mut port_relay_table,
+            &mut channel_relay_table,
+        );
 
-        // The port number assigned to the current session.
-        let local_port = if let Some(it) = session.allocate.port {
-            it
-        } else {
-            return false;
-        };
+        Some(port)
+    }
 
-        // You cannot create permissions for yourself.
-        if ports.contains(&local_port) {
-            return false;
+    /// Find the allocation that the user of the address without an
+    /// allocation has on the same interface from another address, by the
+    /// nonce that was issued to the other address and that the request still
+    /// carries, as the client is not aware that its address changed. The
+    /// allocation must belong to the same user, and the anonymous user is
+    /// never matched, all of the trusted sources share it.
+    ///
+    /// # Test
+    ///
+    /// ```
+    /// use mycrl_turn::*;
+    ///
+    /// #[derive(Clone)]
+    /// struct ObserverTest;
+    ///
+    /// impl Observer for ObserverTest {
+    ///     async fn get_password(
+    ///         &self,
+    ///         addr: &SessionAddr,
+    ///         username: &str,
+    ///     ) -> Option<String> {
+    ///         Some("test".to_string())
+    ///     }
+    /// }
+    ///
+    /// let addr = |address: &str| SessionAddr {
+    ///     address: address.parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
+    ///
+    /// let sessions = Sessions::new(ObserverTest);
+    /// for it in ["127.0.0.1:8080", "127.0.0.2:8080"] {
+    ///     pollster::block_on(sessions.get_digest(&addr(it), "test", "test"));
+    ///     sessions.allocate(&addr(it)).unwrap();
+    /// }
+    ///
+    /// let nonce = sessions.get_nonce(&addr("127.0.0.1:8080")).get_ref().unwrap().0.clone();
+    /// let moved = addr("127.0.0.1:9090");
+    ///
+    /// assert_eq!(sessions.find_moved(&moved, "test", &nonce), Some(addr("127.0.0.1:8080")));
+    /// assert_eq!(sessions.find_moved(&addr("127.0.0.3:9090"), "test", &nonce), Some(addr("127.0.0.1:8080")));
+    /// assert_eq!(sessions.find_moved(&moved, "other", &nonce), None);
+    /// assert_eq!(sessions.find_moved(&moved, "test", "unknown"), None);
+    /// assert_eq!(sessions.find_moved(&addr("127.0.0.1:8080"), "test", &nonce), None);
+    /// assert_eq!(sessions.find_moved(&addr("127.0.0.2:8080"), "test", &nonce), None);
+    /// ```
+    pub fn find_moved(
+        &self,
+        addr: &SessionAddr,
+        username: &str,
+        nonce: &str,
+    ) -> Option<SessionAddr> {
+        if username.is_empty() {
+            return None;
         }
 
-        // Each peer port must be present.
-        let mut peers = Vec::with_capacity(15);
-        for port in ports {
-            if let Some(it) = port_mapping_table.get(&port) {
-                peers.push((it, *port));
-            } else {
-                return false;
-            }
+        let from = *self.state.nonce_address_table.read().get(nonce)?;
+        if from.interface != addr.interface || from.address == addr.address {
+            return None;
         }
 
-        // Create a port forwarding mapping relationship for each peer session.
-        for (peer, port) in peers {
-            port_relay_table
-                .entry(*peer)
-                .or_insert_with(|| HashMap::with_capacity(20))
-                .insert(
-                    local_port,
-                    Endpoint {
-                        address: addr.address,
-                        endpoint: *endpoint,
-                    },
-                );
-
-            // Do not store the same peer ports to the permission list over and over again.
-            if !session.permissions.contains(&port) {
-                session.permissions.push(port);
-            }
+        let sessions = self.state.sessions.read();
+        if sessions
+            .get(addr)
+            .is_some_and(|it| it.allocate.port.is_some())
+        {
+            return None;
         }
 
-        true
+        sessions
+            .get(&from)
+            .is_some_and(|it| it.allocate.port.is_some() && it.auth.username == username)
+            .then_some(from)
     }
 
-    /// Binding a channel to the session.
+    /// Move the allocation of the session to a new address on the same
+    /// interface, with its relay port, permissions, channels and nonce, and
+    /// the forwarding of the peers to it, through the endpoint of the new
+    /// address where the endpoint was the old address, as with a stream
+    /// transport. A session of the new address that is not allocated is
+    /// replaced. Returns `false` if the session has no allocation or the new
+    /// address already has one.
     ///
     /// # Test
     ///
     /// ```
-    /// use mycrl_turn::*;
+    /// use mycrl_turn::{sessions::Counters, *};
     ///
     /// #[derive(Clone)]
     /// struct ObserverTest;
@@ -635,139 +2846,162 @@ impl<T: Observer + 'static> Sessions<T> {
     ///         addr: &SessionAddr,
     ///         username: &str,
     ///     ) -> Option<String> {
-    ///         if username == "test" {
-    ///             Some("test".to_string())
-    ///         } else {
-    ///             None
-    ///         }
+    ///         Some("test".to_string())
     ///     }
     /// }
     ///
     /// let endpoint = "127.0.0.1:3478".parse().unwrap();
-    /// let addr = SessionAddr {
-    ///     address: "127.0.0.1:8080".parse().unwrap(),
+    /// let addr = |address: &str| SessionAddr {
+    ///     address: address.parse().unwrap(),
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let peer_addr = SessionAddr {
-    ///     address: "127.0.0.1:8081".parse().unwrap(),
-    ///     interface: "127.0.0.1:3478".parse().unwrap(),
-    /// };
-    ///
-    /// let digest = [
-    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
-    ///     239,
-    /// ];
-    ///
+    /// let (from, to, peer) = (addr("127.0.0.1:8080"), addr("127.0.0.1:9090"), addr("127.0.0.1:8081"));
     /// let sessions = Sessions::new(ObserverTest);
     ///
-    /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
-    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&from, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer, "test", "test"));
     ///
-    /// let port = sessions.allocate(&addr).unwrap();
-    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_session(&addr)
-    ///         .get_ref()
-    ///         .unwrap()
-    ///         .allocate
-    ///         .channels
-    ///         .len(),
-    ///     0
-    /// );
+    /// let port = sessions.allocate(&from).unwrap();
+    /// let peer_port = sessions.allocate(&peer).unwrap();
+    /// assert!(sessions.bind_channel(&from, &endpoint, peer_port, 0x4000));
+    /// assert!(sessions.bind_channel(&peer, &endpoint, port, 0x4001));
     ///
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_session(&peer_addr)
-    ///         .get_ref()
-    ///         .unwrap()
-    ///         .allocate
-    ///         .channels
-    ///         .len(),
-    ///     0
-    /// );
+    /// // As with a stream transport, the endpoint of the session is its address.
+    /// assert!(sessions.create_permission(&from, &from.address, &[peer_port]));
+    /// let counters = sessions.counters();
     ///
-    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
-    /// assert!(sessions.bind_channel(&peer_addr, &endpoint, port, 0x4000));
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_session(&addr)
-    ///         .get_ref()
-    ///         .unwrap()
-    ///         .allocate
-    ///         .channels,
-    ///     vec![0x4000]
-    /// );
+    /// pollster::block_on(sessions.get_digest(&to, "test", "test"));
+    /// for it in [&from, &to, &peer] {
+    ///     assert!(sessions.get_nonce(it).get_ref().is_some());
+    /// }
     ///
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_session(&peer_addr)
-    ///         .get_ref()
-    ///         .unwrap()
-    ///         .allocate
-    ///         .channels,
-    ///     vec![0x4000]
-    /// );
+    /// let nonce = sessions.get_nonce(&from).get_ref().unwrap().0.clone();
+    ///
+    /// assert!(!sessions.rebind(&to, &from));
+    /// assert!(sessions.rebind(&from, &to));
+    /// assert!(!sessions.rebind(&from, &to));
+    ///
+    /// assert!(sessions.get_session(&from).get_ref().is_none());
+    /// assert_eq!(sessions.get_session(&to).get_ref().unwrap().allocate.port, Some(port));
+    /// assert_eq!(sessions.get_port_session(port), Some(to));
+    /// assert_eq!(sessions.get_relay_address(&peer, port).unwrap().address, to.address);
+    /// assert_eq!(sessions.get_relay_address(&peer, port).unwrap().endpoint, to.address);
+    /// assert_eq!(sessions.get_relay_address(&to, peer_port).unwrap().address, peer.address);
+    /// assert_eq!(sessions.get_channel_relay_address(&peer, 0x4000).unwrap().address, to.address);
+    /// assert_eq!(sessions.get_channel_relay_address(&to, 0x4001).unwrap().address, peer.address);
+    /// assert_eq!(sessions.counters(), Counters { nonces: 2, ..counters });
+    /// assert_eq!(sessions.get_nonce(&to).get_ref().unwrap().0, nonce);
+    /// assert_eq!(sessions.find_moved(&addr("127.0.0.1:7070"), "test", &nonce), Some(to));
+    ///
+    /// // Nothing is left of the session when the moved allocation is closed.
+    /// assert!(sessions.remove_session(&to, sessions::CloseReason::Removed));
+    /// assert!(sessions.remove_session(&peer, sessions::CloseReason::Removed));
+    /// assert_eq!(sessions.counters(), Counters { nonces: 0, ..Default::default() });
     /// ```
-    pub fn bind_channel(
-        &self,
-        addr: &SessionAddr,
-        endpoint: &SocketAddr,
-        port: u16,
-        channel: u16,
-    ) -> bool {
-        // Finds the address of the bound opposing port.
-        let peer = if let Some(it) = self.state.port_mapping_table.read().get(&port) {
-            *it
-        } else {
+    pub fn rebind(&self, from: &SessionAddr, to: &SessionAddr) -> bool {
+        if from.interface != to.interface || from == to {
             return false;
-        };
+        }
 
-        // Records the channel used for the current session.
         {
-            let mut lock = self.state.sessions.write();
-            let session = if let Some(it) = lock.get_mut(addr) {
-                it
-            } else {
+            let mut sessions = self.state.sessions.write();
+            let mut port_mapping_table = self.state.port_mapping_table.write();
+            let mut port_relay_table = self.state.port_relay_table.write();
+            let mut channel_relay_table = self.state.channel_relay_table.write();
+
+            if sessions
+                .get(to)
+                .is_some_and(|it| it.allocate.port.is_some())
+            {
                 return false;
-            };
+            }
 
-            if !session.allocate.channels.contains(&channel) {
-                session.allocate.channels.push(channel);
-            } else {
+            let Some(port) = sessions.get(from).and_then(|it| it.allocate.port) else {
                 return false;
+            };
+
+            if let Some(session) = sessions.remove(from) {
+                sessions.insert(*to, session);
+            }
+
+            if let Some(it) = port_mapping_table.get_mut(&port) {
+                it.session = *to;
+            }
+
+            // The forwarding of the session to its peers moves with it, and the
+            // peers forward the data sent to the port to the new address.
+            if let Some(relays) = port_relay_table.remove(from) {
+                port_relay_table.insert(*to, relays);
+            }
+
+            for relays in port_relay_table.values_mut() {
+                if let Some(it) = relays.get_mut(&port) {
+                    if it.address == from.address {
+                        it.address = to.address;
+                        if it.endpoint == from.address {
+                            it.endpoint = to.address;
+                        }
+                    }
+                }
+            }
+
+            if let Some(relays) = channel_relay_table.remove(from) {
+                channel_relay_table.insert(*to, relays);
+            }
+
+            for relay in channel_relay_table
+                .values_mut()
+                .flat_map(|it| it.values_mut())
+            {
+                if relay.session == *from {
+                    relay.session = *to;
+                    relay.endpoint.address = to.address;
+                    if relay.endpoint.endpoint == from.address {
+                        relay.endpoint.endpoint = to.address;
+                    }
+                }
             }
         }
 
-        // Binding ports also creates permissions.
-        if !self.create_permission(addr, endpoint, &[port]) {
-            return false;
+        // The client keeps the nonce that it authenticated the request with.
+        let mut address_nonce_tanle = self.state.address_nonce_tanle.write();
+        let mut nonce_address_table = self.state.nonce_address_table.write();
+        if let Some((nonce, _)) = address_nonce_tanle.remove(to) {
+            if nonce_address_table.get(&nonce) == Some(to) {
+                nonce_address_table.remove(&nonce);
+            }
         }
 
-        // Create channel forwarding mapping relationships for peers.
-        self.state
-            .channel_relay_table
-            .write()
-            .entry(peer)
-            .or_insert_with(|| HashMap::with_capacity(10))
-            .insert(
-                channel,
-                Endpoint {
-                    address: addr.address,
-                    endpoint: *endpoint,
-                },
-            );
+        if let Some(nonce) = address_nonce_tanle.remove(from) {
+            nonce_address_table.insert(nonce.0.clone(), *to);
+            address_nonce_tanle.insert(*to, nonce);
+        }
 
         true
     }
 
-    /// Gets the peer of the current session bound channel.
+    /// Record the transaction id of the allocate request that allocated the
+    /// port of the session, and the transport of the listener it was received
+    /// on.
+    pub(crate) fn set_allocate_origin(
+        &self,
+        addr: &SessionAddr,
+        token: &[u8],
+        ingress: IngressTransport,
+    ) {
+        if let Some(session) = self.state.sessions.write().get_mut(addr) {
+            session.allocate.token = token.try_into().ok();
+            session.allocate.ingress = Some(ingress);
+        }
+    }
+
+    /// Close all sessions of the user, returns the number of closed sessions.
     ///
     /// # Test
     ///
     /// ```
-    /// use mycrl_turn::*;
+    /// use mycrl_turn::{sessions::CloseReason, *};
     ///
     /// #[derive(Clone)]
     /// struct ObserverTest;
@@ -778,74 +3012,67 @@ impl<T: Observer + 'static> Sessions<T> {
     ///         addr: &SessionAddr,
     ///         username: &str,
     ///     ) -> Option<String> {
-    ///         if username == "test" {
-    ///             Some("test".to_string())
-    ///         } else {
-    ///             None
-    ///         }
+    ///         Some("test".to_string())
     ///     }
     /// }
     ///
-    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
     /// let addr = SessionAddr {
     ///     address: "127.0.0.1:8080".parse().unwrap(),
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let peer_addr = SessionAddr {
+    /// let other_addr = SessionAddr {
     ///     address: "127.0.0.1:8081".parse().unwrap(),
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let digest = [
-    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
-    ///     239,
-    /// ];
+    /// let third_addr = SessionAddr {
+    ///     address: "127.0.0.1:8082".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
     ///
     /// let sessions = Sessions::new(ObserverTest);
     ///
     /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
-    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
-    ///
-    /// let port = sessions.allocate(&addr).unwrap();
-    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
-    ///
-    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
-    /// assert!(sessions.bind_channel(&peer_addr, &endpoint, port, 0x4000));
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_channel_relay_address(&addr, 0x4000)
-    ///         .unwrap()
-    ///         .endpoint,
-    ///     endpoint
-    /// );
+    /// pollster::block_on(sessions.get_digest(&other_addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&third_addr, "other", "test"));
     ///
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_channel_relay_address(&peer_addr, 0x4000)
-    ///         .unwrap()
-    ///         .endpoint,
-    ///     endpoint
-    /// );
+    /// assert_eq!(sessions.remove_user("test", CloseReason::Removed), 2);
+    /// assert!(sessions.get_session(&addr).get_ref().is_none());
+    /// assert!(sessions.get_session(&other_addr).get_ref().is_none());
+    /// assert!(sessions.get_session(&third_addr).get_ref().is_some());
     /// ```
-    pub fn get_channel_relay_address(&self, addr: &SessionAddr, channel: u16) -> Option<Endpoint> {
-        self.state
-            .channel_relay_table
+    pub fn remove_user(&self, username: &str, reason: CloseReason) -> usize {
+        let addrs = self
+            .state
+            .sessions
             .read()
-            .get(&addr)?
-            .get(&channel)
-            .copied()
+            .iter()
+            .filter(|(_, v)| v.auth.username == username)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+
+        if !addrs.is_empty() {
+            self.remove_sessions(&addrs, reason);
+            self.remove_nonces(&addrs);
+        }
+
+        addrs.len()
     }
 
-    /// Get the address of the port binding.
+    /// Check the cached credentials of all sessions against the observer
+    /// again, and remove the sessions whose password is no longer returned by
+    /// the observer. Returns the number of removed sessions.
     ///
     /// # Test
     ///
     /// ```
+    /// use std::sync::{Arc, Mutex};
+    ///
     /// use mycrl_turn::*;
     ///
     /// #[derive(Clone)]
-    /// struct ObserverTest;
+    /// struct ObserverTest(Arc<Mutex<Option<String>>>);
     ///
     /// impl Observer for ObserverTest {
     ///     async fn get_password(
@@ -853,67 +3080,60 @@ impl<T: Observer + 'static> Sessions<T> {
     ///         addr: &SessionAddr,
     ///         username: &str,
     ///     ) -> Option<String> {
-    ///         if username == "test" {
-    ///             Some("test".to_string())
-    ///         } else {
-    ///             None
-    ///         }
+    ///         self.0.lock().unwrap().clone()
     ///     }
     /// }
     ///
-    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
     /// let addr = SessionAddr {
     ///     address: "127.0.0.1:8080".parse().unwrap(),
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let peer_addr = SessionAddr {
-    ///     address: "127.0.0.1:8081".parse().unwrap(),
-    ///     interface: "127.0.0.1:3478".parse().unwrap(),
-    /// };
-    ///
-    /// let digest = [
-    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
-    ///     239,
-    /// ];
-    ///
-    /// let sessions = Sessions::new(ObserverTest);
+    /// let password = Arc::new(Mutex::new(Some("test".to_string())));
+    /// let sessions = Sessions::new(ObserverTest(password.clone()));
     ///
     /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
-    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
-    ///
-    /// let port = sessions.allocate(&addr).unwrap();
-    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
-    ///
-    /// assert!(sessions.create_permission(&addr, &endpoint, &[peer_port]));
-    /// assert!(sessions.create_permission(&peer_addr, &endpoint, &[port]));
-    ///
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_relay_address(&addr, peer_port)
-    ///         .unwrap()
-    ///         .endpoint,
-    ///     endpoint
-    /// );
+    /// assert_eq!(pollster::block_on(sessions.revalidate()), 0);
     ///
-    /// assert_eq!(
-    ///     sessions
-    ///         .get_relay_address(&peer_addr, port)
-    ///         .unwrap()
-    ///         .endpoint,
-    ///     endpoint
-    /// );
+    /// password.lock().unwrap().replace("other".to_string());
+    /// assert_eq!(pollster::block_on(sessions.revalidate()), 1);
+    /// assert!(sessions.get_session(&addr).get_ref().is_none());
     /// ```
-    pub fn get_relay_address(&self, addr: &SessionAddr, port: u16) -> Option<Endpoint> {
-        self.state
-            .port_relay_table
+    pub async fn revalidate(&self) -> usize {
+        let sessions = self
+            .state
+            .sessions
             .read()
-            .get(&addr)?
-            .get(&port)
-            .copied()
+            .iter()
+            .map(|(k, v)| (*k, v.auth.username.clone(), v.auth.password.clone()))
+            .collect::<Vec<_>>();
+
+        let mut addrs = Vec::new();
+        for (addr, username, password) in sessions {
+            if self
+                .observer
+                .get_credential(&addr, &username)
+                .await
+                .map(|it| it.password)
+                .as_ref()
+                != Some(&password)
+            {
+                addrs.push(addr);
+            }
+        }
+
+        if !addrs.is_empty() {
+            self.remove_sessions(&addrs, CloseReason::Revoked);
+            self.remove_nonces(&addrs);
+        }
+
+        addrs.len()
     }
 
-    /// Refresh the session for addr.
+    /// Iterate over a snapshot of all sessions.
+    ///
+    /// The snapshot is taken when this method is called, so the lock is not
+    /// held while iterating.
     ///
     /// # Test
     ///
@@ -929,58 +3149,126 @@ impl<T: Observer + 'static> Sessions<T> {
     ///         addr: &SessionAddr,
     ///         username: &str,
     ///     ) -> Option<String> {
-    ///         if username == "test" {
-    ///             Some("test".to_string())
-    ///         } else {
-    ///             None
-    ///         }
+    ///         Some("test".to_string())
     ///     }
     /// }
     ///
+    /// let endpoint = "127.0.0.1:3478".parse().unwrap();
     /// let addr = SessionAddr {
     ///     address: "127.0.0.1:8080".parse().unwrap(),
     ///     interface: "127.0.0.1:3478".parse().unwrap(),
     /// };
     ///
-    /// let digest = [
-    ///     174, 238, 187, 253, 117, 209, 73, 157, 36, 56, 143, 91, 155, 16, 224,
-    ///     239,
-    /// ];
+    /// let peer_addr = SessionAddr {
+    ///     address: "127.0.0.1:8081".parse().unwrap(),
+    ///     interface: "127.0.0.1:3478".parse().unwrap(),
+    /// };
     ///
     /// let sessions = Sessions::new(ObserverTest);
     ///
-    /// assert!(sessions.get_session(&addr).get_ref().is_none());
-    ///
     /// pollster::block_on(sessions.get_digest(&addr, "test", "test"));
+    /// pollster::block_on(sessions.get_digest(&peer_addr, "test", "test"));
     ///
-    /// let expires = sessions.get_session(&addr).get_ref().unwrap().expires;
-    /// assert!(expires == 600 || expires == 601 || expires == 602);
+    /// sessions.allocate(&addr).unwrap();
+    /// let peer_port = sessions.allocate(&peer_addr).unwrap();
     ///
-    /// assert!(sessions.refresh(&addr, 0));
+    /// assert!(sessions.bind_channel(&addr, &endpoint, peer_port, 0x4000));
     ///
-    /// assert!(sessions.get_session(&addr).get_ref().is_none());
+    /// assert_eq!(sessions.iter_sessions().count(), 2);
+    /// assert_eq!(
+    ///     sessions.iter_channels().collect::<Vec<_>>(),
+    ///     vec![(addr, 0x4000)]
+    /// );
+    ///
+    /// assert_eq!(
+    ///     sessions.iter_permissions().collect::<Vec<_>>(),
+    ///     vec![(addr, peer_port)]
+    /// );
     /// ```
-    pub fn refresh(&self, addr: &SessionAddr, lifetime: u32) -> bool {
-        if lifetime > 3600 {
-            return false;
+    pub fn iter_sessions(&self) -> impl Iterator<Item = (SessionAddr, Session)> {
+        self.state
+            .sessions
+            .read()
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Iterate over a snapshot of the channels bound by each session.
+    pub fn iter_channels(&self) -> impl Iterator<Item = (SessionAddr, u16)> {
+        self.state
+            .sessions
+            .read()
+            .iter()
+            .flat_map(|(k, v)| v.allocate.channels.iter().map(|it| (*k, *it)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Iterate over a snapshot of the peer ports that each session has
+    /// permissions for.
+    pub fn iter_permissions(&self) -> impl Iterator<Item = (SessionAddr, u16)> {
+        self.state
+            .sessions
+            .read()
+            .iter()
+            .flat_map(|(k, v)| v.permissions.iter().map(|it| (*k, it.port)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+// Remove the references to the relay port of the session that is closed or
+// released, so that nothing is relayed between its peers and the next session
+// that the port is allocated to. The permissions and the channels of the
+// session are recorded on the peers, and the permissions and the channels that
+// the peers created for the port are recorded on the session.
+#[allow(clippy::too_many_arguments)]
+fn detach_relay_port(
+    addr: &SessionAddr,
+    port: u16,
+    permissions: &[Permission],
+    channels: &[u16],
+    relays: Option<HashMap<u16, Endpoint>>,
+    channel_relays: Option<HashMap<u16, ChannelRelay>>,
+    sessions: &mut Table<SessionAddr, Session>,
+    port_mapping_table: &Table<u16, RelayPort>,
+    port_relay_table: &mut Table<SessionAddr, HashMap<u16, Endpoint>>,
+    channel_relay_table: &mut Table<SessionAddr, HashMap<u16, ChannelRelay>>,
+) {
+    for permission in permissions {
+        let Some(peer) = port_mapping_table.get(&permission.port) else {
+            continue;
+        };
+
+        if let Some(relays) = port_relay_table.get_mut(&peer.session) {
+            relays.remove(&port);
         }
 
-        if lifetime == 0 {
-            self.remove_session(&[*addr]);
-            self.remove_nonce(&[*addr]);
-        } else {
-            if let Some(session) = self.state.sessions.write().get_mut(addr) {
-                session.expires = self.timer.get() + lifetime as u64;
-            } else {
-                return false;
-            }
+        if let Some(relays) = channel_relay_table.get_mut(&peer.session) {
+            relays.retain(|channel, it| !(it.session == *addr && channels.contains(channel)));
+        }
+    }
 
-            if let Some(nonce) = self.state.address_nonce_tanle.write().get_mut(addr) {
-                nonce.1 = self.timer.get() + lifetime as u64;
-            }
+    for (peer_port, endpoint) in relays.into_iter().flatten() {
+        let Some(peer) = port_mapping_table.get(&peer_port) else {
+            continue;
+        };
+
+        if peer.session.address != endpoint.address {
+            continue;
         }
 
-        true
+        if let Some(it) = sessions.get_mut(&peer.session) {
+            it.permissions.retain(|it| it.port != port);
+        }
+    }
+
+    for (channel, relay) in channel_relays.into_iter().flatten() {
+        if let Some(it) = sessions.get_mut(&relay.session) {
+            it.allocate.channels.retain(|it| *it != channel);
+        }
     }
 }
 
@@ -993,7 +3281,7 @@ pub struct Table<K, V>(HashMap<K, V>);
 
 impl<K, V> Default for Table<K, V> {
     fn default() -> Self {
-        Self(HashMap::with_capacity(PortAllocatePools::capacity()))
+        Self(HashMap::with_capacity(DEFAULT_PORT_RANGE.len()))
     }
 }
 
@@ -1034,12 +3322,11 @@ where
     }
 }
 
-/// Bit Flag
-#[derive(PartialEq, Eq)]
-pub enum Bit {
-    Low,
-    High,
-}
+/// The default range of the relay ports.
+///
+/// The server SHOULD only allocate ports from the range 49152 - 65535 (the
+/// Dynamic and/or Private Port range).
+pub const DEFAULT_PORT_RANGE: Range<u16> = 49152..65535;
 
 /// Random Port
 ///
@@ -1068,76 +3355,99 @@ pub enum Bit {
 /// While the server IP address, the well-known port, and the client IP
 /// address may be known by an attacker, the ephemeral port of the client
 /// is usually unknown and must be guessed.
+///
+/// The pool keeps a bitmap of the allocated ports and a list of the free
+/// ports, a free port is picked at random from the list, so both allocation
+/// and release are O(1) and never scan the range while the lock is held.
 pub struct PortAllocatePools {
-    pub buckets: Vec<u64>,
-    allocated: usize,
-    bit_len: u32,
-    peak: usize,
+    range: Range<u16>,
+    // One bit for each port in the range, a high bit means that the port is
+    // allocated.
+    bitmap: Vec<u64>,
+    // The free ports, in no particular order.
+    free: Vec<u16>,
 }
 
 impl Default for PortAllocatePools {
     fn default() -> Self {
-        Self {
-            buckets: vec![0; Self::bucket_size()],
-            peak: Self::bucket_size() - 1,
-            bit_len: Self::bit_len(),
-            allocated: 0,
-        }
+        Self::new(DEFAULT_PORT_RANGE)
     }
 }
 
 impl PortAllocatePools {
-    /// compute bucket size.
+    /// Create a port pool for the range.
     ///
     /// # Test
     ///
     /// ```
     /// use mycrl_turn::sessions::*;
     ///
-    /// assert_eq!(PortAllocatePools::bucket_size(), 256);
+    /// let pool = PortAllocatePools::new(50000..50010);
+    /// assert_eq!(pool.capacity(), 10);
+    /// assert_eq!(pool.port_range(), 50000..50010);
     /// ```
-    pub fn bucket_size() -> usize {
-        (Self::capacity() as f32 / 64.0).ceil() as usize
+    pub fn new(range: Range<u16>) -> Self {
+        Self {
+            bitmap: vec![0; range.len().div_ceil(64)],
+            free: range.clone().collect(),
+            range,
+        }
     }
 
-    /// compute bucket last bit max offset.
+    /// get pools capacity.
     ///
     /// # Test
     ///
     /// ```
-    /// use mycrl_turn::sessions::*;
+    /// use mycrl_turn::sessions::PortAllocatePools;
     ///
-    /// assert_eq!(PortAllocatePools::bit_len(), 63);
+    /// assert_eq!(PortAllocatePools::default().capacity(), 65535 - 49152);
     /// ```
-    pub fn bit_len() -> u32 {
-        (Self::capacity() as f32 % 64.0).ceil() as u32
+    pub fn capacity(&self) -> usize {
+        self.range.len()
     }
 
-    /// get pools capacity.
+    /// get port range.
     ///
     /// # Test
     ///
     /// ```
-    /// use mycrl_turn::sessions::Bit;
-    /// use mycrl_turn::sessions::PortAllocatePools;
+    /// use mycrl_turn::sessions::*;
     ///
-    /// assert_eq!(PortAllocatePools::capacity(), 65535 - 49152);
+    /// assert_eq!(PortAllocatePools::default().port_range(), 49152..65535);
     /// ```
-    pub const fn capacity() -> usize {
-        65535 - 49152
+    pub fn port_range(&self) -> Range<u16> {
+        self.range.clone()
     }
 
-    /// get port range.
+    /// get pools allocated size, the same as `len`.
     ///
-    /// # Test
+    /// ```
+    /// use mycrl_turn::sessions::PortAllocatePools;
+    ///
+    /// let mut pools = PortAllocatePools::default();
+    /// assert_eq!(pools.used(), 0);
     ///
+    /// pools.alloc().unwrap();
+    /// assert_eq!(pools.used(), 1);
     /// ```
-    /// use mycrl_turn::sessions::*;
+    pub fn used(&self) -> usize {
+        self.capacity() - self.free.len()
+    }
+
+    /// get the number of ports that can still be allocated.
+    ///
+    /// ```
+    /// use mycrl_turn::sessions::PortAllocatePools;
+    ///
+    /// let mut pools = PortAllocatePools::default();
+    /// assert_eq!(pools.available(), pools.capacity());
     ///
-    /// assert_eq!(PortAllocatePools::port_range(), 49152..65535);
+    /// pools.alloc().unwrap();
+    /// assert_eq!(pools.available(), pools.capacity() - 1);
     /// ```
-    pub const fn port_range() -> Range<u16> {
-        49152..65535
+    pub fn available(&self) -> usize {
+        self.free.len()
     }
 
     /// get pools allocated size.
@@ -1148,11 +3458,11 @@ impl PortAllocatePools {
     /// let mut pools = PortAllocatePools::default();
     /// assert_eq!(pools.len(), 0);
     ///
-    /// pools.alloc(None).unwrap();
+    /// pools.alloc().unwrap();
     /// assert_eq!(pools.len(), 1);
     /// ```
     pub fn len(&self) -> usize {
-        self.allocated
+        self.used()
     }
 
     /// get pools allocated size is empty.
@@ -1165,10 +3475,10 @@ impl PortAllocatePools {
     /// assert_eq!(pools.is_empty(), true);
     /// ```
     pub fn is_empty(&self) -> bool {
-        self.allocated == 0
+        self.len() == 0
     }
 
-    /// random assign a port.
+    /// check if the port is allocated.
     ///
     /// # Test
     ///
@@ -1176,142 +3486,90 @@ impl PortAllocatePools {
     /// use mycrl_turn::sessions::PortAllocatePools;
     ///
     /// let mut pool = PortAllocatePools::default();
+    /// let port = pool.alloc().unwrap();
     ///
-    /// assert_eq!(pool.alloc(Some(0)), Some(49152));
-    /// assert_eq!(pool.alloc(Some(0)), Some(49153));
-    ///
-    /// assert!(pool.alloc(None).is_some());
+    /// assert!(pool.is_allocated(port));
+    /// assert!(!pool.is_allocated(80));
     /// ```
-    pub fn alloc(&mut self, start_index: Option<usize>) -> Option<u16> {
-        let mut index = None;
-        let mut start =
-            start_index.unwrap_or_else(|| thread_rng().gen_range(0..self.peak as u16) as usize);
-
-        // When the partition lookup has gone through the entire partition list, the
-        // lookup should be stopped, and the location where it should be stopped is
-        // recorded here.
-        let previous = if start == 0 { self.peak } else { start - 1 };
-
-        loop {
-            // Finds the first high position in the partition.
-            if let Some(i) = {
-                let bucket = self.buckets[start];
-                let offset = if bucket < u64::MAX {
-                    bucket.leading_ones()
-                } else {
-                    return None;
-                };
-
-                // Check to see if the jump is beyond the partition list or the lookup exceeds
-                // the maximum length of the allocation table.
-                if start == self.peak && offset > self.bit_len {
-                    return None;
-                }
-
-                Some(offset)
-            } {
-                index = Some(i as usize);
-                break;
-            }
-
-            // As long as it doesn't find it, it continues to re-find it from the next
-            // partition.
-            if start == self.peak {
-                start = 0;
-            } else {
-                start += 1;
-            }
-
-            // Already gone through all partitions, lookup failed.
-            if start == previous {
-                break;
-            }
-        }
-
-        // Writes to the partition, marking the current location as already allocated.
-        let index = index?;
-        self.set_bit(start, index, Bit::High);
-        self.allocated += 1;
+    pub fn is_allocated(&self, port: u16) -> bool {
+        let (bucket, mask) = match self.position(port) {
+            Some(it) => it,
+            None => return false,
+        };
 
-        // The actual port number is calculated from the partition offset position.
-        let num = (start * 64 + index) as u16;
-        let port = Self::port_range().start + num;
-        Some(port)
+        self.bitmap[bucket] & mask != 0
     }
 
-    /// write bit flag in the bucket.
+    /// random assign a port.
     ///
     /// # Test
     ///
     /// ```
-    /// use mycrl_turn::sessions::Bit;
     /// use mycrl_turn::sessions::PortAllocatePools;
     ///
-    /// let mut pool = PortAllocatePools::default();
-    ///
-    /// assert_eq!(pool.alloc(Some(0)), Some(49152));
-    /// assert_eq!(pool.alloc(Some(0)), Some(49153));
+    /// let mut pool = PortAllocatePools::new(50000..50002);
     ///
-    /// pool.set_bit(0, 0, Bit::High);
-    /// pool.set_bit(0, 1, Bit::High);
+    /// let mut ports = vec![pool.alloc().unwrap(), pool.alloc().unwrap()];
+    /// ports.sort();
     ///
-    /// assert_eq!(pool.alloc(Some(0)), Some(49154));
-    /// assert_eq!(pool.alloc(Some(0)), Some(49155));
+    /// assert_eq!(ports, vec![50000, 50001]);
+    /// assert_eq!(pool.alloc(), None);
     /// ```
-    pub fn set_bit(&mut self, bucket: usize, index: usize, bit: Bit) {
-        let high_mask = 1 << (63 - index);
-        let mask = match bit {
-            Bit::Low => u64::MAX ^ high_mask,
-            Bit::High => high_mask,
-        };
+    pub fn alloc(&mut self) -> Option<u16> {
+        if self.free.is_empty() {
+            return None;
+        }
 
-        let value = self.buckets[bucket];
-        self.buckets[bucket] = match bit {
-            Bit::High => value | mask,
-            Bit::Low => value & mask,
-        };
+        // Removing an item from the middle of the free list is O(1) with
+        // `swap_remove`, as the order of the free list does not matter.
+        let index = thread_rng().gen_range(0..self.free.len());
+        let port = self.free.swap_remove(index);
+
+        let (bucket, mask) = self.position(port)?;
+        self.bitmap[bucket] |= mask;
+        Some(port)
     }
 
-    /// restore port in the buckets.
+    /// restore port in the pool.
+    ///
+    /// Ports that are not allocated or are not in the range are ignored.
     ///
     /// # Test
     ///
     /// ```
     /// use mycrl_turn::sessions::PortAllocatePools;
     ///
-    /// let mut pool = PortAllocatePools::default();
-    ///
-    /// assert_eq!(pool.alloc(Some(0)), Some(49152));
-    /// assert_eq!(pool.alloc(Some(0)), Some(49153));
+    /// let mut pool = PortAllocatePools::new(50000..50001);
+    /// assert_eq!(pool.alloc(), Some(50000));
+    /// assert_eq!(pool.alloc(), None);
     ///
-    /// pool.restore(49152);
-    /// pool.restore(49153);
+    /// pool.restore(50000);
+    /// pool.restore(50000);
+    /// assert_eq!(pool.available(), 1);
     ///
-    /// assert_eq!(pool.alloc(Some(0)), Some(49152));
-    /// assert_eq!(pool.alloc(Some(0)), Some(49153));
+    /// assert_eq!(pool.alloc(), Some(50000));
     /// ```
     pub fn restore(&mut self, port: u16) {
-        assert!(Self::port_range().contains(&port));
-
-        // Calculate the location in the partition from the port number.
-        let offset = (port - Self::port_range().start) as usize;
-        let bucket = offset / 64;
-        let index = offset - (bucket * 64);
-
-        // Gets the bit value in the port position in the partition, if it is low, no
-        // processing is required.
-        if {
-            match (self.buckets[bucket] & (1 << (63 - index))) >> (63 - index) {
-                0 => Bit::Low,
-                1 => Bit::High,
-                _ => panic!(),
-            }
-        } == Bit::Low
-        {
+        let (bucket, mask) = match self.position(port) {
+            Some(it) => it,
+            None => return,
+        };
+
+        if self.bitmap[bucket] & mask == 0 {
             return;
         }
 
-        self.set_bit(bucket, index, Bit::Low);
-        self.allocated -= 1;
+        self.bitmap[bucket] &= !mask;
+        self.free.push(port);
+    }
+
+    // Calculate the bucket and the bit mask of the port in the bitmap.
+    fn position(&self, port: u16) -> Option<(usize, u64)> {
+        if !self.range.contains(&port) {
+            return None;
+        }
+
+        let offset = (port - self.range.start) as usize;
+        Some((offset / 64, 1 << (offset % 64)))
     }
 }